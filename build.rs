@@ -0,0 +1,21 @@
+//! Copies the initial ramdisk named by `POPOEN_INITRD` (a USTAR or newc
+//! cpio archive, relative to this directory) to where the kernel includes it
+//! from. Without it the kernel gets an empty file and boots without one.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo")).join("initrd");
+    println!("cargo:rerun-if-env-changed=POPOEN_INITRD");
+    match env::var_os("POPOEN_INITRD") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", PathBuf::from(&path).display());
+            if let Err(e) = fs::copy(&path, &out) {
+                panic!("cannot read initrd {}: {}", PathBuf::from(&path).display(), e);
+            }
+        }
+        None => fs::write(&out, []).expect("cannot write to OUT_DIR"),
+    }
+}
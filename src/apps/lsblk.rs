@@ -0,0 +1,34 @@
+use crate::driver::disk;
+use crate::{poprint, VFS};
+
+/// Prints `bytes` as a size with a binary unit, rounded down
+fn print_size(bytes: u64) {
+    let (value, unit) = match bytes {
+        b if b >= 1 << 30 => (b >> 30, "G"),
+        b if b >= 1 << 20 => (b >> 20, "M"),
+        b if b >= 1 << 10 => (b >> 10, "K"),
+        b => (b, "B"),
+    };
+    poprint!("{:>5}{}", value, unit);
+}
+
+/// Lists the disks, their partitions and where each is mounted
+pub fn lsblk() {
+    poprint!("NAME        SIZE TYPE  PARTTYPE          MOUNTPOINT\n");
+    let vfs = VFS.lock();
+    let res = disk::list(&mut |name, bytes, entry| {
+        poprint!("{:<10} ", name);
+        print_size(bytes);
+        match entry {
+            Some(e) => poprint!(" part  {:<17}", e.kind.name()),
+            None => poprint!(" disk  {:<17}", ""),
+        }
+        for m in vfs.mounts().filter(|m| m.source() == name.as_str()) {
+            poprint!(" {}", m.path());
+        }
+        poprint!("\n");
+    });
+    if let Err(e) = res {
+        poprint!("lsblk: {}\n", e);
+    }
+}
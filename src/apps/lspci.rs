@@ -0,0 +1,139 @@
+use crate::driver::pci::{
+    self, Bar, PciDevice, CAP_EXPRESS, CAP_MSI, CAP_MSIX, CAP_POWER, CAP_VENDOR, REG_PRIMARY_BUS,
+    REG_SECONDARY_BUS, REG_SUBORDINATE_BUS,
+};
+use crate::poprint;
+
+/// Name of a class code, refined by subclass where it is a common one
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0x00, _) => "Unclassified device",
+        (0xFF, _) => "Unassigned class",
+        _ => "Device",
+    }
+}
+
+/// Size with a binary unit, as lspci prints it
+fn print_size(size: u64) {
+    match size {
+        s if s >= 1 << 30 && s % (1 << 30) == 0 => poprint!("{}G", s >> 30),
+        s if s >= 1 << 20 && s % (1 << 20) == 0 => poprint!("{}M", s >> 20),
+        s if s >= 1 << 10 && s % (1 << 10) == 0 => poprint!("{}K", s >> 10),
+        s => poprint!("{}", s),
+    }
+}
+
+fn print_details(dev: &PciDevice) {
+    if let Some(irq) = dev.addr.interrupt_line() {
+        poprint!("\tIRQ {}\n", irq);
+    }
+    if dev.is_bridge() {
+        poprint!(
+            "\tBus: primary={:02x}, secondary={:02x}, subordinate={:02x}\n",
+            dev.addr.read_u8(REG_PRIMARY_BUS),
+            dev.addr.read_u8(REG_SECONDARY_BUS),
+            dev.addr.read_u8(REG_SUBORDINATE_BUS)
+        );
+    }
+    for (i, info) in dev.bars.iter().enumerate() {
+        let Some(info) = info else { continue };
+        match info.bar {
+            Bar::Io(port) => poprint!("\tBAR{}: I/O ports at {:04x}", i, port),
+            Bar::Memory(base) => poprint!(
+                "\tBAR{}: Memory at {:x} ({}-bit, {}prefetchable)",
+                i,
+                base,
+                if info.wide { 64 } else { 32 },
+                if info.prefetchable { "" } else { "non-" }
+            ),
+        }
+        poprint!(" [size=");
+        print_size(info.size);
+        poprint!("]\n");
+    }
+    dev.addr.capabilities(&mut |offset, id| {
+        poprint!("\tCapabilities: [{:02x}] ", offset);
+        match id {
+            CAP_POWER => poprint!("Power Management\n"),
+            CAP_VENDOR => poprint!("Vendor Specific\n"),
+            CAP_EXPRESS => poprint!("Express\n"),
+            CAP_MSI => match dev.addr.msi() {
+                Some(msi) => poprint!(
+                    "MSI: Enable{} Count={} {}-bit{}\n",
+                    if msi.enabled { "+" } else { "-" },
+                    msi.vectors,
+                    if msi.wide { 64 } else { 32 },
+                    if msi.maskable { " Maskable" } else { "" }
+                ),
+                None => poprint!("MSI\n"),
+            },
+            CAP_MSIX => match dev.addr.msix() {
+                Some(msix) => poprint!(
+                    "MSI-X: Enable{} Count={} Vector table: BAR={} offset={:08x} PBA: BAR={} offset={:08x}\n",
+                    if msix.enabled { "+" } else { "-" },
+                    msix.vectors,
+                    msix.table_bar,
+                    msix.table_offset,
+                    msix.pending_bar,
+                    msix.pending_offset
+                ),
+                None => poprint!("MSI-X\n"),
+            },
+            id => poprint!("ID {:02x}\n", id),
+        }
+    });
+}
+
+/// Lists the functions found on the PCI bus; `verbose` adds BARs, the
+/// interrupt line and capabilities
+pub fn lspci(verbose: bool) {
+    pci::devices(&mut |dev| {
+        poprint!(
+            "{:02x}:{:02x}.{} {} [{:02x}{:02x}]: {:04x}:{:04x}",
+            dev.addr.bus,
+            dev.addr.device,
+            dev.addr.function,
+            class_name(dev.class, dev.subclass),
+            dev.class,
+            dev.subclass,
+            dev.vendor,
+            dev.device
+        );
+        if dev.revision != 0 {
+            poprint!(" (rev {:02x})", dev.revision);
+        }
+        if dev.prog_if != 0 {
+            poprint!(" (prog-if {:02x})", dev.prog_if);
+        }
+        poprint!("\n");
+        if let Some(driver) = dev.driver {
+            poprint!("\tKernel driver in use: {}\n", driver);
+        }
+        if verbose {
+            print_details(dev);
+        }
+    });
+}
@@ -1 +1,3 @@
+pub mod lsblk;
+pub mod lspci;
 pub mod pofetch;
\ No newline at end of file
@@ -1,7 +1,13 @@
-use crate::poprint;
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{Err, Ok};
+
+use alloc::string::String;
+
+use crate::fs::perm::Cred;
+use crate::fs::vfs::O_READ;
+use crate::{poprint, VFS};
 
-const OS: &'static str = "PopoenOS";
-const VER: &'static str = "0.0.1";
 const ASCII_ART: &str = r#"
  /$$$$$$$  /$$$$$$$   /$$$$$$   /$$$$$$ 
 | $$__  $$| $$__  $$ /$$__  $$ /$$__  $$
@@ -13,8 +19,72 @@ const ASCII_ART: &str = r#"
 |__/      |__/       \______/  \______/ 
 "#;
 
-pub fn pofetch() {
+/// The whole of a file under /proc, or None if it cannot be read
+fn read_proc(cred: &Cred, name: &str) -> Option<String> {
+    let mut path = String::from("/proc/");
+    path.push_str(name);
+    let mut vfs = VFS.lock();
+    let fd = vfs.open(cred, &path, O_READ, 0).ok()?;
+    let mut bytes = alloc::vec::Vec::new();
+    let mut buf = [0u8; 256];
+    let res = loop {
+        match vfs.read(fd, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => bytes.extend_from_slice(&buf[..n]),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = vfs.close(fd);
+    res.ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Value of the `key: value` line for `key` in `text`
+fn field<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+/// A meminfo value in kB, as MiB
+fn mib(meminfo: &str, key: &str) -> Option<u64> {
+    let kb = field(meminfo, key)?.trim_end_matches("kB").trim();
+    kb.parse::<u64>().ok().map(|kb| kb / 1024)
+}
+
+pub fn pofetch(cred: &Cred) {
+    poprint!("{}\n", ASCII_ART);
+
+    // "PopoenOS version 0.1.0"
+    let version = read_proc(cred, "version").unwrap_or_default();
+    match version.trim().split_once(" version ") {
+        Some((os, ver)) => poprint!("{} at version {}\n", os, ver),
+        None => poprint!("OS:     unknown\n"),
+    }
+
+    if let Some(cpuinfo) = read_proc(cred, "cpuinfo")
+        && let Some(cpu) = field(&cpuinfo, "model name").or_else(|| field(&cpuinfo, "vendor_id"))
+    {
+        poprint!("CPU:    {}\n", cpu);
+    }
+
+    if let Some(meminfo) = read_proc(cred, "meminfo") {
+        if let (Some(used), Some(total)) = (mib(&meminfo, "MemUsed"), mib(&meminfo, "MemTotal")) {
+            poprint!("Memory: {} MiB / {} MiB\n", used, total);
+        }
+        if let (Some(used), Some(total)) = (mib(&meminfo, "HeapUsed"), mib(&meminfo, "HeapTotal")) {
+            poprint!("Heap:   {} MiB / {} MiB\n", used, total);
+        }
+    }
+
+    if let Some(secs) = read_proc(cred, "uptime").and_then(|t| t.trim().parse::<u32>().ok()) {
+        poprint!("Uptime: {}h {}m {}s\n", secs / 3600, secs / 60 % 60, secs % 60);
+    }
 
-     poprint!("{}\n", ASCII_ART);
-     poprint!("{} at version {}\n", OS, VER);
+    if let Some(status) = read_proc(cred, "self/status")
+        && let Some(name) = field(&status, "Name")
+    {
+        poprint!("Shell:  {}\n", name);
+    }
 }
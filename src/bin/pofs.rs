@@ -0,0 +1,1010 @@
+//! pofs: host-side tool for building and inspecting Popoen disk images.
+//!
+//! Built for the host with `cargo pofs <command> ...` (see `.cargo/config.toml`)
+//! and shares the filesystem code in `src/fs` with the kernel.
+//!
+//! Commands:
+//!   mkfs <image> <blocks>              create and format an image of 512 B blocks
+//!   mkfs <image>:<n> [blocks]          format partition n of an existing image
+//!   parts <image>                      list the partition table
+//!   info <image>                       print the superblock and usage
+//!   ls <image> [-l] [path]             list a directory, -l in long format
+//!   put <image> <host-path> <path>     copy a host file or directory tree in
+//!   get <image> <path> <host-path>     extract a file or directory tree
+//!   mkdir <image> <path>               create a directory
+//!   getfattr <image> [-n name] <path>  print extended attributes
+//!   setfattr <image> -n name [-v value] <path>
+//!   setfattr <image> -x name <path>    set or remove an extended attribute
+//!   fsck <image> [-y]                  check the image, repairing it with -y
+//!   crashtest [-c] [blocks]            cut power at every write of a scripted
+//!                                      workload and check each outcome; -c
+//!                                      runs it through a small block cache
+//!
+//! Wherever a command takes an image, `<image>:<n>` works on partition n of
+//! it instead, as found in its MBR or GPT.
+//!
+//! `cargo pofs-test` runs the crashtest workload, with and without `-c`, as
+//! tests.
+
+extern crate alloc;
+
+use std::env;
+use std::fs as hostfs;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The kernel uses parts of `fs` that the tool does not
+#[allow(dead_code)]
+#[path = "../fs/mod.rs"]
+mod fs;
+
+/// Stand-in for the kernel's VGA `poprint!`, used by the shared `fs` code
+#[macro_export]
+macro_rules! poprint {
+    ($($arg:tt)*) => ({
+        print!($($arg)*);
+    });
+}
+
+use fs::cache::BlockCache;
+use fs::clock::set_clock;
+use fs::error::{FsError, IoError};
+use fs::fsck::fsck;
+use fs::journal::{self, recover, transaction};
+use fs::ops;
+use fs::partition::{read_table, Scheme};
+use fs::perm::Cred;
+use fs::structure::{check_block, BlockDevice, BLOCK_SIZE};
+use fs::utils::{
+    append_file, for_each_entry, free_block_count, ls, mkfs, probe, read_at, read_inode,
+    used_inode_count,
+};
+use fs::xattr;
+
+/// An image file held in memory and written back by `save`. Block
+/// numbers are relative to the partition being worked on, if any.
+struct ImageFile {
+    data: Vec<u8>,
+    start: u32, // first block of the partition, 0 for the whole image
+    count: u32,
+}
+
+/// Splits `<image>:<n>` into the image path and partition number
+fn split_spec(spec: &str) -> (&str, Option<u32>) {
+    match spec.rsplit_once(':') {
+        Some((path, n)) if !path.is_empty() => match n.parse() {
+            Ok(n) => (path, Some(n)),
+            Err(_) => (spec, None),
+        },
+        _ => (spec, None),
+    }
+}
+
+impl ImageFile {
+    fn new(data: Vec<u8>) -> ImageFile {
+        let count = (data.len() / BLOCK_SIZE) as u32;
+        ImageFile { data, start: 0, count }
+    }
+
+    /// Reads the image named by `spec` without checking the filesystem
+    fn load(spec: &str) -> Result<ImageFile, String> {
+        let (path, part) = split_spec(spec);
+        let data = hostfs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if data.len() % BLOCK_SIZE != 0 {
+            return Err(format!("{}: size is not a multiple of {} bytes", path, BLOCK_SIZE));
+        }
+        let mut img = ImageFile::new(data);
+        if let Some(n) = part {
+            let mut found = None;
+            read_table(&img, &mut |e| {
+                if e.number == n {
+                    found = Some(e);
+                }
+            })
+            .map_err(|e| format!("{}: {}", path, e))?;
+            let e = found.ok_or_else(|| format!("{}: no partition {}", path, n))?;
+            img.start = e.start;
+            img.count = e.count;
+        }
+        Ok(img)
+    }
+
+    fn open(spec: &str) -> Result<ImageFile, String> {
+        let mut img = ImageFile::load(spec)?;
+        probe(&img).map_err(|e| format!("{}: {}", spec, e))?;
+        if recover(&mut img).map_err(|e| format!("{}: {}", spec, e))? {
+            eprintln!("pofs: {}: replayed journal", spec);
+        }
+        Ok(img)
+    }
+
+    fn save(&self, spec: &str) -> Result<(), String> {
+        let (path, _) = split_spec(spec);
+        hostfs::write(path, &self.data).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn block_count(&self) -> u32 {
+        self.count
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        let start = (self.start + block_number) as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data[start..start + BLOCK_SIZE]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        let start = (self.start + block_number) as usize * BLOCK_SIZE;
+        self.data[start..start + BLOCK_SIZE].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Formats a new image of `blocks` blocks, or a partition of an existing
+/// one, by default all of it
+fn cmd_mkfs(image: &str, blocks: Option<&str>) -> Result<(), String> {
+    let blocks = match blocks {
+        Some(b) => Some(b.parse::<u32>().map_err(|_| format!("invalid block count '{}'", b))?),
+        None => None,
+    };
+    let mut img = match (split_spec(image).1, blocks) {
+        (Some(_), _) => ImageFile::load(image)?,
+        (None, Some(blocks)) => ImageFile::new(vec![0; blocks as usize * BLOCK_SIZE]),
+        (None, None) => return Err("mkfs: block count needed for a new image".to_string()),
+    };
+    let blocks = blocks.unwrap_or(img.count);
+    mkfs(&mut img, blocks).map_err(|e| format!("mkfs failed: {}", e))?;
+    img.save(image)
+}
+
+/// Prints the partition table of an image
+fn cmd_parts(image: &str) -> Result<(), String> {
+    let img = ImageFile::load(image)?;
+    let mut rows = Vec::new();
+    let scheme = read_table(&img, &mut |e| rows.push(e)).map_err(|e| format!("{}: {}", image, e))?;
+    match scheme {
+        None => println!("{}: no partition table", image),
+        Some(scheme) => {
+            println!("{} table", if scheme == Scheme::Gpt { "GPT" } else { "MBR" });
+            println!("{:>3} {:>10} {:>10}  type", "#", "start", "blocks");
+            for e in rows {
+                println!("{:>3} {:>10} {:>10}  {}", e.number, e.start, e.count, e.kind.name());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_info(image: &str) -> Result<(), String> {
+    let img = ImageFile::open(image)?;
+    let sb = probe(&img).map_err(|e| e.to_string())?;
+    println!("magic                   {:#010x}", sb.magic);
+    println!("version                 {}", sb.version);
+    println!("total_blocks            {}", sb.total_blocks);
+    println!("total_inodes            {}", sb.total_inodes);
+    println!("free_block_bitmap_start {}", sb.free_block_bitmap_start);
+    println!("inode_table_start       {}", sb.inode_table_start);
+    println!("data_blocks_start       {}", sb.data_blocks_start);
+    println!("journal_start           {}", sb.journal_start);
+    println!("journal_blocks          {}", sb.journal_blocks);
+    println!("quota_block             {}", sb.quota_block);
+    println!("free blocks             {}", free_block_count(&img).map_err(|e| e.to_string())?);
+    println!("used inodes             {}", used_inode_count(&img).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+fn cmd_ls(image: &str, path: &str, long: bool) -> Result<(), String> {
+    let img = ImageFile::open(image)?;
+    let err = |e: FsError| format!("{}: {}", path, e);
+    let ino = ops::lookup(&img, &Cred::ROOT, path).map_err(err)?;
+    if read_inode(&img, ino).map_err(err)?.is_dir() {
+        ls(&img, ino, long).map_err(err)?;
+    } else {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+/// Prints one attribute of `path`, or all of them
+fn cmd_getfattr(image: &str, path: &str, name: Option<&str>) -> Result<(), String> {
+    let img = ImageFile::open(image)?;
+    let err = |e: FsError| format!("{}: {}", path, e);
+    match name {
+        Some(name) => {
+            let mut value = [0u8; xattr::VALUE_MAX];
+            let n = xattr::get(&img, &Cred::ROOT, path, name, &mut value).map_err(err)?;
+            println!("{}=\"{}\"", name, String::from_utf8_lossy(&value[..n]));
+        }
+        None => xattr::list(&img, &Cred::ROOT, path, &mut |x| {
+            let name = String::from_utf8_lossy(x.name);
+            println!("{}.{}=\"{}\"", x.namespace, name, String::from_utf8_lossy(x.value));
+        })
+        .map_err(err)?,
+    }
+    Ok(())
+}
+
+/// Sets attribute `name` of `path` to `value`, or removes it if `value` is None
+fn cmd_setfattr(image: &str, path: &str, name: &str, value: Option<&str>) -> Result<(), String> {
+    let mut img = ImageFile::open(image)?;
+    transaction(&mut img, |tx| match value {
+        Some(value) => xattr::set(tx, &Cred::ROOT, path, name, value.as_bytes()),
+        None => xattr::remove(tx, &Cred::ROOT, path, name),
+    })
+    .map_err(|e| format!("{}: {}", path, e))?;
+    img.save(image)
+}
+
+/// Copies a host file, symlink or directory tree to `dst` inside the image
+fn put(img: &mut ImageFile, src: &Path, dst: &str) -> Result<(), String> {
+    let err = |e: FsError| format!("{}: {}", dst, e);
+    let meta = hostfs::symlink_metadata(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+    if meta.file_type().is_symlink() {
+        let target = hostfs::read_link(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+        let target = target.to_string_lossy();
+        transaction(img, |tx| ops::symlink(tx, &Cred::ROOT, &target, dst)).map_err(err)?;
+    } else if meta.is_dir() {
+        match transaction(img, |tx| ops::mkdir(tx, &Cred::ROOT, dst, host_mode(&meta))) {
+            Ok(_) | Err(FsError::Exists) => {}
+            Err(e) => return Err(err(e)),
+        }
+        let entries = hostfs::read_dir(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.file_name();
+            let child = format!("{}/{}", dst.trim_end_matches('/'), name.to_string_lossy());
+            put(img, &entry.path(), &child)?;
+        }
+    } else {
+        let data = hostfs::read(src).map_err(|e| format!("{}: {}", src.display(), e))?;
+        let mode = host_mode(&meta);
+        let ino = transaction(img, |tx| ops::create(tx, &Cred::ROOT, dst, mode)).map_err(err)?;
+        journal::append(img, ino, &data).map_err(err)?;
+    }
+    Ok(())
+}
+
+/// Extracts `src` from the image to the host path `dst`
+fn get(img: &ImageFile, src: &str, dst: &Path) -> Result<(), String> {
+    let err = |e: FsError| format!("{}: {}", src, e);
+    let ino = ops::lookup_nofollow(img, &Cred::ROOT, src).map_err(err)?;
+    let inode = read_inode(img, ino).map_err(err)?;
+    if inode.is_symlink() {
+        let mut buf = [0u8; ops::MAX_SYMLINK_LEN];
+        let n = ops::readlink(img, &Cred::ROOT, src, &mut buf).map_err(err)?;
+        let target = String::from_utf8_lossy(&buf[..n]).into_owned();
+        symlink(&target, dst).map_err(|e| format!("{}: {}", dst.display(), e))?;
+    } else if inode.is_dir() {
+        hostfs::create_dir_all(dst).map_err(|e| format!("{}: {}", dst.display(), e))?;
+        let mut names = Vec::new();
+        for_each_entry(img, &inode, &mut |e| {
+            let name = String::from_utf8_lossy(e.name_bytes()).into_owned();
+            if name != "." && name != ".." {
+                names.push(name);
+            }
+        })
+        .map_err(err)?;
+        for name in names {
+            get(img, &format!("{}/{}", src.trim_end_matches('/'), name), &dst.join(&name))?;
+        }
+    } else {
+        let mut data = vec![0u8; inode.size as usize];
+        let n = read_at(img, &inode, 0, &mut data).map_err(err)?;
+        data.truncate(n);
+        hostfs::write(dst, &data).map_err(|e| format!("{}: {}", dst.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Permission bits for a copy of a host file
+#[cfg(unix)]
+fn host_mode(meta: &hostfs::Metadata) -> u16 {
+    use std::os::unix::fs::PermissionsExt;
+    (meta.permissions().mode() & 0o7777) as u16
+}
+
+#[cfg(not(unix))]
+fn host_mode(meta: &hostfs::Metadata) -> u16 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Seconds since the Unix epoch, for inode timestamps
+fn host_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    hostfs::write(link, target)
+}
+
+/// Exit status 0 if the image was clean, 1 if problems remain, 3 if all were repaired
+fn cmd_fsck(image: &str, repair: bool) -> Result<ExitCode, String> {
+    let mut img = ImageFile::open(image)?;
+    let report = fsck(&mut img, repair).map_err(|e| e.to_string())?;
+    if report.is_clean() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    if repair {
+        img.save(image)?;
+    }
+    Ok(if report.repaired == report.problems { ExitCode::from(3) } else { ExitCode::FAILURE })
+}
+
+/// Passes the first `limit` writes through to `inner` and silently drops
+/// the rest, as if power failed after write number `limit`
+struct CrashDevice<'a> {
+    inner: &'a mut ImageFile,
+    limit: usize,
+    writes: usize,
+}
+
+impl<'a> CrashDevice<'a> {
+    fn new(inner: &'a mut ImageFile, limit: usize) -> CrashDevice<'a> {
+        CrashDevice { inner, limit, writes: 0 }
+    }
+}
+
+impl BlockDevice for CrashDevice<'_> {
+    fn block_count(&self) -> u32 {
+        self.inner.block_count()
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        self.inner.read_block(block_number, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        if self.writes < self.limit {
+            self.inner.write_block(block_number, buf)?;
+        }
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+/// Slots of the cache `crashtest -c` runs through, few enough to force
+/// evictions in the middle of a transaction
+const CRASHTEST_CACHE: usize = 4;
+
+/// Runs a `crashtest` step directly on `dev`, or through a cache that is
+/// synced afterwards
+fn run_step(step: &Step, dev: CrashDevice, cached: bool) -> (usize, Result<(), FsError>) {
+    if cached {
+        let mut cache: BlockCache<CrashDevice, CRASHTEST_CACHE> = BlockCache::new(dev);
+        let res = step.1(&mut cache);
+        let synced = cache.sync();
+        (cache.into_inner().writes, res.and(synced.map_err(FsError::from)))
+    } else {
+        let mut dev = dev;
+        let res = step.1(&mut dev);
+        (dev.writes, res)
+    }
+}
+
+/// Fixed clock for `crashtest`
+const CRASHTEST_TIME: u32 = 1_700_000_000;
+
+type Step = (&'static str, fn(&mut dyn BlockDevice) -> Result<(), FsError>);
+
+/// Workload for `crashtest`, one transaction per step
+const CRASH_STEPS: &[Step] = &[
+    ("mkdir /a", |d| transaction(d, |tx| ops::mkdir(tx, &Cred::ROOT, "/a", 0o755).map(|_| ()))),
+    ("touch /a/f", |d| transaction(d, |tx| ops::create(tx, &Cred::ROOT, "/a/f", 0o644).map(|_| ()))),
+    ("append 3000 bytes to /a/f", |d| {
+        transaction(d, |tx| {
+            let ino = ops::lookup(tx, &Cred::ROOT, "/a/f")?;
+            append_file(tx, ino, &[b'x'; 3000])
+        })
+    }),
+    ("append 6000 bytes to /a/f (indirect block)", |d| {
+        transaction(d, |tx| {
+            let ino = ops::lookup(tx, &Cred::ROOT, "/a/f")?;
+            append_file(tx, ino, &[b'y'; 6000])
+        })
+    }),
+    ("ln /a/f /g", |d| transaction(d, |tx| ops::link(tx, &Cred::ROOT, "/a/f", "/g"))),
+    ("ln -s /a/f /s", |d| transaction(d, |tx| ops::symlink(tx, &Cred::ROOT, "/a/f", "/s").map(|_| ()))),
+    ("mkdir /b", |d| transaction(d, |tx| ops::mkdir(tx, &Cred::ROOT, "/b", 0o755).map(|_| ()))),
+    ("mv /a /b", |d| transaction(d, |tx| ops::rename(tx, &Cred::ROOT, "/a", "/b"))),
+    ("mv /s /b/a/f", |d| transaction(d, |tx| ops::rename(tx, &Cred::ROOT, "/s", "/b/a/f"))),
+    ("rm /g", |d| transaction(d, |tx| ops::unlink(tx, &Cred::ROOT, "/g"))),
+    ("touch 16 files in /b (directory grows)", |d| {
+        transaction(d, |tx| {
+            for i in 0..16 {
+                ops::create(tx, &Cred::ROOT, &format!("/b/file{}", i), 0o644)?;
+            }
+            Ok(())
+        })
+    }),
+    ("setfattr 4 attributes on /b (overflow block)", |d| {
+        transaction(d, |tx| {
+            for i in 0..4 {
+                xattr::set(tx, &Cred::ROOT, "/b", &format!("user.tag{}", i), b"some value")?;
+            }
+            Ok(())
+        })
+    }),
+    ("setfattr -x 3 attributes on /b (overflow block freed)", |d| {
+        transaction(d, |tx| {
+            for i in 1..4 {
+                xattr::remove(tx, &Cred::ROOT, "/b", &format!("user.tag{}", i))?;
+            }
+            Ok(())
+        })
+    }),
+    ("rm /b/a/f, rmdir /b/a", |d| {
+        transaction(d, |tx| {
+            ops::unlink(tx, &Cred::ROOT, "/b/a/f")?;
+            ops::rmdir(tx, &Cred::ROOT, "/b/a")
+        })
+    }),
+];
+
+/// Whether two images hold the same filesystem, ignoring the journal area
+fn same_fs(a: &[u8], b: &[u8], journal: (usize, usize)) -> bool {
+    let (start, end) = (journal.0 * BLOCK_SIZE, journal.1 * BLOCK_SIZE);
+    a[..start] == b[..start] && a[end..] == b[end..]
+}
+
+/// Runs every step of `CRASH_STEPS` once for real, then once per write it
+/// makes with power cut before that write. After recovery (itself also cut
+/// short at every write and rerun) the image must hold either the state
+/// before the step or the state after it. Exit status 1 on any mismatch.
+fn cmd_crashtest(blocks: &str, cached: bool) -> Result<ExitCode, String> {
+    let blocks: u32 = blocks.parse().map_err(|_| format!("invalid block count '{}'", blocks))?;
+    let failures = crashtest(blocks, cached)?;
+    Ok(if failures == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+/// Runs the `crashtest` workload on a fresh image of `blocks` blocks,
+/// printing a line per step, and returns how many crash points left the
+/// filesystem neither before nor after their step
+fn crashtest(blocks: u32, cached: bool) -> Result<usize, String> {
+    let mut img = ImageFile::new(vec![0; blocks as usize * BLOCK_SIZE]);
+    mkfs(&mut img, blocks).map_err(|e| format!("mkfs failed: {}", e))?;
+    let sb = probe(&img).map_err(|e| format!("mkfs failed: {}", e))?;
+    if sb.journal_blocks == 0 {
+        return Err(format!("{} blocks is too small for a journal", blocks));
+    }
+    let journal = (sb.journal_start as usize, (sb.journal_start + sb.journal_blocks) as usize);
+
+    let mut failures = 0;
+    for step in CRASH_STEPS {
+        let name = step.0;
+        let before = img.data.clone();
+        let (writes, res) = run_step(step, CrashDevice::new(&mut img, usize::MAX), cached);
+        res.map_err(|e| format!("{}: {}", name, e))?;
+        let after = img.data.clone();
+        if !fsck(&mut ImageFile::new(after.clone()), false).map_err(|e| e.to_string())?.is_clean() {
+            return Err(format!("{}: filesystem inconsistent after the step", name));
+        }
+
+        let mut bad = 0;
+        for limit in 0..writes {
+            let mut crashed = ImageFile::new(before.clone());
+            // The step sees no error, its writes just stop reaching the disk
+            let _ = run_step(step, CrashDevice::new(&mut crashed, limit), cached);
+            for recover_limit in 0.. {
+                let mut trial = ImageFile::new(crashed.data.clone());
+                let mut dev = CrashDevice::new(&mut trial, recover_limit);
+                recover(&mut dev).map_err(|e| format!("{}: {}", name, e))?;
+                let finished = dev.writes <= recover_limit;
+                recover(&mut trial).map_err(|e| format!("{}: {}", name, e))?;
+                if !same_fs(&trial.data, &before, journal) && !same_fs(&trial.data, &after, journal) {
+                    bad += 1;
+                }
+                if finished {
+                    break;
+                }
+            }
+        }
+        println!("{:4} {:3} write points  {}", if bad == 0 { "ok" } else { "FAIL" }, writes, name);
+        failures += bad;
+    }
+    Ok(failures)
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: pofs mkfs <image> <blocks>");
+    eprintln!("       pofs mkfs <image>:<n> [blocks]");
+    eprintln!("       pofs parts <image>");
+    eprintln!("       pofs info <image>");
+    eprintln!("       pofs ls <image> [-l] [path]");
+    eprintln!("       pofs put <image> <host-path> <path>");
+    eprintln!("       pofs get <image> <path> <host-path>");
+    eprintln!("       pofs mkdir <image> <path>");
+    eprintln!("       pofs getfattr <image> [-n name] <path>");
+    eprintln!("       pofs setfattr <image> -n name [-v value] <path>");
+    eprintln!("       pofs setfattr <image> -x name <path>");
+    eprintln!("       pofs fsck <image> [-y]");
+    eprintln!("       pofs crashtest [-c] [blocks]");
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+    // Crash runs are compared byte for byte, so their timestamps must not move
+    if args.first() == Some(&"crashtest") {
+        set_clock(|| CRASHTEST_TIME);
+    } else {
+        set_clock(host_time);
+    }
+
+    let res = match args.as_slice() {
+        ["mkfs", image, blocks] => cmd_mkfs(image, Some(blocks)),
+        ["mkfs", image] => cmd_mkfs(image, None),
+        ["parts", image] => cmd_parts(image),
+        ["info", image] => cmd_info(image),
+        ["ls", image] => cmd_ls(image, "/", false),
+        ["ls", image, "-l"] => cmd_ls(image, "/", true),
+        ["ls", image, "-l", path] => cmd_ls(image, path, true),
+        ["ls", image, path] => cmd_ls(image, path, false),
+        ["put", image, src, dst] => ImageFile::open(image).and_then(|mut img| {
+            put(&mut img, Path::new(src), dst)?;
+            img.save(image)
+        }),
+        ["get", image, src, dst] => {
+            ImageFile::open(image).and_then(|img| get(&img, src, Path::new(dst)))
+        }
+        ["mkdir", image, path] => ImageFile::open(image).and_then(|mut img| {
+            transaction(&mut img, |tx| ops::mkdir(tx, &Cred::ROOT, path, 0o755))
+                .map_err(|e| format!("{}: {}", path, e))?;
+            img.save(image)
+        }),
+        ["getfattr", image, path] => cmd_getfattr(image, path, None),
+        ["getfattr", image, "-n", name, path] => cmd_getfattr(image, path, Some(name)),
+        ["setfattr", image, "-n", name, "-v", value, path] => cmd_setfattr(image, path, name, Some(value)),
+        ["setfattr", image, "-n", name, path] => cmd_setfattr(image, path, name, Some("")),
+        ["setfattr", image, "-x", name, path] => cmd_setfattr(image, path, name, None),
+        ["fsck", image] => return report(cmd_fsck(image, false)),
+        ["fsck", image, "-y"] => return report(cmd_fsck(image, true)),
+        ["crashtest"] => return report(cmd_crashtest("256", false)),
+        ["crashtest", "-c"] => return report(cmd_crashtest("256", true)),
+        ["crashtest", "-c", blocks] => return report(cmd_crashtest(blocks, true)),
+        ["crashtest", blocks] => return report(cmd_crashtest(blocks, false)),
+        _ => return usage(),
+    };
+
+    report(res.map(|()| ExitCode::SUCCESS))
+}
+
+fn report(res: Result<ExitCode, String>) -> ExitCode {
+    match res {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("pofs: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// `cargo pofs-test` runs these
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs::fat::{Fat, FAT_ROOT};
+    use fs::partition::Partition;
+    use fs::structure::{check_range, S_IFDIR, S_IFLNK, S_IFREG};
+    use fs::vfs::{FileOps, InodeOps};
+
+    /// A memory disk with blocks of any size, as `mkramdisk` makes
+    struct SizedDisk {
+        data: Vec<u8>,
+        block_size: usize,
+    }
+
+    impl BlockDevice for SizedDisk {
+        fn block_size(&self) -> usize {
+            self.block_size
+        }
+
+        fn block_count(&self) -> u32 {
+            (self.data.len() / self.block_size) as u32
+        }
+
+        fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+            check_range(self.block_count(), self.block_size, block_number, buf.len())?;
+            let start = block_number as usize * self.block_size;
+            buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+            check_range(self.block_count(), self.block_size, block_number, buf.len())?;
+            let start = block_number as usize * self.block_size;
+            self.data[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn whole_disk_of_large_blocks() {
+        let disk = SizedDisk { data: vec![0; 8 * 4096], block_size: 4096 };
+        let mut part = Partition::whole(disk);
+        assert_eq!(part.block_count(), 8);
+        let block = [0x5Au8; 4096];
+        part.write_block(7, &block).unwrap();
+        let mut back = [0u8; 4096];
+        part.read_block(7, &mut back).unwrap();
+        assert_eq!(back, block);
+        assert_eq!(part.read_block(7, &mut [0u8; BLOCK_SIZE]), Err(IoError::BufferSize));
+        assert_eq!(part.read_block(8, &mut back), Err(IoError::OutOfRange(8)));
+    }
+
+    #[test]
+    fn crash_at_every_write() {
+        set_clock(|| CRASHTEST_TIME);
+        assert_eq!(crashtest(256, false), Ok(0));
+    }
+
+    #[test]
+    fn crash_at_every_write_cached() {
+        set_clock(|| CRASHTEST_TIME);
+        assert_eq!(crashtest(256, true), Ok(0));
+    }
+
+    #[test]
+    fn inode_numbers_outside_the_table() {
+        let mut img = ImageFile::new(vec![0; 512 * BLOCK_SIZE]);
+        mkfs(&mut img, 512).unwrap();
+        let total = probe(&img).unwrap().total_inodes;
+        assert!(read_inode(&img, 1).is_ok());
+        assert!(matches!(read_inode(&img, 0), Err(FsError::InvalidArgument)));
+        assert!(matches!(read_inode(&img, total + 1), Err(FsError::InvalidArgument)));
+        let root = read_inode(&img, 1).unwrap();
+        assert_eq!(fs::utils::write_inode(&mut img, total + 1, &root), Err(FsError::InvalidArgument));
+    }
+
+    #[test]
+    fn symlinks_are_not_written_as_files() {
+        let mut img = ImageFile::new(vec![0; 512 * BLOCK_SIZE]);
+        mkfs(&mut img, 512).unwrap();
+        let ino = ops::symlink(&mut img, &Cred::ROOT, "/target/of/the/link", "/l").unwrap();
+        let before = img.data.clone();
+        assert_eq!(fs::utils::write_at(&mut img, ino, 0, b"data"), Err(FsError::InvalidArgument));
+        assert_eq!(fs::utils::truncate_file(&mut img, ino, 0), Err(FsError::InvalidArgument));
+        assert_eq!(fs::utils::punch_hole(&mut img, ino, 0, 4), Err(FsError::InvalidArgument));
+        assert!(img.data == before);
+    }
+
+    fn put32(b: &mut [u8], at: usize, v: u32) {
+        b[at..at + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put64(b: &mut [u8], at: usize, v: u64) {
+        b[at..at + 8].copy_from_slice(&v.to_le_bytes());
+    }
+
+    /// CRC‑32 (IEEE), as GPT uses
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &b in data {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+        !crc
+    }
+
+    /// Sets MBR entry `i` of the sector at `at` and its signature
+    fn mbr_entry(img: &mut [u8], at: usize, i: usize, kind: u8, start: u32, count: u32) {
+        let e = at + 446 + i * 16;
+        img[e + 4] = kind;
+        put32(img, e + 8, start);
+        put32(img, e + 12, count);
+        img[at + 510..at + 512].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    /// Writes a GPT header to block `lba` with four 128-byte entries at
+    /// `entries_lba`, whose checksum is `entries_crc`
+    fn gpt_header(img: &mut [u8], lba: u64, entries_lba: u64, entries_crc: u32) {
+        let h = &mut img[lba as usize * BLOCK_SIZE..(lba as usize + 1) * BLOCK_SIZE];
+        h.fill(0);
+        h[..8].copy_from_slice(b"EFI PART");
+        put32(h, 12, 92);
+        put64(h, 24, lba);
+        put64(h, 72, entries_lba);
+        put32(h, 80, 4);
+        put32(h, 84, 128);
+        put32(h, 88, entries_crc);
+        let crc = crc32(&h[..92]);
+        put32(h, 16, crc);
+    }
+
+    /// A 256-block disk with a protective MBR and one GPT partition,
+    /// blocks 34 to 200
+    fn gpt_disk() -> Vec<u8> {
+        let mut img = vec![0u8; 256 * BLOCK_SIZE];
+        mbr_entry(&mut img, 0, 0, 0xEE, 1, 255);
+        let mut entries = [0u8; BLOCK_SIZE];
+        entries[..16].fill(0xAB);
+        put64(&mut entries, 32, 34);
+        put64(&mut entries, 40, 200);
+        let crc = crc32(&entries);
+        img[2 * BLOCK_SIZE..3 * BLOCK_SIZE].copy_from_slice(&entries);
+        img[254 * BLOCK_SIZE..255 * BLOCK_SIZE].copy_from_slice(&entries);
+        gpt_header(&mut img, 1, 2, crc);
+        gpt_header(&mut img, 255, 254, crc);
+        img
+    }
+
+    /// Number, start and count of each partition found
+    type Found = Vec<(u32, u32, u32)>;
+
+    /// The scheme and partitions of `img`
+    fn partitions(img: Vec<u8>) -> Result<(Option<Scheme>, Found), FsError> {
+        let mut found = Vec::new();
+        let scheme = read_table(&ImageFile::new(img), &mut |e| found.push((e.number, e.start, e.count)))?;
+        Ok((scheme, found))
+    }
+
+    #[test]
+    fn gpt_falls_back_to_the_backup_header() {
+        let (scheme, found) = partitions(gpt_disk()).unwrap();
+        assert!(scheme == Some(Scheme::Gpt));
+        assert_eq!(found, [(1, 34, 167)]);
+
+        // A primary header failing its checksum
+        let mut img = gpt_disk();
+        img[BLOCK_SIZE + 40] ^= 1;
+        assert_eq!(partitions(img).unwrap().1, [(1, 34, 167)]);
+
+        // A primary header with a good checksum and entries past the end
+        let mut img = gpt_disk();
+        let crc = crc32(&img[2 * BLOCK_SIZE..3 * BLOCK_SIZE]);
+        gpt_header(&mut img, 1, u64::MAX, crc);
+        assert_eq!(partitions(img).unwrap().1, [(1, 34, 167)]);
+
+        // Primary entries failing their checksum
+        let mut img = gpt_disk();
+        img[2 * BLOCK_SIZE + 33] ^= 1;
+        assert_eq!(partitions(img).unwrap().1, [(1, 34, 167)]);
+
+        let mut img = gpt_disk();
+        img[BLOCK_SIZE + 40] ^= 1;
+        img[255 * BLOCK_SIZE + 40] ^= 1;
+        assert!(matches!(partitions(img), Err(FsError::BadPartitionTable)));
+    }
+
+    #[test]
+    fn logical_partitions_follow_the_ebr_chain() {
+        let mut img = vec![0u8; 256 * BLOCK_SIZE];
+        mbr_entry(&mut img, 0, 0, 0x83, 1, 99);
+        mbr_entry(&mut img, 0, 1, 0x0F, 100, 150);
+        // The logical partition is relative to its EBR, the link to the
+        // extended partition
+        mbr_entry(&mut img, 100 * BLOCK_SIZE, 0, 0x83, 1, 50);
+        mbr_entry(&mut img, 100 * BLOCK_SIZE, 1, 0x05, 60, 41);
+        mbr_entry(&mut img, 160 * BLOCK_SIZE, 0, 0x83, 1, 40);
+        let (scheme, found) = partitions(img.clone()).unwrap();
+        assert!(scheme == Some(Scheme::Mbr));
+        assert_eq!(found, [(1, 1, 99), (2, 100, 150), (5, 101, 50), (6, 161, 40)]);
+
+        // An EBR linking back to itself ends after the last logical number
+        mbr_entry(&mut img, 160 * BLOCK_SIZE, 1, 0x05, 60, 41);
+        let (_, found) = partitions(img).unwrap();
+        assert_eq!(found.len(), 2 + 64);
+        assert!(found[3..].iter().all(|&(_, start, _)| start == 161));
+    }
+
+    /// A 32 KiB disk with an ext2 superblock of 1 KiB blocks claiming
+    /// `blocks` blocks in groups of `per_group`, one inode per group
+    fn ext2_disk(blocks: u32, per_group: u32) -> Vec<u8> {
+        let mut img = vec![0u8; 64 * BLOCK_SIZE];
+        let sb = &mut img[1024..2048];
+        let groups = (blocks - 1).div_ceil(per_group);
+        put32(sb, 0, groups);
+        put32(sb, 4, blocks);
+        put32(sb, 20, 1);
+        put32(sb, 32, per_group);
+        put32(sb, 40, 1);
+        sb[56..58].copy_from_slice(&0xEF53u16.to_le_bytes());
+        put32(sb, 76, 1);
+        sb[88..90].copy_from_slice(&128u16.to_le_bytes());
+        img
+    }
+
+    #[test]
+    fn ext2_larger_than_the_device() {
+        assert!(fs::ext2::Ext2::open(ImageFile::new(ext2_disk(32, 8192))).is_ok());
+        assert!(matches!(fs::ext2::Ext2::open(ImageFile::new(ext2_disk(33, 8192))), Err(FsError::BadVersion)));
+        // Counts that agree but would need a descriptor table of 128 GiB
+        assert!(matches!(fs::ext2::Ext2::open(ImageFile::new(ext2_disk(u32::MAX, 1))), Err(FsError::BadVersion)));
+    }
+
+    /// A 64-sector FAT12 disk: one reserved sector, one FAT sector, a root
+    /// directory of 16 entries holding `root`, and cluster 2 ending a chain
+    fn fat_disk(root: &[[u8; 32]]) -> Vec<u8> {
+        let mut img = vec![0u8; 64 * BLOCK_SIZE];
+        img[11..13].copy_from_slice(&512u16.to_le_bytes());
+        img[13] = 1;
+        img[14..16].copy_from_slice(&1u16.to_le_bytes());
+        img[16] = 1;
+        img[17..19].copy_from_slice(&16u16.to_le_bytes());
+        img[19..21].copy_from_slice(&64u16.to_le_bytes());
+        img[22..24].copy_from_slice(&1u16.to_le_bytes());
+        img[510..512].copy_from_slice(&[0x55, 0xAA]);
+        img[BLOCK_SIZE..BLOCK_SIZE + 5].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF, 0x0F]);
+        for (i, entry) in root.iter().enumerate() {
+            let at = 2 * BLOCK_SIZE + i * 32;
+            img[at..at + 32].copy_from_slice(entry);
+        }
+        img
+    }
+
+    /// A short entry for the 11-byte `name`
+    fn short_entry(name: &[u8; 11], cluster: u16, size: u32) -> [u8; 32] {
+        let mut e = [0u8; 32];
+        e[..11].copy_from_slice(name);
+        e[11] = 0x20;
+        e[26..28].copy_from_slice(&cluster.to_le_bytes());
+        put32(&mut e, 28, size);
+        e
+    }
+
+    /// A long name entry with sequence byte `seq` holding all of `name`
+    fn lfn_entry(seq: u8, name: &str, sum: u8) -> [u8; 32] {
+        const OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        units.resize(13, 0xFFFF);
+        let mut e = [0u8; 32];
+        e[0] = seq;
+        e[11] = 0x0F;
+        e[13] = sum;
+        for (&off, u) in OFFSETS.iter().zip(units) {
+            e[off..off + 2].copy_from_slice(&u.to_le_bytes());
+        }
+        e
+    }
+
+    fn lfn_checksum(short: &[u8; 11]) -> u8 {
+        short.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+    }
+
+    #[test]
+    fn fat_long_names_need_an_unbroken_run() {
+        let img = fat_disk(&[
+            lfn_entry(0x41, "Long name.txt", lfn_checksum(b"LONGNA~1TXT")),
+            short_entry(b"LONGNA~1TXT", 0, 0),
+            // A checksum for another short name
+            lfn_entry(0x41, "Wrong sum.txt", lfn_checksum(b"LONGNA~1TXT")),
+            short_entry(b"WRONGS~1TXT", 0, 0),
+            // An entry of ordinal 0 after a finished run
+            lfn_entry(0x41, "Stray.txt", lfn_checksum(b"STRAY   TXT")),
+            lfn_entry(0x20, "Stray.txt", lfn_checksum(b"STRAY   TXT")),
+            short_entry(b"STRAY   TXT", 0, 0),
+            // Ordinal 0 starting a run
+            lfn_entry(0x40, "Zero.txt", lfn_checksum(b"ZERO    TXT")),
+            short_entry(b"ZERO    TXT", 0, 0),
+        ]);
+        let fat = Fat::open(ImageFile::new(img)).unwrap();
+        let mut names = Vec::new();
+        fat.readdir(FAT_ROOT, &mut |name, _| names.push(String::from(name))).unwrap();
+        assert_eq!(names, [".", "..", "Long name.txt", "WRONGS~1.TXT", "STRAY.TXT", "ZERO.TXT"]);
+    }
+
+    #[test]
+    fn fat_chain_shorter_than_the_file() {
+        let img = fat_disk(&[short_entry(b"BIG     BIN", 2, 5000)]);
+        let mut fat = Fat::open(ImageFile::new(img)).unwrap();
+        let ino = fat.lookup(FAT_ROOT, "big.bin").unwrap();
+        assert!(matches!(fat.read(ino, 0, &mut [0u8; 5000]), Err(FsError::Corrupt(_))));
+        assert!(matches!(fat.truncate(ino, 2000), Err(FsError::Corrupt(_))));
+        fat.truncate(ino, 100).unwrap();
+        assert_eq!(fat.stat(ino).unwrap().size, 100);
+    }
+
+    /// A USTAR header and the data after it, padded to 512 bytes
+    fn tar_member(name: &str, kind: u8, link: &str, data: &[u8]) -> Vec<u8> {
+        let mut h = vec![0u8; 512];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[100..108].copy_from_slice(b"0000644\0");
+        h[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        h[156] = kind;
+        h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        h[257..265].copy_from_slice(b"ustar\x0000");
+        h[148..156].fill(b' ');
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        h[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        h.extend_from_slice(data);
+        h.resize(h.len().next_multiple_of(512), 0);
+        h
+    }
+
+    /// A newc cpio member, padded to 4 bytes
+    fn cpio_member(archive: &mut Vec<u8>, ino: u32, mode: u32, nlink: u32, name: &str, data: &[u8]) {
+        let fields = [ino, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    /// Path, mode, data and hard link target of a member
+    type Owned = (String, u16, Vec<u8>, Option<String>);
+
+    /// Each member of `archive`
+    fn members(archive: &[u8]) -> Result<Vec<Owned>, FsError> {
+        let mut found = Vec::new();
+        fs::archive::members(archive, &mut |m| {
+            found.push((String::from(m.path), m.mode, m.data.to_vec(), m.link.map(String::from)));
+            Ok(())
+        })?;
+        Ok(found)
+    }
+
+    #[test]
+    fn ustar_headers() {
+        let mut tar = tar_member("./dir/", b'5', "", b"");
+        tar.extend(tar_member("dir/hello.txt", b'0', "", b"hello"));
+        tar.extend(tar_member("dir/link", b'2', "hello.txt", b""));
+        tar.extend(tar_member("dir/again", b'1', "dir/hello.txt", b""));
+        tar.extend([0; 1024]);
+        assert_eq!(
+            members(&tar).unwrap(),
+            [
+                (String::from("dir"), S_IFDIR | 0o644, Vec::new(), None),
+                (String::from("dir/hello.txt"), S_IFREG | 0o644, b"hello".to_vec(), None),
+                (String::from("dir/link"), S_IFLNK | 0o644, b"hello.txt".to_vec(), None),
+                (String::from("dir/again"), S_IFREG | 0o644, Vec::new(), Some(String::from("dir/hello.txt"))),
+            ]
+        );
+
+        // A bad checksum in the second header, and data cut short
+        let mut bad = tar.clone();
+        bad[512] ^= 1;
+        assert_eq!(members(&bad), Err(FsError::Corrupt(1)));
+        assert_eq!(members(&tar[..1024 + 3]), Err(FsError::Corrupt(1)));
+        assert_eq!(members(&[0; 600]), Err(FsError::BadMagic));
+    }
+
+    #[test]
+    fn newc_headers() {
+        let mut cpio = Vec::new();
+        cpio_member(&mut cpio, 1, (S_IFDIR | 0o755) as u32, 2, "./dir", b"");
+        cpio_member(&mut cpio, 2, (S_IFREG | 0o600) as u32, 2, "dir/a", b"");
+        cpio_member(&mut cpio, 2, (S_IFREG | 0o600) as u32, 2, "dir/b", b"linked");
+        cpio_member(&mut cpio, 3, (S_IFLNK | 0o777) as u32, 1, "dir/c", b"a");
+        cpio_member(&mut cpio, 0, 0, 1, "TRAILER!!!", b"");
+        assert_eq!(
+            members(&cpio).unwrap(),
+            [
+                (String::from("dir"), S_IFDIR | 0o755, Vec::new(), None),
+                (String::from("dir/a"), S_IFREG | 0o600, Vec::new(), None),
+                (String::from("dir/b"), S_IFREG | 0o600, b"linked".to_vec(), Some(String::from("dir/a"))),
+                (String::from("dir/c"), S_IFLNK | 0o777, b"a".to_vec(), None),
+            ]
+        );
+
+        // A name size that is not hex, and an archive without its trailer
+        let mut bad = cpio.clone();
+        bad[6 + 8 * 11] = b'x';
+        assert_eq!(members(&bad), Err(FsError::Corrupt(0)));
+        let end = cpio.len() - 124;
+        assert_eq!(members(&cpio[..end]), Err(FsError::Corrupt(0)));
+    }
+}
@@ -0,0 +1,89 @@
+//! ACPI table lookup.
+//!
+//! The firmware leaves a root pointer (RSDP) in the first KiB of the EBDA
+//! or in the BIOS area at 0xE0000..0x100000. It leads to the RSDT, or the
+//! XSDT on ACPI 2.0+, which lists the physical address of every other
+//! table. Tables are only read, through the physical memory mapping.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+
+use crate::polib::memory::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Segment of the EBDA, stored by the BIOS in its data area
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA: (u64, u64) = (0xE_0000, 0x10_0000);
+
+/// Length of the header every table starts with
+pub const HEADER_LEN: usize = 36;
+
+/// `len` bytes of physical memory at `phys`
+fn phys_bytes(phys: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(phys) as *const u8, len) }
+}
+
+fn read_u16(phys: u64) -> u16 {
+    let b = phys_bytes(phys, 2);
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn read_u32(phys: u64) -> u32 {
+    let b = phys_bytes(phys, 4);
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn read_u64(phys: u64) -> u64 {
+    read_u32(phys) as u64 | (read_u32(phys + 4) as u64) << 32
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Looks for a valid RSDP on 16-byte boundaries of `start..end`
+fn scan_rsdp(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        phys_bytes(addr, 8) == RSDP_SIGNATURE && checksum_ok(phys_bytes(addr, 20))
+    })
+}
+
+/// Physical address of the RSDT or XSDT, and whether it is the XSDT
+fn root_table() -> Option<(u64, bool)> {
+    let ebda = (read_u16(EBDA_POINTER) as u64) << 4;
+    let rsdp = (if ebda != 0 { scan_rsdp(ebda, ebda + 1024) } else { None })
+        .or_else(|| scan_rsdp(BIOS_AREA.0, BIOS_AREA.1))?;
+    let revision = phys_bytes(rsdp + 15, 1)[0];
+    if revision >= 2 && checksum_ok(phys_bytes(rsdp, read_u32(rsdp + 20) as usize)) {
+        let xsdt = read_u64(rsdp + 24);
+        if xsdt != 0 {
+            return Some((xsdt, true));
+        }
+    }
+    Some((read_u32(rsdp + 16) as u64, false))
+}
+
+/// Length of the table at `phys`, from its header
+fn table_len(phys: u64) -> usize {
+    read_u32(phys + 4) as usize
+}
+
+/// Finds the table with `signature`, returning its contents with the header
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root, wide) = root_table()?;
+    if phys_bytes(root, 4) != if wide { b"XSDT" } else { b"RSDT" } {
+        return None;
+    }
+    let entry = if wide { 8 } else { 4 };
+    let count = table_len(root).saturating_sub(HEADER_LEN) / entry;
+    (0..count).find_map(|i| {
+        let at = root + (HEADER_LEN + i * entry) as u64;
+        let table = if wide { read_u64(at) } else { read_u32(at) as u64 };
+        if table == 0 || phys_bytes(table, 4) != signature {
+            return None;
+        }
+        let bytes = phys_bytes(table, table_len(table));
+        if checksum_ok(bytes) { Some(bytes) } else { None }
+    })
+}
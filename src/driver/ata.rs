@@ -0,0 +1,306 @@
+//! ATA (IDE) disk driver using programmed I/O.
+//!
+//! Each of the two legacy channels, at the standard ports, can hold a master
+//! and a slave drive. `detect` sends IDENTIFY to all four positions and
+//! returns the ATA hard disks that answer; ATAPI drives such as CD‑ROMs are
+//! skipped. Under QEMU the boot image is `hda` (primary master) and a second
+//! `-drive` becomes `hdb` (primary slave).
+//!
+//! Sectors are addressed with 28‑bit LBA, or 48‑bit LBA beyond the first
+//! 128 GiB and for transfers of more than 256 sectors, when the drive
+//! supports it. Transfers poll the status register; the drive's interrupt
+//! is disabled. `flush` sends CACHE FLUSH so writes survive a power cut.
+
+extern crate x86_64;
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+use x86_64::instructions::port::Port;
+
+use crate::fs::error::IoError;
+use crate::fs::structure::{check_block, check_range, BlockDevice, BLOCK_SIZE};
+
+// Registers, as offsets from the channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7; // reads status, writes a command
+
+// Status bits
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+// Device control register bits
+const CONTROL_NIEN: u8 = 0x02; // no interrupts
+
+// Commands
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Highest sector 28‑bit LBA can reach, plus one
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Status polls before a command is given up on
+const TIMEOUT_POLLS: u32 = 1_000_000;
+
+/// I/O and control port bases of the primary and secondary channels
+const CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
+/// Device names by channel and position, as on Linux
+const NAMES: [&str; 4] = ["hda", "hdb", "hdc", "hdd"];
+
+pub struct AtaDrive {
+    io: u16,
+    control: u16,
+    slave: bool,
+    index: usize, // 0..4, primary master first
+    sectors: u64,
+    lba48: bool,
+    model: [u8; 40],
+}
+
+fn inb(port: u16) -> u8 {
+    let mut p: Port<u8> = Port::new(port);
+    unsafe { p.read() }
+}
+
+fn outb(port: u16, value: u8) {
+    let mut p: Port<u8> = Port::new(port);
+    unsafe { p.write(value) }
+}
+
+/// Probes all four drive positions, calling `f` for every ATA disk found
+pub fn detect(f: &mut dyn FnMut(AtaDrive)) {
+    for (channel, &(io, control)) in CHANNELS.iter().enumerate() {
+        // Nothing attached to the channel at all: the bus floats high
+        if inb(io + REG_STATUS) == 0xFF {
+            continue;
+        }
+        for slave in [false, true] {
+            if let Some(drive) = AtaDrive::identify(io, control, slave, channel * 2 + slave as usize) {
+                f(drive);
+            }
+        }
+    }
+}
+
+impl AtaDrive {
+    /// Sends IDENTIFY to one position, returning the drive if an ATA disk
+    /// answers
+    fn identify(io: u16, control: u16, slave: bool, index: usize) -> Option<AtaDrive> {
+        outb(control, CONTROL_NIEN);
+        let mut drive = AtaDrive { io, control, slave, index, sectors: 0, lba48: false, model: [b' '; 40] };
+        drive.select(0xA0);
+        for reg in REG_SECTOR_COUNT..=REG_LBA_HIGH {
+            outb(io + reg, 0);
+        }
+        outb(io + REG_STATUS, CMD_IDENTIFY);
+        if inb(io + REG_STATUS) == 0 {
+            return None; // no drive in this position
+        }
+        drive.wait_idle().ok()?;
+        // ATAPI and SATA devices set the signature in the LBA registers
+        if inb(io + REG_LBA_MID) != 0 || inb(io + REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        drive.wait_data().ok()?;
+
+        let mut id = [0u16; 256];
+        let mut data: Port<u16> = Port::new(io + REG_DATA);
+        for word in id.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        // Strings hold two characters per word, high byte first
+        for (i, pair) in drive.model.chunks_mut(2).enumerate() {
+            pair.copy_from_slice(&id[27 + i].to_be_bytes());
+        }
+        drive.lba48 = id[83] & (1 << 10) != 0;
+        drive.sectors = if drive.lba48 {
+            id[100] as u64 | (id[101] as u64) << 16 | (id[102] as u64) << 32 | (id[103] as u64) << 48
+        } else {
+            id[60] as u64 | (id[61] as u64) << 16
+        };
+        if drive.sectors == 0 {
+            return None;
+        }
+        Some(drive)
+    }
+
+    /// Device name, `hda` to `hdd`
+    pub fn name(&self) -> &'static str {
+        NAMES[self.index]
+    }
+
+    /// Model string reported by IDENTIFY
+    pub fn model(&self) -> &str {
+        str::from_utf8(&self.model).unwrap_or("?").trim()
+    }
+
+    /// Size in 512 B sectors
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn lba48(&self) -> bool {
+        self.lba48
+    }
+
+    /// Writes the drive register and waits the 400 ns the drive needs to
+    /// switch, by reading the alternate status four times
+    fn select(&self, bits: u8) {
+        outb(self.io + REG_DRIVE, bits | (self.slave as u8) << 4);
+        for _ in 0..4 {
+            inb(self.control);
+        }
+    }
+
+    fn error(&self) -> IoError {
+        IoError::Device(inb(self.io + REG_ERROR))
+    }
+
+    /// Waits until the drive is no longer busy and has every bit of `want`
+    /// set in its status, failing if it reports an error instead
+    fn poll(&self, want: u8) -> Result<(), IoError> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = inb(self.io + REG_STATUS);
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(self.error());
+            }
+            if status & want == want {
+                return Ok(());
+            }
+        }
+        Err(IoError::Timeout)
+    }
+
+    fn wait_idle(&self) -> Result<(), IoError> {
+        self.poll(0)
+    }
+
+    /// Waits until the drive has a sector to transfer
+    fn wait_data(&self) -> Result<(), IoError> {
+        self.poll(STATUS_DRQ)
+    }
+
+    /// Sectors per command in the multi‑block calls
+    fn max_transfer(&self) -> usize {
+        if self.lba48 { 65536 } else { 256 }
+    }
+
+    /// Loads the address registers and issues a read or write of `count`
+    /// sectors (1..=65536) from `lba`
+    fn command(&self, lba: u64, count: usize, write: bool) -> Result<(), IoError> {
+        let ext = lba + count as u64 > LBA28_LIMIT || count > 256;
+        let io = self.io;
+        self.wait_idle()?;
+        if ext {
+            if !self.lba48 {
+                return Err(IoError::OutOfRange(lba.min(u32::MAX as u64) as u32));
+            }
+            // High bytes first, then low bytes, through the same registers
+            self.select(0x40);
+            outb(io + REG_SECTOR_COUNT, (count >> 8) as u8);
+            outb(io + REG_LBA_LOW, (lba >> 24) as u8);
+            outb(io + REG_LBA_MID, (lba >> 32) as u8);
+            outb(io + REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(0xE0 | (lba >> 24) as u8 & 0x0F);
+        }
+        // A count of 0 means 256 (or 65536)
+        outb(io + REG_SECTOR_COUNT, count as u8);
+        outb(io + REG_LBA_LOW, lba as u8);
+        outb(io + REG_LBA_MID, (lba >> 8) as u8);
+        outb(io + REG_LBA_HIGH, (lba >> 16) as u8);
+        let cmd = match (write, ext) {
+            (false, false) => CMD_READ,
+            (false, true) => CMD_READ_EXT,
+            (true, false) => CMD_WRITE,
+            (true, true) => CMD_WRITE_EXT,
+        };
+        outb(io + REG_STATUS, cmd);
+        Ok(())
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), IoError> {
+        self.command(lba, buf.len() / BLOCK_SIZE, false)?;
+        let mut data: Port<u16> = Port::new(self.io + REG_DATA);
+        for sector in buf.chunks_mut(BLOCK_SIZE) {
+            self.wait_data()?;
+            for pair in sector.chunks_mut(2) {
+                pair.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), IoError> {
+        self.command(lba, buf.len() / BLOCK_SIZE, true)?;
+        let mut data: Port<u16> = Port::new(self.io + REG_DATA);
+        for sector in buf.chunks(BLOCK_SIZE) {
+            self.wait_data()?;
+            for pair in sector.chunks(2) {
+                unsafe { data.write(u16::from_le_bytes([pair[0], pair[1]])) };
+            }
+        }
+        self.wait_idle()?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_count(&self) -> u32 {
+        self.sectors.min(u32::MAX as u64) as u32
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        self.read_sectors(block_number as u64, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        self.write_sectors(block_number as u64, buf)
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_range(self.block_count(), BLOCK_SIZE, start, buf.len())?;
+        let per = self.max_transfer();
+        for (i, chunk) in buf.chunks_mut(per * BLOCK_SIZE).enumerate() {
+            self.read_sectors(start as u64 + (i * per) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_range(self.block_count(), BLOCK_SIZE, start, buf.len())?;
+        let per = self.max_transfer();
+        for (i, chunk) in buf.chunks(per * BLOCK_SIZE).enumerate() {
+            self.write_sectors(start as u64 + (i * per) as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.wait_idle()?;
+        self.select(0xA0);
+        let cmd = if self.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH };
+        outb(self.io + REG_STATUS, cmd);
+        self.wait_idle()?;
+        Ok(())
+    }
+}
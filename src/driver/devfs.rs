@@ -0,0 +1,334 @@
+//! Device filesystem.
+//!
+//! `Devfs` is one directory of device files: the character devices in
+//! `CHAR_DEVICES`, and every disk of the disk table and each partition on
+//! it as a block device (`hda`, `hda1`, ...). Reading or writing a file
+//! goes straight to the device. Disks are looked up again whenever the
+//! directory is read or searched, so ones added later show up.
+//!
+//! Block device files have the size of the volume and can be read and
+//! written at any offset, a partial block by read-modify-write. Writes go
+//! to the disk directly, past the block cache of any filesystem mounted
+//! from it.
+
+use core::iter::Iterator;
+use core::option::Option::{self, Some};
+use core::result::Result::{self, Err, Ok};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+use crate::driver::disk::{self, Volume, VolumeName};
+use crate::driver::{keyboard, serial};
+use crate::fs::clock;
+use crate::fs::error::FsError;
+use crate::fs::structure::{BlockDevice, S_IFBLK, S_IFCHR, S_IFDIR};
+use crate::fs::vfs::{FileOps, FileSystem, InodeOps, Stat};
+use crate::polib::print::{print_bytes, read_screen, write_screen, SCREEN_SIZE};
+
+/// Inode number of the directory
+const ROOT: u32 = 1;
+
+/// Inode number of character device 0; the others follow
+const CHAR_BASE: u32 = 2;
+
+/// Inode number of the first block device found, in the order of
+/// `disk::block_devices`
+const BLOCK_BASE: u32 = 0x100;
+
+/// A character device: its name, permissions and operations, which take
+/// the file offset
+struct CharDevice {
+    name: &'static str,
+    perm: u16,
+    size: u64,
+    read: fn(u64, &mut [u8]) -> Result<usize, FsError>,
+    write: fn(u64, &[u8]) -> Result<usize, FsError>,
+}
+
+const CHAR_DEVICES: &[CharDevice] = &[
+    CharDevice { name: "null", perm: 0o666, size: 0, read: read_nothing, write: write_anything },
+    CharDevice { name: "zero", perm: 0o666, size: 0, read: read_zero, write: write_anything },
+    CharDevice { name: "random", perm: 0o666, size: 0, read: read_random, write: write_random },
+    CharDevice { name: "console", perm: 0o620, size: 0, read: read_console, write: write_console },
+    CharDevice { name: "kbd", perm: 0o600, size: 0, read: read_kbd, write: write_none },
+    CharDevice { name: "ttyS0", perm: 0o620, size: 0, read: read_serial, write: write_serial },
+    CharDevice { name: "vga", perm: 0o600, size: SCREEN_SIZE as u64, read: read_vga, write: write_vga },
+];
+
+fn read_nothing(_offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+    Ok(0)
+}
+
+fn write_anything(_offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    Ok(data.len())
+}
+
+fn write_none(_offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+    Err(FsError::BadFd)
+}
+
+fn read_zero(_offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    buf.fill(0);
+    Ok(buf.len())
+}
+
+/// State of the xorshift generator behind `random` when the CPU has no
+/// RDRAND; seeded from the time stamp counter on first use
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn next_random() -> u64 {
+    if let Some(r) = RdRand::new().and_then(|r| r.get_u64()) {
+        return r;
+    }
+    let mut x = RANDOM_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RANDOM_STATE.store(x, Ordering::Relaxed);
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+fn read_random(_offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    for chunk in buf.chunks_mut(8) {
+        let r = next_random().to_le_bytes();
+        chunk.copy_from_slice(&r[..chunk.len()]);
+    }
+    Ok(buf.len())
+}
+
+/// Data written is mixed into the generator's state
+fn write_random(_offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    for chunk in data.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let x = RANDOM_STATE.load(Ordering::Relaxed) ^ u64::from_le_bytes(word);
+        RANDOM_STATE.store(x.rotate_left(29) | 1, Ordering::Relaxed);
+    }
+    Ok(data.len())
+}
+
+/// A line typed at the keyboard, echoed as it is typed and ending with its
+/// newline, or as much of it as fits in `buf`
+fn read_console(_offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    let mut n = 0;
+    while n < buf.len() {
+        let Some(c) = keyboard::read_key() else { continue };
+        match c {
+            '\x08' if n > 0 => {
+                n -= 1;
+                print_bytes(b"\x08");
+            }
+            '\x08' | '\0' => {}
+            c => {
+                buf[n] = c as u8;
+                n += 1;
+                print_bytes(&[c as u8]);
+                if c == '\n' {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(n)
+}
+
+fn write_console(_offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    print_bytes(data);
+    Ok(data.len())
+}
+
+/// Keys pressed and not yet read, without waiting; 0 if there are none
+fn read_kbd(_offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    keyboard::init_keyboard();
+    let mut n = 0;
+    while n < buf.len() && keyboard::key_ready() {
+        if let Some(c) = keyboard::read_key()
+            && c != '\0'
+        {
+            buf[n] = c as u8;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Bytes received so far, without waiting
+fn read_serial(_offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    Ok(serial::read(buf))
+}
+
+fn write_serial(_offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    serial::write(data);
+    Ok(data.len())
+}
+
+/// Text screen memory, two bytes per cell: the character and its colours
+fn read_vga(offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    Ok(read_screen(offset as usize, buf))
+}
+
+fn write_vga(offset: u64, data: &[u8]) -> Result<usize, FsError> {
+    match write_screen(offset as usize, data) {
+        0 if !data.is_empty() => Err(FsError::NoSpace),
+        n => Ok(n),
+    }
+}
+
+pub struct Devfs {
+    blocks: Mutex<Vec<(VolumeName, Volume)>>, // as of the last scan
+    mtime: u32,
+}
+
+impl Devfs {
+    pub fn new() -> Devfs {
+        let devfs = Devfs { blocks: Mutex::new(Vec::new()), mtime: clock::now() };
+        devfs.scan();
+        devfs
+    }
+
+    /// Looks the block devices up again
+    fn scan(&self) {
+        let mut blocks = Vec::new();
+        disk::block_devices(&mut |name, volume| blocks.push((name, volume)));
+        *self.blocks.lock() = blocks;
+    }
+
+    fn char_device(ino: u32) -> Option<&'static CharDevice> {
+        CHAR_DEVICES.get(ino.checked_sub(CHAR_BASE)? as usize)
+    }
+
+    fn volume(&self, ino: u32) -> Option<Volume> {
+        let i = ino.checked_sub(BLOCK_BASE)? as usize;
+        self.blocks.lock().get(i).map(|&(_, volume)| volume)
+    }
+}
+
+impl Default for Devfs {
+    fn default() -> Devfs {
+        Devfs::new()
+    }
+}
+
+impl InodeOps for Devfs {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        let (mode, size) = if ino == ROOT {
+            (S_IFDIR | 0o755, (CHAR_DEVICES.len() + self.blocks.lock().len()) as u64)
+        } else if let Some(dev) = Devfs::char_device(ino) {
+            (S_IFCHR | dev.perm, dev.size)
+        } else if let Some(volume) = self.volume(ino) {
+            (S_IFBLK | 0o600, volume.block_count() as u64 * volume.block_size() as u64)
+        } else {
+            return Err(FsError::NotFound);
+        };
+        let links = if ino == ROOT { 2 } else { 1 };
+        Ok(Stat { ino, mode, links, uid: 0, gid: 0, size, blocks: 0, mtime: self.mtime })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        if dir != ROOT {
+            return Err(FsError::NotDir);
+        }
+        if name == "." || name == ".." {
+            return Ok(ROOT);
+        }
+        if let Some(i) = CHAR_DEVICES.iter().position(|d| d.name == name) {
+            return Ok(CHAR_BASE + i as u32);
+        }
+        self.scan();
+        let blocks = self.blocks.lock();
+        let i = blocks.iter().position(|(n, _)| n.as_str() == name).ok_or(FsError::NotFound)?;
+        Ok(BLOCK_BASE + i as u32)
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        if dir != ROOT {
+            return Err(FsError::NotDir);
+        }
+        f(".", ROOT);
+        f("..", ROOT);
+        for (i, dev) in CHAR_DEVICES.iter().enumerate() {
+            f(dev.name, CHAR_BASE + i as u32);
+        }
+        self.scan();
+        // Copied out, as `f` may stat the entries
+        let names: Vec<VolumeName> = self.blocks.lock().iter().map(|&(name, _)| name).collect();
+        for (i, name) in names.iter().enumerate() {
+            f(name.as_str(), BLOCK_BASE + i as u32);
+        }
+        Ok(())
+    }
+}
+
+impl FileOps for Devfs {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if let Some(dev) = Devfs::char_device(ino) {
+            return (dev.read)(offset, buf);
+        }
+        let volume = self.volume(ino).ok_or(if ino == ROOT { FsError::IsDir } else { FsError::NotFound })?;
+        let bs = volume.block_size() as u64;
+        let size = volume.block_count() as u64 * bs;
+        let len = (buf.len() as u64).min(size.saturating_sub(offset)) as usize;
+        let mut block = vec![0u8; bs as usize];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let skip = (pos % bs) as usize;
+            let n = (bs as usize - skip).min(len - done);
+            volume.read_block((pos / bs) as u32, &mut block)?;
+            buf[done..done + n].copy_from_slice(&block[skip..skip + n]);
+            done += n;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if let Some(dev) = Devfs::char_device(ino) {
+            return (dev.write)(offset, data);
+        }
+        let mut volume = self.volume(ino).ok_or(if ino == ROOT { FsError::IsDir } else { FsError::NotFound })?;
+        let bs = volume.block_size() as u64;
+        let size = volume.block_count() as u64 * bs;
+        let len = (data.len() as u64).min(size.saturating_sub(offset)) as usize;
+        if len == 0 && !data.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        let mut block = vec![0u8; bs as usize];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let skip = (pos % bs) as usize;
+            let n = (bs as usize - skip).min(len - done);
+            let number = (pos / bs) as u32;
+            if n < bs as usize {
+                volume.read_block(number, &mut block)?;
+            }
+            block[skip..skip + n].copy_from_slice(&data[done..done + n]);
+            volume.write_block(number, &block)?;
+            done += n;
+        }
+        volume.flush()?;
+        Ok(len)
+    }
+
+    /// Device files keep their size; `O_TRUNC` on one does nothing
+    fn truncate(&mut self, ino: u32, _size: u64) -> Result<(), FsError> {
+        self.stat(ino).map(|_| ())
+    }
+}
+
+impl FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> u32 {
+        ROOT
+    }
+}
@@ -0,0 +1,299 @@
+//! Disks and the volumes on them.
+//!
+//! Disks found at boot go into a fixed table and stay there. A filesystem
+//! reaches its disk through a `Volume`: one partition of a table entry, or
+//! all of an unpartitioned one, which takes the table's lock for each
+//! request. Several filesystems can therefore live on one disk.
+
+use core::fmt;
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use spin::Mutex;
+
+use crate::driver::ata::{self, AtaDrive};
+use crate::driver::virtio::blk::{self as virtio_blk, VirtioBlk};
+use crate::fs::error::{FsError, IoError};
+use crate::fs::partition::{read_table, Partition, PartitionEntry};
+use crate::driver::ramdisk::RamDisk;
+use crate::fs::structure::BlockDevice;
+use crate::poprint;
+
+/// Disks the table holds
+pub const MAX_DISKS: usize = 16;
+
+#[allow(clippy::large_enum_variant)] // lives in `DISKS`, never moved
+pub enum Disk {
+    Ram(RamDisk),
+    Ata(AtaDrive),
+    Virtio(VirtioBlk),
+}
+
+static DISKS: Mutex<[Option<Disk>; MAX_DISKS]> = Mutex::new([const { None }; MAX_DISKS]);
+
+impl Disk {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Disk::Ram(dev) => dev.name(),
+            Disk::Ata(dev) => dev.name(),
+            Disk::Virtio(dev) => dev.name(),
+        }
+    }
+
+    fn get(&self) -> &dyn BlockDevice {
+        match self {
+            Disk::Ram(dev) => dev,
+            Disk::Ata(dev) => dev,
+            Disk::Virtio(dev) => dev,
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut dyn BlockDevice {
+        match self {
+            Disk::Ram(dev) => dev,
+            Disk::Ata(dev) => dev,
+            Disk::Virtio(dev) => dev,
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.get().block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        self.get().block_count()
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        self.get().read_block(block_number, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        self.get_mut().write_block(block_number, buf)
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        self.get().read_blocks(start, buf)
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        self.get_mut().write_blocks(start, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.get_mut().flush()
+    }
+}
+
+/// Adds `disk` to the table, returning a handle to it
+pub fn add(disk: Disk) -> Option<DiskRef> {
+    let mut disks = DISKS.lock();
+    let i = disks.iter().position(|d| d.is_none())?;
+    disks[i] = Some(disk);
+    Some(DiskRef(i))
+}
+
+/// Adds the virtio disks the PCI scan set up and the IDE disks, in that
+/// order, printing each
+pub fn detect() {
+    virtio_blk::take_disks(&mut |disk| {
+        poprint!(
+            "[pomain] {}: virtio {}, {} sectors{}{}\n",
+            disk.name(),
+            if disk.is_modern() { "1.0" } else { "legacy" },
+            disk.sectors(),
+            if disk.read_only() { ", read-only" } else { "" },
+            if disk.uses_interrupt() { "" } else { ", polled" }
+        );
+        if add(Disk::Virtio(disk)).is_none() {
+            poprint!("[pomain] Disk table full\n");
+        }
+    });
+    ata::detect(&mut |drive| {
+        poprint!(
+            "[pomain] {}: {}, {} sectors{}\n",
+            drive.name(),
+            drive.model(),
+            drive.sectors(),
+            if drive.lba48() { ", LBA48" } else { "" }
+        );
+        if add(Disk::Ata(drive)).is_none() {
+            poprint!("[pomain] Disk table full\n");
+        }
+    });
+}
+
+/// A disk in the table, as a block device
+#[derive(Clone, Copy)]
+pub struct DiskRef(usize);
+
+impl DiskRef {
+    /// Runs `f` on the disk with the table locked
+    fn with<T>(&self, f: impl FnOnce(&mut Disk) -> T) -> T {
+        let mut disks = DISKS.lock();
+        // Entries are never removed, so a handle stays valid
+        f(disks[self.0].as_mut().expect("disk table entry"))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.with(|d| d.name())
+    }
+}
+
+impl BlockDevice for DiskRef {
+    fn block_size(&self) -> usize {
+        self.with(|d| d.block_size())
+    }
+
+    fn block_count(&self) -> u32 {
+        self.with(|d| d.block_count())
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        self.with(|d| d.read_block(block_number, buf))
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        self.with(|d| d.write_block(block_number, buf))
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        self.with(|d| d.read_blocks(start, buf))
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        self.with(|d| d.write_blocks(start, buf))
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.with(|d| d.flush())
+    }
+}
+
+/// A partition, or a whole unpartitioned disk
+pub type Volume = Partition<DiskRef>;
+
+/// Name of a volume: the disk's, followed by the partition number
+#[derive(Clone, Copy)]
+pub struct VolumeName {
+    buf: [u8; 12],
+    len: usize,
+}
+
+impl VolumeName {
+    fn new(disk: &str, number: u32) -> VolumeName {
+        let mut name = VolumeName { buf: [0; 12], len: 0 };
+        let _ = fmt::Write::write_str(&mut name, disk);
+        if number != 0 {
+            let _ = fmt::Write::write_fmt(&mut name, format_args!("{}", number));
+        }
+        name
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("?")
+    }
+}
+
+impl fmt::Write for VolumeName {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for VolumeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Name of `volume`, e.g. `vda2`
+pub fn volume_name(volume: &Volume) -> VolumeName {
+    VolumeName::new(volume.device().name(), volume.number())
+}
+
+/// Handles to the disks in the table
+fn disks() -> [Option<DiskRef>; MAX_DISKS] {
+    let table = DISKS.lock();
+    core::array::from_fn(|i| table[i].as_ref().map(|_| DiskRef(i)))
+}
+
+/// Calls `f` for every disk, then each of its partitions with their table
+/// entries, with sizes in bytes, and returns the first error reading a
+/// partition table
+pub fn list(f: &mut dyn FnMut(VolumeName, u64, Option<&PartitionEntry>)) -> Result<(), FsError> {
+    let mut res = Ok(());
+    for disk in disks().into_iter().flatten() {
+        let bs = disk.block_size() as u64;
+        f(VolumeName::new(disk.name(), 0), disk.block_count() as u64 * bs, None);
+        let scanned = read_table(&disk, &mut |entry| {
+            f(VolumeName::new(disk.name(), entry.number), entry.count as u64 * bs, Some(&entry))
+        });
+        if let Err(e) = scanned
+            && res.is_ok()
+        {
+            res = Err(e);
+        }
+    }
+    res
+}
+
+/// Calls `f` for the volumes that may hold a filesystem: each partition
+/// of a partitioned disk, and the whole of any other
+pub fn volumes(f: &mut dyn FnMut(Volume)) {
+    for disk in disks().into_iter().flatten() {
+        let mut entries = [None; 16];
+        let mut n = 0;
+        let scheme = read_table(&disk, &mut |entry| {
+            if n < entries.len() {
+                entries[n] = Some(entry);
+                n += 1;
+            }
+        });
+        match scheme {
+            Ok(Some(_)) => entries.iter().flatten().filter_map(|e| Partition::new(disk, e)).for_each(&mut *f),
+            Ok(None) => f(Partition::whole(disk)),
+            Err(e) => poprint!("[pomain] {}: {}\n", disk.name(), e),
+        }
+    }
+}
+
+/// Calls `f` for every disk as a whole, then each of its partitions
+pub fn block_devices(f: &mut dyn FnMut(VolumeName, Volume)) {
+    for disk in disks().into_iter().flatten() {
+        f(VolumeName::new(disk.name(), 0), Partition::whole(disk));
+        let _ = read_table(&disk, &mut |entry| {
+            if let Some(part) = Partition::new(disk, &entry) {
+                f(VolumeName::new(disk.name(), entry.number), part);
+            }
+        });
+    }
+}
+
+/// Opens the volume named `name`, e.g. `vda` or `hda2`
+pub fn open(name: &str) -> Result<Volume, FsError> {
+    for disk in disks().into_iter().flatten() {
+        let Some(rest) = name.strip_prefix(disk.name()) else { continue };
+        if rest.is_empty() {
+            return Ok(Partition::whole(disk));
+        }
+        let Ok(number) = rest.parse::<u32>() else { continue };
+        let mut found = None;
+        read_table(&disk, &mut |entry| {
+            if entry.number == number {
+                found = Some(entry);
+            }
+        })?;
+        return found.and_then(|e| Partition::new(disk, &e)).ok_or(FsError::NotFound);
+    }
+    Err(FsError::NotFound)
+}
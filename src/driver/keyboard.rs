@@ -129,6 +129,16 @@ pub fn init_keyboard() {
     }
 }
 
+/// Whether a scancode is waiting, so `read_key` will not block.
+pub fn key_ready() -> bool {
+    let mut keyboard = KEYBOARD.lock();
+    if let Some(ref mut kb) = *keyboard {
+        kb.read_status() & 0x01 != 0
+    } else {
+        false
+    }
+}
+
 /// Reads a key from the keyboard, if available.
 pub fn read_key() -> Option<char> {
     let mut keyboard = KEYBOARD.lock();
@@ -1,2 +1,14 @@
+pub mod acpi;
+pub mod ata;
+pub mod devfs;
+pub mod disk;
 pub mod keyboard;
-pub mod shell;
\ No newline at end of file
+pub mod pci;
+pub mod ramdisk;
+pub mod rtc;
+pub mod serial;
+pub mod shell;
+pub mod virtio;
+
+/// Drivers offered the functions found on the PCI bus, in order
+pub const PCI_DRIVERS: &[pci::PciDriver] = &[virtio::blk::DRIVER];
@@ -0,0 +1,483 @@
+//! PCI configuration space and bus enumeration.
+//!
+//! Configuration registers are read and written through the memory-mapped
+//! ECAM region when the ACPI MCFG table describes one, and through the
+//! legacy port pair at 0xCF8/0xCFC (configuration mechanism #1) otherwise.
+//!
+//! `init` walks the buses from the host bridge down through PCI-to-PCI
+//! bridges, using the bus numbers the firmware assigned, and records every
+//! function in a fixed table with its BARs sized. Drivers register by
+//! vendor and device ID; each function is offered to the first driver that
+//! lists it, once enumeration is done.
+
+extern crate x86_64;
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::driver::acpi;
+use crate::polib::memory::map_mmio;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Configuration header offsets
+pub const REG_VENDOR: u8 = 0x00;
+pub const REG_DEVICE: u8 = 0x02;
+pub const REG_COMMAND: u8 = 0x04;
+pub const REG_STATUS: u8 = 0x06;
+pub const REG_REVISION: u8 = 0x08;
+pub const REG_PROG_IF: u8 = 0x09;
+pub const REG_SUBCLASS: u8 = 0x0A;
+pub const REG_CLASS: u8 = 0x0B;
+pub const REG_HEADER_TYPE: u8 = 0x0E;
+pub const REG_BAR0: u8 = 0x10;
+pub const REG_PRIMARY_BUS: u8 = 0x18;
+pub const REG_SECONDARY_BUS: u8 = 0x19;
+pub const REG_SUBORDINATE_BUS: u8 = 0x1A;
+pub const REG_CAPABILITIES: u8 = 0x34;
+pub const REG_INTERRUPT_LINE: u8 = 0x3C;
+
+pub const COMMAND_IO: u16 = 0x1;
+pub const COMMAND_MEMORY: u16 = 0x2;
+pub const COMMAND_BUS_MASTER: u16 = 0x4;
+const STATUS_CAPABILITIES: u16 = 0x10;
+
+// Header types
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+// Capability IDs
+pub const CAP_POWER: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Functions the device table holds; later ones are counted but dropped
+pub const MAX_DEVICES: usize = 64;
+
+/// Virtual address of the ECAM region, 0 if configuration goes through ports
+static ECAM_BASE: AtomicUsize = AtomicUsize::new(0);
+/// First bus of the ECAM region in the low byte, last in the next
+static ECAM_BUSES: AtomicU32 = AtomicU32::new(0);
+
+static DEVICES: Mutex<DeviceTable> = Mutex::new(DeviceTable { list: [None; MAX_DEVICES], count: 0, dropped: 0 });
+
+struct DeviceTable {
+    list: [Option<PciDevice>; MAX_DEVICES],
+    count: usize,
+    dropped: usize,
+}
+
+/// Bus, device and function of a PCI function
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Where the register at `offset` is mapped, if ECAM covers this bus
+    fn ecam_address(&self, offset: u8) -> Option<*mut u32> {
+        let base = ECAM_BASE.load(Ordering::Relaxed);
+        let buses = ECAM_BUSES.load(Ordering::Relaxed);
+        let (first, last) = (buses as u8, (buses >> 8) as u8);
+        if base == 0 || self.bus < first || self.bus > last {
+            return None;
+        }
+        let at = ((self.bus - first) as usize) << 20
+            | (self.device as usize) << 15
+            | (self.function as usize) << 12
+            | (offset & 0xFC) as usize;
+        Some((base + at) as *mut u32)
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        if let Some(reg) = self.ecam_address(offset) {
+            return unsafe { reg.read_volatile() };
+        }
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA);
+        unsafe {
+            address.write(self.config_address(offset));
+            data.read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        if let Some(reg) = self.ecam_address(offset) {
+            unsafe { reg.write_volatile(value) };
+            return;
+        }
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA);
+        unsafe {
+            address.write(self.config_address(offset));
+            data.write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.read_u16(REG_VENDOR)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(REG_DEVICE)
+    }
+
+    /// Sets bits in the command register, e.g. to enable bus mastering
+    pub fn enable(&self, bits: u16) {
+        self.write_u16(REG_COMMAND, self.read_u16(REG_COMMAND) | bits);
+    }
+
+    /// Legacy interrupt line routed by the firmware, if any
+    pub fn interrupt_line(&self) -> Option<u8> {
+        match self.read_u8(REG_INTERRUPT_LINE) {
+            0xFF => None,
+            line => Some(line),
+        }
+    }
+
+    /// Base address register `i`
+    pub fn bar(&self, i: u8) -> Option<Bar> {
+        let raw = self.read_u32(REG_BAR0 + i * 4);
+        if raw & 1 != 0 {
+            let port = (raw & !0x3) as u16;
+            return if port == 0 { None } else { Some(Bar::Io(port)) };
+        }
+        let mut base = (raw & !0xF) as u64;
+        if (raw >> 1) & 0x3 == 0x2 {
+            base |= (self.read_u32(REG_BAR0 + (i + 1) * 4) as u64) << 32;
+        }
+        if base == 0 { None } else { Some(Bar::Memory(base)) }
+    }
+
+    /// Decodes and sizes the base address registers of a header with
+    /// `count` of them. Sizing writes all ones and reads back which bits
+    /// stick, so decoding is switched off meanwhile.
+    fn size_bars(&self, count: u8) -> [Option<BarInfo>; 6] {
+        let mut bars = [None; 6];
+        let command = self.read_u16(REG_COMMAND);
+        self.write_u16(REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let mut i = 0;
+        while i < count {
+            let reg = REG_BAR0 + i * 4;
+            let raw = self.read_u32(reg);
+            self.write_u32(reg, 0xFFFF_FFFF);
+            let mask = self.read_u32(reg);
+            self.write_u32(reg, raw);
+            let io = raw & 1 != 0;
+            let wide = !io && (raw >> 1) & 0x3 == 0x2 && i + 1 < count;
+            let mut size_mask = if io { (mask & !0x3) as u64 | 0xFFFF_0000 } else { (mask & !0xF) as u64 };
+            if wide {
+                let high = reg + 4;
+                let raw_high = self.read_u32(high);
+                self.write_u32(high, 0xFFFF_FFFF);
+                size_mask |= (self.read_u32(high) as u64) << 32;
+                self.write_u32(high, raw_high);
+            } else {
+                size_mask |= 0xFFFF_FFFF_0000_0000;
+            }
+            if mask != 0 && mask != 0xFFFF_FFFF
+                && let Some(bar) = self.bar(i)
+            {
+                bars[i as usize] = Some(BarInfo {
+                    bar,
+                    size: (!size_mask).wrapping_add(1),
+                    wide,
+                    prefetchable: !io && raw & 0x8 != 0,
+                });
+            }
+            i += if wide { 2 } else { 1 };
+        }
+        self.write_u16(REG_COMMAND, command);
+        bars
+    }
+
+    /// Calls `f(offset, id)` for every entry of the capability list
+    pub fn capabilities(&self, f: &mut dyn FnMut(u8, u8)) {
+        if self.read_u16(REG_STATUS) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        let mut offset = self.read_u8(REG_CAPABILITIES) & 0xFC;
+        // The list lives in the 192 bytes after the header; a longer chain
+        // is a loop
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            f(offset, self.read_u8(offset));
+            offset = self.read_u8(offset + 1) & 0xFC;
+        }
+    }
+
+    /// Offset of the first capability with `id`
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        let mut found = None;
+        self.capabilities(&mut |offset, cap| {
+            if cap == id && found.is_none() {
+                found = Some(offset);
+            }
+        });
+        found
+    }
+
+    /// The MSI capability, if the function has one
+    pub fn msi(&self) -> Option<Msi> {
+        let offset = self.find_capability(CAP_MSI)?;
+        let control = self.read_u16(offset + 2);
+        Some(Msi {
+            offset,
+            enabled: control & 0x1 != 0,
+            vectors: 1u8 << ((control >> 1) & 0x7).min(5),
+            wide: control & 0x80 != 0,
+            maskable: control & 0x100 != 0,
+        })
+    }
+
+    /// The MSI-X capability, if the function has one
+    pub fn msix(&self) -> Option<MsiX> {
+        let offset = self.find_capability(CAP_MSIX)?;
+        let control = self.read_u16(offset + 2);
+        let table = self.read_u32(offset + 4);
+        let pending = self.read_u32(offset + 8);
+        Some(MsiX {
+            offset,
+            enabled: control & 0x8000 != 0,
+            vectors: (control & 0x7FF) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pending_bar: (pending & 0x7) as u8,
+            pending_offset: pending & !0x7,
+        })
+    }
+}
+
+/// Decoded base address register
+#[derive(Clone, Copy)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+/// A base address register with the size of the region it decodes
+#[derive(Clone, Copy)]
+pub struct BarInfo {
+    pub bar: Bar,
+    pub size: u64,
+    pub wide: bool, // 64-bit, taking the next register as well
+    pub prefetchable: bool,
+}
+
+/// Message signalled interrupt capability
+pub struct Msi {
+    pub offset: u8,
+    pub enabled: bool,
+    pub vectors: u8, // supported, a power of two up to 32
+    pub wide: bool,  // takes a 64-bit message address
+    pub maskable: bool,
+}
+
+/// MSI-X capability; the vector table and pending bits live in BARs
+pub struct MsiX {
+    pub offset: u8,
+    pub enabled: bool,
+    pub vectors: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pending_bar: u8,
+    pub pending_offset: u32,
+}
+
+/// A function found by enumeration
+#[derive(Clone, Copy)]
+pub struct PciDevice {
+    pub addr: PciAddress,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8, // without the multifunction bit
+    pub bars: [Option<BarInfo>; 6],
+    pub driver: Option<&'static str>,
+}
+
+impl PciDevice {
+    fn read(addr: PciAddress) -> PciDevice {
+        let header_type = addr.read_u8(REG_HEADER_TYPE) & !HEADER_MULTIFUNCTION;
+        let bars = match header_type {
+            HEADER_GENERAL => addr.size_bars(6),
+            HEADER_BRIDGE => addr.size_bars(2),
+            _ => [None; 6],
+        };
+        PciDevice {
+            addr,
+            vendor: addr.vendor(),
+            device: addr.device_id(),
+            class: addr.read_u8(REG_CLASS),
+            subclass: addr.read_u8(REG_SUBCLASS),
+            prog_if: addr.read_u8(REG_PROG_IF),
+            revision: addr.read_u8(REG_REVISION),
+            header_type,
+            bars,
+            driver: None,
+        }
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE
+    }
+}
+
+/// A driver for PCI functions with the listed (vendor, device) IDs.
+/// `probe` sets the function up and returns whether it took it.
+pub struct PciDriver {
+    pub name: &'static str,
+    pub ids: &'static [(u16, u16)],
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+/// Configuration access in use: "ECAM" or "ports"
+pub fn access_method() -> &'static str {
+    if ECAM_BASE.load(Ordering::Relaxed) != 0 { "ECAM" } else { "ports" }
+}
+
+/// Maps the ECAM region of segment 0 from the ACPI MCFG table. Buses
+/// outside it keep using ports.
+fn init_ecam() {
+    let Some(mcfg) = acpi::find_table(b"MCFG") else {
+        return;
+    };
+    // Allocation entries of 16 bytes follow the header and 8 reserved bytes
+    for entry in mcfg[acpi::HEADER_LEN + 8..].chunks_exact(16) {
+        let base = u64::from_le_bytes([
+            entry[0], entry[1], entry[2], entry[3], entry[4], entry[5], entry[6], entry[7],
+        ]);
+        let segment = u16::from_le_bytes([entry[8], entry[9]]);
+        let (first, last) = (entry[10], entry[11]);
+        if segment != 0 || last < first {
+            continue;
+        }
+        let len = ((last - first) as usize + 1) << 20;
+        if let Some(virt) = map_mmio(base + ((first as u64) << 20), len) {
+            ECAM_BUSES.store(first as u32 | (last as u32) << 8, Ordering::Relaxed);
+            ECAM_BASE.store(virt, Ordering::Relaxed);
+        }
+        return;
+    }
+}
+
+fn scan_bus(table: &mut DeviceTable, bus: u8, seen: &mut [bool; 256]) {
+    if seen[bus as usize] {
+        return;
+    }
+    seen[bus as usize] = true;
+    for device in 0..32 {
+        for function in 0..8 {
+            let addr = PciAddress { bus, device, function };
+            if addr.vendor() == 0xFFFF {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            }
+            scan_function(table, addr, seen);
+            // Single‑function devices ignore the function number
+            if function == 0 && addr.read_u8(REG_HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+fn scan_function(table: &mut DeviceTable, addr: PciAddress, seen: &mut [bool; 256]) {
+    let dev = PciDevice::read(addr);
+    if table.count < MAX_DEVICES {
+        table.list[table.count] = Some(dev);
+        table.count += 1;
+    } else {
+        table.dropped += 1;
+    }
+    if dev.is_bridge() {
+        let secondary = addr.read_u8(REG_SECONDARY_BUS);
+        // An unconfigured bridge has secondary bus 0
+        if secondary > addr.bus {
+            scan_bus(table, secondary, seen);
+        }
+    }
+}
+
+/// Enumerates the buses and binds `drivers`; called once at boot. Returns
+/// the number of functions found.
+pub fn init(drivers: &[PciDriver]) -> usize {
+    init_ecam();
+    let mut table = DEVICES.lock();
+    let mut seen = [false; 256];
+    // A multifunction host bridge has one function per host controller,
+    // each owning the bus of its function number
+    let host = PciAddress { bus: 0, device: 0, function: 0 };
+    if host.read_u8(REG_HEADER_TYPE) & HEADER_MULTIFUNCTION == 0 {
+        scan_bus(&mut table, 0, &mut seen);
+    } else {
+        for function in 0..8 {
+            if (PciAddress { function, ..host }).vendor() != 0xFFFF {
+                scan_bus(&mut table, function, &mut seen);
+            }
+        }
+    }
+    let found = table.count + table.dropped;
+    let list = table.list;
+    core::mem::drop(table);
+
+    // Probe without the lock, so drivers can look at the table
+    for (i, dev) in list.iter().enumerate() {
+        let Some(dev) = dev else { break };
+        let driver = drivers.iter().find(|d| d.ids.contains(&(dev.vendor, dev.device)));
+        if let Some(driver) = driver
+            && (driver.probe)(dev)
+            && let Some(entry) = DEVICES.lock().list[i].as_mut()
+        {
+            entry.driver = Some(driver.name);
+        }
+    }
+    found
+}
+
+/// Calls `f` for every function found by `init`
+pub fn devices(f: &mut dyn FnMut(&PciDevice)) {
+    let table = DEVICES.lock();
+    for dev in table.list.iter().flatten() {
+        f(dev);
+    }
+}
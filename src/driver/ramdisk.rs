@@ -0,0 +1,130 @@
+//! RAM disks.
+//!
+//! A RAM disk is a block device over memory taken once and never given
+//! back, like the disk table entry that holds it. Small disks come from the
+//! kernel heap; larger ones from physical frames, so they do not crowd out
+//! the heap. Either way they start zeroed. The block size is any power of
+//! two from `MIN_BLOCK_SIZE` to `PAGE_SIZE`; only `BLOCK_SIZE` disks can
+//! carry a Popoen filesystem, but the others work as devices under `/dev`.
+
+use core::result::Result::{self, Err, Ok};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::error::{FsError, IoError};
+use crate::fs::structure::{check_range, BlockDevice};
+use crate::polib::heap::HEAP_SIZE;
+use crate::polib::memory::{alloc_dma, PAGE_SIZE};
+
+pub const MIN_BLOCK_SIZE: usize = 64;
+
+/// Largest disk taken from the heap; bigger ones get frames of their own
+const MAX_HEAP_BYTES: usize = HEAP_SIZE / 4;
+
+/// Names in the order disks are created; a letter, so that partition
+/// numbers can follow as with `vda1`
+const NAMES: [&str; 8] = ["rda", "rdb", "rdc", "rdd", "rde", "rdf", "rdg", "rdh"];
+
+/// Disks created so far, and so the index of the next name
+static CREATED: Mutex<usize> = Mutex::new(0);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Heap,
+    Frames,
+}
+
+impl Backing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backing::Heap => "heap",
+            Backing::Frames => "physical frames",
+        }
+    }
+}
+
+pub struct RamDisk {
+    data: &'static mut [u8],
+    block_size: usize,
+    blocks: u32,
+    backing: Backing,
+    name: &'static str,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `blocks` blocks of `block_size` bytes. Fails with
+    /// `InvalidArgument` for a bad geometry and `NoSpace` when memory or
+    /// names run out.
+    pub fn new(block_size: usize, blocks: u32) -> Result<RamDisk, FsError> {
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=PAGE_SIZE).contains(&block_size) || blocks == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let len = (blocks as usize).checked_mul(block_size).ok_or(FsError::InvalidArgument)?;
+        // Held until the memory is taken, so a failed attempt keeps its name
+        let mut created = CREATED.lock();
+        let name = *NAMES.get(*created).ok_or(FsError::NoSpace)?;
+        let (data, backing) = if len <= MAX_HEAP_BYTES {
+            let mut buf = Vec::new();
+            buf.try_reserve_exact(len).map_err(|_| FsError::NoSpace)?;
+            buf.resize(len, 0);
+            (buf.leak(), Backing::Heap)
+        } else {
+            let dma = alloc_dma(len.div_ceil(PAGE_SIZE)).ok_or(FsError::NoSpace)?;
+            // The frames are never freed, so the slice may live forever
+            (unsafe { core::slice::from_raw_parts_mut(dma.as_ptr::<u8>(0), len) }, Backing::Frames)
+        };
+        *created += 1;
+        Ok(RamDisk { data, block_size, blocks, backing, name })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    /// Byte range of `len` bytes from block `start`, once checked
+    fn range(&self, start: u32, len: usize) -> Result<core::ops::Range<usize>, IoError> {
+        check_range(self.blocks, self.block_size, start, len)?;
+        let offset = start as usize * self.block_size;
+        Ok(offset..offset + len)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        if buf.len() != self.block_size {
+            return Err(IoError::BufferSize);
+        }
+        self.read_blocks(block_number, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        if buf.len() != self.block_size {
+            return Err(IoError::BufferSize);
+        }
+        self.write_blocks(block_number, buf)
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        buf.copy_from_slice(&self.data[self.range(start, buf.len())?]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        let range = self.range(start, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+extern crate x86_64;
+
+use x86_64::instructions::port::Port;
+
+use crate::fs::clock::days_from_civil;
+
+// I/O Ports for the CMOS real-time clock
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// CMOS registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+fn read_cmos(reg: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        index.write(reg);
+        data.read()
+    }
+}
+
+/// Raw date and time registers: seconds, minutes, hours, day, month, year
+fn read_registers() -> [u8; 6] {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    [
+        read_cmos(REG_SECONDS),
+        read_cmos(REG_MINUTES),
+        read_cmos(REG_HOURS),
+        read_cmos(REG_DAY),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+    ]
+}
+
+fn from_bcd(v: u8) -> u8 {
+    (v & 0x0F) + (v >> 4) * 10
+}
+
+/// Seconds since 1970‑01‑01 00:00 UTC, assuming the RTC keeps UTC
+pub fn unix_time() -> u32 {
+    // Read until two consecutive reads agree, so an update cannot tear them
+    let mut regs = read_registers();
+    loop {
+        let again = read_registers();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+
+    let status_b = read_cmos(REG_STATUS_B);
+    let pm = regs[2] & HOUR_PM != 0;
+    regs[2] &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        for r in regs.iter_mut() {
+            *r = from_bcd(*r);
+        }
+    }
+    let [sec, min, mut hour, day, month, year] = regs;
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    let days = days_from_civil(2000 + year as u32, month as u32, day as u32);
+    days * 86400 + hour as u32 * 3600 + min as u32 * 60 + sec as u32
+}
@@ -0,0 +1,88 @@
+//! Serial port COM1 (16550 UART).
+//!
+//! Set up for 115200 baud, 8N1, and polled: `write` waits for room in the
+//! transmitter and `read` takes whatever has been received. `init` checks
+//! the port with a loopback test, so a machine without one leaves it unused.
+
+extern crate x86_64;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+// Registers, as offsets from the base port
+const DATA: u16 = 0; // divisor low byte while DLAB is set
+const INT_ENABLE: u16 = 1; // divisor high byte while DLAB is set
+const FIFO_CTRL: u16 = 2;
+const LINE_CTRL: u16 = 3;
+const MODEM_CTRL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LCR_DLAB: u8 = 0x80;
+const LCR_8N1: u8 = 0x03;
+const FCR_ENABLE_CLEAR: u8 = 0xC7; // enable and clear FIFOs, 14-byte threshold
+const MCR_LOOPBACK: u8 = 0x1E;
+const MCR_NORMAL: u8 = 0x0F; // DTR, RTS, OUT1, OUT2
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+/// Divisor of the 115200 Hz base clock
+const DIVISOR: u16 = 1;
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+fn outb(reg: u16, val: u8) {
+    unsafe { Port::new(COM1 + reg).write(val) }
+}
+
+fn inb(reg: u16) -> u8 {
+    unsafe { Port::new(COM1 + reg).read() }
+}
+
+/// Programs the UART; returns false if no working port answers
+pub fn init() -> bool {
+    outb(INT_ENABLE, 0);
+    outb(LINE_CTRL, LCR_DLAB);
+    outb(DATA, DIVISOR as u8);
+    outb(INT_ENABLE, (DIVISOR >> 8) as u8);
+    outb(LINE_CTRL, LCR_8N1);
+    outb(FIFO_CTRL, FCR_ENABLE_CLEAR);
+    outb(MODEM_CTRL, MCR_LOOPBACK);
+    outb(DATA, 0xAE);
+    if inb(DATA) != 0xAE {
+        return false;
+    }
+    outb(MODEM_CTRL, MCR_NORMAL);
+    PRESENT.store(true, Ordering::Relaxed);
+    true
+}
+
+pub fn present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Sends `data`; dropped if there is no port
+pub fn write(data: &[u8]) {
+    if !present() {
+        return;
+    }
+    for &b in data {
+        while inb(LINE_STATUS) & LSR_THR_EMPTY == 0 {}
+        outb(DATA, b);
+    }
+}
+
+/// Copies bytes received so far into `buf`, returning how many
+pub fn read(buf: &mut [u8]) -> usize {
+    if !present() {
+        return 0;
+    }
+    let mut n = 0;
+    while n < buf.len() && inb(LINE_STATUS) & LSR_DATA_READY != 0 {
+        buf[n] = inb(DATA);
+        n += 1;
+    }
+    n
+}
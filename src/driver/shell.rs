@@ -1,28 +1,430 @@
 use core::clone::Clone;
 use core::iter::Iterator;
 use core::option::Option::{None, Some};
-use core::result::Result::Ok;
+use core::result::Result::{Err, Ok};
 use core::str;
 
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::apps::lsblk::lsblk;
+use crate::apps::lspci::lspci;
 use crate::apps::pofetch::pofetch;
+use crate::driver::devfs::Devfs;
+use crate::driver::disk::{self, volume_name, Disk};
 use crate::driver::keyboard;
-use crate::fs::structure::{BlockDevice, DirEntry, Inode};
-use crate::fs::utils::ls;
+use crate::driver::ramdisk::RamDisk;
+use crate::fs::clock::{self, DateTime};
+use crate::fs::error::FsError;
+use crate::fs::ext2::Ext2;
+use crate::fs::fat::Fat;
+use crate::fs::ops;
+use crate::fs::partition::Partition;
+use crate::fs::perm::Cred;
+use crate::fs::popoen::PopoenFs;
+use crate::fs::quota::{Grace, Limits};
+use crate::fs::structure::{BLOCK_SIZE, S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT};
+use crate::fs::tmpfs::Tmpfs;
+use crate::fs::utils::{mkfs, mode_string};
+use crate::fs::vfs::{FileSystem, Stat, Vfs, O_APPEND, O_READ, O_WRITE};
+use crate::fs::xattr;
 use crate::polib::print::clear;
-use crate::{GLOBAL_DEVICE, PPDev, ROOT_INODE, poprint};
+use crate::polib::process;
+use crate::polib::procfs::Procfs;
+use crate::{TMPFS_SIZE, VFS, poprint};
 
 const BUFFER_SIZE: usize = 128;
 
-pub fn shell() {
-    {
-        let guard = GLOBAL_DEVICE.lock();
-        if guard.is_none() {
+fn report(cmd: &str, path: &str, err: FsError) {
+    poprint!("{}: '{}': {}\n", cmd, path, err);
+}
+
+/// Lists the directory at `path`, or prints `path` if it is a file; `long`
+/// adds the allocated KiB, mode bits, owner, size and mtime
+fn ls<D: FileSystem + ?Sized>(vfs: &Vfs<D>, cred: &Cred, path: &str, long: bool) -> Result<(), FsError> {
+    if !vfs.stat(cred, path)?.is_dir() {
+        poprint!("{}\n", path);
+        return Ok(());
+    }
+    if !long {
+        poprint!("{:<6} {:>5}  {}\n", "Type", "Links", "Name");
+        poprint!("{:-<6} {:->5}  {:-<28}\n", "", "", "");
+    }
+    vfs.readdir(cred, path, &mut |e| {
+        let Some(st) = e.stat else {
+            poprint!("?      {:>5}  {}\n", "?", e.name);
             return;
+        };
+        if long {
+            let mode = mode_string(st.mode);
+            poprint!(
+                "{:>5} {} {:>3} {:>5} {:>5} {:>8} {}  {}",
+                st.blocks.div_ceil(2),
+                str::from_utf8(&mode).unwrap_or("?"),
+                st.links,
+                st.uid,
+                st.gid,
+                st.size,
+                DateTime::from_unix(st.mtime),
+                e.name
+            );
+        } else {
+            let kind = match st.mode & S_IFMT {
+                S_IFDIR => "dir",
+                S_IFLNK => "link",
+                S_IFCHR => "char",
+                S_IFBLK => "block",
+                _ => "file",
+            };
+            poprint!("{:<6} {:>5}  {}", kind, st.links, e.name);
+        }
+        if let Some(target) = e.target {
+            poprint!(" -> {}", target);
+        }
+        poprint!("\n");
+    })
+}
+
+/// Prints the file at `path`, ending with a newline
+fn cat<D: FileSystem + ?Sized>(vfs: &mut Vfs<D>, cred: &Cred, path: &str) -> Result<(), FsError> {
+    let fd = vfs.open(cred, path, O_READ, 0)?;
+    let mut data = [0u8; 512];
+    let mut last = b'\n';
+    let res = loop {
+        let n = match vfs.read(fd, &mut data) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) => break Err(e),
+        };
+        let txt = str::from_utf8(&data[..n]).unwrap_or("<non-UTF8>");
+        poprint!("{}", txt);
+        last = data[n - 1];
+    };
+    if last != b'\n' {
+        poprint!("\n");
+    }
+    vfs.close(fd)?;
+    res
+}
+
+/// Prints up to `limit` bytes of the file at `path`, 16 to a line, in hex
+/// and as text, and then the offset reached
+fn hexdump<D: FileSystem + ?Sized>(vfs: &mut Vfs<D>, cred: &Cred, path: &str, limit: u64) -> Result<(), FsError> {
+    let fd = vfs.open(cred, path, O_READ, 0)?;
+    let mut line = [0u8; 16];
+    let mut offset = 0u64;
+    let res = loop {
+        let want = (limit - offset).min(line.len() as u64) as usize;
+        if want == 0 {
+            break Ok(());
         }
-        core::mem::drop(guard);
+        let n = match vfs.read(fd, &mut line[..want]) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) => break Err(e),
+        };
+        poprint!("{:08x} ", offset);
+        for i in 0..line.len() {
+            match line[..n].get(i) {
+                Some(b) => poprint!(" {:02x}", b),
+                None => poprint!("   "),
+            }
+        }
+        poprint!("  |");
+        for &b in &line[..n] {
+            // `[` would start a colour tag
+            let c = if b.is_ascii_graphic() && b != b'[' || b == b' ' { b as char } else { '.' };
+            poprint!("{}", c);
+        }
+        poprint!("|\n");
+        offset += n as u64;
+    };
+    poprint!("{:08x}\n", offset);
+    vfs.close(fd)?;
+    res
+}
+
+/// Appends `text` and a newline to the existing file at `path`
+fn append_line<D: FileSystem + ?Sized>(vfs: &mut Vfs<D>, cred: &Cred, path: &str, text: &str) -> Result<(), FsError> {
+    let fd = vfs.open(cred, path, O_WRITE | O_APPEND, 0)?;
+    let res = vfs.write(fd, text.as_bytes()).and_then(|_| vfs.write(fd, b"\n"));
+    vfs.close(fd)?;
+    res.map(|_| ())
+}
+
+/// Prints the space used under `path` for it and every directory below it,
+/// deepest first: allocated KiB, or the apparent size in bytes with
+/// `apparent`. Symbolic links are not followed. Returns the total.
+fn du<D: FileSystem + ?Sized>(vfs: &Vfs<D>, cred: &Cred, path: &str, apparent: bool) -> Result<u64, FsError> {
+    let size = |st: &Stat| if apparent { st.size } else { st.blocks * 512 };
+    let st = vfs.stat(cred, path)?;
+    let mut total = size(&st);
+    if st.is_dir() {
+        let mut dirs = Vec::new();
+        vfs.readdir(cred, path, &mut |e| {
+            let Some(st) = e.stat else {
+                return;
+            };
+            if st.is_dir() {
+                if e.name != "." && e.name != ".." {
+                    dirs.push(format!("{}/{}", path.trim_end_matches('/'), e.name));
+                }
+            } else {
+                total += size(&st);
+            }
+        })?;
+        for dir in dirs {
+            total += du(vfs, cred, &dir, apparent)?;
+        }
+    }
+    if apparent {
+        poprint!("{:<10} {}\n", total, path);
+    } else {
+        poprint!("{:<10} {}\n", total.div_ceil(1024), path);
+    }
+    Ok(total)
+}
+
+/// Parses `uid`, `uid:gid` or `:gid`
+fn parse_owner(spec: &str) -> Option<(Option<u16>, Option<u16>)> {
+    let (uid, gid) = match spec.split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (spec, None),
+    };
+    let uid = if uid.is_empty() { None } else { Some(uid.parse().ok()?) };
+    let gid = match gid {
+        Some(g) => Some(g.parse().ok()?),
+        None => None,
+    };
+    if uid.is_none() && gid.is_none() {
+        return None;
+    }
+    Some((uid, gid))
+}
+
+/// Splits `args` into the words of `argv`, returning how many there were, or
+/// None if there are more than it holds
+fn split_args<'a, const N: usize>(args: &'a str, argv: &mut [&'a str; N]) -> Option<usize> {
+    let mut n = 0;
+    for word in args.split_whitespace() {
+        *argv.get_mut(n)? = word;
+        n += 1;
+    }
+    Some(n)
+}
+
+/// Prints `name="value"` as `getfattr` shows an attribute
+fn print_xattr(name: &[u8], value: &[u8]) {
+    let name = str::from_utf8(name).unwrap_or("<invalid>");
+    match str::from_utf8(value) {
+        Ok(text) => poprint!("{}=\"{}\"\n", name, text),
+        Err(_) => {
+            poprint!("{}=0x", name);
+            for b in value {
+                poprint!("{:02x}", b);
+            }
+            poprint!("\n");
+        }
+    }
+}
+
+/// Whether volumes `a` and `b` share blocks: the same one, or a disk and
+/// one of its partitions
+fn overlaps(a: &str, b: &str) -> bool {
+    let disk = |v: &str| v.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    a == b || a.len() == disk(b) && b.starts_with(a) || b.len() == disk(a) && a.starts_with(b)
+}
+
+/// Mounts the filesystem on volume `source` at directory `path`: a Popoen
+/// filesystem, or else ext2, read-only, or else FAT
+fn mount(cred: &Cred, source: &str, path: &str) -> Result<(), FsError> {
+    let mut vfs = VFS.lock();
+    if !vfs.stat(cred, path)?.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    let volume = disk::open(source)?;
+    let name = volume_name(&volume);
+    if vfs.mounts().any(|m| overlaps(m.source(), name.as_str())) {
+        return Err(FsError::Busy);
+    }
+    let fs: Box<dyn FileSystem + Send> = match PopoenFs::open(volume) {
+        Err(FsError::BadMagic) => match Ext2::open(volume) {
+            Err(FsError::BadMagic) => Box::new(Fat::open(volume)?),
+            fs => Box::new(fs?),
+        },
+        fs => Box::new(fs?),
+    };
+    vfs.mount(cred, path, name.as_str(), fs)
+}
+
+/// Mounts a filesystem of type `kind` that lives in memory, `tmpfs`,
+/// `devfs` or `proc`, at directory `path`, listed as coming from `source`
+fn mount_virtual(cred: &Cred, kind: &str, source: &str, path: &str) -> Result<(), FsError> {
+    let mut vfs = VFS.lock();
+    if !vfs.stat(cred, path)?.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    let fs: Box<dyn FileSystem + Send> = match kind {
+        "tmpfs" => Box::new(Tmpfs::new(TMPFS_SIZE, 0o777)?),
+        "devfs" => Box::new(Devfs::new()),
+        "proc" => Box::new(Procfs::new()),
+        _ => return Err(FsError::Unsupported),
+    };
+    vfs.mount(cred, path, source, fs)
+}
+
+/// Parses a size in bytes with an optional binary suffix, `K`, `M` or `G`
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, shift) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 20),
+        b'G' | b'g' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Creates a RAM disk of at least `size` bytes in blocks of `block_size`
+/// and adds it to the disk table. One of `BLOCK_SIZE` blocks gets a Popoen
+/// filesystem, ready to mount.
+fn mkramdisk(size: u64, block_size: usize) -> Result<(), FsError> {
+    if !block_size.is_power_of_two() {
+        return Err(FsError::InvalidArgument);
+    }
+    let blocks = u32::try_from(size.div_ceil(block_size as u64)).map_err(|_| FsError::InvalidArgument)?;
+    let ram = RamDisk::new(block_size, blocks)?;
+    let (name, backing) = (ram.name(), ram.backing());
+    let disk = disk::add(Disk::Ram(ram)).ok_or(FsError::NoSpace)?;
+    let formatted = block_size == BLOCK_SIZE;
+    if formatted {
+        mkfs(&mut Partition::whole(disk), blocks)?;
+    }
+    poprint!(
+        "{}: {} blocks of {} bytes from {}{}\n",
+        name,
+        blocks,
+        block_size,
+        backing.name(),
+        if formatted { ", formatted" } else { "" }
+    );
+    Ok(())
+}
+
+/// Parses a duration in seconds with an optional unit, `s`, `m`, `h` or `d`
+fn parse_duration(text: &str) -> Option<u32> {
+    let (digits, unit) = match text.as_bytes().last()? {
+        b's' => (&text[..text.len() - 1], 1),
+        b'm' => (&text[..text.len() - 1], 60),
+        b'h' => (&text[..text.len() - 1], 60 * 60),
+        b'd' => (&text[..text.len() - 1], 24 * 60 * 60),
+        _ => (text, 1),
+    };
+    digits.parse::<u32>().ok()?.checked_mul(unit)
+}
+
+/// `secs` as days and hours, or as hours and minutes under a day
+fn duration(secs: u32) -> String {
+    if secs >= 24 * 60 * 60 {
+        format!("{}d{}h", secs / (24 * 60 * 60), secs / (60 * 60) % 24)
+    } else {
+        format!("{}:{:02}", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+/// Time left of a grace period ending at `expires`: blank if none is
+/// running and `none` once it is over, as `quota` shows it
+fn grace_left(expires: u32, now: u32) -> String {
+    match expires {
+        0 => String::new(),
+        e if now >= e => String::from("none"),
+        e => duration(e - now),
+    }
+}
+
+/// Prints the limits and usage of user `uid` on the filesystem holding
+/// `path`. A `*` marks usage over a soft limit.
+fn quota(cred: &Cred, uid: u16, path: &str) -> Result<(), FsError> {
+    if uid != cred.uid && !cred.is_root() {
+        return Err(FsError::NotPermitted);
+    }
+    let mut vfs = VFS.lock();
+    let fs = vfs.filesystem(cred, path)?;
+    let (q, grace) = (fs.quota(uid)?, fs.grace()?);
+    let (l, now) = (q.limits, clock::now());
+    let over = |used: u32, soft: u32| if soft != 0 && used > soft { "*" } else { " " };
+    poprint!("Disk quotas for uid {} on {}:\n", uid, path);
+    poprint!(
+        "{:>9} {:>7} {:>7} {:>7}  {:>9} {:>7} {:>7} {:>7}\n",
+        "blocks", "soft", "hard", "grace", "inodes", "soft", "hard", "grace"
+    );
+    poprint!(
+        "{:>8}{} {:>7} {:>7} {:>7}  {:>8}{} {:>7} {:>7} {:>7}\n",
+        q.blocks,
+        over(q.blocks, l.block_soft),
+        l.block_soft,
+        l.block_hard,
+        grace_left(q.block_expires, now),
+        q.inodes,
+        over(q.inodes, l.inode_soft),
+        l.inode_soft,
+        l.inode_hard,
+        grace_left(q.inode_expires, now)
+    );
+    poprint!("Grace periods: blocks {}, inodes {}\n", duration(grace.blocks), duration(grace.inodes));
+    Ok(())
+}
+
+/// Carries out `setquota` with the words after it, or returns None if they
+/// make no sense
+fn setquota(cred: &Cred, argv: &[&str]) -> Option<Result<(), FsError>> {
+    /// The optional path ending the arguments
+    fn path<'a>(rest: &[&'a str]) -> Option<&'a str> {
+        match *rest {
+            [] => Some("/"),
+            [path] => Some(path),
+            _ => None,
+        }
+    }
+    let limit = |text: &str| text.parse::<u32>().ok();
+    let mut vfs = VFS.lock();
+    match argv {
+        ["-t", blocks, inodes, rest @ ..] => {
+            let grace = Grace { blocks: parse_duration(blocks)?, inodes: parse_duration(inodes)? };
+            let path = path(rest)?;
+            if !cred.is_root() {
+                return Some(Err(FsError::NotPermitted));
+            }
+            Some(vfs.filesystem(cred, path).and_then(|fs| fs.set_grace(grace)))
+        }
+        [uid, block_soft, block_hard, inode_soft, inode_hard, rest @ ..] => {
+            let uid = uid.parse::<u16>().ok()?;
+            let limits = Limits {
+                block_soft: limit(block_soft)?,
+                block_hard: limit(block_hard)?,
+                inode_soft: limit(inode_soft)?,
+                inode_hard: limit(inode_hard)?,
+            };
+            let path = path(rest)?;
+            if !cred.is_root() {
+                return Some(Err(FsError::NotPermitted));
+            }
+            Some(vfs.filesystem(cred, path).and_then(|fs| fs.set_quota(uid, limits)))
+        }
+        _ => None,
+    }
+}
+
+pub fn shell() {
+    if VFS.lock().mounts().next().is_none() {
+        return;
     }
 
     let mut input_buf = [0u8; BUFFER_SIZE];
+    // There is no login; `su` switches freely
+    let mut cred = Cred::ROOT;
+    let pid = process::spawn("shell", cred);
 
     loop {
         poprint!("[bggreen white][[PPOS] ~>[reset] ");
@@ -47,6 +449,12 @@ pub fn shell() {
                     }
                     _ => {}
                 }
+            } else {
+                for m in VFS.lock().mounts_mut() {
+                    if let Err(e) = m.fs.tick() {
+                        poprint!("\nsync: {}: {}\n", m.path(), e);
+                    }
+                }
             }
         }
 
@@ -56,184 +464,415 @@ pub fn shell() {
 
         let command = str::from_utf8(&input_buf[..idx]).unwrap_or("").trim();
 
-        if command == "ls" {
-            let guard = GLOBAL_DEVICE.lock();
-            let dev = guard.as_ref().unwrap();
-            ls(dev);
-            core::mem::drop(guard);
+        if command == "ls" || command.starts_with("ls ") {
+            let args = command["ls".len()..].trim();
+            let (long, path) = match args.strip_prefix("-l") {
+                Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest.trim()),
+                _ => (false, args),
+            };
+            let res = ls(&VFS.lock(), &cred, path, long);
+            if let Err(e) = res {
+                report("ls", path, e);
+            }
             continue;
         }
 
-        if command.starts_with("cat ") {
-            let fname = command["cat ".len()..].trim();
-            let guard = GLOBAL_DEVICE.lock();
-            let dev = guard.as_ref().unwrap();
+        if command == "du" || command.starts_with("du ") {
+            let args = command["du".len()..].trim();
+            let (apparent, path) = match args.strip_prefix("-b") {
+                Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest.trim()),
+                _ => (false, args),
+            };
+            let path = if path.is_empty() { "." } else { path };
+            if let Err(e) = du(&VFS.lock(), &cred, path, apparent) {
+                report("du", path, e);
+            }
+            continue;
+        }
 
-            let mut dir_buf = [0u8; 512];
-            dev.read_block(2, &mut dir_buf);
+        if let Some(args) = command.strip_prefix("cat ") {
+            let fname = args.trim();
+            let res = cat(&mut VFS.lock(), &cred, fname);
+            if let Err(e) = res {
+                report("cat", fname, e);
+            }
+            continue;
+        }
 
-            let ent_sz = core::mem::size_of::<DirEntry>();
-            let max = 512 / ent_sz;
-            let mut found = None;
+        if let Some(args) = command.strip_prefix("hexdump ") {
+            let mut argv = [""; 3];
+            let n = split_args(args, &mut argv).unwrap_or(0);
+            let (limit, path) = match &argv[..n] {
+                [path] => (Some(u64::MAX), *path),
+                ["-n", count, path] => (count.parse().ok(), *path),
+                _ => (None, ""),
+            };
+            let Some(limit) = limit else {
+                poprint!("Usage: hexdump [-n <bytes>] <path>\n");
+                continue;
+            };
+            if let Err(e) = hexdump(&mut VFS.lock(), &cred, path, limit) {
+                report("hexdump", path, e);
+            }
+            continue;
+        }
 
-            for i in 0..max {
-                let base = i * ent_sz;
-                let e = unsafe { &*(dir_buf[base..].as_ptr() as *const DirEntry) };
-                if e.inode_index != 0 {
-                    let len = e.name.iter().position(|&b| b == 0).unwrap_or(32);
-                    let name = str::from_utf8(&e.name[..len]).unwrap_or("");
-                    if name == fname {
-                        found = Some((e.inode_index - 1) as usize);
-                        break;
-                    }
-                }
+        if let Some(args) = command.strip_prefix("touch ") {
+            let fname = args.trim();
+            if fname.is_empty() {
+                poprint!("Usage: touch <name>\n");
+                continue;
+            }
+
+            match VFS.lock().create(&cred, fname, 0o644) {
+                Ok(_) | Err(FsError::Exists) => {}
+                Err(e) => report("touch", fname, e),
             }
+            continue;
+        }
 
-            if let Some(in_idx) = found {
-                let mut ibuf = [0u8; 512];
-                dev.read_block(0, &mut ibuf);
-                let ptr = &ibuf[in_idx * core::mem::size_of::<Inode>()];
-                let inode = unsafe { &*(ptr as *const u8 as *const Inode) };
-                let blk = inode.direct_ptrs[0] as u32;
+        if let Some(rest) = command.strip_prefix(">> ") {
+            let mut sp = rest.splitn(2, ' ');
+            let fname = sp.next().unwrap_or("").trim();
+            let data = sp.next().unwrap_or("").trim();
+            if fname.is_empty() || data.is_empty() {
+                poprint!("Usage: >> <file> <text>\n");
+                continue;
+            }
 
-                let mut data = [0u8; 512];
-                dev.read_block(blk, &mut data);
-                let txt = str::from_utf8(&data).unwrap_or("<non-UTF8>");
-                poprint!("{}", txt.trim_start());
-            } else {
-                poprint!("cat: '{}' not found\n", fname);
+            let res = append_line(&mut VFS.lock(), &cred, fname, data);
+            if let Err(e) = res {
+                report(">>", fname, e);
             }
+            continue;
+        }
 
-            core::mem::drop(guard);
-            poprint!("\n");
+        if let Some(args) = command.strip_prefix("truncate ") {
+            let mut argv = [""; 3];
+            let n = split_args(args, &mut argv).unwrap_or(0);
+            let (size, path) = match &argv[..n] {
+                ["-s", size, path] => (parse_size(size), *path),
+                _ => (None, ""),
+            };
+            let Some(size) = size else {
+                poprint!("Usage: truncate -s <size>[K|M|G] <path>\n");
+                continue;
+            };
+            if let Err(e) = VFS.lock().truncate(&cred, path, size) {
+                report("truncate", path, e);
+            }
             continue;
         }
 
-        if command.starts_with("touch ") {
-            let fname = command["touch ".len()..].trim();
-            if fname.is_empty() {
-                poprint!("Usage: touch <name>\n");
+        if let Some(args) = command.strip_prefix("punch ") {
+            let mut argv = [""; 3];
+            let n = split_args(args, &mut argv).unwrap_or(0);
+            let (range, path) = match &argv[..n] {
+                [offset, len, path] => (parse_size(offset).zip(parse_size(len)), *path),
+                _ => (None, ""),
+            };
+            let Some((offset, len)) = range else {
+                poprint!("Usage: punch <offset>[K|M|G] <length>[K|M|G] <path>\n");
                 continue;
+            };
+            if let Err(e) = VFS.lock().punch_hole(&cred, path, offset, len) {
+                report("punch", path, e);
             }
+            continue;
+        }
 
-            let mut guard = GLOBAL_DEVICE.lock();
-            let dev = guard.as_mut().unwrap();
+        if command.starts_with("mkdir ") || command.starts_with("rmdir ") || command.starts_with("rm ") {
+            let (name, path) = command.split_once(' ').unwrap_or((command, ""));
+            let path = path.trim();
+            let mut vfs = VFS.lock();
+            let res = match name {
+                "mkdir" => vfs.mkdir(&cred, path, 0o755).map(|_| ()),
+                "rmdir" => vfs.rmdir(&cred, path),
+                _ => vfs.unlink(&cred, path),
+            };
+            if let Err(e) = res {
+                report(name, path, e);
+            }
+            continue;
+        }
 
-            let mut ibuf = [0u8; 512];
-            dev.read_block(0, &mut ibuf);
+        if command.starts_with("mv ") || command.starts_with("ln ") {
+            let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+            let mut args = rest.split_whitespace().peekable();
+            let soft = name == "ln" && args.next_if_eq(&"-s").is_some();
+            let (src, dst) = match (args.next(), args.next(), args.next()) {
+                (Some(s), Some(d), None) => (s, d),
+                _ => {
+                    poprint!("Usage: {} <src> <dst>\n", if soft { "ln -s" } else { name });
+                    continue;
+                }
+            };
+            // A symlink's target is stored as text, wherever it points
+            let mut vfs = VFS.lock();
+            let res = if soft {
+                vfs.symlink(&cred, src, dst).map(|_| ())
+            } else if name == "mv" {
+                vfs.rename(&cred, src, dst)
+            } else {
+                vfs.link(&cred, src, dst)
+            };
+            if let Err(e) = res {
+                report(name, if soft { dst } else { src }, e);
+            }
+            continue;
+        }
+
+        if let Some(args) = command.strip_prefix("readlink ") {
+            let path = args.trim();
+            let mut buf = [0u8; ops::MAX_SYMLINK_LEN];
+            match VFS.lock().readlink(&cred, path, &mut buf) {
+                Ok(n) => poprint!("{}\n", str::from_utf8(&buf[..n]).unwrap_or("<invalid>")),
+                Err(e) => report("readlink", path, e),
+            }
+            continue;
+        }
+
+        if command.starts_with("chmod ") || command.starts_with("chown ") {
+            let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+            let mut args = rest.split_whitespace();
+            let (spec, path) = match (args.next(), args.next(), args.next()) {
+                (Some(spec), Some(path), None) => (spec, path),
+                _ => {
+                    poprint!("Usage: {} {} <path>\n", name, if name == "chmod" { "<octal mode>" } else { "<uid>:<gid>" });
+                    continue;
+                }
+            };
+            let res = if name == "chmod" {
+                match u16::from_str_radix(spec, 8) {
+                    Ok(mode) if mode <= 0o7777 => VFS.lock().chmod(&cred, path, mode),
+                    _ => {
+                        poprint!("chmod: invalid mode '{}'\n", spec);
+                        Ok(())
+                    }
+                }
+            } else {
+                match parse_owner(spec) {
+                    Some((uid, gid)) => VFS.lock().chown(&cred, path, uid, gid),
+                    None => {
+                        poprint!("chown: invalid owner '{}'\n", spec);
+                        Ok(())
+                    }
+                }
+            };
+            if let Err(e) = res {
+                report(name, path, e);
+            }
+            continue;
+        }
 
-            let isz = core::mem::size_of::<Inode>();
-            let slots = 512 / isz;
-            let mut slot = None;
-            for i in 0..slots {
-                let ptr = &ibuf[i * isz] as *const u8 as *const Inode;
-                let inode = unsafe { &*ptr };
-                if inode.is_used == 0 {
-                    slot = Some(i);
-                    break;
+        if let Some(args) = command.strip_prefix("getfattr ") {
+            let mut argv = [""; 3];
+            let n = split_args(args, &mut argv).unwrap_or(0);
+            let (name, path) = match &argv[..n] {
+                [path] => (None, *path),
+                ["-n", name, path] => (Some(*name), *path),
+                _ => {
+                    poprint!("Usage: getfattr [-n <name>] <path>\n");
+                    continue;
+                }
+            };
+            let vfs = VFS.lock();
+            let res = match name {
+                Some(name) => {
+                    let mut value = [0u8; xattr::VALUE_MAX];
+                    vfs.get_xattr(&cred, path, name, &mut value)
+                        .map(|len| print_xattr(name.as_bytes(), &value[..len]))
                 }
+                None => vfs.list_xattr(&cred, path, &mut |x| {
+                    poprint!("{}.", x.namespace);
+                    print_xattr(x.name, x.value);
+                }),
+            };
+            if let Err(e) = res {
+                report("getfattr", path, e);
             }
+            continue;
+        }
 
-            if let Some(i) = slot {
-                let inode = Inode {
-                    mode: 0o100644,
-                    size: 0,
-                    direct_ptrs: [3,0,0,0,0,0,0,0,0,0,0,0],
-                    indirect_ptr: 0,
-                    is_used: 1,
-                };
-                let ib = unsafe {
-                    core::slice::from_raw_parts(
-                        &inode as *const Inode as *const u8,
-                        isz,
-                    )
-                };
-                ibuf[i*isz..(i+1)*isz].copy_from_slice(ib);
-                dev.write_block(0, &ibuf);
-
-                let mut ddbuf = [0u8; 512];
-                dev.read_block(2, &mut ddbuf);
-                let ent_sz = core::mem::size_of::<DirEntry>();
-                let max = 512 / ent_sz;
-                for j in 0..max {
-                    let base = j * ent_sz;
-                    let e = unsafe {
-                        &*(ddbuf[base..].as_ptr() as *const DirEntry)
-                    };
-                    if e.inode_index == 0 {
-                        let mut ne = DirEntry {
-                            name: [0;28],
-                            inode_index: (i+1) as u32,
-                            _padding: 0,
-                        };
-                        for (k, &b) in fname.as_bytes().iter().take(31).enumerate() {
-                            ne.name[k] = b;
-                        }
-                        let eb = unsafe {
-                            core::slice::from_raw_parts(
-                                &ne as *const DirEntry as *const u8,
-                                ent_sz,
-                            )
-                        };
-                        ddbuf[base..base+ent_sz].copy_from_slice(eb);
-                        dev.write_block(2, &ddbuf);
-                        break;
+        if let Some(args) = command.strip_prefix("setfattr ") {
+            let mut argv = [""; 5];
+            let n = split_args(args, &mut argv).unwrap_or(0);
+            let (name, value, path) = match &argv[..n] {
+                ["-n", name, "-v", value, path] => (*name, Some(*value), *path),
+                ["-n", name, path] => (*name, Some(""), *path),
+                ["-x", name, path] => (*name, None, *path),
+                _ => {
+                    poprint!("Usage: setfattr -n <name> [-v <value>] <path>\n");
+                    poprint!("       setfattr -x <name> <path>\n");
+                    continue;
+                }
+            };
+            let mut vfs = VFS.lock();
+            let res = match value {
+                Some(value) => vfs.set_xattr(&cred, path, name, value.as_bytes()),
+                None => vfs.remove_xattr(&cred, path, name),
+            };
+            if let Err(e) = res {
+                report("setfattr", path, e);
+            }
+            continue;
+        }
+
+        if let Some(args) = command.strip_prefix("su ") {
+            match parse_owner(args.trim()) {
+                Some((Some(uid), gid)) => {
+                    cred = Cred { uid, gid: gid.unwrap_or(uid) };
+                    if let Some(pid) = pid {
+                        process::set_cred(pid, cred);
                     }
                 }
+                _ => poprint!("Usage: su <uid>[:<gid>]\n"),
             }
+            continue;
+        }
 
-            core::mem::drop(guard);
+        if command == "id" {
+            poprint!("uid={} gid={}\n", cred.uid, cred.gid);
             continue;
         }
 
-        if command.starts_with(">> ") {
-            let rest = &command[3..];
-            let mut sp = rest.splitn(2, ' ');
-            let fname = sp.next().unwrap_or("").trim();
-            let data = sp.next().unwrap_or("").trim();
-            if fname.is_empty() || data.is_empty() {
-                poprint!("Usage: >> <file> <text>\n");
+        if command == "fsck" || command.starts_with("fsck ") {
+            let mut argv = [""; 3];
+            let n = split_args(&command["fsck".len()..], &mut argv).unwrap_or(argv.len());
+            let (repair, path) = match &argv[..n] {
+                [] => (false, "/"),
+                ["-y"] => (true, "/"),
+                ["-y", path] => (true, *path),
+                [path] => (false, *path),
+                _ => {
+                    poprint!("Usage: fsck [-y] [path]\n");
+                    continue;
+                }
+            };
+            if !cred.is_root() {
+                poprint!("fsck: {}\n", FsError::NotPermitted);
+                continue;
+            }
+            if let Err(e) = VFS.lock().filesystem(&cred, path).and_then(|fs| fs.check(repair)) {
+                poprint!("fsck: {}\n", e);
+            }
+            continue;
+        }
+
+        if command == "quota" || command.starts_with("quota ") {
+            let mut argv = [""; 4];
+            let n = split_args(&command["quota".len()..], &mut argv).unwrap_or(argv.len());
+            let (uid, path) = match &argv[..n] {
+                [] => (Some(cred.uid), "/"),
+                [path] => (Some(cred.uid), *path),
+                ["-u", uid] => (uid.parse().ok(), "/"),
+                ["-u", uid, path] => (uid.parse().ok(), *path),
+                _ => (None, ""),
+            };
+            let Some(uid) = uid else {
+                poprint!("Usage: quota [-u <uid>] [path]\n");
                 continue;
+            };
+            if let Err(e) = quota(&cred, uid, path) {
+                report("quota", path, e);
+            }
+            continue;
+        }
+
+        if command.starts_with("setquota ") {
+            let mut argv = [""; 7];
+            let n = split_args(&command["setquota".len()..], &mut argv).unwrap_or(argv.len());
+            match setquota(&cred, &argv[..n]) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => poprint!("setquota: {}\n", e),
+                None => {
+                    poprint!("Usage: setquota <uid> <block soft> <block hard> <inode soft> <inode hard> [path]\n");
+                    poprint!("       setquota -t <block grace> <inode grace> [path]\n");
+                }
             }
+            continue;
+        }
 
-            let mut guard = GLOBAL_DEVICE.lock();
-            let dev = guard.as_mut().unwrap();
-
-            let mut ddbuf = [0u8; 512];
-            dev.read_block(2, &mut ddbuf);
-            let ent_sz = core::mem::size_of::<DirEntry>();
-            let max = 512 / ent_sz;
-            let mut inode_index = None;
-            for j in 0..max {
-                let base = j*ent_sz;
-                let e = unsafe {
-                    &*(ddbuf[base..].as_ptr() as *const DirEntry)
-                };
-                if e.inode_index != 0 {
-                    let len = e.name.iter().position(|&b|b==0).unwrap_or(32);
-                    let nm = str::from_utf8(&e.name[..len]).unwrap_or("");
-                    if nm == fname {
-                        inode_index = Some((e.inode_index - 1) as usize);
-                        break;
+        if command == "sync" {
+            for m in VFS.lock().mounts_mut() {
+                if let Err(e) = m.fs.sync() {
+                    poprint!("sync: {}: {}\n", m.path(), e);
+                }
+            }
+            continue;
+        }
+
+        if command == "cachestat" || command.starts_with("cachestat ") {
+            let path = command["cachestat".len()..].trim();
+            let stats = VFS.lock()
+                .filesystem(&cred, if path.is_empty() { "/" } else { path })
+                .and_then(|fs| fs.cache_stats().ok_or(FsError::Unsupported));
+            match stats {
+                Ok(s) => {
+                    poprint!("hits        {}", s.hits);
+                    if let Some(pct) = (s.hits * 100).checked_div(s.hits + s.misses) {
+                        poprint!(" ({}%)", pct);
                     }
+                    poprint!("\nmisses      {}\n", s.misses);
+                    poprint!("read-ahead  {}\n", s.read_ahead);
+                    poprint!("write-backs {}\n", s.write_backs);
+                    poprint!("dirty       {}/{}\n", s.dirty, s.capacity);
                 }
+                Err(e) => report("cachestat", path, e),
             }
+            continue;
+        }
+
+        if command == "lsblk" {
+            lsblk();
+            continue;
+        }
 
-            if let Some(i) = inode_index {
-                let mut ibuf = [0u8;512];
-                dev.read_block(0, &mut ibuf);
-                let ptr = &ibuf[i * core::mem::size_of::<Inode>()];
-                let inode = unsafe { &*(ptr as *const u8 as *const Inode) };
-                let blk = inode.direct_ptrs[0] as u32;
+        if command == "mkramdisk" || command.starts_with("mkramdisk ") {
+            let mut argv = [""; 2];
+            let n = split_args(&command["mkramdisk".len()..], &mut argv).unwrap_or(0);
+            let (size, block_size) = match &argv[..n] {
+                [size] => (parse_size(size), Some(BLOCK_SIZE)),
+                [size, bs] => (parse_size(size), bs.parse().ok()),
+                _ => (None, None),
+            };
+            let (Some(size), Some(block_size)) = (size, block_size) else {
+                poprint!("Usage: mkramdisk <size>[K|M|G] [block size]\n");
+                continue;
+            };
+            let res = if cred.is_root() { mkramdisk(size, block_size) } else { Err(FsError::NotPermitted) };
+            if let Err(e) = res {
+                poprint!("mkramdisk: {}\n", e);
+            }
+            continue;
+        }
 
-                let mut db = [0u8;512];
-                let bts = data.as_bytes();
-                let wl = core::cmp::min(bts.len(), 512);
-                db[..wl].copy_from_slice(&bts[..wl]);
-                dev.write_block(blk, &db);
+        if command == "mount" {
+            for m in VFS.lock().mounts() {
+                poprint!("{} on {} type {}\n", m.source(), m.path(), m.fs.name());
             }
+            continue;
+        }
 
-            core::mem::drop(guard);
+        if command.starts_with("mount ") || command.starts_with("umount ") {
+            let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+            let mut argv = [""; 4];
+            let n = split_args(rest, &mut argv).unwrap_or(0);
+            let res = match (name, &argv[..n]) {
+                _ if !cred.is_root() => Err(FsError::NotPermitted),
+                ("mount", ["-t", kind, source, path]) => mount_virtual(&cred, kind, source, path),
+                ("mount", [source, path]) => mount(&cred, source, path),
+                ("umount", [path]) => VFS.lock().unmount(&cred, path).map(|_| ()),
+                _ => {
+                    poprint!("Usage: mount <partition> <path>\n");
+                    poprint!("       mount -t tmpfs|devfs|proc <name> <path>\n");
+                    poprint!("       umount <path>\n");
+                    continue;
+                }
+            };
+            if let Err(e) = res {
+                report(name, argv[n.saturating_sub(1)], e);
+            }
             continue;
         }
 
@@ -242,12 +881,25 @@ pub fn shell() {
             continue;
         }
 
+        if command == "lspci" || command == "lspci -v" {
+            lspci(command == "lspci -v");
+            continue;
+        }
+
         if command == "pofetch" || command == "neofetch" {
-            pofetch();
+            pofetch(&cred);
             continue;
         }
 
         if command == "exit" {
+            for m in VFS.lock().mounts_mut() {
+                if let Err(e) = m.fs.sync() {
+                    poprint!("sync: {}: {}\n", m.path(), e);
+                }
+            }
+            if let Some(pid) = pid {
+                process::exit(pid);
+            }
             break;
         }
 
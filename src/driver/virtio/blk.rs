@@ -0,0 +1,229 @@
+//! Virtio block device.
+//!
+//! Each request is a chain of three buffers: a header with the request type
+//! and first sector, the data, and a status byte the device fills in. Data
+//! goes through a bounce buffer in DMA memory, so callers can pass any
+//! slice. One request is in flight at a time and the caller waits until it
+//! completes, or fails it with a timeout after `TIMEOUT_POLLS` checks.
+
+use core::cell::RefCell;
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use spin::Mutex;
+
+use super::{Transport, VirtQueue, VENDOR};
+use crate::driver::pci::{PciAddress, PciDevice, PciDriver};
+use crate::fs::error::IoError;
+use crate::fs::structure::{check_block, check_range, BlockDevice, BLOCK_SIZE};
+use crate::polib::interrupts;
+use crate::polib::memory::{alloc_dma, Dma};
+
+/// Registry entry; the IDs are the transitional and modern‑only devices
+pub const DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    ids: &[(VENDOR, 0x1001), (VENDOR, 0x1042)],
+    probe,
+};
+
+// Feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+
+/// Device configuration: capacity in 512 B sectors
+const CONFIG_CAPACITY: usize = 0;
+
+/// Pages of the bounce buffer, which bounds the size of one request
+const BOUNCE_PAGES: usize = 16;
+
+/// Status polls before a request is given up on
+const TIMEOUT_POLLS: u32 = 10_000_000;
+
+/// Device names in discovery order
+const NAMES: [&str; 4] = ["vda", "vdb", "vdc", "vdd"];
+
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: RefCell<VirtQueue>,
+    header: Dma, // request header at 0, status byte at 16
+    bounce: Dma,
+    index: usize,
+    sectors: u64,
+    features: u64,
+    irq: bool, // completions arrive by interrupt, else polled
+}
+
+/// Disks set up by `probe`, waiting to be claimed by `take_disks`
+static DISKS: Mutex<[Option<VirtioBlk>; 4]> = Mutex::new([None, None, None, None]);
+
+fn probe(dev: &PciDevice) -> bool {
+    let mut disks = DISKS.lock();
+    let Some(index) = (0..NAMES.len()).find(|&i| disks[i].is_none()) else {
+        return false;
+    };
+    match VirtioBlk::new(dev.addr, index) {
+        Some(disk) => {
+            disks[index] = Some(disk);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Calls `f` for every virtio block device the PCI scan initialised,
+/// handing it over
+pub fn take_disks(f: &mut dyn FnMut(VirtioBlk)) {
+    let mut disks = DISKS.lock();
+    for disk in disks.iter_mut() {
+        if let Some(disk) = disk.take() {
+            f(disk);
+        }
+    }
+}
+
+impl VirtioBlk {
+    fn new(pci: PciAddress, index: usize) -> Option<VirtioBlk> {
+        let transport = Transport::probe(pci)?;
+        let features = transport.init(F_RO | F_FLUSH)?;
+        let Some(queue) = transport.setup_queue(0) else {
+            transport.fail();
+            return None;
+        };
+        let (Some(header), Some(bounce)) = (alloc_dma(1), alloc_dma(BOUNCE_PAGES)) else {
+            transport.fail();
+            return None;
+        };
+        let irq = transport.enable_interrupt(pci);
+        transport.ready();
+        let sectors = transport.config_u64(CONFIG_CAPACITY);
+        Some(VirtioBlk { transport, queue: RefCell::new(queue), header, bounce, index, sectors, features, irq })
+    }
+
+    /// Device name, `vda` onwards
+    pub fn name(&self) -> &'static str {
+        NAMES[self.index]
+    }
+
+    /// Size in 512 B sectors
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    /// Whether completions are signalled by interrupt rather than polled
+    pub fn uses_interrupt(&self) -> bool {
+        self.irq
+    }
+
+    /// Runs one request of `len` bytes of the bounce buffer and waits for it
+    fn request(&self, kind: u32, sector: u64, len: usize) -> Result<(), IoError> {
+        let hdr = self.header.as_ptr::<u32>(0);
+        unsafe {
+            hdr.write_volatile(kind);
+            hdr.add(1).write_volatile(0);
+            self.header.as_ptr::<u64>(8).write_volatile(sector);
+            self.header.as_ptr::<u8>(16).write_volatile(0xFF);
+        }
+        let status = (self.header.phys + 16, 1, true);
+        let head = (self.header.phys, 16, false);
+        let mut queue = self.queue.borrow_mut();
+        let pushed = if len == 0 {
+            queue.push(&[head, status])
+        } else {
+            queue.push(&[head, (self.bounce.phys, len as u32, kind == T_IN), status])
+        };
+        pushed.ok_or(IoError::Timeout)?;
+        self.transport.notify(&queue);
+
+        if self.irq {
+            if !interrupts::wait_until(&|| queue.has_used(), TIMEOUT_POLLS) {
+                return Err(IoError::Timeout);
+            }
+        } else {
+            let mut polls = 0;
+            while !queue.has_used() {
+                polls += 1;
+                if polls == TIMEOUT_POLLS {
+                    return Err(IoError::Timeout);
+                }
+            }
+        }
+        queue.pop_used();
+        match unsafe { self.header.as_ptr::<u8>(16).read_volatile() } {
+            S_OK => Ok(()),
+            s => Err(IoError::Device(s)),
+        }
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), IoError> {
+        for (i, chunk) in buf.chunks_mut(self.bounce.len).enumerate() {
+            let first = sector + (i * self.bounce.len / BLOCK_SIZE) as u64;
+            self.request(T_IN, first, chunk.len())?;
+            let src = unsafe { core::slice::from_raw_parts(self.bounce.as_ptr::<u8>(0), chunk.len()) };
+            chunk.copy_from_slice(src);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), IoError> {
+        if self.read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        for (i, chunk) in buf.chunks(self.bounce.len).enumerate() {
+            let first = sector + (i * self.bounce.len / BLOCK_SIZE) as u64;
+            let dst = unsafe { core::slice::from_raw_parts_mut(self.bounce.as_ptr::<u8>(0), chunk.len()) };
+            dst.copy_from_slice(chunk);
+            self.request(T_OUT, first, chunk.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_count(&self) -> u32 {
+        self.sectors.min(u32::MAX as u64) as u32
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        self.read_sectors(block_number as u64, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        self.write_sectors(block_number as u64, buf)
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_range(self.block_count(), BLOCK_SIZE, start, buf.len())?;
+        self.read_sectors(start as u64, buf)
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_range(self.block_count(), BLOCK_SIZE, start, buf.len())?;
+        self.write_sectors(start as u64, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        // Without the feature the device writes through
+        if self.features & F_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(T_FLUSH, 0, 0)
+    }
+}
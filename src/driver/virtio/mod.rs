@@ -0,0 +1,433 @@
+//! Virtio devices on PCI.
+//!
+//! `Transport` hides the two ways a virtio PCI device is programmed:
+//!
+//! - legacy (virtio 0.9.5): all registers in I/O BAR 0, queues placed by
+//!   page frame number in one contiguous block
+//! - modern (virtio 1.0): registers in memory BARs found through vendor
+//!   capabilities, 64‑bit features including `VERSION_1`, and queue parts
+//!   placed separately
+//!
+//! QEMU's default devices are transitional and offer both; the modern
+//! interface is used when its capabilities are present. A `VirtQueue` is the
+//! descriptor table and the two rings in DMA memory. Completions are
+//! signalled through the device's legacy interrupt line, whose handler reads
+//! the ISR register to acknowledge it; drivers wait, for a bounded number
+//! of checks, until the used ring moves.
+
+pub mod blk;
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use super::pci::{Bar, PciAddress, COMMAND_BUS_MASTER, COMMAND_IO, COMMAND_MEMORY};
+use crate::polib::interrupts;
+use crate::polib::memory::{alloc_dma, map_mmio, Dma, PAGE_SIZE};
+
+pub const VENDOR: u16 = 0x1AF4;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit every modern device and driver must agree on
+const F_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, as offsets into I/O BAR 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+const LEGACY_CONFIG: u16 = 0x14;
+
+// Modern common configuration, as offsets into its capability's region
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0C;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_ENABLE: usize = 0x1C;
+const COMMON_Q_NOFF: usize = 0x1E;
+const COMMON_Q_DESC: usize = 0x20;
+const COMMON_Q_AVAIL: usize = 0x28;
+const COMMON_Q_USED: usize = 0x30;
+
+// Vendor capability types
+const CAP_VENDOR: u8 = 0x09;
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+// Descriptor flags
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Largest queue set up on a modern device; legacy ones dictate the size
+const QUEUE_SIZE_MAX: u16 = 128;
+
+fn read<T>(addr: usize) -> T {
+    unsafe { read_volatile(addr as *const T) }
+}
+
+fn write<T>(addr: usize, value: T) {
+    unsafe { write_volatile(addr as *mut T, value) }
+}
+
+fn inb(port: u16) -> u8 {
+    unsafe { Port::<u8>::new(port).read() }
+}
+
+fn outb(port: u16, value: u8) {
+    unsafe { Port::<u8>::new(port).write(value) }
+}
+
+fn inw(port: u16) -> u16 {
+    unsafe { Port::<u16>::new(port).read() }
+}
+
+fn outw(port: u16, value: u16) {
+    unsafe { Port::<u16>::new(port).write(value) }
+}
+
+fn inl(port: u16) -> u32 {
+    unsafe { Port::<u32>::new(port).read() }
+}
+
+fn outl(port: u16, value: u32) {
+    unsafe { Port::<u32>::new(port).write(value) }
+}
+
+/// Where a device's registers live
+pub enum Transport {
+    Legacy { io: u16 },
+    Modern { common: usize, notify: usize, notify_mul: u32, isr: usize, device: usize },
+}
+
+/// ISR status register of a device, read by the interrupt handler
+#[derive(Clone, Copy)]
+enum Isr {
+    Port(u16),
+    Mmio(usize),
+}
+
+/// Devices whose interrupt has been registered
+static IRQ_DEVICES: Mutex<[Option<Isr>; 8]> = Mutex::new([None; 8]);
+
+/// Acknowledges every virtio device that may have raised a shared line
+fn on_interrupt() {
+    for isr in IRQ_DEVICES.lock().iter().flatten() {
+        match *isr {
+            Isr::Port(port) => inb(port),
+            Isr::Mmio(addr) => read::<u8>(addr),
+        };
+    }
+}
+
+impl Transport {
+    /// Finds the registers of the virtio device at `pci` and enables it on
+    /// the bus
+    pub fn probe(pci: PciAddress) -> Option<Transport> {
+        pci.enable(COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_mul = 0;
+        pci.capabilities(&mut |off, id| {
+            if id != CAP_VENDOR {
+                return;
+            }
+            let Some(Bar::Memory(base)) = pci.bar(pci.read_u8(off + 4)) else {
+                return;
+            };
+            let start = base + pci.read_u32(off + 8) as u64;
+            let Some(addr) = map_mmio(start, pci.read_u32(off + 12) as usize) else {
+                return;
+            };
+            match pci.read_u8(off + 3) {
+                CAP_COMMON => common = common.or(Some(addr)),
+                CAP_NOTIFY => {
+                    notify = notify.or(Some(addr));
+                    notify_mul = pci.read_u32(off + 16);
+                }
+                CAP_ISR => isr = isr.or(Some(addr)),
+                CAP_DEVICE => device = device.or(Some(addr)),
+                _ => {}
+            }
+        });
+        if let (Some(common), Some(notify), Some(isr), Some(device)) = (common, notify, isr, device) {
+            return Some(Transport::Modern { common, notify, notify_mul, isr, device });
+        }
+        match pci.bar(0)? {
+            Bar::Io(io) => Some(Transport::Legacy { io }),
+            Bar::Memory(_) => None,
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => inb(io + LEGACY_STATUS),
+            Transport::Modern { common, .. } => read(common + COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io } => outb(io + LEGACY_STATUS, status),
+            Transport::Modern { common, .. } => write(common + COMMON_STATUS, status),
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    /// Resets the device and negotiates the subset of `wanted` it offers.
+    /// Returns the accepted features, or None if the device refuses them.
+    pub fn init(&self, wanted: u64) -> Option<u64> {
+        self.set_status(0);
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let accepted = match *self {
+            Transport::Legacy { io } => {
+                let accepted = inl(io + LEGACY_DEVICE_FEATURES) as u64 & wanted;
+                outl(io + LEGACY_DRIVER_FEATURES, accepted as u32);
+                accepted
+            }
+            Transport::Modern { common, .. } => {
+                let mut offered = 0u64;
+                for half in 0..2 {
+                    write::<u32>(common + COMMON_DFSELECT, half);
+                    offered |= (read::<u32>(common + COMMON_DF) as u64) << (32 * half);
+                }
+                if offered & F_VERSION_1 == 0 {
+                    self.add_status(STATUS_FAILED);
+                    return None;
+                }
+                let accepted = offered & (wanted | F_VERSION_1);
+                for half in 0..2 {
+                    write::<u32>(common + COMMON_GFSELECT, half);
+                    write::<u32>(common + COMMON_GF, (accepted >> (32 * half)) as u32);
+                }
+                self.add_status(STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    self.add_status(STATUS_FAILED);
+                    return None;
+                }
+                accepted
+            }
+        };
+        Some(accepted)
+    }
+
+    /// Tells the device the driver is ready; call after setting up queues
+    pub fn ready(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Marks the device as given up on
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Allocates and registers queue `index`
+    pub fn setup_queue(&self, index: u16) -> Option<VirtQueue> {
+        match *self {
+            Transport::Legacy { io } => {
+                outw(io + LEGACY_QUEUE_SELECT, index);
+                let size = inw(io + LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return None;
+                }
+                let queue = VirtQueue::new(index, size, 0)?;
+                outl(io + LEGACY_QUEUE_PFN, (queue.mem.phys / PAGE_SIZE as u64) as u32);
+                Some(queue)
+            }
+            Transport::Modern { common, .. } => {
+                write::<u16>(common + COMMON_Q_SELECT, index);
+                let size = read::<u16>(common + COMMON_Q_SIZE).min(QUEUE_SIZE_MAX);
+                if size == 0 {
+                    return None;
+                }
+                let notify_off = read::<u16>(common + COMMON_Q_NOFF);
+                let queue = VirtQueue::new(index, size, notify_off)?;
+                write::<u16>(common + COMMON_Q_SIZE, size);
+                for (reg, phys) in [
+                    (COMMON_Q_DESC, queue.mem.phys),
+                    (COMMON_Q_AVAIL, queue.mem.phys + queue.avail as u64),
+                    (COMMON_Q_USED, queue.mem.phys + queue.used as u64),
+                ] {
+                    write::<u32>(common + reg, phys as u32);
+                    write::<u32>(common + reg + 4, (phys >> 32) as u32);
+                }
+                write::<u16>(common + COMMON_Q_ENABLE, 1);
+                Some(queue)
+            }
+        }
+    }
+
+    /// Tells the device new buffers are in `queue`
+    pub fn notify(&self, queue: &VirtQueue) {
+        fence(Ordering::SeqCst);
+        match *self {
+            Transport::Legacy { io } => outw(io + LEGACY_QUEUE_NOTIFY, queue.index),
+            Transport::Modern { notify, notify_mul, .. } => {
+                write::<u16>(notify + queue.notify_off as usize * notify_mul as usize, queue.index)
+            }
+        }
+    }
+
+    /// Reads a 32‑bit field of the device‑specific configuration
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match *self {
+            Transport::Legacy { io } => inl(io + LEGACY_CONFIG + offset as u16),
+            Transport::Modern { device, .. } => read(device + offset),
+        }
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+
+    /// Routes the device's legacy interrupt to the shared handler. Returns
+    /// false if it has none, and the driver has to poll.
+    pub fn enable_interrupt(&self, pci: PciAddress) -> bool {
+        let Some(line) = pci.interrupt_line() else {
+            return false;
+        };
+        let isr = match *self {
+            Transport::Legacy { io } => Isr::Port(io + LEGACY_ISR),
+            Transport::Modern { isr, .. } => Isr::Mmio(isr),
+        };
+        let slot = without_interrupts(|| {
+            let mut devices = IRQ_DEVICES.lock();
+            let slot = devices.iter().position(|d| d.is_none())?;
+            devices[slot] = Some(isr);
+            Some(slot)
+        });
+        let Some(slot) = slot else {
+            return false;
+        };
+        if interrupts::register(line, "virtio", on_interrupt) {
+            return true;
+        }
+        // Polled instead, so the handler has nothing to acknowledge for it
+        without_interrupts(|| IRQ_DEVICES.lock()[slot] = None);
+        false
+    }
+}
+
+/// Descriptor table, available ring and used ring of one queue, laid out as
+/// legacy devices require: the used ring starts on a page boundary
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    notify_off: u16,
+    mem: Dma,
+    avail: usize, // offsets into `mem`
+    used: usize,
+    free_head: u16,
+    num_free: u16,
+    last_used: u16,
+}
+
+impl VirtQueue {
+    fn new(index: u16, size: u16, notify_off: u16) -> Option<VirtQueue> {
+        let n = size as usize;
+        let avail = 16 * n;
+        let used = (avail + 6 + 2 * n).next_multiple_of(PAGE_SIZE);
+        let pages = (used + 6 + 8 * n).div_ceil(PAGE_SIZE);
+        let mem = alloc_dma(pages)?;
+        let queue = VirtQueue { index, size, notify_off, mem, avail, used, free_head: 0, num_free: size, last_used: 0 };
+        // Chain every descriptor into the free list
+        for i in 0..size {
+            write::<u16>(queue.desc(i) + 14, (i + 1) % size);
+        }
+        Some(queue)
+    }
+
+    fn desc(&self, i: u16) -> usize {
+        self.mem.virt + 16 * i as usize
+    }
+
+    /// Queues a chain of buffers, given as physical address, length and
+    /// whether the device writes to it. Returns the head descriptor, or None
+    /// if the queue is full.
+    pub fn push(&mut self, bufs: &[(u64, u32, bool)]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut i = head;
+        for (n, &(addr, len, device_writes)) in bufs.iter().enumerate() {
+            let d = self.desc(i);
+            let next = read::<u16>(d + 14);
+            let mut flags = if device_writes { DESC_F_WRITE } else { 0 };
+            if n + 1 < bufs.len() {
+                flags |= DESC_F_NEXT;
+            }
+            write::<u64>(d, addr);
+            write::<u32>(d + 8, len);
+            write::<u16>(d + 12, flags);
+            if n + 1 < bufs.len() {
+                i = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.num_free -= bufs.len() as u16;
+
+        let avail = self.mem.virt + self.avail;
+        let idx = read::<u16>(avail + 2);
+        write::<u16>(avail + 4 + 2 * (idx % self.size) as usize, head);
+        // The entry must be visible before the index that publishes it
+        fence(Ordering::SeqCst);
+        write::<u16>(avail + 2, idx.wrapping_add(1));
+        Some(head)
+    }
+
+    /// True when the device has completed a chain not yet popped
+    pub fn has_used(&self) -> bool {
+        read::<u16>(self.mem.virt + self.used + 2) != self.last_used
+    }
+
+    /// Takes the next completed chain off the used ring, returning its head
+    /// and the number of bytes the device wrote, and frees its descriptors
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = self.mem.virt + self.used + 4 + 8 * (self.last_used % self.size) as usize;
+        let head = read::<u32>(elem) as u16;
+        let len = read::<u32>(elem + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut i = head;
+        let mut count = 1;
+        while read::<u16>(self.desc(i) + 12) & DESC_F_NEXT != 0 {
+            i = read::<u16>(self.desc(i) + 14);
+            count += 1;
+        }
+        write::<u16>(self.desc(i) + 14, self.free_head);
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, len))
+    }
+}
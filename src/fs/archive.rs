@@ -0,0 +1,309 @@
+//! USTAR and newc cpio archives.
+//!
+//! `members` walks an archive held in memory and hands out its members in
+//! order, telling the two formats apart by the first header. Tar headers
+//! are checked against their checksums; GNU long names (`L` and `K`) and the
+//! `path` and `linkpath` records of pax headers are followed, and other pax
+//! records are ignored. In a cpio archive the names of a file with several
+//! links share an inode number and the data comes with the last of them, so
+//! every name after the first is handed out as a hard link to it, as tar
+//! does. Only directories, regular files, symlinks and hard links are handed
+//! out; device nodes and FIFOs are skipped.
+//!
+//! `unpack` re-creates the members below a directory of the VFS, with their
+//! permission bits and owners, making missing parent directories on the
+//! way. As with tar, a member replaces what an earlier one of the same name
+//! made, unless both are directories. Modification times are not kept.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+use core::str;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::error::FsError;
+use super::perm::Cred;
+use super::structure::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use super::vfs::{FileSystem, Vfs, O_CREATE, O_TRUNC, O_WRITE};
+
+const TAR_BLOCK: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar"; // "ustar\0" in POSIX archives, "ustar " in GNU ones
+
+const CPIO_MAGIC: &[u8] = b"07070"; // then 1, or 2 when the check field is used
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// A file, directory or link in an archive
+pub struct Member<'a> {
+    pub path: &'a str,         // relative, without a leading `./` or `/`
+    pub mode: u16,             // type and permission bits
+    pub uid: u16,
+    pub gid: u16,
+    pub mtime: u32,
+    pub data: &'a [u8],        // contents of a file, or the target of a symlink
+    pub link: Option<&'a str>, // for a hard link, the path it is another name for
+}
+
+/// Calls `f` for every member of `archive`, stopping at the first error.
+/// An archive in neither format gives `BadMagic`; a damaged or cut short
+/// one `Corrupt` with the 512-byte block where it goes wrong.
+pub fn members(archive: &[u8], f: &mut dyn FnMut(&Member) -> Result<(), FsError>) -> Result<(), FsError> {
+    if archive.starts_with(CPIO_MAGIC) {
+        cpio_members(archive, f)
+    } else if archive.get(257..262) == Some(TAR_MAGIC) {
+        tar_members(archive, f)
+    } else {
+        Err(FsError::BadMagic)
+    }
+}
+
+/// `path` without leading `/` and `./` or a trailing `/`
+fn clean(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        let rest = path.trim_start_matches('/');
+        let rest = rest.strip_prefix("./").unwrap_or(rest);
+        if rest.len() == path.len() {
+            return path.trim_end_matches('/');
+        }
+        path = rest;
+    }
+}
+
+/// A string field, ending at the first NUL
+fn text(field: &[u8], block: u32) -> Result<&str, FsError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| FsError::Corrupt(block))
+}
+
+/// A number field of octal digits, padded with spaces or NULs
+fn octal(field: &[u8]) -> Option<u64> {
+    let mut digits = field.iter().copied().skip_while(|&b| b == b' ').take_while(|&b| b != b' ' && b != 0);
+    digits.try_fold(0u64, |n, b| n.checked_mul(8)?.checked_add((b as char).to_digit(8)? as u64))
+}
+
+/// A number field of eight hex digits
+fn hex(field: &[u8]) -> Option<u32> {
+    u32::from_str_radix(str::from_utf8(field).ok()?, 16).ok()
+}
+
+fn tar_members(archive: &[u8], f: &mut dyn FnMut(&Member) -> Result<(), FsError>) -> Result<(), FsError> {
+    let mut pos = 0;
+    let mut long_name = None;
+    let mut long_link = None;
+    while let Some(header) = archive.get(pos..pos + TAR_BLOCK) {
+        let block = (pos / TAR_BLOCK) as u32;
+        // The archive ends with two zero blocks; the first is enough
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+            .sum();
+        if octal(&header[148..156]) != Some(sum) || &header[257..262] != TAR_MAGIC {
+            return Err(FsError::Corrupt(block));
+        }
+        let size = octal(&header[124..136]).ok_or(FsError::Corrupt(block))? as usize;
+        let start = pos + TAR_BLOCK;
+        let data = archive.get(start..start + size).ok_or(FsError::Corrupt(block))?;
+        pos = start + size.next_multiple_of(TAR_BLOCK);
+
+        let kind = header[156];
+        match kind {
+            b'L' => long_name = Some(text(data, block)?),
+            b'K' => long_link = Some(text(data, block)?),
+            b'x' => {
+                for (key, value) in pax_records(data, block)? {
+                    match key {
+                        "path" => long_name = Some(value),
+                        "linkpath" => long_link = Some(value),
+                        _ => {}
+                    }
+                }
+            }
+            b'0' | b'\0' | b'7' | b'1' | b'2' | b'5' => {
+                let name = match long_name.take() {
+                    Some(name) => String::from(name),
+                    None => match text(&header[345..500], block)? {
+                        "" => String::from(text(&header[..100], block)?),
+                        prefix => format!("{}/{}", prefix, text(&header[..100], block)?),
+                    },
+                };
+                let target = match long_link.take() {
+                    Some(target) => target,
+                    None => text(&header[157..257], block)?,
+                };
+                let path = clean(&name);
+                if path.is_empty() || path == "." {
+                    continue;
+                }
+                let (mode, data, link) = match kind {
+                    b'1' => (S_IFREG, &[][..], Some(clean(target))),
+                    b'2' => (S_IFLNK, target.as_bytes(), None),
+                    b'5' => (S_IFDIR, &[][..], None),
+                    _ => (S_IFREG, data, None),
+                };
+                let perm = octal(&header[100..108]).ok_or(FsError::Corrupt(block))? as u16 & 0o7777;
+                f(&Member {
+                    path,
+                    mode: mode | perm,
+                    uid: octal(&header[108..116]).unwrap_or(0) as u16,
+                    gid: octal(&header[116..124]).unwrap_or(0) as u16,
+                    mtime: octal(&header[136..148]).unwrap_or(0) as u32,
+                    data,
+                    link,
+                })?;
+            }
+            // Device nodes, FIFOs, global pax headers and the like
+            _ => {
+                long_name = None;
+                long_link = None;
+            }
+        }
+    }
+    Err(FsError::Corrupt((pos / TAR_BLOCK) as u32))
+}
+
+/// The `key=value` records of a pax header, each written as
+/// `<length> key=value\n`
+fn pax_records(data: &[u8], block: u32) -> Result<Vec<(&str, &str)>, FsError> {
+    let text = str::from_utf8(data).map_err(|_| FsError::Corrupt(block))?;
+    let mut records = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (len, _) = rest.split_once(' ').ok_or(FsError::Corrupt(block))?;
+        let len: usize = len.parse().map_err(|_| FsError::Corrupt(block))?;
+        let record = rest.get(..len).ok_or(FsError::Corrupt(block))?;
+        let (_, body) = record.trim_end_matches('\n').split_once(' ').ok_or(FsError::Corrupt(block))?;
+        if let Some(pair) = body.split_once('=') {
+            records.push(pair);
+        }
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+fn cpio_members(archive: &[u8], f: &mut dyn FnMut(&Member) -> Result<(), FsError>) -> Result<(), FsError> {
+    let mut pos = 0;
+    // First name of each file with several links, by device and inode number
+    let mut first_names: BTreeMap<(u32, u32, u32), String> = BTreeMap::new();
+    loop {
+        let block = (pos / TAR_BLOCK) as u32;
+        let bad = FsError::Corrupt(block);
+        let header = archive.get(pos..pos + CPIO_HEADER).ok_or(bad)?;
+        if !header.starts_with(CPIO_MAGIC) || !matches!(header[5], b'1' | b'2') {
+            return Err(bad);
+        }
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+        // rdevmajor, rdevminor, namesize, check
+        let field = |i: usize| hex(&header[6 + 8 * i..14 + 8 * i]).ok_or(FsError::Corrupt(block));
+        let name_start = pos + CPIO_HEADER;
+        let name_size = field(11)? as usize;
+        let name = text(archive.get(name_start..name_start + name_size).ok_or(bad)?, block)?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let size = field(6)? as usize;
+        let data = archive.get(data_start..data_start + size).ok_or(bad)?;
+        pos = (data_start + size).next_multiple_of(4);
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+
+        let mode = field(1)? as u16;
+        let path = clean(name);
+        if path.is_empty() || path == "." || !matches!(mode & S_IFMT, S_IFDIR | S_IFREG | S_IFLNK) {
+            continue;
+        }
+        let mut first = None;
+        if mode & S_IFMT == S_IFREG && field(4)? > 1 {
+            let key = (field(7)?, field(8)?, field(0)?);
+            first = first_names.get(&key).cloned();
+            if first.is_none() {
+                first_names.insert(key, path.to_string());
+            }
+        }
+        f(&Member {
+            path,
+            mode,
+            uid: field(2)? as u16,
+            gid: field(3)? as u16,
+            mtime: field(5)?,
+            data,
+            link: first.as_deref(),
+        })?;
+    }
+}
+
+/// `path` below directory `dir`
+fn join(dir: &str, path: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), path)
+}
+
+/// Writes all of `data` to the file at `path`, creating it if needed
+fn write_file<D: FileSystem + ?Sized>(vfs: &mut Vfs<D>, cred: &Cred, path: &str, perm: u16, data: &[u8]) -> Result<(), FsError> {
+    let fd = vfs.open(cred, path, O_WRITE | O_CREATE | O_TRUNC, perm)?;
+    let mut done = 0;
+    let res = loop {
+        if done == data.len() {
+            break Ok(());
+        }
+        match vfs.write(fd, &data[done..]) {
+            Ok(0) => break Err(FsError::NoSpace),
+            Ok(n) => done += n,
+            Err(e) => break Err(e),
+        }
+    };
+    vfs.close(fd)?;
+    res
+}
+
+/// Unpacks `archive` below directory `dir`. Returns how many members were
+/// made.
+pub fn unpack<D: FileSystem + ?Sized>(vfs: &mut Vfs<D>, cred: &Cred, dir: &str, archive: &[u8]) -> Result<usize, FsError> {
+    let mut count = 0;
+    members(archive, &mut |m| {
+        for (i, _) in m.path.match_indices('/') {
+            match vfs.mkdir(cred, &join(dir, &m.path[..i]), 0o755) {
+                Ok(_) | Err(FsError::Exists) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let path = join(dir, m.path);
+        let perm = m.mode & 0o7777;
+        if m.mode & S_IFMT != S_IFDIR {
+            match vfs.unlink(cred, &path) {
+                Ok(()) | Err(FsError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match (m.link, m.mode & S_IFMT) {
+            (Some(target), _) => {
+                vfs.link(cred, &join(dir, target), &path)?;
+                if !m.data.is_empty() {
+                    write_file(vfs, cred, &path, perm, m.data)?;
+                }
+            }
+            (None, S_IFDIR) => match vfs.mkdir(cred, &path, perm) {
+                Ok(_) | Err(FsError::Exists) => {}
+                Err(e) => return Err(e),
+            },
+            (None, S_IFLNK) => {
+                let target = str::from_utf8(m.data).map_err(|_| FsError::InvalidPath)?;
+                vfs.symlink(cred, target, &path)?;
+            }
+            _ => write_file(vfs, cred, &path, perm, m.data)?,
+        }
+        if m.mode & S_IFMT != S_IFLNK {
+            vfs.chmod(cred, &path, perm)?;
+        }
+        vfs.chown(cred, &path, Some(m.uid), Some(m.gid))?;
+        count += 1;
+        Ok(())
+    })?;
+    Ok(count)
+}
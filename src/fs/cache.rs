@@ -0,0 +1,249 @@
+//! Write‑back block cache.
+//!
+//! `BlockCache` wraps any `BlockDevice` and keeps the `N` most recently used
+//! blocks in memory. Reads of a cached block never reach the device and
+//! writes only mark the cached copy dirty; dirty blocks are written back when
+//! they are evicted, on `sync`, and from `tick` once `FLUSH_INTERVAL` seconds
+//! have passed since the last flush.
+//!
+//! A read of the block right after the previously read one is taken as part
+//! of a sequential read, such as a file being read front to back. If the
+//! following block is not cached yet, the next `READ_AHEAD` blocks are loaded
+//! along with it. Hits on other blocks, such as the file's inode or indirect
+//! block read in between, do not break the sequence.
+//!
+//! Because writes reach the device late and in any order, code that depends
+//! on ordering must call `flush` between the writes that have to land first
+//! and the ones that follow; the journal does so around its commit record.
+//!
+//! A device error during a write‑back leaves the block dirty in its slot, so
+//! the next `sync` tries again; the error is returned to whichever call
+//! caused the write‑back.
+
+use core::cell::{Cell, RefCell};
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Ok};
+
+use super::clock;
+use super::error::IoError;
+use super::structure::{check_block, BlockDevice, BLOCK_SIZE};
+
+/// Slots in the kernel's cache
+pub const CACHE_BLOCKS: usize = 16;
+
+/// Blocks loaded ahead of a sequential read
+pub const READ_AHEAD: u32 = 4;
+
+/// Seconds dirty blocks may stay in memory before `tick` writes them back
+pub const FLUSH_INTERVAL: u32 = 5;
+
+/// Counters since the cache was created, and its current fill
+#[derive(Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,        // reads served from memory
+    pub misses: u64,      // reads that went to the device
+    pub read_ahead: u64,  // blocks loaded ahead of a sequential read
+    pub write_backs: u64, // dirty blocks written to the device
+    pub dirty: usize,     // slots not yet written back
+    pub capacity: usize,  // slots in the cache
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    block: u32,
+    valid: bool,
+    dirty: bool,
+    used: u64, // value of `Inner::clock` at the last access
+    data: [u8; BLOCK_SIZE],
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot { block: 0, valid: false, dirty: false, used: 0, data: [0; BLOCK_SIZE] };
+}
+
+/// Everything a read may change, behind the `RefCell`
+struct Inner<D, const N: usize> {
+    device: D,
+    slots: [Slot; N],
+    clock: u64,
+    next_seq: u32, // block a sequential reader would ask for next
+    stats: CacheStats,
+}
+
+impl<D: BlockDevice, const N: usize> Inner<D, N> {
+    fn find(&self, block: u32) -> Option<usize> {
+        self.slots.iter().position(|s| s.valid && s.block == block)
+    }
+
+    fn touch(&mut self, i: usize) {
+        self.clock += 1;
+        self.slots[i].used = self.clock;
+    }
+
+    fn write_back(&mut self, i: usize) -> Result<(), IoError> {
+        let slot = &mut self.slots[i];
+        if slot.valid && slot.dirty {
+            self.device.write_block(slot.block, &slot.data)?;
+            slot.dirty = false;
+            self.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// Frees the least recently used slot for `block`, writing back its old
+    /// contents if dirty. The slot's data is left for the caller to fill.
+    fn claim(&mut self, block: u32) -> Result<usize, IoError> {
+        let i = match self.slots.iter().position(|s| !s.valid) {
+            Some(i) => i,
+            None => (0..N).min_by_key(|&i| self.slots[i].used).unwrap_or(0),
+        };
+        self.write_back(i)?;
+        let slot = &mut self.slots[i];
+        slot.block = block;
+        slot.valid = true;
+        slot.dirty = false;
+        self.touch(i);
+        Ok(i)
+    }
+
+    /// Reads `block` from the device into a fresh slot
+    fn load(&mut self, block: u32) -> Result<usize, IoError> {
+        let i = self.claim(block)?;
+        if let Err(e) = self.device.read_block(block, &mut self.slots[i].data) {
+            self.slots[i].valid = false;
+            return Err(e);
+        }
+        Ok(i)
+    }
+}
+
+pub struct BlockCache<D, const N: usize = CACHE_BLOCKS> {
+    inner: RefCell<Inner<D, N>>,
+    block_count: u32,
+    last_flush: Cell<u32>,
+}
+
+impl<D: BlockDevice, const N: usize> BlockCache<D, N> {
+    pub fn new(device: D) -> BlockCache<D, N> {
+        let block_count = device.block_count();
+        BlockCache {
+            inner: RefCell::new(Inner {
+                device,
+                slots: [Slot::EMPTY; N],
+                clock: 0,
+                next_seq: u32::MAX,
+                stats: CacheStats::default(),
+            }),
+            block_count,
+            last_flush: Cell::new(clock::now()),
+        }
+    }
+
+    /// Writes every dirty block back to the device
+    pub fn sync(&mut self) -> Result<(), IoError> {
+        let inner = self.inner.get_mut();
+        for i in 0..N {
+            inner.write_back(i)?;
+        }
+        inner.device.flush()?;
+        self.last_flush.set(clock::now());
+        Ok(())
+    }
+
+    /// Periodic flush: syncs if blocks are dirty and `FLUSH_INTERVAL` seconds
+    /// have passed since the last flush
+    pub fn tick(&mut self) -> Result<(), IoError> {
+        let now = clock::now();
+        if now.wrapping_sub(self.last_flush.get()) < FLUSH_INTERVAL {
+            return Ok(());
+        }
+        if self.dirty_count() == 0 {
+            self.last_flush.set(now);
+            return Ok(());
+        }
+        let result = self.sync();
+        // Retry on the next interval rather than on every tick
+        self.last_flush.set(now);
+        result
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { dirty: self.dirty_count(), capacity: N, ..self.inner.borrow().stats }
+    }
+
+    /// Number of slots holding data not yet written to the device
+    pub fn dirty_count(&self) -> usize {
+        self.inner.borrow().slots.iter().filter(|s| s.valid && s.dirty).count()
+    }
+
+    /// Number of blocks the cache holds
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the underlying device, dropping blocks still dirty; `sync`
+    /// first to keep them
+    pub fn into_inner(self) -> D {
+        self.inner.into_inner().device
+    }
+}
+
+impl<D: BlockDevice, const N: usize> BlockDevice for BlockCache<D, N> {
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_block(self.block_count, block_number, buf.len())?;
+        let mut inner = self.inner.borrow_mut();
+        let cached = inner.find(block_number);
+        let i = match cached {
+            Some(i) => {
+                inner.stats.hits += 1;
+                inner.touch(i);
+                i
+            }
+            None => {
+                inner.stats.misses += 1;
+                inner.load(block_number)?
+            }
+        };
+        buf.copy_from_slice(&inner.slots[i].data);
+
+        let sequential = block_number == inner.next_seq;
+        if sequential || cached.is_none() {
+            inner.next_seq = block_number.wrapping_add(1);
+        }
+        if sequential && inner.find(inner.next_seq).is_none() {
+            let end = block_number.saturating_add(READ_AHEAD).min(self.block_count - 1);
+            for ahead in block_number + 1..=end {
+                // Read‑ahead is a guess; a failure here is not the caller's
+                if inner.find(ahead).is_none() && inner.load(ahead).is_ok() {
+                    inner.stats.read_ahead += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_block(self.block_count, block_number, buf.len())?;
+        let inner = self.inner.get_mut();
+        let i = match inner.find(block_number) {
+            Some(i) => {
+                inner.touch(i);
+                i
+            }
+            None => inner.claim(block_number)?,
+        };
+        let slot = &mut inner.slots[i];
+        slot.data.copy_from_slice(buf);
+        slot.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.sync()
+    }
+}
@@ -0,0 +1,67 @@
+//! Wall‑clock time for inode timestamps.
+//!
+//! The filesystem has no clock of its own: the kernel installs the RTC and
+//! the host tools the system time with `set_clock`. Until then timestamps
+//! are 0 (1970‑01‑01).
+
+use core::fmt;
+
+use spin::Once;
+
+static CLOCK: Once<fn() -> u32> = Once::new();
+
+/// Installs the source of the current time, in seconds since the Unix epoch.
+/// Only the first call has an effect.
+pub fn set_clock(now: fn() -> u32) {
+    CLOCK.call_once(|| now);
+}
+
+/// Current time in seconds since the Unix epoch
+pub fn now() -> u32 {
+    CLOCK.get().map_or(0, |now| now())
+}
+
+/// Days since 1970‑01‑01 of a proleptic Gregorian date (year ≥ 1970)
+pub fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    // Howard Hinnant's algorithm, with years starting in March
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// A timestamp split into calendar fields, printed as `YYYY-MM-DD HH:MM`
+#[derive(Clone, Copy)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_unix(t: u32) -> DateTime {
+        let (days, secs) = (t / 86400, t % 86400);
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateTime { year, month, day, hour: secs / 3600, minute: secs / 60 % 60, second: secs % 60 }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute)
+    }
+}
@@ -0,0 +1,34 @@
+//! CRC‑32C (Castagnoli), the checksum used by iSCSI, ext4 and btrfs.
+
+const POLY: u32 = 0x82f6_3b78; // reflected 0x1edc6f41
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues a checksum: `crc32c_update(crc32c(a), b) == crc32c(a ++ b)`
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc = TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_update(0, data)
+}
@@ -0,0 +1,110 @@
+use core::convert::From;
+use core::fmt;
+
+/// Errors returned by block devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoError {
+    OutOfRange(u32), // block number at or past the end of the device
+    BufferSize,      // buffer is not one block, or a whole number of blocks
+    Device(u8),      // the device failed the command; its error register
+    Timeout,         // the device stopped responding
+    ReadOnly,        // the device does not accept writes
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoError::OutOfRange(block) => write!(f, "block {} is past the end of the device", block),
+            IoError::BufferSize => f.write_str("buffer is not a whole number of blocks"),
+            IoError::Device(err) => write!(f, "device error {:#04x}", err),
+            IoError::Timeout => f.write_str("device timed out"),
+            IoError::ReadOnly => f.write_str("device is read-only"),
+        }
+    }
+}
+
+/// Errors returned by filesystem operations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,        // a path component does not exist
+    Exists,          // the target name is already taken
+    NotDir,          // a path component is not a directory
+    IsDir,           // the operation needs a non‑directory
+    NotEmpty,        // directory still has entries
+    NameTooLong,     // name longer than `DirEntry::MAX_NAME`
+    NoSpace,         // no free data block or directory slot
+    NoInodes,        // inode table is full
+    QuotaExceeded,   // the owner's block or inode quota is used up (EDQUOT)
+    TooManyLinks,    // link count would overflow
+    PermissionDenied,// mode bits deny the access (EACCES)
+    NotPermitted,    // only the owner or superuser may do this (EPERM)
+    InvalidPath,     // empty path, "." / ".." as a target, or moving a directory into itself
+    Loop,            // too many symbolic links while resolving a path (ELOOP)
+    NoAttr,          // extended attribute not set (ENODATA)
+    Unsupported,     // unknown extended attribute namespace (EOPNOTSUPP)
+    BadMagic,        // superblock magic does not match the filesystem type
+    BadVersion,      // on‑disk format version this code does not understand
+    BadJournal,      // journal header or layout is damaged
+    TxTooLarge,      // operation writes more blocks than one transaction holds
+    Corrupt(u32),    // checksum mismatch in the given metadata block
+    BadPartitionTable, // GPT headers or entry arrays fail their checks
+    Busy,            // filesystem or mount point in use (EBUSY)
+    CrossDevice,     // rename or link between filesystems (EXDEV)
+    TooManyFiles,    // open file table is full (EMFILE)
+    BadFd,           // not an open file, or not open for this access (EBADF)
+    InvalidArgument, // e.g. a seek to before the start of a file (EINVAL)
+    ReadOnly,        // the filesystem cannot be changed (EROFS)
+    Io(IoError),     // the block device failed
+}
+
+impl From<IoError> for FsError {
+    fn from(e: IoError) -> FsError {
+        FsError::Io(e)
+    }
+}
+
+impl FsError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::Exists => "file exists",
+            FsError::NotDir => "not a directory",
+            FsError::IsDir => "is a directory",
+            FsError::NotEmpty => "directory not empty",
+            FsError::NameTooLong => "file name too long",
+            FsError::NoSpace => "no space left on device",
+            FsError::NoInodes => "no free inodes",
+            FsError::QuotaExceeded => "disk quota exceeded",
+            FsError::TooManyLinks => "too many links",
+            FsError::PermissionDenied => "permission denied",
+            FsError::NotPermitted => "operation not permitted",
+            FsError::InvalidPath => "invalid path",
+            FsError::Loop => "too many levels of symbolic links",
+            FsError::NoAttr => "no such attribute",
+            FsError::Unsupported => "operation not supported",
+            FsError::BadMagic => "wrong filesystem type",
+            FsError::BadVersion => "unsupported filesystem format version",
+            FsError::BadJournal => "journal is damaged",
+            FsError::TxTooLarge => "operation too large for the journal",
+            FsError::Corrupt(_) => "metadata checksum mismatch",
+            FsError::BadPartitionTable => "partition table is damaged",
+            FsError::Busy => "device or resource busy",
+            FsError::CrossDevice => "invalid cross-device link",
+            FsError::TooManyFiles => "too many open files",
+            FsError::BadFd => "bad file descriptor",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::ReadOnly => "read-only file system",
+            FsError::Io(_) => "I/O error",
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::Corrupt(block) => write!(f, "{} in block {}", self.as_str(), block),
+            FsError::Io(e) => write!(f, "{}: {}", self.as_str(), e),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Read-only ext2.
+//!
+//! `Ext2` mounts images made by `mke2fs` on the host. The superblock at byte
+//! 1024 gives the block size and the group geometry; the block group
+//! descriptors after it give where each group's inode table starts. An
+//! inode's data is found through its 12 direct pointers and the single,
+//! double and triple indirect blocks after them, a pointer of 0 being a
+//! hole that reads as zeros. Directories are read as the linear list of
+//! entries they start out as; the hash index of `dir_index` directories is
+//! stored in entries that look empty, so it is simply skipped. Short
+//! symlinks keep their target in the block pointers themselves.
+//!
+//! Nothing is ever written: every change fails with `ReadOnly`. An image
+//! using an incompatible feature other than `filetype` or `flex_bg`
+//! (extents, 64-bit block numbers, a journal waiting for recovery, ...) is
+//! refused with `BadVersion`. Blocks are read through a `BlockCache`, so
+//! the device must have `BLOCK_SIZE` sectors.
+
+use core::iter::Iterator;
+use core::option::Option::{None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::cache::{BlockCache, CacheStats};
+use super::error::{FsError, IoError};
+use super::perm::Cred;
+use super::structure::{get_u16, get_u32, BlockDevice, BLOCK_SIZE, S_IFDIR, S_IFLNK, S_IFMT};
+use super::vfs::{FileOps, FileSystem, InodeOps, Stat};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+
+/// Inode number of the root directory
+pub const EXT2_ROOT: u32 = 2;
+
+/// Incompatible features this driver reads correctly
+const INCOMPAT_FILETYPE: u32 = 0x0002; // file type byte in directory entries
+const INCOMPAT_FLEX_BG: u32 = 0x0200; // group metadata packed together
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+const DESCRIPTOR_SIZE: usize = 32;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Block pointers in an inode: 12 direct, then single, double and triple
+/// indirect
+const DIRECT_BLOCKS: u32 = 12;
+const POINTERS: usize = 15;
+
+/// Longest symlink target that can be kept in the block pointers
+const FAST_SYMLINK_MAX: u64 = 60;
+
+/// Header of a directory entry: inode, record length, name length, type
+const DIRENT_HEADER: usize = 8;
+
+/// The fields of an on-disk inode that are used
+struct Inode {
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    mtime: u32,
+    links: u16,
+    sectors: u32,  // i_blocks: 512-byte units allocated, extended attributes included
+    file_acl: u32, // block holding extended attributes, or 0
+    block: [u8; POINTERS * 4], // the pointers, or a fast symlink's target
+}
+
+impl Inode {
+    fn parse(buf: &[u8]) -> Inode {
+        let mode = get_u16(buf, 0);
+        let mut size = get_u32(buf, 4) as u64;
+        // Revision 1 keeps the high half of a regular file's size here
+        if mode & S_IFMT != S_IFDIR {
+            size |= (get_u32(buf, 108) as u64) << 32;
+        }
+        let mut block = [0u8; POINTERS * 4];
+        block.copy_from_slice(&buf[40..40 + POINTERS * 4]);
+        Inode {
+            mode,
+            uid: get_u16(buf, 2),
+            gid: get_u16(buf, 24),
+            size,
+            mtime: get_u32(buf, 16),
+            links: get_u16(buf, 26),
+            sectors: get_u32(buf, 28),
+            file_acl: get_u32(buf, 104),
+            block,
+        }
+    }
+
+    fn pointer(&self, i: usize) -> u32 {
+        get_u32(&self.block, i * 4)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+pub struct Ext2<D> {
+    device: BlockCache<D>,
+    block_size: usize,
+    inode_size: usize,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_tables: Vec<u32>, // first block of each group's inode table
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Reads the superblock and group descriptors of the ext2 filesystem on
+    /// `device`
+    pub fn open(device: D) -> Result<Ext2<D>, FsError> {
+        if device.block_size() != BLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut fs = Ext2 {
+            device: BlockCache::new(device),
+            block_size: 0,
+            inode_size: 0,
+            inodes_count: 0,
+            inodes_per_group: 0,
+            inode_tables: Vec::new(),
+        };
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        fs.read_bytes(SUPERBLOCK_OFFSET, &mut sb)?;
+        if get_u16(&sb, 56) != EXT2_MAGIC {
+            return Err(FsError::BadMagic);
+        }
+        let log_block_size = get_u32(&sb, 24);
+        let revision = get_u32(&sb, 76);
+        if log_block_size > 6 || revision > 1 || get_u32(&sb, 96) & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::BadVersion);
+        }
+        fs.block_size = 1024 << log_block_size;
+        fs.inode_size = if revision == 0 { GOOD_OLD_INODE_SIZE } else { get_u16(&sb, 88) as usize };
+        fs.inodes_count = get_u32(&sb, 0);
+        fs.inodes_per_group = get_u32(&sb, 40);
+        if !fs.inode_size.is_power_of_two()
+            || fs.inode_size < GOOD_OLD_INODE_SIZE
+            || fs.inode_size > BLOCK_SIZE
+            || fs.inodes_per_group == 0
+        {
+            return Err(FsError::BadVersion);
+        }
+
+        // Both counts give the number of groups, and a damaged superblock
+        // is caught before a huge descriptor table is read
+        let groups = fs.inodes_count.div_ceil(fs.inodes_per_group) as usize;
+        let first_data_block = get_u32(&sb, 20);
+        let blocks_per_group = get_u32(&sb, 32);
+        if blocks_per_group == 0
+            || get_u32(&sb, 4).saturating_sub(first_data_block).div_ceil(blocks_per_group) as usize != groups
+        {
+            return Err(FsError::BadVersion);
+        }
+        // Nor may the filesystem be larger than the device holding it
+        let device_bytes = fs.device.block_count() as u64 * BLOCK_SIZE as u64;
+        if get_u32(&sb, 4) as u64 * fs.block_size as u64 > device_bytes {
+            return Err(FsError::BadVersion);
+        }
+
+        // The descriptor table starts in the block after the superblock's
+        let table = (first_data_block as u64 + 1) * fs.block_size as u64;
+        let mut descriptors = Vec::new();
+        descriptors.try_reserve_exact(groups * DESCRIPTOR_SIZE).map_err(|_| FsError::NoSpace)?;
+        descriptors.resize(groups * DESCRIPTOR_SIZE, 0);
+        fs.read_bytes(table, &mut descriptors)?;
+        fs.inode_tables.try_reserve_exact(groups).map_err(|_| FsError::NoSpace)?;
+        fs.inode_tables.extend(descriptors.chunks(DESCRIPTOR_SIZE).map(|d| get_u32(d, 8)));
+        Ok(fs)
+    }
+
+    /// Sectors making up one filesystem block
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size / BLOCK_SIZE) as u64
+    }
+
+    /// Reads `buf.len()` bytes from byte `pos` of the device
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut sector = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let number = u32::try_from(at / BLOCK_SIZE as u64).map_err(|_| IoError::OutOfRange(u32::MAX))?;
+            let skip = (at % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - skip).min(buf.len() - done);
+            self.device.read_block(number, &mut sector)?;
+            buf[done..done + n].copy_from_slice(&sector[skip..skip + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Reads filesystem block `block` into `buf`, one block long
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        let start = block as u64 * self.sectors_per_block();
+        let start = u32::try_from(start).map_err(|_| IoError::OutOfRange(block))?;
+        self.device.read_blocks(start, buf)?;
+        Ok(())
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::NotFound);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = *self.inode_tables.get(group).ok_or(FsError::NotFound)?;
+        let pos = table as u64 * self.block_size as u64 + index * self.inode_size as u64;
+        let mut buf = [0u8; GOOD_OLD_INODE_SIZE];
+        self.read_bytes(pos, &mut buf)?;
+        let inode = Inode::parse(&buf);
+        if inode.links == 0 || inode.mode == 0 {
+            return Err(FsError::NotFound);
+        }
+        Ok(inode)
+    }
+
+    /// Entry `index` of the pointer block `block`; 0 if `block` is a hole
+    fn indirect(&self, block: u32, index: u64) -> Result<u32, FsError> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut ptr = [0u8; 4];
+        self.read_bytes(block as u64 * self.block_size as u64 + index * 4, &mut ptr)?;
+        Ok(u32::from_le_bytes(ptr))
+    }
+
+    /// Block holding block `n` of `inode`'s data; 0 for a hole
+    fn map(&self, inode: &Inode, n: u64) -> Result<u32, FsError> {
+        let per = (self.block_size / 4) as u64;
+        if n < DIRECT_BLOCKS as u64 {
+            return Ok(inode.pointer(n as usize));
+        }
+        let n = n - DIRECT_BLOCKS as u64;
+        if n < per {
+            return self.indirect(inode.pointer(12), n);
+        }
+        let n = n - per;
+        if n < per * per {
+            let b = self.indirect(inode.pointer(13), n / per)?;
+            return self.indirect(b, n % per);
+        }
+        let n = n - per * per;
+        if n < per * per * per {
+            let b = self.indirect(inode.pointer(14), n / (per * per))?;
+            let b = self.indirect(b, n / per % per)?;
+            return self.indirect(b, n % per);
+        }
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Reads `inode`'s data from `offset`, holes as zeros
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let bs = self.block_size as u64;
+        let len = (buf.len() as u64).min(inode.size.saturating_sub(offset)) as usize;
+        let mut block = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let skip = (pos % bs) as usize;
+            let n = (self.block_size - skip).min(len - done);
+            match self.map(inode, pos / bs)? {
+                0 => buf[done..done + n].fill(0),
+                b => {
+                    self.read_block(b, &mut block)?;
+                    buf[done..done + n].copy_from_slice(&block[skip..skip + n]);
+                }
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Calls `f(name, ino)` for each entry of directory `dir` until it
+    /// returns true
+    fn entries(&self, dir: &Inode, f: &mut dyn FnMut(&str, u32) -> bool) -> Result<(), FsError> {
+        let mut block = vec![0u8; self.block_size];
+        for n in 0..dir.size.div_ceil(self.block_size as u64) {
+            let b = self.map(dir, n)?;
+            if b == 0 {
+                continue;
+            }
+            self.read_block(b, &mut block)?;
+            let mut pos = 0;
+            while pos + DIRENT_HEADER <= self.block_size {
+                let ino = get_u32(&block, pos);
+                let rec_len = get_u16(&block, pos + 4) as usize;
+                let name_len = block[pos + 6] as usize;
+                if rec_len < DIRENT_HEADER || pos + rec_len > self.block_size || DIRENT_HEADER + name_len > rec_len {
+                    return Err(FsError::Corrupt(b));
+                }
+                let name = &block[pos + DIRENT_HEADER..pos + DIRENT_HEADER + name_len];
+                if ino != 0
+                    && let Ok(name) = core::str::from_utf8(name)
+                    && f(name, ino)
+                {
+                    return Ok(());
+                }
+                pos += rec_len;
+            }
+        }
+        Ok(())
+    }
+
+    fn dir(&self, ino: u32) -> Result<Inode, FsError> {
+        let inode = self.inode(ino)?;
+        if !inode.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        Ok(inode)
+    }
+}
+
+impl<D: BlockDevice> InodeOps for Ext2<D> {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        let inode = self.inode(ino)?;
+        Ok(Stat {
+            ino,
+            mode: inode.mode,
+            links: inode.links,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: inode.size,
+            blocks: inode.sectors as u64,
+            mtime: inode.mtime,
+        })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let inode = self.dir(dir)?;
+        let mut found = None;
+        self.entries(&inode, &mut |n, ino| {
+            if n == name {
+                found = Some(ino);
+            }
+            found.is_some()
+        })?;
+        found.ok_or(FsError::NotFound)
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        let inode = self.dir(dir)?;
+        self.entries(&inode, &mut |name, ino| {
+            f(name, ino);
+            false
+        })
+    }
+
+    fn readlink(&self, ino: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(ino)?;
+        if inode.mode & S_IFMT != S_IFLNK {
+            return Err(FsError::InvalidPath);
+        }
+        // A fast symlink has no data blocks, only perhaps an attribute block
+        let xattr_sectors = if inode.file_acl != 0 { self.sectors_per_block() as u32 } else { 0 };
+        if inode.size <= FAST_SYMLINK_MAX && inode.sectors == xattr_sectors {
+            let n = (inode.size as usize).min(buf.len());
+            buf[..n].copy_from_slice(&inode.block[..n]);
+            return Ok(n);
+        }
+        self.read_data(&inode, 0, buf)
+    }
+
+    fn create(&mut self, _cred: &Cred, _dir: u32, _name: &str, _perm: u16) -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn mkdir(&mut self, _cred: &Cred, _dir: u32, _name: &str, _perm: u16) -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&mut self, _cred: &Cred, _dir: u32, _name: &str, _target: &str) -> Result<u32, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn link(&mut self, _ino: u32, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&mut self, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rmdir(&mut self, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rename(&mut self, _from_dir: u32, _from: &str, _to_dir: u32, _to: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn chmod(&mut self, _ino: u32, _mode: u16) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn chown(&mut self, _ino: u32, _uid: Option<u16>, _gid: Option<u16>) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn set_xattr(&mut self, _cred: &Cred, _ino: u32, _name: &str, _value: &[u8]) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove_xattr(&mut self, _cred: &Cred, _ino: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl<D: BlockDevice> FileOps for Ext2<D> {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        self.read_data(&inode, offset, buf)
+    }
+
+    fn write(&mut self, _ino: u32, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, _ino: u32, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Ext2<D> {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> u32 {
+        EXT2_ROOT
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.device.stats())
+    }
+}
@@ -0,0 +1,1303 @@
+//! FAT12, FAT16 and FAT32.
+//!
+//! `Fat` reads the BIOS parameter block in the boot sector to find the
+//! FATs, the root directory and the data area, and tells the three
+//! variants apart by their cluster count as the specification does. A
+//! file's data is a chain of clusters linked through the FAT; directories
+//! are files of 32-byte entries, except the root of FAT12 and FAT16, which
+//! is a fixed region before the data area. Every copy of the FAT is kept
+//! up to date unless the volume turned mirroring off. On FAT32 the free
+//! cluster count and the next-free hint of the FSInfo sector are kept and
+//! written back on `sync`.
+//!
+//! Names longer than 8.3, or not expressible in it, are stored as VFAT long
+//! name entries in front of the short entry, tied to it by a checksum. The
+//! short name is then derived from the long one with a `~N` tail, as
+//! Windows does. Names that fit 8.3 in one case per part are stored short,
+//! with the case flags Windows NT added. Lookups ignore ASCII case and match
+//! either name.
+//!
+//! FAT has no inode numbers, so they are handed out as files are first
+//! found and remembered, with the position of the file's short entry and
+//! its parent directory, until the file is removed or the filesystem
+//! unmounted. Everything belongs to root; a directory's mode is 0755 and a
+//! file's 0644, or 0444 if it has the read-only attribute, which `chmod`
+//! sets and clears. Blocks go through a `BlockCache`, so sectors must be a
+//! multiple of `BLOCK_SIZE`.
+
+use core::cell::RefCell;
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use alloc::collections::BTreeMap;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::cache::{BlockCache, CacheStats};
+use super::clock::{self, days_from_civil, DateTime};
+use super::error::{FsError, IoError};
+use super::perm::Cred;
+use super::structure::{get_u16, get_u32, put_u16, put_u32, BlockDevice, BLOCK_SIZE, S_IFDIR, S_IFREG};
+use super::vfs::{FileOps, FileSystem, InodeOps, Stat};
+
+/// Inode number of the root directory
+pub const FAT_ROOT: u32 = 1;
+
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F; // read-only, hidden, system and volume ID together
+const ATTR_LFN_MASK: u8 = 0x3F;
+
+/// First byte of a short name: end of the directory, or a deleted entry
+const SLOT_END: u8 = 0x00;
+const SLOT_DELETED: u8 = 0xE5;
+/// Stands for 0xE5 as the first byte of a real name
+const SLOT_KANJI: u8 = 0x05;
+
+/// Case flags of the short name, in the byte Windows NT reserved
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// Long name entries: the ordinal flag of the last one, the UTF-16 units in
+/// each and where they sit in it
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Longest long name, in UTF-16 units, and the entries it takes
+pub const MAX_NAME: usize = 255;
+const MAX_LFN_ENTRIES: usize = MAX_NAME.div_ceil(LFN_CHARS);
+
+/// A directory holds at most this many entries
+const MAX_DIR_SLOTS: u32 = 65536;
+
+/// Characters allowed in short names besides letters and digits
+const SHORT_EXTRA: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// Characters no name may contain
+const FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// Cluster counts that tell the variants apart
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FREE_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// FAT entries at or above this end a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// Value written to end a chain
+    fn eoc(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+/// A file known to the inode table: where its short entry is, and the
+/// directory holding it
+#[derive(Clone, Copy)]
+struct Node {
+    entry: u64,
+    parent: u32,
+}
+
+#[derive(Default)]
+struct Nodes {
+    table: Vec<Option<Node>>, // indexed by inode number
+    by_entry: BTreeMap<u64, u32>,
+}
+
+/// Where the entries of a directory are: the fixed root region of FAT12
+/// and FAT16, or the clusters of its chain
+enum DirMap {
+    Fixed { start: u64, slots: u32 },
+    Chain(Vec<u32>),
+}
+
+/// An entry as a directory lists it, with the long name if it has one
+struct DirEntry {
+    name: String,
+    short: [u8; 11],
+    raw: [u8; ENTRY_SIZE],
+    first: u32, // slot of its first long name entry, or of the short entry
+    slot: u32,  // slot of the short entry
+    pos: u64,   // position of the short entry on the device
+}
+
+impl DirEntry {
+    fn is_dir(&self) -> bool {
+        self.raw[11] & ATTR_DIRECTORY != 0
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// A short name as shown: base and extension without padding, lowered as
+/// the case flags say
+fn short_display(raw: &[u8]) -> String {
+    let lower = |bytes: &[u8], flag: u8| -> String {
+        let mut s = String::new();
+        for (i, &b) in bytes.iter().enumerate() {
+            let b = if i == 0 && b == SLOT_KANJI { SLOT_DELETED } else { b };
+            let c = b as char;
+            s.push(if raw[12] & flag != 0 { c.to_ascii_lowercase() } else { c });
+        }
+        s.truncate(s.trim_end_matches(' ').len());
+        s
+    };
+    let mut name = lower(&raw[0..8], NT_LOWER_BASE);
+    let ext = lower(&raw[8..11], NT_LOWER_EXT);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+fn short_char(c: char) -> Option<u8> {
+    let c = c.to_ascii_uppercase();
+    (c.is_ascii_alphanumeric() || c.is_ascii() && SHORT_EXTRA.contains(&(c as u8))).then_some(c as u8)
+}
+
+/// `name` as a short name with its case flags, if it is a valid 8.3 name
+/// whose base and extension are each in one case
+fn fits_short(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, Some(ext)),
+        None => (name, None),
+    };
+    let ext = ext.unwrap_or("");
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') || name.ends_with('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut flags = 0;
+    for (part, at, flag) in [(base, 0, NT_LOWER_BASE), (ext, 8, NT_LOWER_EXT)] {
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            flags |= flag;
+        }
+        for (i, c) in part.chars().enumerate() {
+            short[at + i] = short_char(c)?;
+        }
+    }
+    if short[0] == SLOT_DELETED {
+        short[0] = SLOT_KANJI;
+    }
+    Some((short, flags))
+}
+
+/// The short name Windows would start from for long name `name`, and
+/// whether anything was lost making it
+fn short_basis(name: &str) -> ([u8; 11], bool) {
+    let stripped = name.trim_start_matches('.');
+    let mut lossy = stripped.len() != name.len() || stripped.contains(' ');
+    let (base, ext) = match stripped.rfind('.') {
+        Some(i) => (&stripped[..i], &stripped[i + 1..]),
+        None => (stripped, ""),
+    };
+    let mut short = [b' '; 11];
+    for (part, at, len) in [(base, 0, 8), (ext, 8, 3)] {
+        let mut n = 0;
+        for c in part.chars().filter(|&c| c != ' ') {
+            if c == '.' {
+                lossy = true;
+                continue;
+            }
+            if n == len {
+                lossy = true;
+                break;
+            }
+            short[at + n] = short_char(c).unwrap_or_else(|| {
+                lossy = true;
+                b'_'
+            });
+            n += 1;
+        }
+    }
+    if short[0] == b' ' {
+        short[0] = b'_';
+        lossy = true;
+    }
+    if short[0] == SLOT_DELETED {
+        short[0] = SLOT_KANJI;
+    }
+    (short, lossy)
+}
+
+/// `basis` with the numeric tail `~n`, the base cut short to make room
+fn with_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut v = n;
+    loop {
+        digits[len] = b'0' + (v % 10) as u8;
+        len += 1;
+        v /= 10;
+        if v == 0 {
+            break;
+        }
+    }
+    let base_len = basis[..8].iter().position(|&c| c == b' ').unwrap_or(8).min(7 - len);
+    let mut short = *basis;
+    short[base_len] = b'~';
+    for i in 0..len {
+        short[base_len + 1 + i] = digits[len - 1 - i];
+    }
+    for c in &mut short[base_len + 1 + len..8] {
+        *c = b' ';
+    }
+    short
+}
+
+/// DOS date and time of a Unix timestamp; the epoch is 1980
+fn dos_time(t: u32) -> (u16, u16) {
+    let dt = DateTime::from_unix(t);
+    if dt.year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    let date = ((dt.year - 1980).min(127) << 9 | dt.month << 5 | dt.day) as u16;
+    let time = (dt.hour << 11 | dt.minute << 5 | (dt.second / 2)) as u16;
+    (date, time)
+}
+
+fn unix_time(date: u16, time: u16) -> u32 {
+    let (year, month, day) = (1980 + (date >> 9) as u32, (date >> 5 & 0xF) as u32, (date & 0x1F) as u32);
+    if !(1..=12).contains(&month) || day == 0 {
+        return 0;
+    }
+    let secs = (time >> 11) as u32 * 3600 + (time >> 5 & 0x3F) as u32 * 60 + (time & 0x1F) as u32 * 2;
+    days_from_civil(year, month, day) * 86400 + secs
+}
+
+/// Stamps the modification time, and the creation time if `created`
+fn touch_entry(raw: &mut [u8], created: bool) {
+    let (date, time) = dos_time(clock::now());
+    put_u16(raw, 22, time);
+    put_u16(raw, 24, date);
+    put_u16(raw, 18, date);
+    if created {
+        raw[13] = 0;
+        put_u16(raw, 14, time);
+        put_u16(raw, 16, date);
+    }
+}
+
+/// A fresh short entry
+fn new_entry(short: &[u8; 11], attr: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[11] = attr;
+    set_cluster(&mut raw, cluster);
+    touch_entry(&mut raw, true);
+    raw
+}
+
+fn set_cluster(raw: &mut [u8], cluster: u32) {
+    put_u16(raw, 20, (cluster >> 16) as u16);
+    put_u16(raw, 26, cluster as u16);
+}
+
+/// Checks a name for a new entry and returns it in UTF-16
+fn check_name(name: &str) -> Result<Vec<u16>, FsError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.ends_with(['.', ' '])
+        || name.contains(FORBIDDEN)
+        || name.chars().any(|c| (c as u32) < 0x20)
+    {
+        return Err(FsError::InvalidPath);
+    }
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > MAX_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(units)
+}
+
+pub struct Fat<D> {
+    device: BlockCache<D>,
+    kind: FatType,
+    sector_size: u64,
+    cluster_size: u64,
+    fat_start: u64,  // position of the first FAT
+    fat_size: u64,   // bytes per FAT
+    fats: u32,
+    active_fat: Option<u32>, // the one FAT in use, when mirroring is off
+    root_start: u64, // fixed root directory of FAT12 and FAT16
+    root_slots: u32,
+    root_cluster: u32, // first cluster of the FAT32 root directory
+    data_start: u64,
+    max_cluster: u32,
+    fsinfo: Option<u64>, // position of the FSInfo sector
+    free_count: Option<u32>,
+    next_free: u32,
+    fsinfo_dirty: bool,
+    mtime: u32,
+    nodes: RefCell<Nodes>,
+}
+
+impl<D: BlockDevice> Fat<D> {
+    /// Reads the boot sector of the FAT volume on `device`
+    pub fn open(device: D) -> Result<Fat<D>, FsError> {
+        if device.block_size() != BLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let device = BlockCache::new(device);
+        let mut boot = [0u8; BLOCK_SIZE];
+        device.read_block(0, &mut boot)?;
+        let bps = get_u16(&boot, 11) as u64;
+        let spc = boot[13] as u64;
+        let reserved = get_u16(&boot, 14) as u64;
+        let fats = boot[16] as u32;
+        let root_entries = get_u16(&boot, 17) as u64;
+        let total = match get_u16(&boot, 19) {
+            0 => get_u32(&boot, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match get_u16(&boot, 22) {
+            0 => get_u32(&boot, 36) as u64,
+            n => n as u64,
+        };
+        if boot[510..512] != [0x55, 0xAA]
+            || !bps.is_power_of_two()
+            || !(512..=4096).contains(&bps)
+            || !spc.is_power_of_two()
+            || bps * spc > 64 * 1024
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+        {
+            return Err(FsError::BadMagic);
+        }
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(bps);
+        let data_sector = reserved + fats as u64 * fat_sectors + root_sectors;
+        if total <= data_sector {
+            return Err(FsError::BadMagic);
+        }
+        let clusters = ((total - data_sector) / spc) as u32;
+        let kind = if clusters <= FAT12_MAX_CLUSTERS {
+            FatType::Fat12
+        } else if clusters <= FAT16_MAX_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let entry_bits = match kind {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if (clusters as u64 + 2) * entry_bits > fat_sectors * bps * 8 || (kind == FatType::Fat32) != (root_entries == 0) {
+            return Err(FsError::BadMagic);
+        }
+
+        let mut fs = Fat {
+            device,
+            kind,
+            sector_size: bps,
+            cluster_size: bps * spc,
+            fat_start: reserved * bps,
+            fat_size: fat_sectors * bps,
+            fats,
+            active_fat: None,
+            root_start: (reserved + fats as u64 * fat_sectors) * bps,
+            root_slots: root_entries as u32,
+            root_cluster: 0,
+            data_start: data_sector * bps,
+            max_cluster: clusters + 1,
+            fsinfo: None,
+            free_count: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+            mtime: clock::now(),
+            nodes: RefCell::new(Nodes::default()),
+        };
+        if kind == FatType::Fat32 {
+            let flags = get_u16(&boot, 40);
+            if flags & 0x80 != 0 {
+                fs.active_fat = Some((flags & 0xF) as u32).filter(|&f| f < fats);
+            }
+            fs.root_cluster = get_u32(&boot, 44);
+            if !(2..=fs.max_cluster).contains(&fs.root_cluster) {
+                return Err(FsError::BadMagic);
+            }
+            match get_u16(&boot, 48) as u64 {
+                0 | 0xFFFF => {}
+                sector => fs.read_fsinfo(sector * bps)?,
+            }
+        }
+        Ok(fs)
+    }
+
+    pub fn kind(&self) -> FatType {
+        self.kind
+    }
+
+    fn read_fsinfo(&mut self, pos: u64) -> Result<(), FsError> {
+        let mut info = [0u8; BLOCK_SIZE];
+        self.read_bytes(pos, &mut info)?;
+        if get_u32(&info, 0) != FSINFO_LEAD || get_u32(&info, 484) != FSINFO_STRUCT {
+            return Ok(());
+        }
+        self.fsinfo = Some(pos);
+        let free = get_u32(&info, 488);
+        self.free_count = (free < self.max_cluster).then_some(free);
+        let next = get_u32(&info, 492);
+        if (2..=self.max_cluster).contains(&next) {
+            self.next_free = next;
+        }
+        Ok(())
+    }
+
+    fn write_fsinfo(&mut self) -> Result<(), FsError> {
+        let Some(pos) = self.fsinfo.filter(|_| self.fsinfo_dirty) else {
+            return Ok(());
+        };
+        let mut info = [0u8; BLOCK_SIZE];
+        self.read_bytes(pos, &mut info)?;
+        put_u32(&mut info, 488, self.free_count.unwrap_or(FREE_UNKNOWN));
+        put_u32(&mut info, 492, self.next_free);
+        self.write_bytes(pos, &info)?;
+        self.fsinfo_dirty = false;
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes from byte `pos` of the device
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let number = u32::try_from(at / BLOCK_SIZE as u64).map_err(|_| IoError::OutOfRange(u32::MAX))?;
+            let skip = (at % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - skip).min(buf.len() - done);
+            self.device.read_block(number, &mut block)?;
+            buf[done..done + n].copy_from_slice(&block[skip..skip + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at byte `pos` of the device, a partial block by
+    /// read-modify-write
+    fn write_bytes(&mut self, pos: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let at = pos + done as u64;
+            let number = u32::try_from(at / BLOCK_SIZE as u64).map_err(|_| IoError::OutOfRange(u32::MAX))?;
+            let skip = (at % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - skip).min(data.len() - done);
+            if n < BLOCK_SIZE {
+                self.device.read_block(number, &mut block)?;
+            }
+            block[skip..skip + n].copy_from_slice(&data[done..done + n]);
+            self.device.write_block(number, &block)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    /// Position of `cluster`'s entry in FAT `copy`
+    fn fat_pos(&self, copy: u32, cluster: u32) -> u64 {
+        let offset = match self.kind {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        self.fat_start + copy as u64 * self.fat_size + offset
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let pos = self.fat_pos(self.active_fat.unwrap_or(0), cluster);
+        let mut buf = [0u8; 4];
+        match self.kind {
+            FatType::Fat12 => {
+                self.read_bytes(pos, &mut buf[..2])?;
+                let v = get_u16(&buf, 0) as u32;
+                Ok(if cluster & 1 == 1 { v >> 4 } else { v & 0xFFF })
+            }
+            FatType::Fat16 => {
+                self.read_bytes(pos, &mut buf[..2])?;
+                Ok(get_u16(&buf, 0) as u32)
+            }
+            FatType::Fat32 => {
+                self.read_bytes(pos, &mut buf)?;
+                Ok(get_u32(&buf, 0) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// Sets `cluster`'s entry in every FAT in use
+    fn set_fat(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let copies = match self.active_fat {
+            Some(f) => f..f + 1,
+            None => 0..self.fats,
+        };
+        for copy in copies {
+            let pos = self.fat_pos(copy, cluster);
+            let mut buf = [0u8; 4];
+            match self.kind {
+                FatType::Fat12 => {
+                    self.read_bytes(pos, &mut buf[..2])?;
+                    let old = get_u16(&buf, 0);
+                    let v = value as u16 & 0xFFF;
+                    let new = if cluster & 1 == 1 { old & 0x000F | v << 4 } else { old & 0xF000 | v };
+                    self.write_bytes(pos, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(pos, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved and kept
+                    self.read_bytes(pos, &mut buf)?;
+                    let new = get_u32(&buf, 0) & 0xF000_0000 | value & 0x0FFF_FFFF;
+                    self.write_bytes(pos, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        match self.fat_entry(cluster)? {
+            v if v >= self.kind.end_of_chain() => Ok(None),
+            v if (2..=self.max_cluster).contains(&v) => Ok(Some(v)),
+            _ => Err(FsError::Corrupt(cluster)),
+        }
+    }
+
+    /// The clusters of the chain starting at `first`; none for 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut next = Some(first).filter(|&c| c != 0);
+        while let Some(c) = next {
+            if !(2..=self.max_cluster).contains(&c) || chain.len() as u32 >= self.max_cluster {
+                return Err(FsError::Corrupt(c));
+            }
+            chain.push(c);
+            next = self.next_cluster(c)?;
+        }
+        Ok(chain)
+    }
+
+    /// A free cluster, zeroed and marked as the end of a chain
+    fn alloc_cluster(&mut self) -> Result<u32, FsError> {
+        let count = self.max_cluster - 1;
+        let start = self.next_free.clamp(2, self.max_cluster);
+        for i in 0..count {
+            let c = 2 + (start - 2 + i) % count;
+            if self.fat_entry(c)? != 0 {
+                continue;
+            }
+            self.set_fat(c, self.kind.eoc())?;
+            let zeros = vec![0u8; self.cluster_size as usize];
+            self.write_bytes(self.cluster_pos(c), &zeros)?;
+            self.next_free = if c == self.max_cluster { 2 } else { c + 1 };
+            self.free_count = self.free_count.map(|n| n.saturating_sub(1));
+            self.fsinfo_dirty = true;
+            return Ok(c);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees the clusters of `chain`
+    fn free_clusters(&mut self, chain: &[u32]) -> Result<(), FsError> {
+        for &c in chain {
+            self.set_fat(c, 0)?;
+        }
+        if let Some(&first) = chain.first() {
+            self.free_count = self.free_count.map(|n| n + chain.len() as u32);
+            self.next_free = self.next_free.min(first);
+            self.fsinfo_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Adds clusters to `chain` until it has `len`; on failure the chain
+    /// keeps those already added
+    fn grow(&mut self, chain: &mut Vec<u32>, len: usize) -> Result<(), FsError> {
+        while chain.len() < len {
+            let c = self.alloc_cluster()?;
+            if let Some(&last) = chain.last() {
+                self.set_fat(last, c)?;
+            }
+            chain.push(c);
+        }
+        Ok(())
+    }
+
+    /// Reads or writes bytes of the data in `chain` from `offset`
+    fn chain_io(&mut self, chain: &[u32], offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let c = chain[(pos / self.cluster_size) as usize];
+            let skip = pos % self.cluster_size;
+            let n = ((self.cluster_size - skip) as usize).min(data.len() - done);
+            self.write_bytes(self.cluster_pos(c) + skip, &data[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn root_map(&self) -> Result<DirMap, FsError> {
+        match self.kind {
+            FatType::Fat32 => Ok(DirMap::Chain(self.chain(self.root_cluster)?)),
+            _ => Ok(DirMap::Fixed { start: self.root_start, slots: self.root_slots }),
+        }
+    }
+
+    fn slots(&self, map: &DirMap) -> u32 {
+        match map {
+            DirMap::Fixed { slots, .. } => *slots,
+            DirMap::Chain(chain) => chain.len() as u32 * (self.cluster_size / ENTRY_SIZE as u64) as u32,
+        }
+    }
+
+    fn slot_pos(&self, map: &DirMap, slot: u32) -> u64 {
+        match map {
+            DirMap::Fixed { start, .. } => start + slot as u64 * ENTRY_SIZE as u64,
+            DirMap::Chain(chain) => {
+                let per = (self.cluster_size / ENTRY_SIZE as u64) as u32;
+                self.cluster_pos(chain[(slot / per) as usize]) + (slot % per) as u64 * ENTRY_SIZE as u64
+            }
+        }
+    }
+
+    /// Calls `f(slot, raw)` for every slot of the directory, up to the end
+    /// marker unless `all`, until it returns true
+    fn scan(&self, map: &DirMap, all: bool, f: &mut dyn FnMut(u32, &[u8]) -> bool) -> Result<(), FsError> {
+        let mut sector = vec![0u8; self.sector_size as usize];
+        let slots = self.slots(map);
+        let mut slot = 0;
+        while slot < slots {
+            self.read_bytes(self.slot_pos(map, slot), &mut sector)?;
+            for raw in sector.chunks(ENTRY_SIZE) {
+                if raw[0] == SLOT_END && !all {
+                    return Ok(());
+                }
+                if f(slot, raw) {
+                    return Ok(());
+                }
+                slot += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` for every file in the directory, `.` and `..` left out,
+    /// putting long names back together, until it returns true
+    fn entries(&self, map: &DirMap, f: &mut dyn FnMut(&DirEntry) -> bool) -> Result<(), FsError> {
+        let mut units = [0u16; MAX_LFN_ENTRIES * LFN_CHARS];
+        // Entries of the long name seen so far: count, next ordinal, checksum, first slot
+        let mut long: Option<(u8, u8, u8, u32)> = None;
+        self.scan(map, false, &mut |slot, raw| {
+            if raw[0] == SLOT_DELETED {
+                long = None;
+                return false;
+            }
+            if raw[11] & ATTR_LFN_MASK == ATTR_LFN {
+                let ord = raw[0] & 0x1F;
+                if raw[0] & LFN_LAST != 0 && ord != 0 && ord as usize <= MAX_LFN_ENTRIES {
+                    long = Some((ord, ord, raw[13], slot));
+                    units = [0; MAX_LFN_ENTRIES * LFN_CHARS];
+                }
+                match long {
+                    Some((count, next, sum, first)) if ord != 0 && next == ord && sum == raw[13] => {
+                        for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+                            units[(ord as usize - 1) * LFN_CHARS + i] = get_u16(raw, off);
+                        }
+                        long = Some((count, next - 1, sum, first));
+                    }
+                    _ => long = None,
+                }
+                return false;
+            }
+            let pending = long.take();
+            if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' {
+                return false;
+            }
+            let mut short = [0u8; 11];
+            short.copy_from_slice(&raw[..11]);
+            let (name, first) = match pending {
+                Some((count, 0, sum, first)) if sum == lfn_checksum(&short) => {
+                    let units = &units[..count as usize * LFN_CHARS];
+                    let len = units.iter().position(|&u| u == 0).unwrap_or(units.len());
+                    let name: String = char::decode_utf16(units[..len].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, first)
+                }
+                _ => (short_display(raw), slot),
+            };
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry.copy_from_slice(raw);
+            f(&DirEntry { name, short, raw: entry, first, slot, pos: self.slot_pos(map, slot) })
+        })
+    }
+
+    /// The entry named `name`, matching either name without regard to case
+    fn find(&self, map: &DirMap, name: &str) -> Result<DirEntry, FsError> {
+        let mut found = None;
+        self.entries(map, &mut |e| {
+            if e.name.eq_ignore_ascii_case(name) || short_display(&e.raw).eq_ignore_ascii_case(name) {
+                found = Some(DirEntry { name: e.name.clone(), short: e.short, raw: e.raw, first: e.first, slot: e.slot, pos: e.pos });
+            }
+            found.is_some()
+        })?;
+        found.ok_or(FsError::NotFound)
+    }
+
+    fn node(&self, ino: u32) -> Result<Node, FsError> {
+        self.nodes.borrow().table.get(ino as usize).copied().flatten().ok_or(FsError::NotFound)
+    }
+
+    /// Inode number of the file whose short entry is at `entry`, handing
+    /// one out if it has none yet
+    fn ino_for(&self, entry: u64, parent: u32) -> u32 {
+        let mut nodes = self.nodes.borrow_mut();
+        if let Some(&ino) = nodes.by_entry.get(&entry) {
+            return ino;
+        }
+        let node = Some(Node { entry, parent });
+        let ino = match nodes.table.iter().skip(FAT_ROOT as usize + 1).position(|n| n.is_none()) {
+            Some(i) => {
+                let ino = i + FAT_ROOT as usize + 1;
+                nodes.table[ino] = node;
+                ino
+            }
+            None => {
+                let len = nodes.table.len().max(FAT_ROOT as usize + 1);
+                nodes.table.resize(len, None);
+                nodes.table.push(node);
+                len
+            }
+        } as u32;
+        nodes.by_entry.insert(entry, ino);
+        ino
+    }
+
+    /// Drops the inode number of the file whose short entry is at `entry`
+    fn forget(&self, entry: u64) {
+        let mut nodes = self.nodes.borrow_mut();
+        if let Some(ino) = nodes.by_entry.remove(&entry) {
+            nodes.table[ino as usize] = None;
+        }
+    }
+
+    /// Records that the short entry of `ino` moved to `entry` in `parent`
+    fn moved(&self, ino: u32, entry: u64, parent: u32) {
+        let mut nodes = self.nodes.borrow_mut();
+        if let Some(Some(node)) = nodes.table.get(ino as usize) {
+            let old = node.entry;
+            nodes.by_entry.remove(&old);
+        }
+        if let Some(slot) = nodes.table.get_mut(ino as usize) {
+            *slot = Some(Node { entry, parent });
+            nodes.by_entry.insert(entry, ino);
+        }
+    }
+
+    /// The short entry of `ino`, which must not be the root
+    fn entry(&self, ino: u32) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let node = self.node(ino)?;
+        let mut raw = [0u8; ENTRY_SIZE];
+        self.read_bytes(node.entry, &mut raw)?;
+        if raw[0] == SLOT_END || raw[0] == SLOT_DELETED {
+            return Err(FsError::NotFound);
+        }
+        Ok(raw)
+    }
+
+    fn cluster_of(&self, raw: &[u8]) -> u32 {
+        let high = if self.kind == FatType::Fat32 { (get_u16(raw, 20) as u32) << 16 } else { 0 };
+        high | get_u16(raw, 26) as u32
+    }
+
+    /// First cluster of directory `ino` as `..` entries name it: 0 for the root
+    fn dir_cluster(&self, ino: u32) -> Result<u32, FsError> {
+        if ino == FAT_ROOT {
+            return Ok(0);
+        }
+        let raw = self.entry(ino)?;
+        if raw[11] & ATTR_DIRECTORY == 0 {
+            return Err(FsError::NotDir);
+        }
+        Ok(self.cluster_of(&raw))
+    }
+
+    fn dir_map(&self, ino: u32) -> Result<DirMap, FsError> {
+        match self.dir_cluster(ino)? {
+            0 => self.root_map(),
+            c => Ok(DirMap::Chain(self.chain(c)?)),
+        }
+    }
+
+    /// Adds entries for `name` to directory `dir`, the short one made from
+    /// `raw` with a short name of its own, growing the directory if needed.
+    /// The entry at `ignore`, about to be removed, does not count as taking
+    /// the name. Returns the position of the short entry.
+    fn insert(&mut self, dir: u32, name: &str, mut raw: [u8; ENTRY_SIZE], ignore: Option<u64>) -> Result<u64, FsError> {
+        let units = check_name(name)?;
+        let mut map = self.dir_map(dir)?;
+        let mut taken = BTreeSet::new();
+        let mut exists = false;
+        self.entries(&map, &mut |e| {
+            if Some(e.pos) == ignore {
+                return false;
+            }
+            exists |= e.name.eq_ignore_ascii_case(name) || short_display(&e.raw).eq_ignore_ascii_case(name);
+            taken.insert(e.short);
+            exists
+        })?;
+        if exists {
+            return Err(FsError::Exists);
+        }
+
+        let (short, flags, long) = match fits_short(name) {
+            Some((short, flags)) if !taken.contains(&short) => (short, flags, false),
+            _ => {
+                let (basis, lossy) = short_basis(name);
+                let short = if !lossy && !taken.contains(&basis) {
+                    basis
+                } else {
+                    (1..1_000_000).map(|n| with_tail(&basis, n)).find(|s| !taken.contains(s)).ok_or(FsError::Exists)?
+                };
+                (short, 0, true)
+            }
+        };
+        raw[..11].copy_from_slice(&short);
+        raw[12] = flags;
+        let count = if long { units.len().div_ceil(LFN_CHARS) } else { 0 };
+
+        // A run of free slots for the long entries and the short one
+        let need = count as u32 + 1;
+        let start = loop {
+            let mut run = (0, 0);
+            self.scan(&map, true, &mut |slot, raw| {
+                if raw[0] == SLOT_END || raw[0] == SLOT_DELETED {
+                    if run.1 == 0 {
+                        run.0 = slot;
+                    }
+                    run.1 += 1;
+                } else {
+                    run.1 = 0;
+                }
+                run.1 == need
+            })?;
+            if run.1 == need {
+                break run.0;
+            }
+            if self.slots(&map) >= MAX_DIR_SLOTS {
+                return Err(FsError::NoSpace);
+            }
+            let DirMap::Chain(chain) = &mut map else {
+                return Err(FsError::NoSpace);
+            };
+            let len = chain.len() + 1;
+            self.grow(chain, len)?;
+        };
+
+        let sum = lfn_checksum(&short);
+        for i in 0..count {
+            let ord = (count - i) as u8;
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = if i == 0 { ord | LFN_LAST } else { ord };
+            entry[11] = ATTR_LFN;
+            entry[13] = sum;
+            for (j, &off) in LFN_OFFSETS.iter().enumerate() {
+                let at = (ord as usize - 1) * LFN_CHARS + j;
+                let unit = match at.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[at],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                put_u16(&mut entry, off, unit);
+            }
+            self.write_bytes(self.slot_pos(&map, start + i as u32), &entry)?;
+        }
+        let pos = self.slot_pos(&map, start + count as u32);
+        self.write_bytes(pos, &raw)?;
+        Ok(pos)
+    }
+
+    /// Marks the slots of `entry` deleted
+    fn remove(&mut self, map: &DirMap, entry: &DirEntry) -> Result<(), FsError> {
+        for slot in entry.first..=entry.slot {
+            self.write_bytes(self.slot_pos(map, slot), &[SLOT_DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Whether directory `dir` is `ino` or below it
+    fn is_below(&self, mut dir: u32, ino: u32) -> Result<bool, FsError> {
+        loop {
+            if dir == ino {
+                return Ok(true);
+            }
+            if dir == FAT_ROOT {
+                return Ok(false);
+            }
+            dir = self.node(dir)?.parent;
+        }
+    }
+
+    fn is_empty(&self, map: &DirMap) -> Result<bool, FsError> {
+        let mut empty = true;
+        self.entries(map, &mut |_| {
+            empty = false;
+            true
+        })?;
+        Ok(empty)
+    }
+
+    /// Writes `data` at `offset` of file `ino`, first growing it to `offset`
+    /// with zeros
+    fn write_file(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut raw = self.entry(ino)?;
+        if raw[11] & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDir);
+        }
+        let size = get_u32(&raw, 28) as u64;
+        let end = offset + data.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let first = self.cluster_of(&raw);
+        let mut chain = self.chain(first)?;
+        let len = chain.len();
+        if let Err(e) = self.grow(&mut chain, end.max(size).div_ceil(self.cluster_size) as usize) {
+            // Give back what was added, so the chain still fits the size
+            if len > 0 {
+                self.set_fat(chain[len - 1], self.kind.eoc())?;
+            }
+            self.free_clusters(&chain[len..])?;
+            return Err(e);
+        }
+        if first == 0
+            && let Some(&c) = chain.first()
+        {
+            set_cluster(&mut raw, c);
+        }
+        // New clusters are zeroed; the rest of the old last one may not be
+        if offset > size {
+            let slack = size.next_multiple_of(self.cluster_size).min(offset);
+            self.chain_io(&chain, size, &vec![0u8; (slack - size) as usize])?;
+        }
+        self.chain_io(&chain, offset, data)?;
+        put_u32(&mut raw, 28, end.max(size) as u32);
+        raw[11] |= ATTR_ARCHIVE;
+        touch_entry(&mut raw, false);
+        self.write_bytes(self.node(ino)?.entry, &raw)
+    }
+}
+
+impl<D: BlockDevice> InodeOps for Fat<D> {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        if ino == FAT_ROOT {
+            let size = self.slots(&self.root_map()?) as u64 * ENTRY_SIZE as u64;
+            return Ok(Stat { ino, mode: S_IFDIR | 0o755, links: 2, uid: 0, gid: 0, size, blocks: size / 512, mtime: self.mtime });
+        }
+        let raw = self.entry(ino)?;
+        let (mode, size) = if raw[11] & ATTR_DIRECTORY != 0 {
+            let clusters = self.chain(self.cluster_of(&raw))?.len() as u64;
+            (S_IFDIR | 0o755, clusters * self.cluster_size)
+        } else if raw[11] & ATTR_READ_ONLY != 0 {
+            (S_IFREG | 0o444, get_u32(&raw, 28) as u64)
+        } else {
+            (S_IFREG | 0o644, get_u32(&raw, 28) as u64)
+        };
+        let links = if mode & S_IFDIR != 0 { 2 } else { 1 };
+        let mtime = unix_time(get_u16(&raw, 24), get_u16(&raw, 22));
+        let blocks = size.div_ceil(self.cluster_size) * self.cluster_size / 512;
+        Ok(Stat { ino, mode, links, uid: 0, gid: 0, size, blocks, mtime })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let map = self.dir_map(dir)?;
+        match name {
+            "." => Ok(dir),
+            ".." if dir == FAT_ROOT => Ok(FAT_ROOT),
+            ".." => Ok(self.node(dir)?.parent),
+            _ => Ok(self.ino_for(self.find(&map, name)?.pos, dir)),
+        }
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        let map = self.dir_map(dir)?;
+        let parent = if dir == FAT_ROOT { FAT_ROOT } else { self.node(dir)?.parent };
+        // Collected first, as `f` may stat the entries
+        let mut found = Vec::new();
+        self.entries(&map, &mut |e| {
+            found.push((e.name.clone(), e.pos));
+            false
+        })?;
+        f(".", dir);
+        f("..", parent);
+        for (name, pos) in found {
+            f(&name, self.ino_for(pos, dir));
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, _cred: &Cred, dir: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+        let attr = if perm & 0o222 == 0 { ATTR_ARCHIVE | ATTR_READ_ONLY } else { ATTR_ARCHIVE };
+        let pos = self.insert(dir, name, new_entry(&[b' '; 11], attr, 0), None)?;
+        Ok(self.ino_for(pos, dir))
+    }
+
+    fn mkdir(&mut self, _cred: &Cred, dir: u32, name: &str, _perm: u16) -> Result<u32, FsError> {
+        check_name(name)?;
+        let parent_cluster = self.dir_cluster(dir)?;
+        let c = self.alloc_cluster()?;
+        let mut dots = [0u8; 2 * ENTRY_SIZE];
+        dots[..ENTRY_SIZE].copy_from_slice(&new_entry(b".          ", ATTR_DIRECTORY, c));
+        dots[ENTRY_SIZE..].copy_from_slice(&new_entry(b"..         ", ATTR_DIRECTORY, parent_cluster));
+        let res = self
+            .write_bytes(self.cluster_pos(c), &dots)
+            .and_then(|_| self.insert(dir, name, new_entry(&[b' '; 11], ATTR_DIRECTORY, c), None));
+        match res {
+            Ok(pos) => Ok(self.ino_for(pos, dir)),
+            Err(e) => {
+                self.free_clusters(&[c])?;
+                Err(e)
+            }
+        }
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        let map = self.dir_map(dir)?;
+        let entry = self.find(&map, name)?;
+        if entry.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let chain = self.chain(self.cluster_of(&entry.raw))?;
+        self.remove(&map, &entry)?;
+        self.forget(entry.pos);
+        self.free_clusters(&chain)
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        let map = self.dir_map(dir)?;
+        let entry = self.find(&map, name)?;
+        if !entry.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        let chain = self.chain(self.cluster_of(&entry.raw))?;
+        if !self.is_empty(&DirMap::Chain(chain.clone()))? {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove(&map, &entry)?;
+        self.forget(entry.pos);
+        self.free_clusters(&chain)
+    }
+
+    fn rename(&mut self, from_dir: u32, from: &str, to_dir: u32, to: &str) -> Result<(), FsError> {
+        if [from, to].iter().any(|&n| n == "." || n == "..") {
+            return Err(FsError::InvalidPath);
+        }
+        let src_map = self.dir_map(from_dir)?;
+        let src = self.find(&src_map, from)?;
+        let ino = self.ino_for(src.pos, from_dir);
+        if src.is_dir() && self.is_below(to_dir, ino)? {
+            return Err(FsError::InvalidPath);
+        }
+        let dst_map = self.dir_map(to_dir)?;
+        match self.find(&dst_map, to) {
+            // The same entry, perhaps in another case: only the name changes
+            Ok(dst) if dst.pos == src.pos => {
+                if dst.name == to {
+                    return Ok(());
+                }
+            }
+            Ok(dst) => {
+                let dst_chain = self.chain(self.cluster_of(&dst.raw))?;
+                match (src.is_dir(), dst.is_dir()) {
+                    (false, true) => return Err(FsError::IsDir),
+                    (true, false) => return Err(FsError::NotDir),
+                    (true, true) if !self.is_empty(&DirMap::Chain(dst_chain.clone()))? => {
+                        return Err(FsError::NotEmpty);
+                    }
+                    _ => {}
+                }
+                self.remove(&dst_map, &dst)?;
+                self.forget(dst.pos);
+                self.free_clusters(&dst_chain)?;
+            }
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        // The old entries stay until the new ones are written
+        let mut raw = src.raw;
+        let pos = self.insert(to_dir, to, raw, Some(src.pos))?;
+        let src_map = self.dir_map(from_dir)?;
+        self.remove(&src_map, &src)?;
+        self.moved(ino, pos, to_dir);
+        if src.is_dir() && from_dir != to_dir {
+            // Point its `..` at the new parent
+            let dir = self.cluster_of(&src.raw);
+            raw = [0u8; ENTRY_SIZE];
+            self.read_bytes(self.cluster_pos(dir) + ENTRY_SIZE as u64, &mut raw)?;
+            set_cluster(&mut raw, self.dir_cluster(to_dir)?);
+            self.write_bytes(self.cluster_pos(dir) + ENTRY_SIZE as u64, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// Only the write bits mean anything: none set makes the file read-only
+    fn chmod(&mut self, ino: u32, mode: u16) -> Result<(), FsError> {
+        if ino == FAT_ROOT {
+            return Err(FsError::Unsupported);
+        }
+        let mut raw = self.entry(ino)?;
+        if mode & 0o222 == 0 {
+            raw[11] |= ATTR_READ_ONLY;
+        } else {
+            raw[11] &= !ATTR_READ_ONLY;
+        }
+        self.write_bytes(self.node(ino)?.entry, &raw)
+    }
+}
+
+impl<D: BlockDevice> FileOps for Fat<D> {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if ino == FAT_ROOT {
+            return Err(FsError::IsDir);
+        }
+        let raw = self.entry(ino)?;
+        if raw[11] & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDir);
+        }
+        let size = get_u32(&raw, 28) as u64;
+        let len = (buf.len() as u64).min(size.saturating_sub(offset)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        // Walk to the cluster holding `offset`, then along the chain
+        let mut cluster = self.cluster_of(&raw);
+        for _ in 0..offset / self.cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(FsError::Corrupt(cluster))?;
+        }
+        let mut done = 0;
+        loop {
+            if !(2..=self.max_cluster).contains(&cluster) {
+                return Err(FsError::Corrupt(cluster));
+            }
+            let pos = offset + done as u64;
+            let skip = pos % self.cluster_size;
+            let n = ((self.cluster_size - skip) as usize).min(len - done);
+            self.read_bytes(self.cluster_pos(cluster) + skip, &mut buf[done..done + n])?;
+            done += n;
+            if done == len {
+                return Ok(len);
+            }
+            cluster = self.next_cluster(cluster)?.ok_or(FsError::Corrupt(cluster))?;
+        }
+    }
+
+    fn write(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if ino == FAT_ROOT {
+            return Err(FsError::IsDir);
+        }
+        self.write_file(ino, offset, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: u32, size: u64) -> Result<(), FsError> {
+        if ino == FAT_ROOT {
+            return Err(FsError::IsDir);
+        }
+        let mut raw = self.entry(ino)?;
+        if raw[11] & ATTR_DIRECTORY != 0 {
+            return Err(FsError::IsDir);
+        }
+        if size >= get_u32(&raw, 28) as u64 {
+            return self.write_file(ino, size, &[]);
+        }
+        let first = self.cluster_of(&raw);
+        let chain = self.chain(first)?;
+        let keep = size.div_ceil(self.cluster_size) as usize;
+        if keep > chain.len() {
+            // The chain ends before the size says it does
+            return Err(FsError::Corrupt(chain.last().copied().unwrap_or(first)));
+        }
+        if keep == 0 {
+            set_cluster(&mut raw, 0);
+        } else {
+            self.set_fat(chain[keep - 1], self.kind.eoc())?;
+        }
+        self.free_clusters(&chain[keep..])?;
+        put_u32(&mut raw, 28, size as u32);
+        raw[11] |= ATTR_ARCHIVE;
+        touch_entry(&mut raw, false);
+        self.write_bytes(self.node(ino)?.entry, &raw)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Fat<D> {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> u32 {
+        FAT_ROOT
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.write_fsinfo()?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<(), FsError> {
+        self.device.tick()?;
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.device.stats())
+    }
+}
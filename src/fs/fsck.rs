@@ -0,0 +1,614 @@
+//! Filesystem consistency checker.
+//!
+//! Runs in six passes, each of which can repair what it finds:
+//!
+//! 0. checksums: damaged inodes are cleared, damaged bitmap and directory
+//!    blocks resealed for the later passes to check their contents, and
+//!    damaged extended attribute blocks dropped, as is a damaged quota
+//!    block with the rest of the chain. Without repair the check stops
+//!    here, as the later passes cannot trust them.
+//! 1. inodes: unknown file types, block pointers outside the data area and
+//!    impossible sizes
+//! 2. blocks: duplicate claims and disagreements between the inode block
+//!    pointers and the on‑disk bitmap
+//! 3. directories: entries naming free or out‑of‑range inodes, and bad "."
+//! 4. links: inodes no directory names (orphans) are moved to `/lost+found`,
+//!    then every link count is recomputed from the directory entries
+//! 5. quotas: the usage recorded for each user with limits is recounted
+//!    from the inodes
+//!
+//! A committed journal transaction is replayed before the passes run.
+//!
+//! No heap is needed: the block pass works one bitmap block (4096 blocks) at
+//! a time and the link pass one chunk of inodes at a time, rescanning the
+//! inode table or the directories for each.
+
+use core::fmt;
+use core::option::Option::{None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use crate::poprint;
+
+use super::error::FsError;
+use super::journal;
+use super::ops::{self, release_inode, MAX_SYMLINK_LEN};
+use super::perm::Cred;
+use super::quota::{self, read_quota_block, RECORDS_PER_BLOCK};
+use super::structure::{
+    get_u32, put_u32, seal_block, BlockDevice, DirEntry, Inode, Superblock, BLOCK_SIZE,
+    CHECKSUM_OFFSET, ROOT_INO, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+};
+use super::utils::{
+    dir_add, dir_lookup, dir_set, file_block, probe, read_bitmap, read_dir_block, read_inode,
+    read_superblock, scan_dir, write_bitmap, write_entry, write_inode, BITS_PER_BLOCK, MAX_FILE_BLOCKS,
+    PTRS_PER_BLOCK,
+};
+use super::xattr::read_xattr_block;
+
+/// Name of the directory orphaned inodes are reconnected to
+pub const LOST_AND_FOUND: &str = "/lost+found";
+
+/// Inodes counted per pass of the link check
+const COUNT_CHUNK: u32 = 256;
+
+/// Outcome of a check
+#[derive(Clone, Copy, Default)]
+pub struct FsckReport {
+    pub problems: u32, // inconsistencies found
+    pub repaired: u32, // of those, how many were fixed
+}
+
+impl FsckReport {
+    fn problem(&mut self, repair: bool, args: fmt::Arguments) {
+        self.problems += 1;
+        if repair {
+            self.repaired += 1;
+        }
+        poprint!("fsck: {}{}\n", args, if repair { " (fixed)" } else { "" });
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.problems == 0
+    }
+}
+
+/// Checks the filesystem on `device`, repairing it when `repair` is set
+pub fn fsck(device: &mut dyn BlockDevice, repair: bool) -> Result<FsckReport, FsError> {
+    let sb = probe(device)?;
+    if journal::recover(device)? {
+        poprint!("fsck: replayed journal\n");
+    }
+    let mut report = FsckReport::default();
+
+    check_checksums(device, &sb, repair, &mut report)?;
+    if !report.is_clean() && !repair {
+        poprint!("fsck: {} checksum errors, repair to check further\n", report.problems);
+        return Ok(report);
+    }
+
+    let root = read_inode(device, ROOT_INO)?;
+    if root.is_used == 0 || !root.is_dir() {
+        report.problem(false, format_args!("root inode is not a directory, giving up"));
+        return Ok(report);
+    }
+
+    check_inodes(device, &sb, repair, &mut report)?;
+    check_blocks(device, &sb, repair, &mut report)?;
+    check_dirs(device, &sb, repair, &mut report)?;
+    check_links(device, &sb, repair, &mut report)?;
+    check_quotas(device, repair, &mut report)?;
+
+    if report.is_clean() {
+        poprint!("fsck: clean, {} blocks, {} inodes\n", sb.total_blocks, sb.total_inodes);
+    } else {
+        poprint!("fsck: {} problems, {} fixed\n", report.problems, report.repaired);
+    }
+    Ok(report)
+}
+
+/// Calls `f` with every block `inode` owns: the extended attribute block,
+/// direct blocks, the indirect block and the blocks it lists. Pointers for
+/// which `f` returns false are cleared in `inode` (and in the indirect block
+/// on disk); returns true if any were.
+fn visit_blocks(
+    device: &mut dyn BlockDevice,
+    inode: &mut Inode,
+    f: &mut dyn FnMut(u32) -> bool,
+) -> Result<bool, FsError> {
+    let mut changed = false;
+    if inode.xattr_block != 0 && !f(inode.xattr_block) {
+        inode.xattr_block = 0;
+        changed = true;
+    }
+    if inode.is_fast_symlink() {
+        return Ok(changed);
+    }
+    for ptr in inode.direct_ptrs.iter_mut() {
+        if *ptr != 0 && !f(*ptr) {
+            *ptr = 0;
+            changed = true;
+        }
+    }
+    if inode.indirect_ptr != 0 {
+        if !f(inode.indirect_ptr) {
+            inode.indirect_ptr = 0;
+            return Ok(true);
+        }
+        let mut ind = [0u8; BLOCK_SIZE];
+        device.read_block(inode.indirect_ptr, &mut ind)?;
+        let mut dirty = false;
+        for i in 0..PTRS_PER_BLOCK {
+            let block = get_u32(&ind, i * 4);
+            if block != 0 && !f(block) {
+                put_u32(&mut ind, i * 4, 0);
+                dirty = true;
+            }
+        }
+        if dirty {
+            device.write_block(inode.indirect_ptr, &ind)?;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// True for a checksum mismatch; other errors are passed on
+fn is_corrupt(result: Result<(), FsError>) -> Result<bool, FsError> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(FsError::Corrupt(_)) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pass 0: checksums of bitmap blocks, inodes, directory blocks and extended
+/// attribute blocks
+fn check_checksums(
+    device: &mut dyn BlockDevice,
+    sb: &Superblock,
+    repair: bool,
+    report: &mut FsckReport,
+) -> Result<(), FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    for i in 0..sb.inode_table_start - sb.free_block_bitmap_start {
+        if is_corrupt(read_bitmap(device, sb, i, &mut buf))? {
+            report.problem(repair, format_args!("bitmap block {}: bad checksum", sb.free_block_bitmap_start + i));
+            if repair {
+                // Its contents are compared with the inodes in pass 2
+                write_bitmap(device, sb, i, &mut buf)?;
+            }
+        }
+    }
+
+    for ino in 1..=sb.total_inodes {
+        let mut inode = match read_inode(device, ino) {
+            Ok(inode) => inode,
+            Err(FsError::Corrupt(block)) => {
+                report.problem(repair, format_args!("inode {} in block {}: bad checksum, clearing", ino, block));
+                if repair {
+                    write_inode(device, ino, &Inode::EMPTY)?;
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if inode.is_used == 0 {
+            continue;
+        }
+        let xblock = inode.xattr_block;
+        let in_range = xblock >= sb.data_blocks_start && xblock < sb.total_blocks;
+        if in_range && is_corrupt(read_xattr_block(device, xblock, &mut buf))? {
+            report.problem(repair, format_args!("inode {}: attribute block {} is damaged, dropping it", ino, xblock));
+            if repair {
+                // The block becomes unclaimed and is freed by the block pass
+                inode.xattr_block = 0;
+                write_inode(device, ino, &inode)?;
+            }
+        }
+        if !inode.is_dir() {
+            continue;
+        }
+        // Directories only use direct blocks; larger sizes are fixed in pass 1
+        let blocks = (inode.size as usize).div_ceil(BLOCK_SIZE).min(12);
+        for index in 0..blocks {
+            let block = file_block(device, &inode, index)?;
+            // Pointers outside the data area are pass 1's business
+            if block < sb.data_blocks_start || block >= sb.total_blocks {
+                continue;
+            }
+            if is_corrupt(read_dir_block(device, block, &mut buf))? {
+                report.problem(repair, format_args!("dir {}: block {} has a bad checksum", ino, block));
+                if repair {
+                    // Entries naming bad inodes are cleared in pass 3
+                    seal_block(&mut buf);
+                    device.write_block(block, &buf)?;
+                }
+            }
+        }
+    }
+
+    // The dropped quota blocks are freed by the block pass
+    let (mut prev, mut block) = (None, sb.quota_block);
+    for _ in 0..sb.total_blocks {
+        if block == 0 {
+            return Ok(());
+        }
+        if is_corrupt(read_quota_block(device, sb, block, &mut buf))? {
+            report.problem(repair, format_args!("quota block {} is damaged, dropping the quotas from there", block));
+            if repair {
+                quota::truncate_chain(device, prev)?;
+            }
+            return Ok(());
+        }
+        prev = Some(block);
+        block = get_u32(&buf, 4);
+    }
+    report.problem(repair, format_args!("quota chain loops at block {}, cutting it", block));
+    if repair {
+        quota::truncate_chain(device, prev)?;
+    }
+    Ok(())
+}
+
+/// Pass 1: file types, block pointer ranges and sizes
+fn check_inodes(
+    device: &mut dyn BlockDevice,
+    sb: &Superblock,
+    repair: bool,
+    report: &mut FsckReport,
+) -> Result<(), FsError> {
+    for ino in 1..=sb.total_inodes {
+        let mut inode = read_inode(device, ino)?;
+        if inode.is_used == 0 {
+            continue;
+        }
+        let kind = inode.mode & S_IFMT;
+        if kind != S_IFDIR && kind != S_IFREG && kind != S_IFLNK {
+            report.problem(repair, format_args!("inode {}: unknown file type {:#o}, clearing", ino, inode.mode));
+            if repair {
+                // Its blocks become unclaimed and are freed by the block pass
+                write_inode(device, ino, &Inode::EMPTY)?;
+            }
+            continue;
+        }
+
+        let mut changed = visit_blocks(device, &mut inode, &mut |block| {
+            let ok = block >= sb.data_blocks_start && block < sb.total_blocks;
+            if !ok {
+                report.problem(repair, format_args!("inode {}: block pointer {} out of range", ino, block));
+            }
+            ok || !repair
+        })?;
+
+        let size = inode.size as usize;
+        if inode.is_symlink() && (size == 0 || size > MAX_SYMLINK_LEN) {
+            report.problem(repair, format_args!("inode {}: symlink of bad length {}, clearing", ino, size));
+            if repair {
+                release_inode(device, ino)?;
+            }
+            continue;
+        } else if inode.is_dir() && (size == 0 || !size.is_multiple_of(BLOCK_SIZE) || size > 12 * BLOCK_SIZE) {
+            let blocks = (0..12).rev().find(|&i| inode.direct_ptrs[i] != 0).map_or(0, |i| i + 1);
+            let fixed = (blocks.max(1) * BLOCK_SIZE) as u32;
+            report.problem(repair, format_args!("inode {}: directory size {} should be {}", ino, size, fixed));
+            if repair {
+                inode.size = fixed;
+                changed = true;
+            }
+        } else if size > MAX_FILE_BLOCKS * BLOCK_SIZE {
+            let fixed = (MAX_FILE_BLOCKS * BLOCK_SIZE) as u32;
+            report.problem(repair, format_args!("inode {}: size {} exceeds maximum {}", ino, size, fixed));
+            if repair {
+                inode.size = fixed;
+                changed = true;
+            }
+        }
+
+        if changed && repair {
+            write_inode(device, ino, &inode)?;
+        }
+    }
+    Ok(())
+}
+
+/// Pass 2: rebuilds the bitmap from the inodes, one bitmap block at a time,
+/// and compares it with the one on disk. Of two inodes claiming the same
+/// block the first keeps it.
+fn check_blocks(
+    device: &mut dyn BlockDevice,
+    sb: &Superblock,
+    repair: bool,
+    report: &mut FsckReport,
+) -> Result<(), FsError> {
+    for chunk in 0..sb.inode_table_start - sb.free_block_bitmap_start {
+        let first = chunk * BITS_PER_BLOCK;
+        let mut seen = [0u8; BLOCK_SIZE];
+        for bit in 0..BITS_PER_BLOCK {
+            let block = first + bit;
+            if block < sb.data_blocks_start || block >= sb.total_blocks {
+                seen[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        // Quota blocks go first, so an inode claiming one loses it
+        quota::walk(device, &read_superblock(device)?, &mut |block, _| {
+            if block >= first && block < first + BITS_PER_BLOCK {
+                let bit = block - first;
+                seen[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+            false
+        })?;
+
+        for ino in 1..=sb.total_inodes {
+            let mut inode = read_inode(device, ino)?;
+            if inode.is_used == 0 {
+                continue;
+            }
+            let changed = visit_blocks(device, &mut inode, &mut |block| {
+                // Out-of-range pointers were already reported by pass 1
+                if block < first.max(sb.data_blocks_start) || block >= (first + BITS_PER_BLOCK).min(sb.total_blocks) {
+                    return true;
+                }
+                let bit = block - first;
+                let (byte, mask) = ((bit / 8) as usize, 1 << (bit % 8));
+                if seen[byte] & mask != 0 {
+                    report.problem(repair, format_args!("inode {}: block {} is claimed twice", ino, block));
+                    return !repair;
+                }
+                seen[byte] |= mask;
+                true
+            })?;
+            if changed {
+                write_inode(device, ino, &inode)?;
+            }
+        }
+
+        let mut bitmap = [0u8; BLOCK_SIZE];
+        read_bitmap(device, sb, chunk, &mut bitmap)?;
+        let (mut leaked, mut unmarked) = (0, 0);
+        for (disk, want) in bitmap[..CHECKSUM_OFFSET].iter().zip(seen.iter()) {
+            leaked += (disk & !want).count_ones();
+            unmarked += (want & !disk).count_ones();
+        }
+        if leaked != 0 {
+            report.problem(repair, format_args!("bitmap: {} free blocks marked in use", leaked));
+        }
+        if unmarked != 0 {
+            report.problem(repair, format_args!("bitmap: {} used blocks marked free", unmarked));
+        }
+        if repair && (leaked != 0 || unmarked != 0) {
+            write_bitmap(device, sb, chunk, &mut seen)?;
+        }
+    }
+    Ok(())
+}
+
+/// Pass 3: directory entries must name used inodes and "." the directory itself
+fn check_dirs(
+    device: &mut dyn BlockDevice,
+    sb: &Superblock,
+    repair: bool,
+    report: &mut FsckReport,
+) -> Result<(), FsError> {
+    for dir in 1..=sb.total_inodes {
+        let inode = read_inode(device, dir)?;
+        if inode.is_used == 0 || !inode.is_dir() {
+            continue;
+        }
+        let mut bad: [Option<(u32, usize, DirEntry)>; 8] = [None; 8];
+        loop {
+            // Collect a few bad slots, fix them, and rescan until none are left
+            let mut n = 0;
+            scan_dir(device, &inode, &mut |block, slot, e| {
+                if e.inode_index == 0 {
+                    return false;
+                }
+                let target_ok = e.inode_index <= sb.total_inodes
+                    && read_inode(device, e.inode_index).is_ok_and(|t| t.is_used != 0);
+                let dot_ok = e.name_bytes() != b"." || e.inode_index == dir;
+                if !target_ok || !dot_ok {
+                    bad[n] = Some((block, slot, *e));
+                    n += 1;
+                }
+                n == bad.len()
+            })?;
+            for &(block, slot, e) in bad[..n].iter().flatten() {
+                let name = str::from_utf8(e.name_bytes()).unwrap_or("<invalid>");
+                if e.name_bytes() == b"." && e.inode_index != dir {
+                    report.problem(repair, format_args!("dir {}: '.' points at {}", dir, e.inode_index));
+                    if repair {
+                        write_entry(device, block, slot, &DirEntry::new(dir, "."))?;
+                    }
+                } else {
+                    report.problem(
+                        repair,
+                        format_args!("dir {}: entry '{}' names free inode {}", dir, name, e.inode_index),
+                    );
+                    if repair {
+                        write_entry(device, block, slot, &DirEntry::new(0, ""))?;
+                    }
+                }
+            }
+            if n < bad.len() || !repair {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counts, for inodes `start..start + COUNT_CHUNK`, the entries naming them
+/// (`named`, excluding "." and "..") and the ".." entries pointing at them (`up`)
+fn count_refs(
+    device: &dyn BlockDevice,
+    sb: &Superblock,
+    start: u32,
+    named: &mut [u16],
+    up: &mut [u16],
+) -> Result<(), FsError> {
+    named.fill(0);
+    up.fill(0);
+    for dir in 1..=sb.total_inodes {
+        let inode = read_inode(device, dir)?;
+        if inode.is_used == 0 || !inode.is_dir() {
+            continue;
+        }
+        scan_dir(device, &inode, &mut |_, _, e| {
+            let t = e.inode_index;
+            if t >= start && t < start + COUNT_CHUNK {
+                let i = (t - start) as usize;
+                match e.name_bytes() {
+                    b"." => {}
+                    b".." => up[i] = up[i].saturating_add(1),
+                    _ => named[i] = named[i].saturating_add(1),
+                }
+            }
+            false
+        })?;
+    }
+    Ok(())
+}
+
+/// Pass 4: reconnects orphans, then recomputes link counts
+fn check_links(
+    device: &mut dyn BlockDevice,
+    sb: &Superblock,
+    repair: bool,
+    report: &mut FsckReport,
+) -> Result<(), FsError> {
+    let mut named = [0u16; COUNT_CHUNK as usize];
+    let mut up = [0u16; COUNT_CHUNK as usize];
+
+    let mut start = 1;
+    while start <= sb.total_inodes {
+        count_refs(device, sb, start, &mut named, &mut up)?;
+        let end = (start + COUNT_CHUNK).min(sb.total_inodes + 1);
+        for ino in start..end {
+            let inode = read_inode(device, ino)?;
+            if ino == ROOT_INO || inode.is_used == 0 || named[(ino - start) as usize] != 0 {
+                continue;
+            }
+            // Created by an earlier reconnect, after this chunk was counted
+            if ops::lookup(device, &Cred::ROOT, LOST_AND_FOUND) == Ok(ino) {
+                continue;
+            }
+            if !inode.is_dir() && inode.size == 0 {
+                report.problem(repair, format_args!("inode {}: unreferenced empty file, clearing", ino));
+                if repair {
+                    release_inode(device, ino)?;
+                }
+            } else {
+                report.problem(repair, format_args!("inode {}: unreferenced, moving to {}", ino, LOST_AND_FOUND));
+                if repair {
+                    reconnect(device, ino, &inode);
+                }
+            }
+        }
+        start += COUNT_CHUNK;
+    }
+
+    let mut start = 1;
+    while start <= sb.total_inodes {
+        count_refs(device, sb, start, &mut named, &mut up)?;
+        let end = (start + COUNT_CHUNK).min(sb.total_inodes + 1);
+        for ino in start..end {
+            let mut inode = read_inode(device, ino)?;
+            if inode.is_used == 0 {
+                continue;
+            }
+            let i = (ino - start) as usize;
+            let expected = if inode.is_dir() {
+                named[i].saturating_add(1).saturating_add(up[i])
+            } else {
+                named[i]
+            };
+            if inode.links != expected {
+                report.problem(repair, format_args!("inode {}: link count {} should be {}", ino, inode.links, expected));
+                if repair {
+                    inode.links = expected;
+                    write_inode(device, ino, &inode)?;
+                }
+            }
+        }
+        start += COUNT_CHUNK;
+    }
+    Ok(())
+}
+
+/// Pass 5: recounts the blocks and inodes of the users in each quota block
+fn check_quotas(device: &mut dyn BlockDevice, repair: bool, report: &mut FsckReport) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    let mut block = sb.quota_block;
+    let mut buf = [0u8; BLOCK_SIZE];
+    // The chain was checked in pass 0, so this ends
+    while block != 0 {
+        read_quota_block(device, &sb, block, &mut buf)?;
+        let records = quota::records(&buf);
+        let uids = records.map(|(uid, _)| uid);
+        let mut usage = [(0, 0); RECORDS_PER_BLOCK];
+        quota::count_usage(device, &uids, &mut usage)?;
+        let mut wrong = false;
+        for (&(uid, q), &(blocks, inodes)) in records.iter().zip(usage.iter()) {
+            if uid != 0 && (q.blocks, q.inodes) != (blocks, inodes) {
+                report.problem(
+                    repair,
+                    format_args!(
+                        "quota of uid {}: {} blocks and {} inodes recorded, {} and {} in use",
+                        uid, q.blocks, q.inodes, blocks, inodes
+                    ),
+                );
+                wrong = true;
+            }
+        }
+        if wrong && repair {
+            quota::set_usage(device, block, &usage)?;
+        }
+        block = get_u32(&buf, 4);
+    }
+    Ok(())
+}
+
+/// Links an orphaned inode into `/lost+found` as `#<ino>`. Link counts are
+/// left to the recount that follows.
+fn reconnect(device: &mut dyn BlockDevice, ino: u32, inode: &Inode) {
+    let lf = match ops::lookup(device, &Cred::ROOT, LOST_AND_FOUND) {
+        Ok(lf) => lf,
+        Err(_) => match ops::mkdir(device, &Cred::ROOT, LOST_AND_FOUND, 0o700) {
+            Ok(lf) => lf,
+            Err(e) => {
+                poprint!("fsck: cannot create {}: {}\n", LOST_AND_FOUND, e);
+                return;
+            }
+        },
+    };
+
+    let mut name = [0u8; 11];
+    let len = format_name(ino, &mut name);
+    let name = str::from_utf8(&name[..len]).unwrap_or("#");
+    if let Err(e) = dir_add(device, lf, name, ino) {
+        poprint!("fsck: cannot link inode {} into {}: {}\n", ino, LOST_AND_FOUND, e);
+        return;
+    }
+    if inode.is_dir() && dir_lookup(device, ino, "..").is_ok() {
+        let _ = dir_set(device, ino, "..", lf);
+    }
+}
+
+/// Writes `#<ino>` into `buf`, returning its length
+fn format_name(ino: u32, buf: &mut [u8; 11]) -> usize {
+    let mut digits = [0u8; 10];
+    let (mut n, mut len) = (ino, 0);
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    buf[0] = b'#';
+    for i in 0..len {
+        buf[1 + i] = digits[len - 1 - i];
+    }
+    len + 1
+}
+
@@ -0,0 +1,278 @@
+//! Write‑ahead metadata journal.
+//!
+//! A filesystem operation runs inside `transaction`, against a `Transaction`
+//! that buffers every block it writes instead of touching the disk. When the
+//! operation succeeds the buffered blocks are committed:
+//!
+//! 1. the descriptor (sequence number and home block numbers) and a copy of
+//!    each block are written to the journal
+//! 2. the commit record, carrying a hash of the descriptor and the copies,
+//!    is written and flushed; from this point on the operation is durable
+//! 3. the blocks are written to their home locations (checkpoint) and flushed
+//! 4. the header's sequence number is advanced, retiring the transaction
+//!
+//! The flushes keep a write‑back cache below the journal from reordering the
+//! checkpoint before the commit, or the new header before the checkpoint.
+//! Within a flush the copies and the commit record may still land in any
+//! order, which the hash catches.
+//!
+//! The hash is FNV‑1a rather than CRC‑32C: a copy of an inode table block
+//! that differs only in whole checksummed inodes has the same CRC‑32C as the
+//! new one, so a stale copy would pass a CRC check.
+//!
+//! If the operation fails nothing is written at all. After a crash `recover`
+//! replays a transaction whose commit record is present and whose checksum
+//! matches, and ignores anything else, so every operation is applied either
+//! completely or not at all. Replaying twice is harmless.
+//!
+//! Filesystems too small for a journal run operations directly on the device.
+//!
+//! A device error while committing fails the operation. Whatever reached the
+//! journal before the error is not replayed unless its commit record made it
+//! too, in which case replaying it completes the operation.
+
+use core::option::Option::{None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use super::error::{FsError, IoError};
+use super::structure::{check_block, get_u32, put_u32, BlockDevice, Superblock, BLOCK_SIZE};
+use super::utils::{append_file, probe, read_superblock, write_at};
+
+const HEADER_MAGIC: u32 = 0x4c4a5050; // "PPJL"
+const DESC_MAGIC: u32 = 0x53444a50; // "PJDS"
+const COMMIT_MAGIC: u32 = 0x4d434a50; // "PJCM"
+
+/// Most distinct blocks a single transaction may write
+pub const MAX_TX_BLOCKS: usize = 24;
+
+/// Journal size: header, descriptor, block copies and commit record
+pub const JOURNAL_BLOCKS: u32 = MAX_TX_BLOCKS as u32 + 3;
+
+/// Smallest filesystem `mkfs` reserves a journal on
+pub const MIN_JOURNALED_BLOCKS: u32 = 256;
+
+/// Bytes `append` and `write` write per transaction, leaving room for the bitmap, inode
+/// and indirect blocks the same transaction touches
+pub const APPEND_CHUNK: usize = 16 * BLOCK_SIZE;
+
+const FNV_OFFSET: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// Continues a 32‑bit FNV‑1a hash over `data`
+fn fnv1a(mut hash: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        hash = (hash ^ b as u32).wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// A block device view that collects writes until the transaction commits
+pub struct Transaction<'a> {
+    device: &'a mut dyn BlockDevice,
+    targets: [u32; MAX_TX_BLOCKS],
+    blocks: [[u8; BLOCK_SIZE]; MAX_TX_BLOCKS],
+    count: usize,
+    overflow: bool,
+}
+
+impl<'a> Transaction<'a> {
+    fn new(device: &'a mut dyn BlockDevice) -> Transaction<'a> {
+        Transaction {
+            device,
+            targets: [0; MAX_TX_BLOCKS],
+            blocks: [[0; BLOCK_SIZE]; MAX_TX_BLOCKS],
+            count: 0,
+            overflow: false,
+        }
+    }
+
+    fn slot(&self, block: u32) -> Option<usize> {
+        self.targets[..self.count].iter().position(|&t| t == block)
+    }
+
+    fn commit(&mut self, sb: &Superblock) -> Result<(), IoError> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        let start = sb.journal_start;
+        let mut buf = [0u8; BLOCK_SIZE];
+        self.device.read_block(start, &mut buf)?;
+        let seq = get_u32(&buf, 4);
+
+        buf.fill(0);
+        put_u32(&mut buf, 0, DESC_MAGIC);
+        put_u32(&mut buf, 4, seq);
+        put_u32(&mut buf, 8, self.count as u32);
+        for (i, &target) in self.targets[..self.count].iter().enumerate() {
+            put_u32(&mut buf, 12 + i * 4, target);
+        }
+        self.device.write_block(start + 1, &buf)?;
+        let copies = self.blocks[..self.count].as_flattened();
+        self.device.write_blocks(start + 2, copies)?;
+        let hash = fnv1a(fnv1a(FNV_OFFSET, &buf), copies);
+
+        buf.fill(0);
+        put_u32(&mut buf, 0, COMMIT_MAGIC);
+        put_u32(&mut buf, 4, seq);
+        put_u32(&mut buf, 8, self.count as u32);
+        put_u32(&mut buf, 12, hash);
+        self.device.write_block(start + 2 + self.count as u32, &buf)?;
+        self.device.flush()?;
+
+        for i in 0..self.count {
+            self.device.write_block(self.targets[i], &self.blocks[i])?;
+        }
+        self.device.flush()?;
+        write_header(self.device, sb, seq.wrapping_add(1))
+    }
+}
+
+impl BlockDevice for Transaction<'_> {
+    fn block_count(&self) -> u32 {
+        self.device.block_count()
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        match self.slot(block_number) {
+            Some(i) => {
+                check_block(self.block_count(), block_number, buf.len())?;
+                buf.copy_from_slice(&self.blocks[i]);
+                Ok(())
+            }
+            None => self.device.read_block(block_number, buf),
+        }
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_block(self.block_count(), block_number, buf.len())?;
+        let i = match self.slot(block_number) {
+            Some(i) => i,
+            None if self.count < MAX_TX_BLOCKS => {
+                self.targets[self.count] = block_number;
+                self.count += 1;
+                self.count - 1
+            }
+            None => {
+                self.overflow = true;
+                return Ok(());
+            }
+        };
+        self.blocks[i].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Runs `op` as one atomic, crash‑safe update of the filesystem on `device`.
+/// The writes of an operation that fails are discarded.
+pub fn transaction<T>(
+    device: &mut dyn BlockDevice,
+    op: impl FnOnce(&mut dyn BlockDevice) -> Result<T, FsError>,
+) -> Result<T, FsError> {
+    let sb = read_superblock(device)?;
+    if sb.journal_blocks == 0 {
+        return op(device);
+    }
+    let mut tx = Transaction::new(device);
+    let res = op(&mut tx);
+    if tx.overflow {
+        return Err(FsError::TxTooLarge);
+    }
+    if res.is_ok() {
+        tx.commit(&sb)?;
+    }
+    res
+}
+
+/// Appends `data` to file `ino` one transaction per `APPEND_CHUNK` bytes
+pub fn append(device: &mut dyn BlockDevice, ino: u32, data: &[u8]) -> Result<(), FsError> {
+    for chunk in data.chunks(APPEND_CHUNK) {
+        transaction(device, |tx| append_file(tx, ino, chunk))?;
+    }
+    Ok(())
+}
+
+/// Writes `data` into file `ino` at `offset` one transaction per
+/// `APPEND_CHUNK` bytes. A gap before `offset` becomes a hole.
+pub fn write(device: &mut dyn BlockDevice, ino: u32, offset: usize, data: &[u8]) -> Result<(), FsError> {
+    for (i, chunk) in data.chunks(APPEND_CHUNK).enumerate() {
+        transaction(device, |tx| write_at(tx, ino, offset + i * APPEND_CHUNK, chunk))?;
+    }
+    Ok(())
+}
+
+fn write_header(device: &mut dyn BlockDevice, sb: &Superblock, seq: u32) -> Result<(), IoError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    put_u32(&mut buf, 0, HEADER_MAGIC);
+    put_u32(&mut buf, 4, seq);
+    device.write_block(sb.journal_start, &buf)
+}
+
+/// Initialises an empty journal; called by `mkfs`
+pub fn format(device: &mut dyn BlockDevice, sb: &Superblock) -> Result<(), IoError> {
+    if sb.journal_blocks != 0 {
+        write_header(device, sb, 1)?;
+    }
+    Ok(())
+}
+
+/// Replays a committed but unfinished transaction, as done at mount time.
+/// Returns whether one was replayed.
+pub fn recover(device: &mut dyn BlockDevice) -> Result<bool, FsError> {
+    let sb = probe(device)?;
+    if sb.journal_blocks == 0 {
+        return Ok(false);
+    }
+    if sb.journal_blocks < 3
+        || sb.journal_start < sb.inode_table_start
+        || sb.journal_start + sb.journal_blocks > sb.data_blocks_start
+    {
+        return Err(FsError::BadJournal);
+    }
+    let start = sb.journal_start;
+    let mut header = [0u8; BLOCK_SIZE];
+    device.read_block(start, &mut header)?;
+    if get_u32(&header, 0) != HEADER_MAGIC {
+        return Err(FsError::BadJournal);
+    }
+    let seq = get_u32(&header, 4);
+
+    // A transaction counts only once its commit record is on disk
+    let mut desc = [0u8; BLOCK_SIZE];
+    device.read_block(start + 1, &mut desc)?;
+    let count = get_u32(&desc, 8);
+    if get_u32(&desc, 0) != DESC_MAGIC
+        || get_u32(&desc, 4) != seq
+        || count == 0
+        || count > sb.journal_blocks - 3
+    {
+        return Ok(false);
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(start + 2 + count, &mut buf)?;
+    if get_u32(&buf, 0) != COMMIT_MAGIC || get_u32(&buf, 4) != seq || get_u32(&buf, 8) != count {
+        return Ok(false);
+    }
+    let expected = get_u32(&buf, 12);
+    let mut hash = fnv1a(FNV_OFFSET, &desc);
+    for i in 0..count {
+        device.read_block(start + 2 + i, &mut buf)?;
+        hash = fnv1a(hash, &buf);
+    }
+    if hash != expected {
+        return Ok(false);
+    }
+
+    for i in 0..count {
+        let target = get_u32(&desc, 12 + i as usize * 4);
+        if target >= sb.total_blocks || (target >= start && target < start + sb.journal_blocks) {
+            return Err(FsError::BadJournal);
+        }
+    }
+    for i in 0..count {
+        device.read_block(start + 2 + i, &mut buf)?;
+        device.write_block(get_u32(&desc, 12 + i as usize * 4), &buf)?;
+    }
+    device.flush()?;
+    write_header(device, &sb, seq.wrapping_add(1))?;
+    Ok(true)
+}
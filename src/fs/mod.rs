@@ -1,2 +1,20 @@
+pub mod archive;
+pub mod cache;
+pub mod clock;
+pub mod crc32c;
+pub mod error;
+pub mod ext2;
+pub mod fat;
+pub mod fsck;
+pub mod journal;
+pub mod mount;
+pub mod ops;
+pub mod partition;
+pub mod perm;
+pub mod popoen;
+pub mod quota;
 pub mod structure;
-pub mod utils;
\ No newline at end of file
+pub mod tmpfs;
+pub mod utils;
+pub mod vfs;
+pub mod xattr;
\ No newline at end of file
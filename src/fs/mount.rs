@@ -0,0 +1,152 @@
+//! Mount table.
+//!
+//! Records which filesystem is mounted where. Every mount but the root is
+//! attached to a directory of another mount, named by that mount's index in
+//! the table and the directory's inode number, so the path walk of the VFS
+//! (`vfs.rs`) can step into a mounted filesystem at its mount point and back
+//! out of it at `..`. The path the mount point was given by is kept for
+//! listing. Filesystems are boxed, so one table can hold different types
+//! behind `dyn FileSystem`.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use alloc::boxed::Box;
+
+use super::error::FsError;
+
+/// Filesystems mounted at once, the root included
+pub const MAX_MOUNTS: usize = 8;
+
+/// Longest mount point path
+pub const MAX_MOUNT_PATH: usize = 64;
+
+/// Longest name of the device a filesystem is mounted from
+pub const MAX_SOURCE: usize = 16;
+
+/// A filesystem and the directory it is mounted on
+pub struct Mount<D: ?Sized> {
+    path: [u8; MAX_MOUNT_PATH],
+    len: usize,
+    source: [u8; MAX_SOURCE],
+    source_len: usize,
+    parent: Option<(usize, u32)>, // mount and directory inode covered, None for the root
+    pub fs: Box<D>,
+}
+
+impl<D: ?Sized> Mount<D> {
+    /// Mount point, as an absolute path without `.` or repeated slashes
+    pub fn path(&self) -> &str {
+        core::str::from_utf8(&self.path[..self.len]).unwrap_or("?")
+    }
+
+    /// Device the filesystem was mounted from, e.g. `vda2`
+    pub fn source(&self) -> &str {
+        core::str::from_utf8(&self.source[..self.source_len]).unwrap_or("?")
+    }
+
+    /// Index of the mount holding the mount point and the directory's inode
+    /// number there; None for the root
+    pub fn parent(&self) -> Option<(usize, u32)> {
+        self.parent
+    }
+}
+
+pub struct MountTable<D: ?Sized> {
+    mounts: [Option<Mount<D>>; MAX_MOUNTS],
+}
+
+impl<D: ?Sized> Default for MountTable<D> {
+    fn default() -> MountTable<D> {
+        MountTable::new()
+    }
+}
+
+impl<D: ?Sized> MountTable<D> {
+    pub const fn new() -> MountTable<D> {
+        MountTable { mounts: [const { None }; MAX_MOUNTS] }
+    }
+
+    /// Mounts `fs`, read from the device named `source`, on directory `at`
+    /// (mount index and inode number), or as the root if `at` is None, and
+    /// returns its index. `path` names the mount point for listing. The
+    /// caller checks that `at` is a directory.
+    pub fn mount(&mut self, path: &str, source: &str, at: Option<(usize, u32)>, fs: Box<D>) -> Result<usize, FsError> {
+        if source.len() > MAX_SOURCE {
+            return Err(FsError::NameTooLong);
+        }
+        let mut src = [0u8; MAX_SOURCE];
+        src[..source.len()].copy_from_slice(source.as_bytes());
+        let mut buf = [0u8; MAX_MOUNT_PATH];
+        let mut len = 0;
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if name == ".." {
+                return Err(FsError::InvalidPath);
+            }
+            let end = len + 1 + name.len();
+            if end > MAX_MOUNT_PATH {
+                return Err(FsError::NameTooLong);
+            }
+            buf[len] = b'/';
+            buf[len + 1..end].copy_from_slice(name.as_bytes());
+            len = end;
+        }
+        if len == 0 {
+            buf[0] = b'/';
+            len = 1;
+        }
+        match at {
+            None if self.root_index().is_some() => return Err(FsError::Busy),
+            None => {}
+            Some((mount, _)) if self.get(mount).is_none() => return Err(FsError::NotFound),
+            Some(_) if self.iter().any(|m| m.parent == at) => return Err(FsError::Busy),
+            Some(_) => {}
+        }
+        let i = self.mounts.iter().position(|m| m.is_none()).ok_or(FsError::NoSpace)?;
+        self.mounts[i] = Some(Mount { path: buf, len, source: src, source_len: source.len(), parent: at, fs });
+        Ok(i)
+    }
+
+    /// Removes mount `index` and returns its filesystem. The root, and
+    /// mounts with others mounted on their directories, stay.
+    pub fn unmount(&mut self, index: usize) -> Result<Box<D>, FsError> {
+        let m = self.get(index).ok_or(FsError::InvalidPath)?;
+        if m.parent.is_none() || self.iter().any(|m| m.parent.is_some_and(|(p, _)| p == index)) {
+            return Err(FsError::Busy);
+        }
+        self.mounts[index].take().map(|m| m.fs).ok_or(FsError::InvalidPath)
+    }
+
+    /// The mount on directory `ino` of mount `index`, if any
+    pub fn covering(&self, index: usize, ino: u32) -> Option<usize> {
+        self.mounts.iter().position(|m| m.as_ref().is_some_and(|m| m.parent == Some((index, ino))))
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Mount<D>> {
+        self.mounts.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Mount<D>> {
+        self.mounts.get_mut(index)?.as_mut()
+    }
+
+    /// Index of the root filesystem
+    pub fn root_index(&self) -> Option<usize> {
+        self.mounts.iter().position(|m| m.as_ref().is_some_and(|m| m.parent.is_none()))
+    }
+
+    /// The root filesystem
+    pub fn root(&mut self) -> Option<&mut D> {
+        let i = self.root_index()?;
+        self.get_mut(i).map(|m| &mut *m.fs)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Mount<D>> {
+        self.mounts.iter().flatten()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Mount<D>> {
+        self.mounts.iter_mut().flatten()
+    }
+}
@@ -0,0 +1,489 @@
+//! Namespace operations: path lookup, create, link, symlink, unlink and rename.
+//!
+//! Every multi‑block update is ordered so that an interrupted operation never
+//! loses a name: link counts are raised before a new entry is written and
+//! lowered only after an old entry is gone. A crash can therefore leave a link
+//! count that is too high (leaking the inode until it is repaired) but never
+//! one that is too low.
+//!
+//! Every operation runs with the caller's credentials (see `perm.rs`). Each
+//! directory walked needs search permission and each directory changed
+//! needs write and search permission.
+//!
+//! The path operations resolve their paths and check permissions, then call
+//! an `_at` variant taking a directory inode and a name. The VFS calls the
+//! `_at` variants directly, after walking the path and checking permissions
+//! itself.
+
+use core::result::Result::{self, Err, Ok};
+use core::option::Option::{self, None, Some};
+
+use super::clock;
+use super::error::FsError;
+use super::perm::{owner_or_root, permission, Cred, MAY_EXEC, MAY_WRITE};
+use super::structure::{
+    BlockDevice, DirEntry, Inode, ROOT_INO, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+};
+use super::quota;
+use super::utils::{
+    allocated_blocks, alloc_inode, dir_add, dir_is_empty, dir_lookup, dir_remove, dir_set, free_file_blocks,
+    init_dir, read_inode, read_superblock, read_symlink, write_inode, write_symlink,
+};
+use super::xattr;
+
+/// Symlinks followed while resolving one path before giving up with `Loop`
+pub const MAX_SYMLINKS: u32 = 8;
+
+/// Longest symlink target accepted by `symlink`
+pub const MAX_SYMLINK_LEN: usize = 255;
+
+/// Resolves an absolute or root‑relative path to an inode number, following
+/// symlinks including one in the last component
+pub fn lookup(device: &dyn BlockDevice, cred: &Cred, path: &str) -> Result<u32, FsError> {
+    resolve(device, cred, ROOT_INO, path, true, &mut 0)
+}
+
+/// Like `lookup`, but a symlink in the last component is returned itself
+pub fn lookup_nofollow(device: &dyn BlockDevice, cred: &Cred, path: &str) -> Result<u32, FsError> {
+    resolve(device, cred, ROOT_INO, path, false, &mut 0)
+}
+
+/// Walks `path` starting at `dir` (or the root for absolute paths). Symlink
+/// targets are resolved relative to the directory holding the link; `depth`
+/// counts links followed so far across the nested walks.
+fn resolve(
+    device: &dyn BlockDevice,
+    cred: &Cred,
+    dir: u32,
+    path: &str,
+    follow_last: bool,
+    depth: &mut u32,
+) -> Result<u32, FsError> {
+    let mut cur = if path.starts_with('/') { ROOT_INO } else { dir };
+    let mut names = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(name) = names.next() {
+        let dir = read_inode(device, cur)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        permission(&dir, cred, MAY_EXEC)?;
+        let mut ino = dir_lookup(device, cur, name)?;
+        let inode = read_inode(device, ino)?;
+        if inode.is_symlink() && (follow_last || names.peek().is_some()) {
+            *depth += 1;
+            if *depth > MAX_SYMLINKS {
+                return Err(FsError::Loop);
+            }
+            let mut buf = [0u8; MAX_SYMLINK_LEN];
+            let n = read_symlink(device, &inode, &mut buf)?;
+            let target = str::from_utf8(&buf[..n]).map_err(|_| FsError::InvalidPath)?;
+            ino = resolve(device, cred, cur, target, true, depth)?;
+        }
+        cur = ino;
+    }
+    Ok(cur)
+}
+
+/// Resolves everything but the last component, returning (parent inode, name)
+pub fn lookup_parent<'a>(device: &dyn BlockDevice, cred: &Cred, path: &'a str) -> Result<(u32, &'a str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > DirEntry::MAX_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    let parent = lookup(device, cred, dir)?;
+    if !read_inode(device, parent)?.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    Ok((parent, name))
+}
+
+/// Entries may be added to or removed from `dir` only with write and search
+/// permission on it
+fn may_modify(device: &dyn BlockDevice, cred: &Cred, dir: u32) -> Result<(), FsError> {
+    permission(&read_inode(device, dir)?, cred, MAY_WRITE | MAY_EXEC)
+}
+
+/// A fresh in‑memory inode owned by the caller
+fn new_inode(cred: &Cred, mode: u16, links: u16) -> Inode {
+    let mut inode = Inode::new(mode);
+    inode.links = links;
+    inode.uid = cred.uid;
+    inode.gid = cred.gid;
+    inode.mtime = clock::now();
+    inode
+}
+
+/// Resolves `path` and checks that `cred` may access it for `mask`
+/// (`MAY_READ`, `MAY_WRITE`, ...), returning the inode number
+pub fn open(device: &dyn BlockDevice, cred: &Cred, path: &str, mask: u16) -> Result<u32, FsError> {
+    let ino = lookup(device, cred, path)?;
+    let inode = read_inode(device, ino)?;
+    if inode.is_dir() && mask & MAY_WRITE != 0 {
+        return Err(FsError::IsDir);
+    }
+    permission(&inode, cred, mask)?;
+    Ok(ino)
+}
+
+/// Changes the permission bits of `path`; the file type is kept
+pub fn chmod(device: &mut dyn BlockDevice, cred: &Cred, path: &str, mode: u16) -> Result<(), FsError> {
+    let ino = lookup(device, cred, path)?;
+    owner_or_root(read_inode(device, ino)?.uid, cred)?;
+    chmod_at(device, ino, mode)
+}
+
+/// Sets the permission bits of inode `ino`
+pub fn chmod_at(device: &mut dyn BlockDevice, ino: u32, mode: u16) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    inode.mode = (inode.mode & S_IFMT) | (mode & 0o7777);
+    write_inode(device, ino, &inode)
+}
+
+/// Changes the owner and/or group of `path`; superuser only
+pub fn chown(
+    device: &mut dyn BlockDevice,
+    cred: &Cred,
+    path: &str,
+    uid: Option<u16>,
+    gid: Option<u16>,
+) -> Result<(), FsError> {
+    let ino = lookup_nofollow(device, cred, path)?;
+    if !cred.is_root() {
+        return Err(FsError::NotPermitted);
+    }
+    chown_at(device, ino, uid, gid)
+}
+
+/// Sets the owner and/or group of inode `ino`. The inode and its blocks
+/// move to the new owner's quota, which may end up past its limits.
+pub fn chown_at(device: &mut dyn BlockDevice, ino: u32, uid: Option<u16>, gid: Option<u16>) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    if let Some(uid) = uid
+        && uid != inode.uid
+    {
+        let blocks = allocated_blocks(device, &inode)?;
+        quota::transfer(device, inode.uid, uid, blocks, 1)?;
+        inode.uid = uid;
+    }
+    if let Some(gid) = gid {
+        inode.gid = gid;
+    }
+    write_inode(device, ino, &inode)
+}
+
+/// Creates an empty regular file
+pub fn create(device: &mut dyn BlockDevice, cred: &Cred, path: &str, perm: u16) -> Result<u32, FsError> {
+    let (parent, name) = lookup_parent(device, cred, path)?;
+    may_modify(device, cred, parent)?;
+    create_at(device, cred, parent, name, perm)
+}
+
+/// Creates an empty regular file `name` in directory `parent`. The `_at`
+/// operations leave permission checks to the caller; `cred` only sets the
+/// owner of a new inode.
+pub fn create_at(device: &mut dyn BlockDevice, cred: &Cred, parent: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+    if dir_lookup(device, parent, name).is_ok() {
+        return Err(FsError::Exists);
+    }
+    let inode = new_inode(cred, S_IFREG | (perm & 0o7777), 1);
+    let ino = alloc_inode(device, &inode)?;
+    if let Err(e) = dir_add(device, parent, name, ino) {
+        release_inode(device, ino)?;
+        return Err(e);
+    }
+    Ok(ino)
+}
+
+/// Creates an empty directory
+pub fn mkdir(device: &mut dyn BlockDevice, cred: &Cred, path: &str, perm: u16) -> Result<u32, FsError> {
+    let (parent, name) = lookup_parent(device, cred, path)?;
+    may_modify(device, cred, parent)?;
+    mkdir_at(device, cred, parent, name, perm)
+}
+
+/// Creates an empty directory `name` in directory `parent`
+pub fn mkdir_at(device: &mut dyn BlockDevice, cred: &Cred, parent: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+    if dir_lookup(device, parent, name).is_ok() {
+        return Err(FsError::Exists);
+    }
+    let mut parent_inode = read_inode(device, parent)?;
+    if parent_inode.links == u16::MAX {
+        return Err(FsError::TooManyLinks);
+    }
+
+    let inode = new_inode(cred, S_IFDIR | (perm & 0o7777), 2);
+    let ino = alloc_inode(device, &inode)?;
+    if let Err(e) = init_dir(device, ino, parent).and_then(|_| dir_add(device, parent, name, ino)) {
+        release_inode(device, ino)?;
+        return Err(e);
+    }
+
+    // The child's ".." is a link to the parent
+    parent_inode = read_inode(device, parent)?;
+    parent_inode.links += 1;
+    write_inode(device, parent, &parent_inode)?;
+    Ok(ino)
+}
+
+/// Creates a symbolic link at `path` pointing at `target`
+pub fn symlink(device: &mut dyn BlockDevice, cred: &Cred, target: &str, path: &str) -> Result<u32, FsError> {
+    let (parent, name) = lookup_parent(device, cred, path)?;
+    may_modify(device, cred, parent)?;
+    symlink_at(device, cred, parent, name, target)
+}
+
+/// Creates a symbolic link `name` in directory `parent` pointing at `target`
+pub fn symlink_at(
+    device: &mut dyn BlockDevice,
+    cred: &Cred,
+    parent: u32,
+    name: &str,
+    target: &str,
+) -> Result<u32, FsError> {
+    if target.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    if target.len() > MAX_SYMLINK_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    if dir_lookup(device, parent, name).is_ok() {
+        return Err(FsError::Exists);
+    }
+    let mut inode = new_inode(cred, S_IFLNK | 0o777, 1);
+    let ino = alloc_inode(device, &inode)?;
+    if let Err(e) = write_symlink(device, &mut inode, target.as_bytes()) {
+        release_inode(device, ino)?;
+        return Err(e);
+    }
+    write_inode(device, ino, &inode)?;
+    if let Err(e) = dir_add(device, parent, name, ino) {
+        release_inode(device, ino)?;
+        return Err(e);
+    }
+    Ok(ino)
+}
+
+/// Copies the target of the symlink at `path` into `buf`, returning its length
+pub fn readlink(device: &dyn BlockDevice, cred: &Cred, path: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+    let inode = read_inode(device, lookup_nofollow(device, cred, path)?)?;
+    if !inode.is_symlink() {
+        return Err(FsError::InvalidPath);
+    }
+    read_symlink(device, &inode, buf)
+}
+
+/// Adds a second name for an existing non‑directory. A symlink is linked
+/// itself rather than its target.
+pub fn link(device: &mut dyn BlockDevice, cred: &Cred, existing: &str, new: &str) -> Result<(), FsError> {
+    let ino = lookup_nofollow(device, cred, existing)?;
+    let (parent, name) = lookup_parent(device, cred, new)?;
+    may_modify(device, cred, parent)?;
+    link_at(device, ino, parent, name)
+}
+
+/// Adds the name `name` in directory `parent` for inode `ino`
+pub fn link_at(device: &mut dyn BlockDevice, ino: u32, parent: u32, name: &str) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    if inode.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    if dir_lookup(device, parent, name).is_ok() {
+        return Err(FsError::Exists);
+    }
+
+    bump_links(device, ino, &mut inode)?;
+    if let Err(e) = dir_add(device, parent, name, ino) {
+        drop_link(device, ino)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Removes a name; the inode and its blocks are freed with the last link
+pub fn unlink(device: &mut dyn BlockDevice, cred: &Cred, path: &str) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(device, cred, path)?;
+    may_modify(device, cred, parent)?;
+    unlink_at(device, parent, name)
+}
+
+/// Removes the non‑directory entry `name` from directory `parent`
+pub fn unlink_at(device: &mut dyn BlockDevice, parent: u32, name: &str) -> Result<(), FsError> {
+    let ino = dir_lookup(device, parent, name)?;
+    if read_inode(device, ino)?.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    dir_remove(device, parent, name)?;
+    drop_link(device, ino)
+}
+
+/// Removes an empty directory
+pub fn rmdir(device: &mut dyn BlockDevice, cred: &Cred, path: &str) -> Result<(), FsError> {
+    let (parent, name) = lookup_parent(device, cred, path)?;
+    may_modify(device, cred, parent)?;
+    rmdir_at(device, parent, name)
+}
+
+/// Removes the empty directory `name` from directory `parent`
+pub fn rmdir_at(device: &mut dyn BlockDevice, parent: u32, name: &str) -> Result<(), FsError> {
+    if name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    let ino = dir_lookup(device, parent, name)?;
+    let inode = read_inode(device, ino)?;
+    if !inode.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    if !dir_is_empty(device, &inode)? {
+        return Err(FsError::NotEmpty);
+    }
+    dir_remove(device, parent, name)?;
+    release_inode(device, ino)?;
+    drop_link(device, parent)
+}
+
+/// Moves `from` to `to`. If `to` names an existing directory the source is
+/// moved into it under its current name; an existing non‑directory target
+/// (or empty directory, when moving a directory) is replaced.
+pub fn rename(device: &mut dyn BlockDevice, cred: &Cred, from: &str, to: &str) -> Result<(), FsError> {
+    let (src_parent, src_name) = lookup_parent(device, cred, from)?;
+    let ino = dir_lookup(device, src_parent, src_name)?;
+    let inode = read_inode(device, ino)?;
+
+    let (dst_parent, dst_name) = match lookup(device, cred, to) {
+        Ok(t) if t != ino && read_inode(device, t)?.is_dir() => (t, src_name),
+        _ => lookup_parent(device, cred, to)?,
+    };
+
+    may_modify(device, cred, src_parent)?;
+    may_modify(device, cred, dst_parent)?;
+    if inode.is_dir() && src_parent != dst_parent {
+        // Its ".." entry is rewritten
+        permission(&inode, cred, MAY_WRITE)?;
+    }
+    rename_at(device, src_parent, src_name, dst_parent, dst_name)
+}
+
+/// Moves entry `src_name` of directory `src_parent` to `dst_name` in
+/// `dst_parent`, replacing a non‑directory or empty directory there.
+///
+/// The source inode gains a link before the new entry is written and loses
+/// it after the old entry is cleared, so at every point in between the file is
+/// reachable from at least one of the two directories.
+pub fn rename_at(
+    device: &mut dyn BlockDevice,
+    src_parent: u32,
+    src_name: &str,
+    dst_parent: u32,
+    dst_name: &str,
+) -> Result<(), FsError> {
+    if [src_name, dst_name].iter().any(|&n| n == "." || n == "..") {
+        return Err(FsError::InvalidPath);
+    }
+    let ino = dir_lookup(device, src_parent, src_name)?;
+    let mut inode = read_inode(device, ino)?;
+
+    if inode.is_dir() && is_ancestor(device, ino, dst_parent)? {
+        return Err(FsError::InvalidPath);
+    }
+
+    let target = match dir_lookup(device, dst_parent, dst_name) {
+        Ok(t) if t == ino => return Ok(()),
+        Ok(t) => {
+            let tinode = read_inode(device, t)?;
+            match (inode.is_dir(), tinode.is_dir()) {
+                (false, true) => return Err(FsError::IsDir),
+                (true, false) => return Err(FsError::NotDir),
+                (true, true) if !dir_is_empty(device, &tinode)? => return Err(FsError::NotEmpty),
+                _ => Some(t),
+            }
+        }
+        Err(FsError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    // 1. the new name will exist before the old one is gone
+    bump_links(device, ino, &mut inode)?;
+
+    // 2. publish the new name, atomically replacing any old target entry
+    let published = match target {
+        Some(_) => dir_set(device, dst_parent, dst_name, ino).map(|_| ()),
+        None => dir_add(device, dst_parent, dst_name, ino),
+    };
+    if let Err(e) = published {
+        drop_link(device, ino)?;
+        return Err(e);
+    }
+
+    // 3. retire the old name and the extra link
+    dir_remove(device, src_parent, src_name)?;
+    drop_link(device, ino)?;
+
+    // 4. a moved directory's ".." follows it to the new parent
+    if inode.is_dir() && src_parent != dst_parent {
+        dir_set(device, ino, "..", dst_parent)?;
+        let mut parent = read_inode(device, dst_parent)?;
+        bump_links(device, dst_parent, &mut parent)?;
+        drop_link(device, src_parent)?;
+    }
+
+    // 5. release whatever the new name used to point at
+    if let Some(t) = target {
+        if read_inode(device, t)?.is_dir() {
+            release_inode(device, t)?;
+            drop_link(device, dst_parent)?;
+        } else {
+            drop_link(device, t)?;
+        }
+    }
+    Ok(())
+}
+
+/// True if directory `anc` is `dir` or one of its ancestors
+fn is_ancestor(device: &dyn BlockDevice, anc: u32, mut dir: u32) -> Result<bool, FsError> {
+    // Bounded by the inode count so a corrupted ".." chain cannot loop forever
+    for _ in 0..read_superblock(device)?.total_inodes {
+        if dir == anc {
+            return Ok(true);
+        }
+        if dir == ROOT_INO {
+            return Ok(false);
+        }
+        dir = dir_lookup(device, dir, "..")?;
+    }
+    Err(FsError::InvalidPath)
+}
+
+fn bump_links(device: &mut dyn BlockDevice, ino: u32, inode: &mut Inode) -> Result<(), FsError> {
+    if inode.links == u16::MAX {
+        return Err(FsError::TooManyLinks);
+    }
+    inode.links += 1;
+    write_inode(device, ino, inode)
+}
+
+/// Drops one link, freeing a non‑directory inode once nothing names it
+fn drop_link(device: &mut dyn BlockDevice, ino: u32) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    inode.links = inode.links.saturating_sub(1);
+    if inode.links == 0 && !inode.is_dir() {
+        release_inode(device, ino)
+    } else {
+        write_inode(device, ino, &inode)
+    }
+}
+
+/// Frees an inode, all of its data blocks and its extended attributes
+pub fn release_inode(device: &mut dyn BlockDevice, ino: u32) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    free_file_blocks(device, &mut inode)?;
+    xattr::release(device, &mut inode)?;
+    quota::release(device, inode.uid, 0, 1)?;
+    write_inode(device, ino, &Inode::EMPTY)
+}
@@ -0,0 +1,382 @@
+//! MBR and GPT partition tables.
+//!
+//! `read_table` parses the table at the start of any block device. An MBR
+//! whose only entry has type 0xEE is the protective MBR of a GPT disk; the
+//! GPT header and entry array are then checked against their CRC‑32s, and
+//! the backup header in the last block is used if the primary one is
+//! damaged. Extended MBR partitions are followed through their chain of
+//! EBRs, and the logical partitions in them numbered from 5 as Linux does.
+//!
+//! A `Partition` wraps a device and exposes one partition as a block device
+//! of its own, translating block numbers by the partition's start and
+//! refusing any outside it.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Ok};
+
+use super::error::{FsError, IoError};
+use super::structure::{check_range, BlockDevice, BLOCK_SIZE};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// Logical partitions followed before an EBR chain is taken to be a loop
+const MAX_LOGICAL: u32 = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN: usize = 92;
+const GPT_ENTRY_MIN: usize = 128;
+/// GPT entries read at most; the usual array has 128
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// Which kind of table a device holds
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+/// Partition type: an MBR type byte or a GPT type GUID as stored on disk
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt([u8; 16]),
+}
+
+// GPT type GUIDs in their on‑disk (mixed endian) byte order
+const GUID_EFI_SYSTEM: [u8; 16] = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+const GUID_BASIC_DATA: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+const GUID_LINUX_DATA: [u8; 16] = [
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+];
+const GUID_LINUX_SWAP: [u8; 16] = [
+    0x6D, 0xFD, 0x57, 0x06, 0xAB, 0xA4, 0xC4, 0x43, 0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F,
+];
+
+impl PartitionType {
+    /// Short description, as fdisk names the common types
+    pub fn name(&self) -> &'static str {
+        match self {
+            PartitionType::Mbr(0x01) => "FAT12",
+            PartitionType::Mbr(0x04 | 0x06 | 0x0E) => "FAT16",
+            PartitionType::Mbr(0x05 | 0x0F | 0x85) => "Extended",
+            PartitionType::Mbr(0x07) => "NTFS/exFAT",
+            PartitionType::Mbr(0x0B | 0x0C) => "FAT32",
+            PartitionType::Mbr(0x82) => "Linux swap",
+            PartitionType::Mbr(0x83) => "Linux",
+            PartitionType::Mbr(0xEF) => "EFI System",
+            PartitionType::Gpt(GUID_EFI_SYSTEM) => "EFI System",
+            PartitionType::Gpt(GUID_BASIC_DATA) => "Basic data",
+            PartitionType::Gpt(GUID_LINUX_DATA) => "Linux filesystem",
+            PartitionType::Gpt(GUID_LINUX_SWAP) => "Linux swap",
+            _ => "unknown",
+        }
+    }
+}
+
+/// One partition found in a table
+#[derive(Clone, Copy)]
+pub struct PartitionEntry {
+    pub number: u32, // 1‑based, as in `vda1`
+    pub start: u32,
+    pub count: u32,
+    pub kind: PartitionType,
+}
+
+fn le32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn le64(b: &[u8], at: usize) -> u64 {
+    le32(b, at) as u64 | (le32(b, at + 4) as u64) << 32
+}
+
+/// CRC‑32 (IEEE), which GPT uses; `crc` is the value of the data before,
+/// 0 to start
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Calls `f` with the entry if it lies within a device of `blocks` blocks;
+/// tables pointing past the end are ignored entry by entry
+fn emit(blocks: u32, entry: PartitionEntry, f: &mut dyn FnMut(PartitionEntry)) {
+    if entry.count != 0 && entry.start.checked_add(entry.count).is_some_and(|end| end <= blocks) {
+        f(entry);
+    }
+}
+
+/// Reads the partition table of `device`, calling `f` for each partition.
+/// Returns the kind of table, or None if the device is not partitioned.
+pub fn read_table(
+    device: &dyn BlockDevice,
+    f: &mut dyn FnMut(PartitionEntry),
+) -> Result<Option<Scheme>, FsError> {
+    // Both schemes are read in 512 B sectors
+    if device.block_size() != BLOCK_SIZE {
+        return Ok(None);
+    }
+    let mut mbr = [0u8; BLOCK_SIZE];
+    device.read_block(0, &mut mbr)?;
+    if mbr[510..] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+    let entries: [&[u8]; 4] = core::array::from_fn(|i| &mbr[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16]);
+    // A boot sector or filesystem that happens to end in 55 AA has no sane
+    // status bytes, or no entries at all
+    if entries.iter().any(|e| e[0] & 0x7F != 0) || entries.iter().all(|e| e[4] == 0) {
+        return Ok(None);
+    }
+    if entries.iter().any(|e| e[4] == TYPE_GPT_PROTECTIVE) {
+        return read_gpt(device, f).map(|_| Some(Scheme::Gpt));
+    }
+
+    let blocks = device.block_count();
+    let mut extended = None;
+    for (i, e) in entries.iter().enumerate() {
+        let entry = PartitionEntry {
+            number: i as u32 + 1,
+            start: le32(e, 8),
+            count: le32(e, 12),
+            kind: PartitionType::Mbr(e[4]),
+        };
+        if e[4] == 0 {
+            continue;
+        }
+        if TYPES_EXTENDED.contains(&e[4]) {
+            extended.get_or_insert(entry.start);
+        }
+        emit(blocks, entry, f);
+    }
+    if let Some(start) = extended {
+        read_logical(device, start, f)?;
+    }
+    Ok(Some(Scheme::Mbr))
+}
+
+/// Follows the EBR chain of the extended partition at `base`. Each EBR
+/// holds a logical partition relative to itself and a link to the next EBR
+/// relative to `base`.
+fn read_logical(device: &dyn BlockDevice, base: u32, f: &mut dyn FnMut(PartitionEntry)) -> Result<(), FsError> {
+    let blocks = device.block_count();
+    let mut ebr = base;
+    let mut buf = [0u8; BLOCK_SIZE];
+    for number in 5..5 + MAX_LOGICAL {
+        if ebr >= blocks {
+            break;
+        }
+        device.read_block(ebr, &mut buf)?;
+        if buf[510..] != MBR_SIGNATURE {
+            break;
+        }
+        let (this, next) = (&buf[MBR_ENTRIES..MBR_ENTRIES + 16], &buf[MBR_ENTRIES + 16..MBR_ENTRIES + 32]);
+        if this[4] != 0 {
+            let entry = PartitionEntry {
+                number,
+                start: ebr.saturating_add(le32(this, 8)),
+                count: le32(this, 12),
+                kind: PartitionType::Mbr(this[4]),
+            };
+            emit(blocks, entry, f);
+        }
+        if next[4] == 0 || le32(next, 8) == 0 {
+            break;
+        }
+        ebr = base.saturating_add(le32(next, 8));
+    }
+    Ok(())
+}
+
+/// GPT header fields used to find the entries
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Reads and checks the GPT header in block `lba`
+fn gpt_header(device: &dyn BlockDevice, lba: u32) -> Result<Option<GptHeader>, FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(lba, &mut buf)?;
+    let size = le32(&buf, 12) as usize;
+    if &buf[..8] != GPT_SIGNATURE || !(GPT_HEADER_MIN..=BLOCK_SIZE).contains(&size) || le64(&buf, 24) != lba as u64 {
+        return Ok(None);
+    }
+    let stored = le32(&buf, 16);
+    buf[16..20].fill(0);
+    if crc32(0, &buf[..size]) != stored {
+        return Ok(None);
+    }
+    let entry_size = le32(&buf, 84) as usize;
+    let entry_count = le32(&buf, 80);
+    if entry_size < GPT_ENTRY_MIN || !entry_size.is_power_of_two() || entry_size > BLOCK_SIZE || entry_count > GPT_MAX_ENTRIES {
+        return Ok(None);
+    }
+    Ok(Some(GptHeader {
+        entries_lba: le64(&buf, 72),
+        entry_count,
+        entry_size,
+        entries_crc: le32(&buf, 88),
+    }))
+}
+
+/// Reads the entry array `header` describes, checking it before calling
+/// `f` for each entry in use
+fn gpt_entries(
+    device: &dyn BlockDevice,
+    header: &GptHeader,
+    f: &mut dyn FnMut(PartitionEntry),
+) -> Result<bool, FsError> {
+    let per_block = BLOCK_SIZE / header.entry_size;
+    let block_count = (header.entry_count as usize).div_ceil(per_block);
+    let first = header.entries_lba;
+    if first.checked_add(block_count as u64).is_none_or(|end| end > device.block_count() as u64) {
+        return Ok(false);
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+
+    // Check the whole array before trusting any of it
+    let mut crc = 0;
+    let mut left = header.entry_count as usize * header.entry_size;
+    for i in 0..block_count {
+        device.read_block(first as u32 + i as u32, &mut buf)?;
+        crc = crc32(crc, &buf[..left.min(BLOCK_SIZE)]);
+        left = left.saturating_sub(BLOCK_SIZE);
+    }
+    if crc != header.entries_crc {
+        return Ok(false);
+    }
+
+    let blocks = device.block_count();
+    for i in 0..block_count {
+        device.read_block(first as u32 + i as u32, &mut buf)?;
+        for (j, e) in buf.chunks_exact(header.entry_size).enumerate() {
+            let number = (i * per_block + j) as u32 + 1;
+            if number > header.entry_count || e[..16].iter().all(|&b| b == 0) {
+                continue;
+            }
+            let (start, last) = (le64(e, 32), le64(e, 40));
+            if last < start || last >= blocks as u64 {
+                continue;
+            }
+            let mut kind = [0u8; 16];
+            kind.copy_from_slice(&e[..16]);
+            let entry = PartitionEntry {
+                number,
+                start: start as u32,
+                count: (last - start + 1) as u32,
+                kind: PartitionType::Gpt(kind),
+            };
+            emit(blocks, entry, f);
+        }
+    }
+    Ok(true)
+}
+
+/// Reads the GPT from the primary header, or the backup in the last block
+fn read_gpt(device: &dyn BlockDevice, f: &mut dyn FnMut(PartitionEntry)) -> Result<(), FsError> {
+    let last = device.block_count().saturating_sub(1);
+    for lba in [1, last] {
+        if let Some(header) = gpt_header(device, lba)?
+            && gpt_entries(device, &header, f)?
+        {
+            return Ok(());
+        }
+    }
+    Err(FsError::BadPartitionTable)
+}
+
+/// One partition of `device`, or all of it, as a block device
+#[derive(Clone, Copy)]
+pub struct Partition<D> {
+    device: D,
+    number: u32, // 0 for the whole device
+    start: u32,
+    count: u32,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// The partition `entry` of `device`, or None if it does not fit
+    pub fn new(device: D, entry: &PartitionEntry) -> Option<Partition<D>> {
+        let end = entry.start.checked_add(entry.count)?;
+        if end > device.block_count() {
+            return None;
+        }
+        Some(Partition { device, number: entry.number, start: entry.start, count: entry.count })
+    }
+
+    /// All of `device`, for unpartitioned disks
+    pub fn whole(device: D) -> Partition<D> {
+        let count = device.block_count();
+        Partition { device, number: 0, start: 0, count }
+    }
+
+    /// Partition number, 0 for the whole device
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        self.count
+    }
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        // A whole disk may have blocks of any size
+        if buf.len() != self.block_size() {
+            return Err(IoError::BufferSize);
+        }
+        check_range(self.count, self.block_size(), block_number, buf.len())?;
+        self.device.read_block(self.start + block_number, buf)
+    }
+
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError> {
+        // A whole disk may have blocks of any size
+        if buf.len() != self.block_size() {
+            return Err(IoError::BufferSize);
+        }
+        check_range(self.count, self.block_size(), block_number, buf.len())?;
+        self.device.write_block(self.start + block_number, buf)
+    }
+
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        check_range(self.count, self.block_size(), start, buf.len())?;
+        self.device.read_blocks(self.start + start, buf)
+    }
+
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        check_range(self.count, self.block_size(), start, buf.len())?;
+        self.device.write_blocks(self.start + start, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.device.flush()
+    }
+}
@@ -0,0 +1,66 @@
+//! Unix permission checks.
+//!
+//! Every namespace operation takes the caller's credentials. Access to an
+//! inode is decided by the owner, group or other bits of its mode, whichever
+//! class the caller falls in first. The superuser (uid 0) may read and write
+//! anything, search any directory, and execute a file if any execute bit is
+//! set.
+
+use core::result::Result::{self, Err, Ok};
+
+use super::error::FsError;
+use super::structure::{Inode, S_IFDIR, S_IFMT};
+
+/// Permission bits requested from `permission`, as in the "other" triplet
+pub const MAY_READ: u16 = 0o4;
+pub const MAY_WRITE: u16 = 0o2;
+pub const MAY_EXEC: u16 = 0o1;
+
+/// Identity an operation is performed as
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u16,
+    pub gid: u16,
+}
+
+impl Cred {
+    pub const ROOT: Cred = Cred { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+/// Checks that `cred` may access `inode` for every bit in `mask`
+pub fn permission(inode: &Inode, cred: &Cred, mask: u16) -> Result<(), FsError> {
+    access(inode.mode, inode.uid, inode.gid, cred, mask)
+}
+
+/// `permission` for a file of any filesystem, given its mode and owner
+pub fn access(mode: u16, uid: u16, gid: u16, cred: &Cred, mask: u16) -> Result<(), FsError> {
+    if cred.is_root() {
+        let exec_ok = mode & S_IFMT == S_IFDIR || mode & 0o111 != 0;
+        return if mask & MAY_EXEC == 0 || exec_ok { Ok(()) } else { Err(FsError::PermissionDenied) };
+    }
+    let granted = if cred.uid == uid {
+        mode >> 6
+    } else if cred.gid == gid {
+        mode >> 3
+    } else {
+        mode
+    } & 0o7;
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+/// Only the owner (`uid`) or the superuser may change an inode's mode
+pub fn owner_or_root(uid: u16, cred: &Cred) -> Result<(), FsError> {
+    if cred.is_root() || cred.uid == uid {
+        Ok(())
+    } else {
+        Err(FsError::NotPermitted)
+    }
+}
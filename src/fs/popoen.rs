@@ -0,0 +1,202 @@
+//! The Popoen filesystem behind the VFS.
+//!
+//! `PopoenFs` owns the block cache of one volume and carries VFS operations
+//! out with the inode-level functions of `ops`, `utils` and `xattr`. Every
+//! change runs as one journal transaction, except that writes are split
+//! into `APPEND_CHUNK` sized transactions like `journal::append`.
+
+use core::option::Option::{self, Some};
+use core::result::Result::{self, Err, Ok};
+
+use super::cache::{BlockCache, CacheStats};
+use super::error::FsError;
+use super::fsck::fsck;
+use super::journal::{self, recover, transaction};
+use super::ops;
+use super::perm::Cred;
+use super::quota::{self, Grace, Limits, Quota};
+use super::structure::{BlockDevice, ROOT_INO};
+use super::utils::{
+    allocated_blocks, dir_lookup, for_each_entry, probe, punch_hole, read_at, read_inode, read_symlink, truncate_file,
+};
+use super::vfs::{FileOps, FileSystem, InodeOps, Stat};
+use super::xattr::{self, Xattr};
+
+pub struct PopoenFs<D> {
+    device: BlockCache<D>,
+}
+
+impl<D: BlockDevice> PopoenFs<D> {
+    /// Wraps a cache over a device already checked with `probe`, its
+    /// journal replayed
+    pub fn new(device: BlockCache<D>) -> PopoenFs<D> {
+        PopoenFs { device }
+    }
+
+    /// Checks that `device` holds a Popoen filesystem and replays its journal
+    pub fn open(device: D) -> Result<PopoenFs<D>, FsError> {
+        probe(&device)?;
+        let mut device = BlockCache::new(device);
+        recover(&mut device)?;
+        Ok(PopoenFs::new(device))
+    }
+
+    pub fn device(&mut self) -> &mut BlockCache<D> {
+        &mut self.device
+    }
+}
+
+impl<D: BlockDevice> InodeOps for PopoenFs<D> {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        let inode = read_inode(&self.device, ino)?;
+        Ok(Stat {
+            ino,
+            mode: inode.mode,
+            links: inode.links,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: inode.size as u64,
+            blocks: allocated_blocks(&self.device, &inode)? as u64,
+            mtime: inode.mtime,
+        })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        dir_lookup(&self.device, dir, name)
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        let inode = read_inode(&self.device, dir)?;
+        if !inode.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        for_each_entry(&self.device, &inode, &mut |e| {
+            f(core::str::from_utf8(e.name_bytes()).unwrap_or("<invalid>"), e.inode_index)
+        })
+    }
+
+    fn readlink(&self, ino: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = read_inode(&self.device, ino)?;
+        if !inode.is_symlink() {
+            return Err(FsError::InvalidPath);
+        }
+        read_symlink(&self.device, &inode, buf)
+    }
+
+    fn create(&mut self, cred: &Cred, dir: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+        transaction(&mut self.device, |tx| ops::create_at(tx, cred, dir, name, perm))
+    }
+
+    fn mkdir(&mut self, cred: &Cred, dir: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+        transaction(&mut self.device, |tx| ops::mkdir_at(tx, cred, dir, name, perm))
+    }
+
+    fn symlink(&mut self, cred: &Cred, dir: u32, name: &str, target: &str) -> Result<u32, FsError> {
+        transaction(&mut self.device, |tx| ops::symlink_at(tx, cred, dir, name, target))
+    }
+
+    fn link(&mut self, ino: u32, dir: u32, name: &str) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::link_at(tx, ino, dir, name))
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::unlink_at(tx, dir, name))
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::rmdir_at(tx, dir, name))
+    }
+
+    fn rename(&mut self, from_dir: u32, from: &str, to_dir: u32, to: &str) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::rename_at(tx, from_dir, from, to_dir, to))
+    }
+
+    fn chmod(&mut self, ino: u32, mode: u16) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::chmod_at(tx, ino, mode))
+    }
+
+    fn chown(&mut self, ino: u32, uid: Option<u16>, gid: Option<u16>) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| ops::chown_at(tx, ino, uid, gid))
+    }
+
+    fn get_xattr(&self, cred: &Cred, ino: u32, name: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+        xattr::get_at(&self.device, cred, ino, name, buf)
+    }
+
+    fn set_xattr(&mut self, cred: &Cred, ino: u32, name: &str, value: &[u8]) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| xattr::set_at(tx, cred, ino, name, value))
+    }
+
+    fn remove_xattr(&mut self, cred: &Cred, ino: u32, name: &str) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| xattr::remove_at(tx, cred, ino, name))
+    }
+
+    fn list_xattr(&self, cred: &Cred, ino: u32, f: &mut dyn FnMut(&Xattr)) -> Result<(), FsError> {
+        xattr::list_at(&self.device, cred, ino, f)
+    }
+}
+
+impl<D: BlockDevice> FileOps for PopoenFs<D> {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = read_inode(&self.device, ino)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        read_at(&self.device, &inode, offset as usize, buf)
+    }
+
+    fn write(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        journal::write(&mut self.device, ino, offset as usize, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: u32, size: u64) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| truncate_file(tx, ino, size as usize))
+    }
+
+    fn punch_hole(&mut self, ino: u32, offset: u64, len: u64) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| punch_hole(tx, ino, offset as usize, len as usize))
+    }
+}
+
+impl<D: BlockDevice> FileSystem for PopoenFs<D> {
+    fn name(&self) -> &'static str {
+        "popoen"
+    }
+
+    fn root(&self) -> u32 {
+        ROOT_INO
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(self.device.sync()?)
+    }
+
+    fn tick(&mut self) -> Result<(), FsError> {
+        Ok(self.device.tick()?)
+    }
+
+    fn check(&mut self, repair: bool) -> Result<(), FsError> {
+        fsck(&mut self.device, repair).map(|_| ())
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.device.stats())
+    }
+
+    fn quota(&self, uid: u16) -> Result<Quota, FsError> {
+        quota::get(&self.device, uid)
+    }
+
+    fn set_quota(&mut self, uid: u16, limits: Limits) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| quota::set_limits(tx, uid, limits))
+    }
+
+    fn grace(&self) -> Result<Grace, FsError> {
+        quota::grace(&self.device)
+    }
+
+    fn set_grace(&mut self, grace: Grace) -> Result<(), FsError> {
+        transaction(&mut self.device, |tx| quota::set_grace(tx, grace))
+    }
+}
@@ -0,0 +1,433 @@
+//! Per-user quotas.
+//!
+//! A user can be given a soft and a hard limit on the blocks their files
+//! hold and on the number of their inodes. An allocation that would take
+//! them past a hard limit fails with `QuotaExceeded`. Past a soft limit
+//! allocations go on for a grace period, which starts when usage first
+//! goes over it, and then fail the same way until usage drops back under
+//! the soft limit. A limit of 0 means none. Files of root are never
+//! limited or counted.
+//!
+//! Limits and usage live in a chain of quota blocks the superblock points
+//! at (layout in `structure.rs`), one record per user with limits. Setting
+//! the first limits of a user counts what they already own; from then on
+//! the block and inode allocators in `utils.rs` keep the count up to date,
+//! charging the owner of the inode. Users without a record are not counted
+//! until they get one. `fsck` recounts the usage of every record.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use super::clock;
+use super::error::FsError;
+use super::structure::{
+    block_checksum_ok, get_u16, get_u32, put_u16, put_u32, seal_block, BlockDevice, Superblock,
+    BLOCK_SIZE, CHECKSUM_OFFSET,
+};
+use super::utils::{
+    alloc_data_block, allocated_blocks, read_inode, read_superblock, write_superblock,
+};
+
+const MAGIC: u32 = 0x54515050; // "PPQT"
+
+/// Bytes in front of the records of each quota block
+const HEADER: usize = 16;
+
+const RECORD_SIZE: usize = 36;
+
+/// Records per quota block
+pub const RECORDS_PER_BLOCK: usize = (CHECKSUM_OFFSET - HEADER) / RECORD_SIZE;
+
+/// Grace periods a new quota chain starts with: seven days
+pub const DEFAULT_GRACE: u32 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub block_soft: u32,
+    pub block_hard: u32,
+    pub inode_soft: u32,
+    pub inode_hard: u32,
+}
+
+impl Limits {
+    pub fn is_none(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// Limits and usage of one user
+#[derive(Clone, Copy, Default)]
+pub struct Quota {
+    pub limits: Limits,
+    pub blocks: u32,
+    pub inodes: u32,
+    pub block_expires: u32, // end of the block grace period, 0 = not running
+    pub inode_expires: u32,
+}
+
+/// How long usage may stay over a soft limit, in seconds
+#[derive(Clone, Copy)]
+pub struct Grace {
+    pub blocks: u32,
+    pub inodes: u32,
+}
+
+impl Quota {
+    fn decode(buf: &[u8]) -> (u16, Quota) {
+        let quota = Quota {
+            limits: Limits {
+                block_soft: get_u32(buf, 4),
+                block_hard: get_u32(buf, 8),
+                inode_soft: get_u32(buf, 12),
+                inode_hard: get_u32(buf, 16),
+            },
+            blocks: get_u32(buf, 20),
+            inodes: get_u32(buf, 24),
+            block_expires: get_u32(buf, 28),
+            inode_expires: get_u32(buf, 32),
+        };
+        (get_u16(buf, 0), quota)
+    }
+
+    fn encode(&self, uid: u16, buf: &mut [u8]) {
+        put_u16(buf, 0, uid);
+        put_u16(buf, 2, 0);
+        put_u32(buf, 4, self.limits.block_soft);
+        put_u32(buf, 8, self.limits.block_hard);
+        put_u32(buf, 12, self.limits.inode_soft);
+        put_u32(buf, 16, self.limits.inode_hard);
+        put_u32(buf, 20, self.blocks);
+        put_u32(buf, 24, self.inodes);
+        put_u32(buf, 28, self.block_expires);
+        put_u32(buf, 32, self.inode_expires);
+    }
+
+    /// Starts the grace periods of the soft limits usage is over, and ends
+    /// those of the ones it is back under
+    fn settle(&mut self, grace: &Grace, now: u32) {
+        let l = self.limits;
+        self.block_expires = expiry(self.blocks, l.block_soft, self.block_expires, grace.blocks, now);
+        self.inode_expires = expiry(self.inodes, l.inode_soft, self.inode_expires, grace.inodes, now);
+    }
+}
+
+/// End of the grace period for usage `used` against soft limit `soft`:
+/// 0 under the limit, else the running period's end or a new one
+fn expiry(used: u32, soft: u32, expires: u32, period: u32, now: u32) -> u32 {
+    if soft == 0 || used <= soft {
+        0
+    } else if expires != 0 {
+        expires
+    } else {
+        // Never 0, which would mean it is not running
+        now.saturating_add(period).max(1)
+    }
+}
+
+/// Whether usage `used` breaks a hard limit, or a soft limit whose grace
+/// period is over
+fn exceeds(used: u32, soft: u32, hard: u32, expires: u32, now: u32) -> bool {
+    (hard != 0 && used > hard) || (soft != 0 && used > soft && expires != 0 && now >= expires)
+}
+
+fn record_offset(slot: usize) -> usize {
+    HEADER + slot * RECORD_SIZE
+}
+
+/// Reads quota block `block`, checking its place, magic and checksum
+pub fn read_quota_block(
+    device: &dyn BlockDevice,
+    sb: &Superblock,
+    block: u32,
+    buf: &mut [u8; BLOCK_SIZE],
+) -> Result<(), FsError> {
+    if block < sb.data_blocks_start || block >= sb.total_blocks {
+        return Err(FsError::Corrupt(block));
+    }
+    device.read_block(block, buf)?;
+    if !block_checksum_ok(buf) || get_u32(buf, 0) != MAGIC {
+        return Err(FsError::Corrupt(block));
+    }
+    Ok(())
+}
+
+/// Seals and writes quota block `block`
+fn write_quota_block(device: &mut dyn BlockDevice, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    seal_block(buf);
+    device.write_block(block, buf)?;
+    Ok(())
+}
+
+/// Calls `f(block, contents)` for every block of the quota chain, stopping
+/// early when it returns true
+pub fn walk(
+    device: &dyn BlockDevice,
+    sb: &Superblock,
+    f: &mut dyn FnMut(u32, &[u8; BLOCK_SIZE]) -> bool,
+) -> Result<bool, FsError> {
+    let mut block = sb.quota_block;
+    // Bounded by the device size so a looping chain cannot hang us
+    for _ in 0..sb.total_blocks {
+        if block == 0 {
+            return Ok(false);
+        }
+        let mut buf = [0u8; BLOCK_SIZE];
+        read_quota_block(device, sb, block, &mut buf)?;
+        if f(block, &buf) {
+            return Ok(true);
+        }
+        block = get_u32(&buf, 4);
+    }
+    Err(FsError::Corrupt(block))
+}
+
+/// The record of `uid`: its block, slot and contents
+fn find(device: &dyn BlockDevice, sb: &Superblock, uid: u16) -> Result<Option<(u32, usize, Quota)>, FsError> {
+    let mut found = None;
+    walk(device, sb, &mut |block, buf| {
+        found = (0..RECORDS_PER_BLOCK).find_map(|slot| match Quota::decode(&buf[record_offset(slot)..]) {
+            (u, quota) if u == uid => Some((block, slot, quota)),
+            _ => None,
+        });
+        found.is_some()
+    })?;
+    Ok(found)
+}
+
+fn write_record(device: &mut dyn BlockDevice, block: u32, slot: usize, uid: u16, quota: &Quota) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    let mut buf = [0u8; BLOCK_SIZE];
+    read_quota_block(device, &sb, block, &mut buf)?;
+    quota.encode(uid, &mut buf[record_offset(slot)..]);
+    write_quota_block(device, block, &mut buf)
+}
+
+/// Grace periods; the defaults when there is no quota chain
+pub fn grace(device: &dyn BlockDevice) -> Result<Grace, FsError> {
+    let sb = read_superblock(device)?;
+    if sb.quota_block == 0 {
+        return Ok(Grace { blocks: DEFAULT_GRACE, inodes: DEFAULT_GRACE });
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+    read_quota_block(device, &sb, sb.quota_block, &mut buf)?;
+    Ok(Grace { blocks: get_u32(&buf, 8), inodes: get_u32(&buf, 12) })
+}
+
+/// Applies `f` to the record of `uid`, if it has one, and writes it back
+fn update(
+    device: &mut dyn BlockDevice,
+    uid: u16,
+    f: impl FnOnce(&mut Quota, &Grace, u32) -> Result<(), FsError>,
+) -> Result<(), FsError> {
+    if uid == 0 {
+        return Ok(());
+    }
+    let sb = read_superblock(device)?;
+    if sb.quota_block == 0 {
+        return Ok(());
+    }
+    let Some((block, slot, mut quota)) = find(device, &sb, uid)? else {
+        return Ok(());
+    };
+    f(&mut quota, &grace(device)?, clock::now())?;
+    write_record(device, block, slot, uid, &quota)
+}
+
+/// Adds `blocks` and `inodes` to the usage of `uid`. Fails with
+/// `QuotaExceeded`, changing nothing, if that breaks a limit.
+pub fn charge(device: &mut dyn BlockDevice, uid: u16, blocks: u32, inodes: u32) -> Result<(), FsError> {
+    update(device, uid, |q, grace, now| {
+        let (new_blocks, new_inodes) = (q.blocks.saturating_add(blocks), q.inodes.saturating_add(inodes));
+        let l = q.limits;
+        if (blocks != 0 && exceeds(new_blocks, l.block_soft, l.block_hard, q.block_expires, now))
+            || (inodes != 0 && exceeds(new_inodes, l.inode_soft, l.inode_hard, q.inode_expires, now))
+        {
+            return Err(FsError::QuotaExceeded);
+        }
+        q.blocks = new_blocks;
+        q.inodes = new_inodes;
+        q.settle(grace, now);
+        Ok(())
+    })
+}
+
+/// Takes `blocks` and `inodes` off the usage of `uid`
+pub fn release(device: &mut dyn BlockDevice, uid: u16, blocks: u32, inodes: u32) -> Result<(), FsError> {
+    update(device, uid, |q, grace, now| {
+        q.blocks = q.blocks.saturating_sub(blocks);
+        q.inodes = q.inodes.saturating_sub(inodes);
+        q.settle(grace, now);
+        Ok(())
+    })
+}
+
+/// Moves usage from `from` to `to`, for a change of owner. The new owner's
+/// limits are not checked.
+pub fn transfer(device: &mut dyn BlockDevice, from: u16, to: u16, blocks: u32, inodes: u32) -> Result<(), FsError> {
+    release(device, from, blocks, inodes)?;
+    update(device, to, |q, grace, now| {
+        q.blocks = q.blocks.saturating_add(blocks);
+        q.inodes = q.inodes.saturating_add(inodes);
+        q.settle(grace, now);
+        Ok(())
+    })
+}
+
+/// Counts the blocks and inodes owned by each of `uids` into `usage`
+pub fn count_usage(device: &dyn BlockDevice, uids: &[u16], usage: &mut [(u32, u32)]) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    usage.fill((0, 0));
+    for ino in 1..=sb.total_inodes {
+        let inode = read_inode(device, ino)?;
+        if inode.is_used == 0 {
+            continue;
+        }
+        if let Some(i) = uids.iter().position(|&u| u == inode.uid) {
+            usage[i].0 += allocated_blocks(device, &inode)?;
+            usage[i].1 += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Limits and usage of `uid`. A user without limits has their usage
+/// counted from the inodes.
+pub fn get(device: &dyn BlockDevice, uid: u16) -> Result<Quota, FsError> {
+    let sb = read_superblock(device)?;
+    if uid != 0
+        && sb.quota_block != 0
+        && let Some((_, _, quota)) = find(device, &sb, uid)?
+    {
+        return Ok(quota);
+    }
+    let mut usage = [(0, 0)];
+    count_usage(device, &[uid], &mut usage)?;
+    Ok(Quota { blocks: usage[0].0, inodes: usage[0].1, ..Quota::default() })
+}
+
+/// Appends an empty quota block to the chain, starting it if there is none,
+/// and returns its number
+fn add_block(device: &mut dyn BlockDevice) -> Result<u32, FsError> {
+    let mut sb = read_superblock(device)?;
+    let mut last = None;
+    walk(device, &sb, &mut |block, _| {
+        last = Some(block);
+        false
+    })?;
+    // Quota blocks belong to no one
+    let block = alloc_data_block(device, 0)?;
+    let mut buf = [0u8; BLOCK_SIZE];
+    put_u32(&mut buf, 0, MAGIC);
+    if last.is_none() {
+        put_u32(&mut buf, 8, DEFAULT_GRACE);
+        put_u32(&mut buf, 12, DEFAULT_GRACE);
+    }
+    write_quota_block(device, block, &mut buf)?;
+    match last {
+        Some(last) => {
+            read_quota_block(device, &sb, last, &mut buf)?;
+            put_u32(&mut buf, 4, block);
+            write_quota_block(device, last, &mut buf)?;
+        }
+        None => {
+            sb.quota_block = block;
+            write_superblock(device, &sb)?;
+        }
+    }
+    Ok(block)
+}
+
+/// Sets the limits of `uid`, counting its usage if it had none. Limits of
+/// all zeros remove its record.
+pub fn set_limits(device: &mut dyn BlockDevice, uid: u16, limits: Limits) -> Result<(), FsError> {
+    if uid == 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let sb = read_superblock(device)?;
+    let found = if sb.quota_block == 0 { None } else { find(device, &sb, uid)? };
+    let (block, slot, mut quota) = match found {
+        Some(found) => found,
+        None if limits.is_none() => return Ok(()),
+        None => {
+            let mut free = None;
+            walk(device, &sb, &mut |block, buf| {
+                free = (0..RECORDS_PER_BLOCK)
+                    .find(|&slot| get_u16(buf, record_offset(slot)) == 0)
+                    .map(|slot| (block, slot));
+                free.is_some()
+            })?;
+            let (block, slot) = match free {
+                Some(free) => free,
+                None => (add_block(device)?, 0),
+            };
+            let mut usage = [(0, 0)];
+            count_usage(device, &[uid], &mut usage)?;
+            (block, slot, Quota { blocks: usage[0].0, inodes: usage[0].1, ..Quota::default() })
+        }
+    };
+    if limits.is_none() {
+        return write_record(device, block, slot, 0, &Quota::default());
+    }
+    quota.limits = limits;
+    quota.settle(&grace(device)?, clock::now());
+    write_record(device, block, slot, uid, &quota)
+}
+
+/// Sets the grace periods. Running ones keep their end.
+pub fn set_grace(device: &mut dyn BlockDevice, grace: Grace) -> Result<(), FsError> {
+    let mut sb = read_superblock(device)?;
+    if sb.quota_block == 0 {
+        add_block(device)?;
+        sb = read_superblock(device)?;
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+    read_quota_block(device, &sb, sb.quota_block, &mut buf)?;
+    put_u32(&mut buf, 8, grace.blocks);
+    put_u32(&mut buf, 12, grace.inodes);
+    write_quota_block(device, sb.quota_block, &mut buf)
+}
+
+/// Rewrites the usage recorded in quota block `block` with `usage`, in
+/// record order; for `fsck`
+pub fn set_usage(device: &mut dyn BlockDevice, block: u32, usage: &[(u32, u32)]) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    let mut buf = [0u8; BLOCK_SIZE];
+    read_quota_block(device, &sb, block, &mut buf)?;
+    let grace = grace(device)?;
+    let now = clock::now();
+    for (slot, &(blocks, inodes)) in usage.iter().enumerate().take(RECORDS_PER_BLOCK) {
+        let (uid, mut quota) = Quota::decode(&buf[record_offset(slot)..]);
+        if uid != 0 {
+            quota.blocks = blocks;
+            quota.inodes = inodes;
+            quota.settle(&grace, now);
+            quota.encode(uid, &mut buf[record_offset(slot)..]);
+        }
+    }
+    write_quota_block(device, block, &mut buf)
+}
+
+/// The uid of every record of a quota block, 0 for free ones, and the usage
+/// recorded for it
+pub fn records(buf: &[u8; BLOCK_SIZE]) -> [(u16, Quota); RECORDS_PER_BLOCK] {
+    core::array::from_fn(|slot| Quota::decode(&buf[record_offset(slot)..]))
+}
+
+/// Cuts the chain after `prev`, or entirely if it is None; for `fsck` to
+/// drop a damaged block and those following it
+pub fn truncate_chain(device: &mut dyn BlockDevice, prev: Option<u32>) -> Result<(), FsError> {
+    let mut sb = read_superblock(device)?;
+    match prev {
+        Some(block) => {
+            let mut buf = [0u8; BLOCK_SIZE];
+            read_quota_block(device, &sb, block, &mut buf)?;
+            put_u32(&mut buf, 4, 0);
+            write_quota_block(device, block, &mut buf)
+        }
+        None => {
+            sb.quota_block = 0;
+            write_superblock(device, &sb)
+        }
+    }
+}
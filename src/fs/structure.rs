@@ -1,85 +1,462 @@
+//! On‑disk format of the Popoen filesystem.
+//!
+//! All integers are little‑endian and every structure is encoded field by
+//! field at the fixed offsets below, so an image can be read on any host
+//! independently of the compiler's struct layout. Block size is 512 bytes.
+//!
+//! Metadata is checksummed with CRC‑32C. The superblock, bitmap blocks and
+//! directory blocks end in a checksum of their first 508 bytes, and every
+//! inode ends in a checksum of its first 124 bytes. Reads verify it and fail
+//! with `FsError::Corrupt(block)` on a mismatch.
+//!
+//! Disk layout (block numbers):
+//!
+//! | block                                  | contents                       |
+//! |----------------------------------------|--------------------------------|
+//! | 0                                      | superblock                     |
+//! | `free_block_bitmap_start` ..           | block bitmap, bit n = block n  |
+//! | `inode_table_start` ..                 | inode table, 4 inodes/block    |
+//! | `journal_start` ..                     | metadata journal (may be empty)|
+//! | `data_blocks_start` .. `total_blocks`  | file, directory and link data  |
+//!
+//! Superblock (block 0, 40 bytes, zero up to the checksum at 508):
+//!
+//! | offset | size | field                     |
+//! |--------|------|---------------------------|
+//! | 0      | 4    | magic `0x50504f53` "PPOS" |
+//! | 4      | 4    | format version            |
+//! | 8      | 4    | total_blocks              |
+//! | 12     | 4    | total_inodes              |
+//! | 16     | 4    | free_block_bitmap_start   |
+//! | 20     | 4    | inode_table_start         |
+//! | 24     | 4    | data_blocks_start         |
+//! | 28     | 4    | journal_start             |
+//! | 32     | 4    | journal_blocks, 0 = none  |
+//! | 36     | 4    | quota_block, 0 = none     |
+//!
+//! Bitmap blocks hold 4064 bits each (508 bytes), block n being bit n % 4064
+//! of bitmap block n / 4064.
+//!
+//! Inode (128 bytes, inode number n lives in slot n‑1 of the table):
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 2    | mode (type bits + permissions)                |
+//! | 2      | 2    | links                                         |
+//! | 4      | 4    | size in bytes                                 |
+//! | 8      | 48   | 12 direct block pointers                      |
+//! | 56     | 4    | single indirect block pointer                 |
+//! | 60     | 1    | is_used (0 = free)                            |
+//! | 61     | 3    | reserved, zero                                |
+//! | 64     | 2    | owner uid                                     |
+//! | 66     | 2    | owner gid                                     |
+//! | 68     | 4    | mtime, seconds since the Unix epoch           |
+//! | 72     | 4    | extended attribute block, 0 = none            |
+//! | 76     | 48   | inline extended attributes                    |
+//! | 124    | 4    | CRC‑32C of bytes 0..124                       |
+//!
+//! Fast symlinks store their target in bytes 8..60 instead of pointers.
+//! A data block pointer of 0, direct or in the indirect block, is a hole
+//! that reads as zeros; blocks wholly past the size are always holes.
+//!
+//! Extended attributes are a list of entries, ended by a zero namespace byte
+//! or the end of the area. They fill the inline area first; the rest go to the
+//! attribute block, which starts with the magic `0x41585050` "PPXA" and ends
+//! in the block checksum (see `xattr.rs`):
+//!
+//! | offset | size | field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 1    | namespace, 1 = `user`, 2 = `system`   |
+//! | 1      | 1    | name length n, without the namespace  |
+//! | 2      | 2    | value length v                        |
+//! | 4      | n    | name                                  |
+//! | 4 + n  | v    | value                                 |
+//!
+//! Directory entry (36 bytes, 14 per block, then 4 unused bytes and the
+//! block checksum):
+//!
+//! | offset | size | field                                 |
+//! |--------|------|---------------------------------------|
+//! | 0      | 4    | inode number, 0 = free slot           |
+//! | 4      | 28   | name, NUL padded (at most 27 bytes)   |
+//! | 32     | 4    | reserved, zero                        |
+//!
+//! Indirect blocks hold 128 block numbers of 4 bytes each.
+//!
+//! Quotas are kept in a chain of data blocks starting at `quota_block`, each
+//! with a 16 byte header, 13 records of 36 bytes and the block checksum (see
+//! `quota.rs`). Only the first block's grace periods are used:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | magic `0x54515050` "PPQT"                     |
+//! | 4      | 4    | next quota block, 0 = last                    |
+//! | 8      | 4    | block grace period in seconds                 |
+//! | 12     | 4    | inode grace period in seconds                 |
+//!
+//! | offset | size | record field                                  |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 2    | uid, 0 = free record                          |
+//! | 2      | 2    | reserved, zero                                |
+//! | 4      | 4    | block soft limit, 0 = none                    |
+//! | 8      | 4    | block hard limit, 0 = none                    |
+//! | 12     | 4    | inode soft limit, 0 = none                    |
+//! | 16     | 4    | inode hard limit, 0 = none                    |
+//! | 20     | 4    | blocks in use                                 |
+//! | 24     | 4    | inodes in use                                 |
+//! | 28     | 4    | block grace period end, 0 = not running       |
+//! | 32     | 4    | inode grace period end, 0 = not running       |
+//!
+//! The journal holds at most one transaction. Its first block is a header,
+//! followed by a descriptor, copies of the logged blocks and a commit record
+//! (all fields 4 bytes; see `journal.rs` for the protocol):
+//!
+//! | block          | offset | field                                        |
+//! |----------------|--------|----------------------------------------------|
+//! | header         | 0      | magic `0x4c4a5050` "PPJL"                    |
+//! |                | 4      | sequence number of the next transaction      |
+//! | descriptor     | 0      | magic `0x53444a50` "PJDS"                    |
+//! |                | 4      | sequence number                              |
+//! |                | 8      | count n of logged blocks                     |
+//! |                | 12     | n home block numbers                         |
+//! | n block copies |        |                                              |
+//! | commit         | 0      | magic `0x4d434a50` "PJCM"                    |
+//! |                | 4      | sequence number                              |
+//! |                | 8      | count n                                      |
+//! |                | 12     | FNV‑1a hash of the descriptor and the copies |
+
 use core::iter::Iterator;
 use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use super::crc32c::crc32c;
+use super::error::IoError;
+
+/// Size of a logical block in bytes
+pub const BLOCK_SIZE: usize = 512;
+
+/// Superblock magic, "PPOS"
+pub const MAGIC: u32 = 0x50504f53;
+
+/// On‑disk format version written by `mkfs` and required at mount
+pub const FORMAT_VERSION: u32 = 6;
+
+/// Inode number of the root directory (inode numbers are slot+1, 0 = none)
+pub const ROOT_INO: u32 = 1;
+
+/// File type bits of `Inode::mode`
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFCHR: u16 = 0o020000; // device files, only on devfs
+pub const S_IFBLK: u16 = 0o060000;
+
+/// Symlink targets up to this many bytes live in the block pointer area
+pub const FAST_SYMLINK_MAX: usize = 13 * 4;
+
+/// Bytes of extended attributes stored inside the inode
+pub const XATTR_INLINE_SIZE: usize = 48;
+
+/// Offset of the CRC‑32C that ends superblock, bitmap and directory blocks
+pub const CHECKSUM_OFFSET: usize = BLOCK_SIZE - 4;
+
+/// Stores the checksum of `buf[..CHECKSUM_OFFSET]` at its end
+pub fn seal_block(buf: &mut [u8]) {
+    let crc = crc32c(&buf[..CHECKSUM_OFFSET]);
+    put_u32(buf, CHECKSUM_OFFSET, crc);
+}
+
+/// Whether a block written by `seal_block` is intact
+pub fn block_checksum_ok(buf: &[u8]) -> bool {
+    get_u32(buf, CHECKSUM_OFFSET) == crc32c(&buf[..CHECKSUM_OFFSET])
+}
+
+pub fn get_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+pub fn get_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+pub fn put_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+pub fn put_u32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
 
 /// On‑disk superblock
-#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Superblock {
     pub magic: u32,
+    pub version: u32,
     pub total_blocks: u32,
     pub total_inodes: u32,
     pub free_block_bitmap_start: u32,
     pub inode_table_start: u32,
     pub data_blocks_start: u32,
+    pub journal_start: u32,
+    pub journal_blocks: u32,
+    pub quota_block: u32,
+}
+
+impl Superblock {
+    pub const DISK_SIZE: usize = 40;
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        put_u32(buf, 0, self.magic);
+        put_u32(buf, 4, self.version);
+        put_u32(buf, 8, self.total_blocks);
+        put_u32(buf, 12, self.total_inodes);
+        put_u32(buf, 16, self.free_block_bitmap_start);
+        put_u32(buf, 20, self.inode_table_start);
+        put_u32(buf, 24, self.data_blocks_start);
+        put_u32(buf, 28, self.journal_start);
+        put_u32(buf, 32, self.journal_blocks);
+        put_u32(buf, 36, self.quota_block);
+    }
+
+    pub fn decode(buf: &[u8]) -> Superblock {
+        Superblock {
+            magic: get_u32(buf, 0),
+            version: get_u32(buf, 4),
+            total_blocks: get_u32(buf, 8),
+            total_inodes: get_u32(buf, 12),
+            free_block_bitmap_start: get_u32(buf, 16),
+            inode_table_start: get_u32(buf, 20),
+            data_blocks_start: get_u32(buf, 24),
+            journal_start: get_u32(buf, 28),
+            journal_blocks: get_u32(buf, 32),
+            quota_block: get_u32(buf, 36),
+        }
+    }
 }
 
 /// On‑disk inode
-#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Inode {
     pub mode: u16,             // file type + permissions
+    pub links: u16,            // number of directory entries naming this inode
     pub size: u32,             // size in bytes
     pub direct_ptrs: [u32; 12],// direct block pointers
     pub indirect_ptr: u32,     // single indirect
     pub is_used: u8,           // 0 = free, 1 = in use
+    pub uid: u16,              // owner
+    pub gid: u16,              // group
+    pub mtime: u32,            // last modification, seconds since the epoch
+    pub xattr_block: u32,      // overflow block for extended attributes
+    pub xattr_inline: [u8; XATTR_INLINE_SIZE], // first extended attributes
+}
+
+impl Inode {
+    pub const DISK_SIZE: usize = 128;
+
+    /// Offset of the checksum within the encoded inode
+    const CHECKSUM_OFFSET: usize = Self::DISK_SIZE - 4;
+
+    pub const EMPTY: Inode = Inode {
+        mode: 0,
+        links: 0,
+        size: 0,
+        direct_ptrs: [0; 12],
+        indirect_ptr: 0,
+        is_used: 0,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        xattr_block: 0,
+        xattr_inline: [0; XATTR_INLINE_SIZE],
+    };
+
+    pub fn new(mode: u16) -> Inode {
+        Inode { mode, is_used: 1, ..Inode::EMPTY }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        put_u16(buf, 0, self.mode);
+        put_u16(buf, 2, self.links);
+        put_u32(buf, 4, self.size);
+        for (i, &p) in self.direct_ptrs.iter().enumerate() {
+            put_u32(buf, 8 + i * 4, p);
+        }
+        put_u32(buf, 56, self.indirect_ptr);
+        buf[60] = self.is_used;
+        buf[61..64].fill(0);
+        put_u16(buf, 64, self.uid);
+        put_u16(buf, 66, self.gid);
+        put_u32(buf, 68, self.mtime);
+        put_u32(buf, 72, self.xattr_block);
+        buf[76..Self::CHECKSUM_OFFSET].copy_from_slice(&self.xattr_inline);
+        let crc = crc32c(&buf[..Self::CHECKSUM_OFFSET]);
+        put_u32(buf, Self::CHECKSUM_OFFSET, crc);
+    }
+
+    /// Whether an encoded inode is intact
+    pub fn checksum_ok(buf: &[u8]) -> bool {
+        get_u32(buf, Self::CHECKSUM_OFFSET) == crc32c(&buf[..Self::CHECKSUM_OFFSET])
+    }
+
+    pub fn decode(buf: &[u8]) -> Inode {
+        let mut direct_ptrs = [0u32; 12];
+        for (i, p) in direct_ptrs.iter_mut().enumerate() {
+            *p = get_u32(buf, 8 + i * 4);
+        }
+        let mut xattr_inline = [0u8; XATTR_INLINE_SIZE];
+        xattr_inline.copy_from_slice(&buf[76..Self::CHECKSUM_OFFSET]);
+        Inode {
+            mode: get_u16(buf, 0),
+            links: get_u16(buf, 2),
+            size: get_u32(buf, 4),
+            direct_ptrs,
+            indirect_ptr: get_u32(buf, 56),
+            is_used: buf[60],
+            uid: get_u16(buf, 64),
+            gid: get_u16(buf, 66),
+            mtime: get_u32(buf, 68),
+            xattr_block: get_u32(buf, 72),
+            xattr_inline,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// A symlink whose target is stored inline instead of in a data block
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.size as usize <= FAST_SYMLINK_MAX
+    }
+
+    /// The block pointer area viewed as bytes (little‑endian words)
+    pub fn inline_data(&self) -> [u8; FAST_SYMLINK_MAX] {
+        let mut out = [0u8; FAST_SYMLINK_MAX];
+        let ptrs = self.direct_ptrs.iter().chain(core::iter::once(&self.indirect_ptr));
+        for (chunk, p) in out.chunks_mut(4).zip(ptrs) {
+            chunk.copy_from_slice(&p.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        let mut bytes = [0u8; FAST_SYMLINK_MAX];
+        bytes[..data.len()].copy_from_slice(data);
+        let ptrs = self.direct_ptrs.iter_mut().chain(core::iter::once(&mut self.indirect_ptr));
+        for (chunk, p) in bytes.chunks(4).zip(ptrs) {
+            *p = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+    }
 }
 
 /// Directory entry (fixed‑size name + inode index)
-#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct DirEntry {
     pub inode_index: u32,      // 0 = unused, otherwise inode slot+1
     pub name: [u8; 28],        // up to 27 bytes + NUL
-    pub _padding: u16,         // pad to 36 bytes
 }
 
-/// Block device trait
-pub trait BlockDevice {
-    fn read_block(&self, block_number: u32, buf: &mut [u8]);
-    fn write_block(&mut self, block_number: u32, buf: &[u8]);
-}
+impl DirEntry {
+    pub const DISK_SIZE: usize = 36;
 
-/// In‑memory “physical” device: 64 chunks × 64 B = 4096 B
-pub struct PPDev {
-    pub blocks: [[u8; 64]; 64],
-}
+    /// Longest name that still leaves room for the terminating NUL
+    pub const MAX_NAME: usize = 27;
 
-impl BlockDevice for PPDev {
-    fn read_block(&self, block_number: u32, buf: &mut [u8]) {
-        // Each 512 B “logical” block = 8 × 64 B chunks
-        let idx = block_number as usize;
-        let chunks_per_block = 512 / 64;
-        if idx * chunks_per_block + chunks_per_block > self.blocks.len() {
-            return; // out of bounds
+    pub fn new(inode_index: u32, name: &str) -> DirEntry {
+        let mut e = DirEntry { inode_index, name: [0; 28] };
+        for (k, &b) in name.as_bytes().iter().take(Self::MAX_NAME).enumerate() {
+            e.name[k] = b;
         }
+        e
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        put_u32(buf, 0, self.inode_index);
+        buf[4..32].copy_from_slice(&self.name);
+        buf[32..36].fill(0);
+    }
 
-        let mut bi = 0;
-        for i in 0..chunks_per_block {
-            let chunk = &self.blocks[idx * chunks_per_block + i];
-            for &b in chunk.iter() {
-                buf[bi] = b;
-                bi += 1;
-            }
+    pub fn decode(buf: &[u8]) -> DirEntry {
+        let mut name = [0u8; 28];
+        name.copy_from_slice(&buf[4..32]);
+        DirEntry { inode_index: get_u32(buf, 0), name }
+    }
+
+    /// Name bytes up to the first NUL
+    pub fn name_bytes(&self) -> &[u8] {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(28);
+        &self.name[..len]
+    }
+}
+
+/// Block device trait. Blocks are numbered from 0 to `block_count() - 1`
+/// and transferred through buffers of exactly `block_size()` bytes, or a
+/// whole number of blocks for the multi‑block calls.
+pub trait BlockDevice {
+    /// Bytes per block; the filesystem only runs on `BLOCK_SIZE` devices
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u32;
+
+    fn read_block(&self, block_number: u32, buf: &mut [u8]) -> Result<(), IoError>;
+    fn write_block(&mut self, block_number: u32, buf: &[u8]) -> Result<(), IoError>;
+
+    /// Reads `buf.len() / block_size()` consecutive blocks from `start`
+    fn read_blocks(&self, start: u32, buf: &mut [u8]) -> Result<(), IoError> {
+        let size = self.block_size();
+        check_range(self.block_count(), size, start, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(size).enumerate() {
+            self.read_block(start + i as u32, chunk)?;
         }
+        Ok(())
     }
 
-    fn write_block(&mut self, block_number: u32, buf: &[u8]) {
-        let start = (block_number as usize) * 512;
-
-        if buf.len() == 512 {
-            // fast full‑block copy
-            self.blocks
-                .iter_mut()
-                .flat_map(|c| c.iter_mut())
-                .skip(start)
-                .take(512)
-                .zip(buf.iter())
-                .for_each(|(dst, &src)| *dst = src);
-        } else {
-            // partial write
-            for (i, &b) in buf.iter().enumerate() {
-                let off = start + i;
-                let block_i = off / 64;
-                let byte_i = off % 64;
-                self.blocks[block_i][byte_i] = b;
-            }
+    /// Writes `buf.len() / block_size()` consecutive blocks from `start`
+    fn write_blocks(&mut self, start: u32, buf: &[u8]) -> Result<(), IoError> {
+        let size = self.block_size();
+        check_range(self.block_count(), size, start, buf.len())?;
+        for (i, chunk) in buf.chunks(size).enumerate() {
+            self.write_block(start + i as u32, chunk)?;
         }
+        Ok(())
+    }
+
+    /// Makes every write so far reach the medium before any later one.
+    /// Devices that write through need not do anything.
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+/// Checks a transfer of `len` bytes starting at block `start` against a
+/// device of `count` blocks of `size` bytes
+pub fn check_range(count: u32, size: usize, start: u32, len: usize) -> Result<(), IoError> {
+    if len == 0 || !len.is_multiple_of(size) {
+        return Err(IoError::BufferSize);
+    }
+    let blocks = (len / size) as u64;
+    if start as u64 + blocks > count as u64 {
+        return Err(IoError::OutOfRange(start.max(count)));
+    }
+    Ok(())
+}
+
+/// Checks a single‑block transfer of `len` bytes on a device of `count`
+/// blocks of `BLOCK_SIZE` bytes
+pub fn check_block(count: u32, block: u32, len: usize) -> Result<(), IoError> {
+    if len != BLOCK_SIZE {
+        return Err(IoError::BufferSize);
+    }
+    if block >= count {
+        return Err(IoError::OutOfRange(block));
     }
+    Ok(())
 }
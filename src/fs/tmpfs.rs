@@ -0,0 +1,489 @@
+//! In-memory filesystem.
+//!
+//! `Tmpfs` keeps its inodes in a table on the heap, inode n at index n - 1,
+//! with the root directory at 1. Regular files hold their contents in a
+//! vector, directories a list of names and the inode number of their parent
+//! for `..`, and symlinks their target. Nothing is written anywhere, so
+//! everything is gone once it is unmounted.
+//!
+//! The size limit covers file contents, symlink targets, names and a fixed
+//! charge per inode; an operation that would go past it, or that the heap
+//! cannot satisfy, fails with `NoSpace`.
+
+use core::iter::Iterator;
+use core::mem::size_of;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::clock;
+use super::error::FsError;
+use super::ops::MAX_SYMLINK_LEN;
+use super::perm::Cred;
+use super::structure::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use super::vfs::{FileOps, FileSystem, InodeOps, Stat};
+
+/// Inode number of the root directory
+pub const TMPFS_ROOT: u32 = 1;
+
+/// Longest file name
+pub const MAX_NAME: usize = 255;
+
+/// Bytes charged for every inode, whatever it holds
+const INODE_COST: usize = size_of::<Node>();
+
+enum Data {
+    File(Vec<u8>),
+    Dir { parent: u32, entries: Vec<(String, u32)> },
+    Symlink(String),
+}
+
+struct Node {
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    links: u16,
+    mtime: u32,
+    data: Data,
+}
+
+pub struct Tmpfs {
+    nodes: Vec<Option<Node>>,
+    limit: usize, // bytes
+    used: usize,
+}
+
+impl Tmpfs {
+    /// An empty filesystem holding at most `limit` bytes, its root directory
+    /// owned by root with permissions `perm`
+    pub fn new(limit: usize, perm: u16) -> Result<Tmpfs, FsError> {
+        let mut fs = Tmpfs { nodes: Vec::new(), limit, used: 0 };
+        let root = Node {
+            mode: S_IFDIR | (perm & 0o7777),
+            uid: 0,
+            gid: 0,
+            links: 2,
+            mtime: clock::now(),
+            data: Data::Dir { parent: TMPFS_ROOT, entries: Vec::new() },
+        };
+        fs.alloc_node(root)?;
+        Ok(fs)
+    }
+
+    /// Bytes in use, and the limit
+    pub fn usage(&self) -> (usize, usize) {
+        (self.used, self.limit)
+    }
+
+    fn node(&self, ino: u32) -> Result<&Node, FsError> {
+        let i = (ino as usize).checked_sub(1).ok_or(FsError::NotFound)?;
+        self.nodes.get(i).and_then(|n| n.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: u32) -> Result<&mut Node, FsError> {
+        let i = (ino as usize).checked_sub(1).ok_or(FsError::NotFound)?;
+        self.nodes.get_mut(i).and_then(|n| n.as_mut()).ok_or(FsError::NotFound)
+    }
+
+    /// Counts `bytes` more against the limit
+    fn charge(&mut self, bytes: usize) -> Result<(), FsError> {
+        if self.used + bytes > self.limit {
+            return Err(FsError::NoSpace);
+        }
+        self.used += bytes;
+        Ok(())
+    }
+
+    fn alloc_node(&mut self, node: Node) -> Result<u32, FsError> {
+        self.charge(INODE_COST)?;
+        let i = match self.nodes.iter().position(|n| n.is_none()) {
+            Some(i) => i,
+            None => {
+                if self.nodes.len() >= u32::MAX as usize || self.nodes.try_reserve(1).is_err() {
+                    self.used -= INODE_COST;
+                    return Err(FsError::NoSpace);
+                }
+                self.nodes.push(None);
+                self.nodes.len() - 1
+            }
+        };
+        self.nodes[i] = Some(node);
+        Ok(i as u32 + 1)
+    }
+
+    /// Frees inode `ino` and returns what it held to the limit
+    fn free_node(&mut self, ino: u32) {
+        let Some(node) = self.nodes.get_mut(ino as usize - 1).and_then(|n| n.take()) else {
+            return;
+        };
+        self.used -= INODE_COST
+            + match node.data {
+                Data::File(data) => data.len(),
+                Data::Symlink(target) => target.len(),
+                Data::Dir { .. } => 0,
+            };
+    }
+
+    fn entries(&self, dir: u32) -> Result<&Vec<(String, u32)>, FsError> {
+        match &self.node(dir)?.data {
+            Data::Dir { entries, .. } => Ok(entries),
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: u32) -> Result<&mut Vec<(String, u32)>, FsError> {
+        match &mut self.node_mut(dir)?.data {
+            Data::Dir { entries, .. } => Ok(entries),
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    /// Index of `name` among the entries of `dir`
+    fn find(&self, dir: u32, name: &str) -> Result<usize, FsError> {
+        self.entries(dir)?.iter().position(|(n, _)| n == name).ok_or(FsError::NotFound)
+    }
+
+    /// Adds the entry `name` for `ino` to `dir`, which must not have one
+    fn add_entry(&mut self, dir: u32, name: &str, ino: u32) -> Result<(), FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+        if name.len() > MAX_NAME {
+            return Err(FsError::NameTooLong);
+        }
+        match self.find(dir, name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        self.charge(name.len())?;
+        let mut owned = String::new();
+        if owned.try_reserve(name.len()).is_err() || self.entries_mut(dir)?.try_reserve(1).is_err() {
+            self.used -= name.len();
+            return Err(FsError::NoSpace);
+        }
+        owned.push_str(name);
+        self.entries_mut(dir)?.push((owned, ino));
+        self.touch(dir)
+    }
+
+    /// Removes entry `name` from `dir`, returning its inode number
+    fn remove_entry(&mut self, dir: u32, name: &str) -> Result<u32, FsError> {
+        let i = self.find(dir, name)?;
+        let (name, ino) = self.entries_mut(dir)?.swap_remove(i);
+        self.used -= name.len();
+        self.touch(dir)?;
+        Ok(ino)
+    }
+
+    fn touch(&mut self, ino: u32) -> Result<(), FsError> {
+        self.node_mut(ino)?.mtime = clock::now();
+        Ok(())
+    }
+
+    fn add_link(&mut self, ino: u32) -> Result<(), FsError> {
+        let node = self.node_mut(ino)?;
+        node.links = node.links.checked_add(1).ok_or(FsError::TooManyLinks)?;
+        Ok(())
+    }
+
+    /// Drops a link to `ino`, freeing it with the last one
+    fn drop_link(&mut self, ino: u32) -> Result<(), FsError> {
+        let node = self.node_mut(ino)?;
+        node.links = node.links.saturating_sub(1);
+        if node.links == 0 {
+            self.free_node(ino);
+        }
+        Ok(())
+    }
+
+    /// A new inode owned by `cred`, entered as `name` in `dir`
+    fn new_entry(&mut self, cred: &Cred, dir: u32, name: &str, mode: u16, data: Data) -> Result<u32, FsError> {
+        match self.find(dir, name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let links = if mode & S_IFMT == S_IFDIR { 2 } else { 1 };
+        let node = Node { mode, uid: cred.uid, gid: cred.gid, links, mtime: clock::now(), data };
+        let ino = self.alloc_node(node)?;
+        if let Err(e) = self.add_entry(dir, name, ino) {
+            self.free_node(ino);
+            return Err(e);
+        }
+        Ok(ino)
+    }
+
+    /// Whether directory `dir` is `ancestor` or lies below it
+    fn is_below(&self, mut dir: u32, ancestor: u32) -> Result<bool, FsError> {
+        loop {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            match self.node(dir)?.data {
+                Data::Dir { parent, .. } if dir != TMPFS_ROOT => dir = parent,
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    fn file_mut(&mut self, ino: u32) -> Result<&mut Vec<u8>, FsError> {
+        match &mut self.node_mut(ino)?.data {
+            Data::File(data) => Ok(data),
+            Data::Dir { .. } => Err(FsError::IsDir),
+            Data::Symlink(_) => Err(FsError::InvalidArgument),
+        }
+    }
+
+    /// Makes file `ino` `size` bytes long, zero-filling any growth
+    fn resize(&mut self, ino: u32, size: usize) -> Result<(), FsError> {
+        let len = self.file_mut(ino)?.len();
+        if size > len {
+            self.charge(size - len)?;
+            let data = self.file_mut(ino)?;
+            if data.try_reserve(size - len).is_err() {
+                self.used -= size - len;
+                return Err(FsError::NoSpace);
+            }
+            data.resize(size, 0);
+        } else {
+            let data = self.file_mut(ino)?;
+            data.truncate(size);
+            data.shrink_to_fit();
+            self.used -= len - size;
+        }
+        Ok(())
+    }
+}
+
+impl InodeOps for Tmpfs {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        let node = self.node(ino)?;
+        let size = match &node.data {
+            Data::File(data) => data.len(),
+            Data::Dir { entries, .. } => entries.len() + 2,
+            Data::Symlink(target) => target.len(),
+        };
+        Ok(Stat {
+            ino,
+            mode: node.mode,
+            links: node.links,
+            uid: node.uid,
+            gid: node.gid,
+            size: size as u64,
+            blocks: size.div_ceil(512) as u64,
+            mtime: node.mtime,
+        })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        match (&self.node(dir)?.data, name) {
+            (Data::Dir { .. }, ".") => Ok(dir),
+            (Data::Dir { parent, .. }, "..") => Ok(*parent),
+            (Data::Dir { entries, .. }, _) => {
+                entries.iter().find(|(n, _)| n == name).map(|&(_, ino)| ino).ok_or(FsError::NotFound)
+            }
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        let Data::Dir { parent, entries } = &self.node(dir)?.data else {
+            return Err(FsError::NotDir);
+        };
+        f(".", dir);
+        f("..", *parent);
+        for (name, ino) in entries {
+            f(name, *ino);
+        }
+        Ok(())
+    }
+
+    fn readlink(&self, ino: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        let Data::Symlink(target) = &self.node(ino)?.data else {
+            return Err(FsError::InvalidPath);
+        };
+        let n = target.len().min(buf.len());
+        buf[..n].copy_from_slice(&target.as_bytes()[..n]);
+        Ok(n)
+    }
+
+    fn create(&mut self, cred: &Cred, dir: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+        self.new_entry(cred, dir, name, S_IFREG | (perm & 0o7777), Data::File(Vec::new()))
+    }
+
+    fn mkdir(&mut self, cred: &Cred, dir: u32, name: &str, perm: u16) -> Result<u32, FsError> {
+        if self.node(dir)?.links == u16::MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        let data = Data::Dir { parent: dir, entries: Vec::new() };
+        let ino = self.new_entry(cred, dir, name, S_IFDIR | (perm & 0o7777), data)?;
+        // The child's ".." is a link to the parent
+        self.add_link(dir)?;
+        Ok(ino)
+    }
+
+    fn symlink(&mut self, cred: &Cred, dir: u32, name: &str, target: &str) -> Result<u32, FsError> {
+        if target.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+        if target.len() > MAX_SYMLINK_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        self.charge(target.len())?;
+        let mut owned = String::new();
+        if owned.try_reserve(target.len()).is_err() {
+            self.used -= target.len();
+            return Err(FsError::NoSpace);
+        }
+        owned.push_str(target);
+        // Freeing the node on failure returns the target's charge
+        self.new_entry(cred, dir, name, S_IFLNK | 0o777, Data::Symlink(owned))
+    }
+
+    fn link(&mut self, ino: u32, dir: u32, name: &str) -> Result<(), FsError> {
+        if self.node(ino)?.mode & S_IFMT == S_IFDIR {
+            return Err(FsError::IsDir);
+        }
+        self.add_link(ino)?;
+        if let Err(e) = self.add_entry(dir, name, ino) {
+            self.drop_link(ino)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        let ino = self.lookup(dir, name)?;
+        if self.node(ino)?.mode & S_IFMT == S_IFDIR {
+            return Err(FsError::IsDir);
+        }
+        self.remove_entry(dir, name)?;
+        self.drop_link(ino)
+    }
+
+    fn rmdir(&mut self, dir: u32, name: &str) -> Result<(), FsError> {
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        let ino = self.lookup(dir, name)?;
+        if !self.entries(ino)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove_entry(dir, name)?;
+        self.free_node(ino);
+        self.drop_link(dir)
+    }
+
+    fn rename(&mut self, from_dir: u32, from: &str, to_dir: u32, to: &str) -> Result<(), FsError> {
+        if [from, to].iter().any(|&n| n == "." || n == "..") {
+            return Err(FsError::InvalidPath);
+        }
+        let ino = self.lookup(from_dir, from)?;
+        let is_dir = self.node(ino)?.mode & S_IFMT == S_IFDIR;
+        if is_dir && self.is_below(to_dir, ino)? {
+            return Err(FsError::InvalidPath);
+        }
+        let target = match self.lookup(to_dir, to) {
+            Ok(t) if t == ino => return Ok(()),
+            Ok(t) => {
+                let t_dir = self.node(t)?.mode & S_IFMT == S_IFDIR;
+                match (is_dir, t_dir) {
+                    (false, true) => return Err(FsError::IsDir),
+                    (true, false) => return Err(FsError::NotDir),
+                    (true, true) if !self.entries(t)?.is_empty() => return Err(FsError::NotEmpty),
+                    _ => Some(t),
+                }
+            }
+            Err(FsError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if is_dir && from_dir != to_dir && target.is_none() && self.node(to_dir)?.links == u16::MAX {
+            return Err(FsError::TooManyLinks);
+        }
+
+        // Name the inode under `to` first, so nothing is lost if that fails
+        match target {
+            Some(t) => {
+                let i = self.find(to_dir, to)?;
+                self.entries_mut(to_dir)?[i].1 = ino;
+                self.touch(to_dir)?;
+                if is_dir {
+                    self.free_node(t);
+                    self.drop_link(to_dir)?;
+                } else {
+                    self.drop_link(t)?;
+                }
+            }
+            None => self.add_entry(to_dir, to, ino)?,
+        }
+        self.remove_entry(from_dir, from)?;
+        if is_dir && from_dir != to_dir {
+            if let Data::Dir { parent, .. } = &mut self.node_mut(ino)?.data {
+                *parent = to_dir;
+            }
+            self.drop_link(from_dir)?;
+            self.add_link(to_dir)?;
+        }
+        Ok(())
+    }
+
+    fn chmod(&mut self, ino: u32, mode: u16) -> Result<(), FsError> {
+        let node = self.node_mut(ino)?;
+        node.mode = (node.mode & S_IFMT) | (mode & 0o7777);
+        Ok(())
+    }
+
+    fn chown(&mut self, ino: u32, uid: Option<u16>, gid: Option<u16>) -> Result<(), FsError> {
+        let node = self.node_mut(ino)?;
+        if let Some(uid) = uid {
+            node.uid = uid;
+        }
+        if let Some(gid) = gid {
+            node.gid = gid;
+        }
+        Ok(())
+    }
+}
+
+impl FileOps for Tmpfs {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = match &self.node(ino)?.data {
+            Data::File(data) => data,
+            Data::Dir { .. } => return Err(FsError::IsDir),
+            Data::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, ino: u32, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let offset = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = offset.checked_add(data.len()).ok_or(FsError::NoSpace)?;
+        if end > self.file_mut(ino)?.len() {
+            self.resize(ino, end)?;
+        }
+        self.file_mut(ino)?[offset..end].copy_from_slice(data);
+        self.touch(ino)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: u32, size: u64) -> Result<(), FsError> {
+        self.resize(ino, usize::try_from(size).map_err(|_| FsError::NoSpace)?)?;
+        self.touch(ino)
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> u32 {
+        TMPFS_ROOT
+    }
+}
@@ -1,69 +1,203 @@
-use core::result::Result::Ok;
+use core::result::Result::{self, Err, Ok};
 pub use core::{
     cmp::Ord,
     iter::Iterator,
     option::Option::{self, None, Some},
 };
 
-use crate::{fs::structure::{Inode, Superblock}, poprint, PPDev};
+use crate::{fs::structure::{Inode, Superblock}, poprint};
 
-use super::structure::{BlockDevice, DirEntry};
+use super::clock::{self, DateTime};
+use super::error::FsError;
+use super::journal;
+use super::quota;
+use super::structure::{
+    block_checksum_ok, get_u32, put_u32, seal_block, BlockDevice, DirEntry, BLOCK_SIZE,
+    CHECKSUM_OFFSET, FAST_SYMLINK_MAX, FORMAT_VERSION, MAGIC, ROOT_INO, S_IFBLK, S_IFCHR, S_IFDIR,
+    S_IFLNK, S_IFMT,
+};
+
+const INODE_SIZE: usize = Inode::DISK_SIZE;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+const ENTRY_SIZE: usize = DirEntry::DISK_SIZE;
+pub const ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / ENTRY_SIZE;
+/// Blocks tracked per bitmap block; the last 4 bytes hold its checksum
+pub const BITS_PER_BLOCK: u32 = (CHECKSUM_OFFSET * 8) as u32;
+pub const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+/// Largest file in blocks: 12 direct pointers plus one indirect block
+pub const MAX_FILE_BLOCKS: usize = 12 + PTRS_PER_BLOCK;
 
-pub fn mkfs(device: &mut dyn BlockDevice) {
+pub fn mkfs(device: &mut dyn BlockDevice, total_blocks: u32) -> Result<(), FsError> {
     poprint!("[mkfs] Entered mkfs()\n");
+    if total_blocks > device.block_count() {
+        poprint!("[mkfs] Device holds only {} blocks\n", device.block_count());
+        return Err(FsError::NoSpace);
+    }
 
+    // Block 0 superblock, then the block bitmap, the inode table, the journal
+    // and data. Devices too small to spare the journal go without one.
+    let bitmap_blocks = total_blocks.div_ceil(BITS_PER_BLOCK);
+    let total_inodes = (total_blocks / 4).max(8).next_multiple_of(INODES_PER_BLOCK as u32);
+    let inode_blocks = total_inodes / INODES_PER_BLOCK as u32;
+    let journal_start = 1 + bitmap_blocks + inode_blocks;
+    let journal_blocks = if total_blocks >= journal::MIN_JOURNALED_BLOCKS {
+        journal::JOURNAL_BLOCKS
+    } else {
+        0
+    };
     let sb = Superblock {
-        magic: 0x50504f53, // "PPOS"
-        total_blocks: 1024,
-        total_inodes: 128,
+        magic: MAGIC,
+        version: FORMAT_VERSION,
+        total_blocks,
+        total_inodes,
         free_block_bitmap_start: 1,
-        inode_table_start: 2,
-        data_blocks_start: 10,
+        inode_table_start: 1 + bitmap_blocks,
+        data_blocks_start: journal_start + journal_blocks,
+        journal_start,
+        journal_blocks,
+        quota_block: 0,
     };
+    if sb.data_blocks_start >= total_blocks {
+        poprint!("[mkfs] Device too small ({} blocks)\n", total_blocks);
+        return Err(FsError::NoSpace);
+    }
 
     poprint!("[mkfs] Superblock constructed\n");
 
-    // Create a zeroed buffer
-    let mut buf = [0u8; 512];
-    poprint!("[mkfs] Zeroed buffer created\n");
-    // After zeroing inode block (block 0) and root‐inode in block 1:
-    let dir_block = [0u8; 512];
-    device.write_block(2, &dir_block);
-
-    // Convert Superblock to bytes
-    let sb_ptr = (&sb as *const Superblock) as *const u8;
-    let sb_size = core::mem::size_of::<Superblock>();
-    poprint!(
-        "[mkfs] Superblock ptr: {:#x}, size: {}\n",
-        sb_ptr as usize,
-        sb_size
-    );
-
-    let sb_bytes = unsafe { core::slice::from_raw_parts(sb_ptr, sb_size) };
-    poprint!("[mkfs] Created byte slice from superblock\n");
-
-    // Copy bytes into the buffer
-    buf[..sb_bytes.len()].copy_from_slice(sb_bytes);
-    poprint!("[mkfs] Copied superblock into buffer\n");
-
-    // Write the block to disk
-    poprint!("[mkfs] About to write block 0\n");
-    device.write_block(0, &buf);
+    // Metadata blocks and everything past the end of the device are never
+    // handed out by the allocator.
+    let zero = [0u8; BLOCK_SIZE];
+    for b in 0..sb.data_blocks_start {
+        device.write_block(b, &zero)?;
+    }
+    for i in 0..bitmap_blocks {
+        let mut buf = [0u8; BLOCK_SIZE];
+        for bit in 0..BITS_PER_BLOCK {
+            let block = i * BITS_PER_BLOCK + bit;
+            if block < sb.data_blocks_start || block >= total_blocks {
+                buf[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        seal_block(&mut buf);
+        device.write_block(sb.free_block_bitmap_start + i, &buf)?;
+    }
+
+    // Free inodes carry a checksum like used ones
+    let mut table = [0u8; BLOCK_SIZE];
+    for slot in table.chunks_mut(INODE_SIZE) {
+        Inode::EMPTY.encode(slot);
+    }
+    for i in 0..inode_blocks {
+        device.write_block(sb.inode_table_start + i, &table)?;
+    }
+
+    write_superblock(device, &sb)?;
     poprint!("[mkfs] Block 0 written!\n");
+    journal::format(device, &sb)?;
+
+    // Root directory: inode 1, whose ".." points back at itself.
+    let mut root = Inode::new(S_IFDIR | 0o755);
+    root.links = 2;
+    root.mtime = clock::now();
+    let created = alloc_inode(device, &root).and_then(|ino| {
+        if ino != ROOT_INO {
+            return Err(FsError::Corrupt(sb.inode_table_start));
+        }
+        init_dir(device, ino, ino)
+    });
+    if let Err(e) = created {
+        poprint!("[mkfs] Failed to create root directory\n");
+        return Err(e);
+    }
+    poprint!("[mkfs] Root directory created\n");
+    Ok(())
 }
 
-pub fn read_file(device: &dyn BlockDevice, inode: &Inode, buf: &mut [u8]) {
-    let mut offset = 0;
-    for &block in &inode.direct_ptrs {
-        if block == 0 || offset >= buf.len() {
-            break;
+/// Reads the superblock, verifying its checksum
+pub fn read_superblock(device: &dyn BlockDevice) -> Result<Superblock, FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(0, &mut buf)?;
+    if !block_checksum_ok(&buf) {
+        return Err(FsError::Corrupt(0));
+    }
+    Ok(Superblock::decode(&buf))
+}
+
+/// Seals and writes the superblock
+pub fn write_superblock(device: &mut dyn BlockDevice, sb: &Superblock) -> Result<(), FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    sb.encode(&mut buf);
+    seal_block(&mut buf);
+    device.write_block(0, &buf)?;
+    Ok(())
+}
+
+/// Reads the superblock and checks that this code understands the image
+pub fn probe(device: &dyn BlockDevice) -> Result<Superblock, FsError> {
+    if device.block_size() != BLOCK_SIZE {
+        return Err(FsError::Unsupported);
+    }
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(0, &mut buf)?;
+    let sb = Superblock::decode(&buf);
+    if sb.magic != MAGIC {
+        return Err(FsError::BadMagic);
+    }
+    if sb.version != FORMAT_VERSION {
+        return Err(FsError::BadVersion);
+    }
+    read_superblock(device)
+}
+
+fn inode_location(sb: &Superblock, ino: u32) -> (u32, usize) {
+    let slot = (ino - 1) as usize;
+    (
+        sb.inode_table_start + (slot / INODES_PER_BLOCK) as u32,
+        (slot % INODES_PER_BLOCK) * INODE_SIZE,
+    )
+}
+
+/// Reads inode `ino`; a number outside the table is `InvalidArgument`
+pub fn read_inode(device: &dyn BlockDevice, ino: u32) -> Result<Inode, FsError> {
+    let sb = read_superblock(device)?;
+    if ino == 0 || ino > sb.total_inodes {
+        return Err(FsError::InvalidArgument);
+    }
+    let (block, off) = inode_location(&sb, ino);
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(block, &mut buf)?;
+    let raw = &buf[off..off + INODE_SIZE];
+    if !Inode::checksum_ok(raw) {
+        return Err(FsError::Corrupt(block));
+    }
+    Ok(Inode::decode(raw))
+}
+
+pub fn write_inode(device: &mut dyn BlockDevice, ino: u32, inode: &Inode) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    if ino == 0 || ino > sb.total_inodes {
+        return Err(FsError::InvalidArgument);
+    }
+    let (block, off) = inode_location(&sb, ino);
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read_block(block, &mut buf)?;
+    inode.encode(&mut buf[off..off + INODE_SIZE]);
+    device.write_block(block, &buf)?;
+    Ok(())
+}
+
+/// Writes `inode` into the first free slot of the inode table and returns its
+/// number, charging it to the owner's quota
+pub fn alloc_inode(device: &mut dyn BlockDevice, inode: &Inode) -> Result<u32, FsError> {
+    let sb = read_superblock(device)?;
+    for ino in 1..=sb.total_inodes {
+        if read_inode(device, ino)?.is_used == 0 {
+            quota::charge(device, inode.uid, 0, 1)?;
+            write_inode(device, ino, inode)?;
+            return Ok(ino);
         }
-        let mut tmp = [0u8; 512];
-        device.read_block(block, &mut tmp);
-        let len = (buf.len() - offset).min(512);
-        buf[offset..offset + len].copy_from_slice(&tmp[..len]);
-        offset += len;
     }
+    Err(FsError::NoInodes)
 }
 
 pub fn alloc_block(bitmap: &mut [u8]) -> Option<u32> {
@@ -80,79 +214,667 @@ pub fn alloc_block(bitmap: &mut [u8]) -> Option<u32> {
     None
 }
 
-pub fn list_dir(device: &dyn BlockDevice, dir_inode: &Inode) {
-    for &block in &dir_inode.direct_ptrs {
+pub fn free_block(bitmap: &mut [u8], bit: u32) {
+    bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+}
+
+/// Reads bitmap block `i`, verifying its checksum
+pub fn read_bitmap(device: &dyn BlockDevice, sb: &Superblock, i: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    let block = sb.free_block_bitmap_start + i;
+    device.read_block(block, buf)?;
+    if !block_checksum_ok(buf) {
+        return Err(FsError::Corrupt(block));
+    }
+    Ok(())
+}
+
+/// Seals and writes bitmap block `i`
+pub fn write_bitmap(device: &mut dyn BlockDevice, sb: &Superblock, i: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    seal_block(buf);
+    device.write_block(sb.free_block_bitmap_start + i, buf)?;
+    Ok(())
+}
+
+/// Claims a free block in the on‑disk bitmap for a file of user `uid` and
+/// zeroes it. The quota is checked first, so a user at their limit gets
+/// `QuotaExceeded` even on a full disk.
+pub fn alloc_data_block(device: &mut dyn BlockDevice, uid: u16) -> Result<u32, FsError> {
+    let sb = read_superblock(device)?;
+    quota::charge(device, uid, 1, 0)?;
+    let bitmap_blocks = sb.inode_table_start - sb.free_block_bitmap_start;
+    for i in 0..bitmap_blocks {
+        let mut bitmap = [0u8; BLOCK_SIZE];
+        read_bitmap(device, &sb, i, &mut bitmap)?;
+        if let Some(bit) = alloc_block(&mut bitmap[..CHECKSUM_OFFSET]) {
+            let block = i * BITS_PER_BLOCK + bit;
+            write_bitmap(device, &sb, i, &mut bitmap)?;
+            device.write_block(block, &[0u8; BLOCK_SIZE])?;
+            return Ok(block);
+        }
+    }
+    quota::release(device, uid, 1, 0)?;
+    Err(FsError::NoSpace)
+}
+
+/// Number of blocks still free in the on‑disk bitmap
+pub fn free_block_count(device: &dyn BlockDevice) -> Result<u32, FsError> {
+    let sb = read_superblock(device)?;
+    let mut free = 0;
+    for i in 0..sb.inode_table_start - sb.free_block_bitmap_start {
+        let mut bitmap = [0u8; BLOCK_SIZE];
+        read_bitmap(device, &sb, i, &mut bitmap)?;
+        free += bitmap[..CHECKSUM_OFFSET].iter().map(|b| b.count_zeros()).sum::<u32>();
+    }
+    Ok(free)
+}
+
+/// Number of inodes in use
+pub fn used_inode_count(device: &dyn BlockDevice) -> Result<u32, FsError> {
+    let sb = read_superblock(device)?;
+    let mut used = 0;
+    for ino in 1..=sb.total_inodes {
+        if read_inode(device, ino)?.is_used != 0 {
+            used += 1;
+        }
+    }
+    Ok(used)
+}
+
+/// Returns a block of a file of user `uid` to the on‑disk bitmap
+pub fn release_data_block(device: &mut dyn BlockDevice, block: u32, uid: u16) -> Result<(), FsError> {
+    let sb = read_superblock(device)?;
+    if block < sb.data_blocks_start || block >= sb.total_blocks {
+        return Ok(());
+    }
+    let mut bitmap = [0u8; BLOCK_SIZE];
+    read_bitmap(device, &sb, block / BITS_PER_BLOCK, &mut bitmap)?;
+    free_block(&mut bitmap, block % BITS_PER_BLOCK);
+    write_bitmap(device, &sb, block / BITS_PER_BLOCK, &mut bitmap)?;
+    quota::release(device, uid, 1, 0)
+}
+
+/// Blocks `inode` holds: data, indirect and extended attribute blocks
+pub fn allocated_blocks(device: &dyn BlockDevice, inode: &Inode) -> Result<u32, FsError> {
+    let mut count = (inode.xattr_block != 0) as u32;
+    if inode.is_fast_symlink() {
+        return Ok(count);
+    }
+    count += inode.direct_ptrs.iter().filter(|&&p| p != 0).count() as u32;
+    if inode.indirect_ptr != 0 {
+        let mut ind = [0u8; BLOCK_SIZE];
+        device.read_block(inode.indirect_ptr, &mut ind)?;
+        count += 1 + (0..PTRS_PER_BLOCK).filter(|&i| get_u32(&ind, i * 4) != 0).count() as u32;
+    }
+    Ok(count)
+}
+
+/// Block holding the `index`th 512 B chunk of a file, 0 if none is allocated
+pub fn file_block(device: &dyn BlockDevice, inode: &Inode, index: usize) -> Result<u32, FsError> {
+    if index < 12 {
+        return Ok(inode.direct_ptrs[index]);
+    }
+    if index >= MAX_FILE_BLOCKS || inode.indirect_ptr == 0 {
+        return Ok(0);
+    }
+    let mut ind = [0u8; BLOCK_SIZE];
+    device.read_block(inode.indirect_ptr, &mut ind)?;
+    Ok(get_u32(&ind, (index - 12) * 4))
+}
+
+/// Like `file_block`, but allocates the block (and the indirect block) when
+/// missing. Only `inode` in memory is updated; the caller writes it back.
+fn file_block_alloc(device: &mut dyn BlockDevice, inode: &mut Inode, index: usize) -> Result<u32, FsError> {
+    if index < 12 {
+        if inode.direct_ptrs[index] == 0 {
+            inode.direct_ptrs[index] = alloc_data_block(device, inode.uid)?;
+        }
+        return Ok(inode.direct_ptrs[index]);
+    }
+    if index >= MAX_FILE_BLOCKS {
+        return Err(FsError::NoSpace);
+    }
+    if inode.indirect_ptr == 0 {
+        inode.indirect_ptr = alloc_data_block(device, inode.uid)?;
+    }
+    let mut ind = [0u8; BLOCK_SIZE];
+    device.read_block(inode.indirect_ptr, &mut ind)?;
+    let mut block = get_u32(&ind, (index - 12) * 4);
+    if block == 0 {
+        block = alloc_data_block(device, inode.uid)?;
+        put_u32(&mut ind, (index - 12) * 4, block);
+        device.write_block(inode.indirect_ptr, &ind)?;
+    }
+    Ok(block)
+}
+
+/// Releases every data block of `inode` and resets its size
+pub fn free_file_blocks(device: &mut dyn BlockDevice, inode: &mut Inode) -> Result<(), FsError> {
+    if inode.is_fast_symlink() {
+        // The "pointers" are the link target, not blocks
+        inode.set_inline_data(&[]);
+        inode.size = 0;
+        return Ok(());
+    }
+    for ptr in inode.direct_ptrs.iter_mut() {
+        if *ptr != 0 {
+            release_data_block(device, *ptr, inode.uid)?;
+            *ptr = 0;
+        }
+    }
+    if inode.indirect_ptr != 0 {
+        let mut ind = [0u8; BLOCK_SIZE];
+        device.read_block(inode.indirect_ptr, &mut ind)?;
+        for i in 0..PTRS_PER_BLOCK {
+            let block = get_u32(&ind, i * 4);
+            if block != 0 {
+                release_data_block(device, block, inode.uid)?;
+            }
+        }
+        release_data_block(device, inode.indirect_ptr, inode.uid)?;
+        inode.indirect_ptr = 0;
+    }
+    inode.size = 0;
+    Ok(())
+}
+
+/// Reads up to `buf.len()` bytes starting at `offset`, returns the count read
+pub fn read_at(device: &dyn BlockDevice, inode: &Inode, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    let size = inode.size as usize;
+    let mut done = 0;
+    while done < buf.len() && offset + done < size {
+        let pos = offset + done;
+        let in_block = pos % BLOCK_SIZE;
+        let len = (buf.len() - done).min(BLOCK_SIZE - in_block).min(size - pos);
+        let block = file_block(device, inode, pos / BLOCK_SIZE)?;
         if block == 0 {
-            continue; // skip unused blocks
+            buf[done..done + len].fill(0);
+        } else {
+            let mut tmp = [0u8; BLOCK_SIZE];
+            device.read_block(block, &mut tmp)?;
+            buf[done..done + len].copy_from_slice(&tmp[in_block..in_block + len]);
         }
+        done += len;
+    }
+    Ok(done)
+}
 
-        let mut buf = [0u8; 512];
-        device.read_block(block, &mut buf);
+pub fn read_file(device: &dyn BlockDevice, inode: &Inode, buf: &mut [u8]) -> Result<usize, FsError> {
+    read_at(device, inode, 0, buf)
+}
 
-        let entry_count = 512 / core::mem::size_of::<DirEntry>();
-        let entries =
-            unsafe { core::slice::from_raw_parts(buf.as_ptr() as *const DirEntry, entry_count) };
+/// Appends `data` to the end of file `ino`
+pub fn append_file(device: &mut dyn BlockDevice, ino: u32, data: &[u8]) -> Result<(), FsError> {
+    let size = read_inode(device, ino)?.size as usize;
+    write_at(device, ino, size, data)
+}
 
-        for entry in entries {
-            if entry.inode_index == 0 {
-                continue; // empty entry
+/// Writes `data` into file `ino` at `offset`, growing the file as needed.
+/// A gap between the old end and `offset` is left as a hole: no blocks are
+/// allocated for it and it reads as zeros.
+pub fn write_at(device: &mut dyn BlockDevice, ino: u32, offset: usize, data: &[u8]) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    if inode.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    if inode.is_symlink() {
+        return Err(FsError::InvalidArgument);
+    }
+    if offset > MAX_FILE_BLOCKS * BLOCK_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    let end = offset + data.len();
+    let size = inode.size as usize;
+    // Bytes past the end of the last block may predate a shrink; the gap
+    // must read as zeros
+    if offset > size && !size.is_multiple_of(BLOCK_SIZE) {
+        let block = file_block(device, &inode, size / BLOCK_SIZE)?;
+        if block != 0 {
+            let mut tmp = [0u8; BLOCK_SIZE];
+            device.read_block(block, &mut tmp)?;
+            tmp[size % BLOCK_SIZE..].fill(0);
+            device.write_block(block, &tmp)?;
+        }
+    }
+    inode.size = inode.size.max(offset as u32);
+    let mut pos = offset;
+    let mut result = Ok(());
+    while pos < end {
+        let in_block = pos % BLOCK_SIZE;
+        let len = (end - pos).min(BLOCK_SIZE - in_block);
+        let block = match file_block_alloc(device, &mut inode, pos / BLOCK_SIZE) {
+            Ok(b) => b,
+            Err(e) => {
+                result = Err(e);
+                break;
             }
+        };
+        let mut tmp = [0u8; BLOCK_SIZE];
+        let copied = device.read_block(block, &mut tmp).and_then(|()| {
+            tmp[in_block..in_block + len].copy_from_slice(&data[pos - offset..pos - offset + len]);
+            device.write_block(block, &tmp)
+        });
+        if let Err(e) = copied {
+            result = Err(e.into());
+            break;
+        }
+        pos += len;
+        inode.size = inode.size.max(pos as u32);
+    }
+    inode.mtime = clock::now();
+    write_inode(device, ino, &inode)?;
+    result
+}
 
-            // Print the name manually (no Vec, no String, just char output)
-            let mut i = 0;
-            while i < entry.name.len() {
-                let byte = entry.name[i];
-                if byte == 0 {
-                    break;
-                }
-                poprint!("{}", byte as char); // assuming you have a working `print!`
-                i += 1;
+/// Turns `len` bytes of file `ino` from `offset` into a hole, keeping its
+/// size. Blocks wholly inside the range are released; the parts of blocks
+/// at either edge are zeroed.
+pub fn punch_hole(device: &mut dyn BlockDevice, ino: u32, offset: usize, len: usize) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    if inode.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    if inode.is_symlink() {
+        return Err(FsError::InvalidArgument);
+    }
+    let size = inode.size as usize;
+    let end = offset.saturating_add(len).min(size);
+    if offset >= end {
+        return Ok(());
+    }
+    // Whole blocks from `first` up to `last`; one holding the end of the
+    // file has nothing to keep past it
+    let first = offset.div_ceil(BLOCK_SIZE);
+    let last = if end == size { end.div_ceil(BLOCK_SIZE) } else { end / BLOCK_SIZE };
+    for (from, to) in [(offset, end.min(first * BLOCK_SIZE)), (offset.max(last * BLOCK_SIZE), end)] {
+        if from >= to {
+            continue;
+        }
+        let block = file_block(device, &inode, from / BLOCK_SIZE)?;
+        if block != 0 {
+            let mut tmp = [0u8; BLOCK_SIZE];
+            device.read_block(block, &mut tmp)?;
+            tmp[from % BLOCK_SIZE..(to - 1) % BLOCK_SIZE + 1].fill(0);
+            device.write_block(block, &tmp)?;
+        }
+    }
+    for ptr in inode.direct_ptrs.iter_mut().take(last.min(12)).skip(first) {
+        if *ptr != 0 {
+            release_data_block(device, *ptr, inode.uid)?;
+            *ptr = 0;
+        }
+    }
+    if inode.indirect_ptr != 0 && last > 12 {
+        let mut ind = [0u8; BLOCK_SIZE];
+        device.read_block(inode.indirect_ptr, &mut ind)?;
+        for i in first.saturating_sub(12)..last - 12 {
+            let block = get_u32(&ind, i * 4);
+            if block != 0 {
+                release_data_block(device, block, inode.uid)?;
+                put_u32(&mut ind, i * 4, 0);
             }
-            poprint!("\n"); // newline after each entry
+        }
+        if (0..PTRS_PER_BLOCK).all(|i| get_u32(&ind, i * 4) == 0) {
+            release_data_block(device, inode.indirect_ptr, inode.uid)?;
+            inode.indirect_ptr = 0;
+        } else {
+            device.write_block(inode.indirect_ptr, &ind)?;
         }
     }
+    inode.mtime = clock::now();
+    write_inode(device, ino, &inode)
 }
-pub fn ls(device: &dyn BlockDevice) {
-    // Read the directory block (we reserve block 2 for DirEntry[])
-    let mut dir_buf = [0u8; 512];
-    device.read_block(2, &mut dir_buf);
 
-    let entry_size = core::mem::size_of::<DirEntry>();
-    let max_entries = 512 / entry_size;
+/// Shrinks file `ino` to `size` bytes, releasing the blocks past the new end,
+/// or grows it with a hole
+pub fn truncate_file(device: &mut dyn BlockDevice, ino: u32, size: usize) -> Result<(), FsError> {
+    let mut inode = read_inode(device, ino)?;
+    if inode.is_dir() {
+        return Err(FsError::IsDir);
+    }
+    if inode.is_symlink() {
+        return Err(FsError::InvalidArgument);
+    }
+    if size >= inode.size as usize {
+        return write_at(device, ino, size, &[]);
+    }
+    if size == 0 {
+        free_file_blocks(device, &mut inode)?;
+        inode.mtime = clock::now();
+        return write_inode(device, ino, &inode);
+    }
 
-    // Header
-    poprint!("{:<6}  {}\n", "Type", "Name");
-    poprint!("{:-<6}  {:-<28}\n", "", "");
+    // Zero the tail of the last kept block, so growing the file again
+    // reads zeros there
+    if !size.is_multiple_of(BLOCK_SIZE) {
+        let block = file_block(device, &inode, size / BLOCK_SIZE)?;
+        if block != 0 {
+            let mut tmp = [0u8; BLOCK_SIZE];
+            device.read_block(block, &mut tmp)?;
+            tmp[size % BLOCK_SIZE..].fill(0);
+            device.write_block(block, &tmp)?;
+        }
+    }
+    let keep = size.div_ceil(BLOCK_SIZE);
+    for ptr in inode.direct_ptrs.iter_mut().skip(keep) {
+        if *ptr != 0 {
+            release_data_block(device, *ptr, inode.uid)?;
+            *ptr = 0;
+        }
+    }
+    if inode.indirect_ptr != 0 {
+        let first = keep.saturating_sub(12);
+        let mut ind = [0u8; BLOCK_SIZE];
+        device.read_block(inode.indirect_ptr, &mut ind)?;
+        for i in first..PTRS_PER_BLOCK {
+            let block = get_u32(&ind, i * 4);
+            if block != 0 {
+                release_data_block(device, block, inode.uid)?;
+                put_u32(&mut ind, i * 4, 0);
+            }
+        }
+        if first == 0 {
+            release_data_block(device, inode.indirect_ptr, inode.uid)?;
+            inode.indirect_ptr = 0;
+        } else {
+            device.write_block(inode.indirect_ptr, &ind)?;
+        }
+    }
+    inode.size = size as u32;
+    inode.mtime = clock::now();
+    write_inode(device, ino, &inode)
+}
 
-    // For each entry, if in use, look up its inode to get mode
-    for i in 0..max_entries {
-        let base = i * entry_size;
-        let entry: &DirEntry = unsafe {
-            &*(dir_buf[base..].as_ptr() as *const DirEntry)
-        };
+/// Copies a symlink's target into `buf`, returning its length
+pub fn read_symlink(device: &dyn BlockDevice, inode: &Inode, buf: &mut [u8]) -> Result<usize, FsError> {
+    if inode.is_fast_symlink() {
+        let len = (inode.size as usize).min(buf.len());
+        buf[..len].copy_from_slice(&inode.inline_data()[..len]);
+        return Ok(len);
+    }
+    read_at(device, inode, 0, buf)
+}
+
+/// Stores a symlink target in a fresh symlink inode, inline when it fits
+pub fn write_symlink(device: &mut dyn BlockDevice, inode: &mut Inode, target: &[u8]) -> Result<(), FsError> {
+    if target.len() <= FAST_SYMLINK_MAX {
+        inode.set_inline_data(target);
+    } else {
+        let block = file_block_alloc(device, inode, 0)?;
+        let mut tmp = [0u8; BLOCK_SIZE];
+        tmp[..target.len()].copy_from_slice(target);
+        device.write_block(block, &tmp)?;
+    }
+    inode.size = target.len() as u32;
+    Ok(())
+}
+
+/// Reads directory block `block`, verifying its checksum
+pub fn read_dir_block(device: &dyn BlockDevice, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    device.read_block(block, buf)?;
+    if !block_checksum_ok(buf) {
+        return Err(FsError::Corrupt(block));
+    }
+    Ok(())
+}
 
-        if entry.inode_index == 0 {
+/// Calls `f(block, slot, entry)` for every slot of a directory, used or not,
+/// stopping early when it returns true
+pub fn scan_dir(
+    device: &dyn BlockDevice,
+    dir: &Inode,
+    f: &mut dyn FnMut(u32, usize, &DirEntry) -> bool,
+) -> Result<bool, FsError> {
+    let blocks = (dir.size as usize).div_ceil(BLOCK_SIZE);
+    for index in 0..blocks {
+        let block = file_block(device, dir, index)?;
+        if block == 0 {
             continue;
         }
+        let mut buf = [0u8; BLOCK_SIZE];
+        read_dir_block(device, block, &mut buf)?;
+        for slot in 0..ENTRIES_PER_BLOCK {
+            let entry = DirEntry::decode(&buf[slot * ENTRY_SIZE..]);
+            if f(block, slot, &entry) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
 
-        // Extract name (NUL‐terminated)
-        let name_len = entry.name.iter().position(|&b| b == 0).unwrap_or(28);
-        let name = str::from_utf8(&entry.name[..name_len]).unwrap_or("<invalid>");
+pub fn write_entry(device: &mut dyn BlockDevice, block: u32, slot: usize, entry: &DirEntry) -> Result<(), FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    read_dir_block(device, block, &mut buf)?;
+    entry.encode(&mut buf[slot * ENTRY_SIZE..(slot + 1) * ENTRY_SIZE]);
+    seal_block(&mut buf);
+    device.write_block(block, &buf)?;
+    Ok(())
+}
 
-        // Read the corresponding inode to check directory bit
-        let mut inode_buf = [0u8; 512];
-        device.read_block(0, &mut inode_buf);
-        let inode_offset = (entry.inode_index as usize - 1) * core::mem::size_of::<Inode>();
-        let inode: &Inode = unsafe {
-            &*(inode_buf[inode_offset..].as_ptr() as *const Inode)
-        };
+/// Writes an empty, sealed directory block
+fn clear_dir_block(device: &mut dyn BlockDevice, block: u32) -> Result<(), FsError> {
+    let mut buf = [0u8; BLOCK_SIZE];
+    seal_block(&mut buf);
+    device.write_block(block, &buf)?;
+    Ok(())
+}
+
+/// Calls `f` for every used entry of a directory
+pub fn for_each_entry(device: &dyn BlockDevice, dir: &Inode, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FsError> {
+    scan_dir(device, dir, &mut |_, _, e| {
+        if e.inode_index != 0 {
+            f(e);
+        }
+        false
+    })?;
+    Ok(())
+}
+
+/// Finds `name` in a directory, returning (block, slot, inode number)
+fn find_entry(device: &dyn BlockDevice, dir: &Inode, name: &str) -> Result<Option<(u32, usize, u32)>, FsError> {
+    let mut found = None;
+    scan_dir(device, dir, &mut |block, slot, e| {
+        if e.inode_index != 0 && e.name_bytes() == name.as_bytes() {
+            found = Some((block, slot, e.inode_index));
+            return true;
+        }
+        false
+    })?;
+    Ok(found)
+}
+
+pub fn dir_lookup(device: &dyn BlockDevice, dir_ino: u32, name: &str) -> Result<u32, FsError> {
+    let dir = read_inode(device, dir_ino)?;
+    if !dir.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    find_entry(device, &dir, name)?.map(|(_, _, ino)| ino).ok_or(FsError::NotFound)
+}
+
+/// Adds `name -> ino` to a directory, growing it by one block when full
+pub fn dir_add(device: &mut dyn BlockDevice, dir_ino: u32, name: &str, ino: u32) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > DirEntry::MAX_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    let mut dir = read_inode(device, dir_ino)?;
+    if !dir.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    if find_entry(device, &dir, name)?.is_some() {
+        return Err(FsError::Exists);
+    }
+
+    let mut free = None;
+    scan_dir(device, &dir, &mut |block, slot, e| {
+        if e.inode_index == 0 {
+            free = Some((block, slot));
+            return true;
+        }
+        false
+    })?;
+    let (block, slot) = match free {
+        Some(f) => f,
+        None => {
+            let index = (dir.size as usize).div_ceil(BLOCK_SIZE);
+            let block = file_block_alloc(device, &mut dir, index)?;
+            clear_dir_block(device, block)?;
+            dir.size = ((index + 1) * BLOCK_SIZE) as u32;
+            write_inode(device, dir_ino, &dir)?;
+            (block, 0)
+        }
+    };
+    write_entry(device, block, slot, &DirEntry::new(ino, name))?;
+    touch(device, dir_ino, &mut dir)
+}
+
+/// Records a modification of `inode` now
+fn touch(device: &mut dyn BlockDevice, ino: u32, inode: &mut Inode) -> Result<(), FsError> {
+    inode.mtime = clock::now();
+    write_inode(device, ino, inode)
+}
 
-        // Determine type
-        // On Unix, directory bit is 0o40000; here we check the high bit of mode:
-        let is_dir = (inode.mode & 0o40000) != 0;
-        let kind = if is_dir { "dir" } else { "file" };
+/// Points an existing entry at another inode, returning the one it replaced
+pub fn dir_set(device: &mut dyn BlockDevice, dir_ino: u32, name: &str, ino: u32) -> Result<u32, FsError> {
+    let mut dir = read_inode(device, dir_ino)?;
+    if !dir.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    let (block, slot, old) = find_entry(device, &dir, name)?.ok_or(FsError::NotFound)?;
+    write_entry(device, block, slot, &DirEntry::new(ino, name))?;
+    touch(device, dir_ino, &mut dir)?;
+    Ok(old)
+}
 
-        // Print
-        poprint!("{:<6}  {}\n", kind, name);
+/// Clears the entry `name`, returning the inode number it pointed at
+pub fn dir_remove(device: &mut dyn BlockDevice, dir_ino: u32, name: &str) -> Result<u32, FsError> {
+    let mut dir = read_inode(device, dir_ino)?;
+    if !dir.is_dir() {
+        return Err(FsError::NotDir);
     }
-}
\ No newline at end of file
+    let (block, slot, old) = find_entry(device, &dir, name)?.ok_or(FsError::NotFound)?;
+    write_entry(device, block, slot, &DirEntry::new(0, ""))?;
+    touch(device, dir_ino, &mut dir)?;
+    Ok(old)
+}
+
+/// True when a directory holds nothing but "." and ".."
+pub fn dir_is_empty(device: &dyn BlockDevice, dir: &Inode) -> Result<bool, FsError> {
+    let other = scan_dir(device, dir, &mut |_, _, e| {
+        e.inode_index != 0 && e.name_bytes() != b"." && e.name_bytes() != b".."
+    })?;
+    Ok(!other)
+}
+
+/// Gives a freshly allocated directory inode its "." and ".." entries
+pub fn init_dir(device: &mut dyn BlockDevice, ino: u32, parent: u32) -> Result<(), FsError> {
+    let mut dir = read_inode(device, ino)?;
+    let block = file_block_alloc(device, &mut dir, 0)?;
+    clear_dir_block(device, block)?;
+    dir.size = BLOCK_SIZE as u32;
+    write_entry(device, block, 0, &DirEntry::new(ino, "."))?;
+    write_entry(device, block, 1, &DirEntry::new(parent, ".."))?;
+    write_inode(device, ino, &dir)
+}
+
+pub fn list_dir(device: &dyn BlockDevice, dir_inode: &Inode) -> Result<(), FsError> {
+    for_each_entry(device, dir_inode, &mut |entry| {
+        // Print the name manually (no Vec, no String, just char output)
+        for &byte in entry.name_bytes() {
+            poprint!("{}", byte as char);
+        }
+        poprint!("\n"); // newline after each entry
+    })
+}
+
+/// `ls -l` style rendering of a mode, e.g. `drwxr-xr-x`
+pub fn mode_string(mode: u16) -> [u8; 10] {
+    let mut out = *b"----------";
+    out[0] = match mode & S_IFMT {
+        S_IFDIR => b'd',
+        S_IFLNK => b'l',
+        S_IFCHR => b'c',
+        S_IFBLK => b'b',
+        _ => b'-',
+    };
+    for (i, &c) in b"rwxrwxrwx".iter().enumerate() {
+        if mode & (0o400 >> i) != 0 {
+            out[1 + i] = c;
+        }
+    }
+    // set-uid, set-gid and sticky show in the execute columns
+    for (col, bit, c) in [(3, 0o4000, b's'), (6, 0o2000, b's'), (9, 0o1000, b't')] {
+        if mode & bit != 0 {
+            out[col] = if out[col] == b'x' { c } else { c.to_ascii_uppercase() };
+        }
+    }
+    out
+}
+
+/// Lists a directory; `long` adds mode bits, owner, size and mtime
+pub fn ls(device: &dyn BlockDevice, dir_ino: u32, long: bool) -> Result<(), FsError> {
+    let dir = read_inode(device, dir_ino)?;
+
+    // Header
+    if !long {
+        poprint!("{:<6} {:>5}  {}\n", "Type", "Links", "Name");
+        poprint!("{:-<6} {:->5}  {:-<28}\n", "", "", "");
+    }
+
+    // For each entry, if in use, look up its inode to get mode
+    let mut result = Ok(());
+    for_each_entry(device, &dir, &mut |entry| {
+        let name = str::from_utf8(entry.name_bytes()).unwrap_or("<invalid>");
+        let inode = match read_inode(device, entry.inode_index) {
+            Ok(inode) => inode,
+            Err(e) => {
+                poprint!("?      {:>5}  {}\n", "?", name);
+                result = Err(e);
+                return;
+            }
+        };
+
+        if long {
+            let blocks = match allocated_blocks(device, &inode) {
+                Ok(n) => n,
+                Err(e) => {
+                    result = Err(e);
+                    0
+                }
+            };
+            let mode = mode_string(inode.mode);
+            poprint!(
+                "{:>5} {} {:>3} {:>5} {:>5} {:>8} {}  {}",
+                blocks.div_ceil(2),
+                str::from_utf8(&mode).unwrap_or("?"),
+                inode.links,
+                inode.uid,
+                inode.gid,
+                inode.size,
+                DateTime::from_unix(inode.mtime),
+                name
+            );
+        } else {
+            // Determine type
+            let kind = if inode.is_dir() {
+                "dir"
+            } else if inode.is_symlink() {
+                "link"
+            } else {
+                "file"
+            };
+            poprint!("{:<6} {:>5}  {}", kind, inode.links, name);
+        }
+        if inode.is_symlink() {
+            let mut target = [0u8; BLOCK_SIZE];
+            match read_symlink(device, &inode, &mut target) {
+                Ok(n) => poprint!(" -> {}", str::from_utf8(&target[..n]).unwrap_or("<invalid>")),
+                Err(e) => result = Err(e),
+            }
+        }
+        poprint!("\n");
+    })?;
+    result
+}
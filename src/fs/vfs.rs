@@ -0,0 +1,718 @@
+//! Virtual filesystem.
+//!
+//! A filesystem plugs in by implementing `FileSystem`, which brings in the
+//! directory and metadata operations of `InodeOps` and the contents
+//! operations of `FileOps`. They name inodes by their number within the
+//! filesystem; a file anywhere in the tree is a `Loc`, the index of its
+//! mount in the mount table and that number.
+//!
+//! `Vfs` owns the mount table and walks paths through it. A component
+//! naming a directory something is mounted on leads to the root of that
+//! mount, and `..` at the root of a mount leads to the parent of the
+//! directory it is mounted on. Symlinks are followed through the whole
+//! tree, so an absolute target starts again at `/`.
+//!
+//! Permissions are checked here, against the mode and owner a filesystem
+//! reports from `stat`, before an operation reaches the filesystem: search
+//! on every directory walked, write and search on a directory whose entries
+//! change, and read or write on a file being opened. Filesystems only carry
+//! the operations out, using the caller's credentials for the owner of new
+//! inodes.
+//!
+//! Open files are descriptions in a fixed table, each with its own offset,
+//! referred to by index (`Fd`). A filesystem with open files cannot be
+//! unmounted.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use alloc::boxed::Box;
+
+use super::cache::CacheStats;
+use super::error::FsError;
+use super::mount::{Mount, MountTable};
+use super::ops::{MAX_SYMLINKS, MAX_SYMLINK_LEN};
+use super::perm::{access, owner_or_root, Cred, MAY_EXEC, MAY_READ, MAY_WRITE};
+use super::quota::{Grace, Limits, Quota};
+use super::structure::{S_IFDIR, S_IFLNK, S_IFMT};
+use super::xattr::Xattr;
+
+/// Files open at once, across all filesystems
+pub const MAX_OPEN_FILES: usize = 16;
+
+/// Flags of `Vfs::open`
+pub const O_READ: u16 = 0o1;
+pub const O_WRITE: u16 = 0o2;
+pub const O_APPEND: u16 = 0o4; // every write goes to the end of the file
+pub const O_CREATE: u16 = 0o10; // create a missing regular file
+pub const O_TRUNC: u16 = 0o20; // empty the file; needs `O_WRITE`
+
+/// Index of an open file description
+pub type Fd = usize;
+
+/// Attributes of an inode, whatever filesystem it is on
+#[derive(Clone, Copy)]
+pub struct Stat {
+    pub ino: u32,
+    pub mode: u16, // file type + permissions, as `Inode::mode`
+    pub links: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u64,
+    pub blocks: u64, // 512-byte units allocated; less than `size` for sparse files
+    pub mtime: u32, // seconds since the epoch
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Directory and metadata operations. Those that change something fail
+/// with `Unsupported` unless the filesystem provides them.
+pub trait InodeOps {
+    /// Attributes of inode `ino`
+    fn stat(&self, ino: u32) -> Result<Stat, FsError>;
+
+    /// The inode named `name` in directory `dir`. `..` of the root is the
+    /// root itself; the VFS handles `..` across mount points.
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError>;
+
+    /// Calls `f(name, ino)` for every entry of directory `dir`
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError>;
+
+    /// Copies the target of symlink `ino` into `buf`, returning its length
+    fn readlink(&self, _ino: u32, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::InvalidPath)
+    }
+
+    /// Creates an empty regular file `name` in `dir`
+    fn create(&mut self, _cred: &Cred, _dir: u32, _name: &str, _perm: u16) -> Result<u32, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn mkdir(&mut self, _cred: &Cred, _dir: u32, _name: &str, _perm: u16) -> Result<u32, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn symlink(&mut self, _cred: &Cred, _dir: u32, _name: &str, _target: &str) -> Result<u32, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Adds the name `name` in `dir` for non‑directory `ino`
+    fn link(&mut self, _ino: u32, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Removes non‑directory entry `name` from `dir`
+    fn unlink(&mut self, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Removes empty directory `name` from `dir`
+    fn rmdir(&mut self, _dir: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Moves `from_dir/from` to `to_dir/to`, replacing a non‑directory or
+    /// empty directory there
+    fn rename(&mut self, _from_dir: u32, _from: &str, _to_dir: u32, _to: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Sets the permission bits of `ino`, keeping its type
+    fn chmod(&mut self, _ino: u32, _mode: u16) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn chown(&mut self, _ino: u32, _uid: Option<u16>, _gid: Option<u16>) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Extended attributes; namespace rules are the filesystem's, so these
+    /// take the caller's credentials
+    fn get_xattr(&self, _cred: &Cred, _ino: u32, _name: &str, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn set_xattr(&mut self, _cred: &Cred, _ino: u32, _name: &str, _value: &[u8]) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn remove_xattr(&mut self, _cred: &Cred, _ino: u32, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn list_xattr(&self, _cred: &Cred, _ino: u32, _f: &mut dyn FnMut(&Xattr)) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// Operations on the contents of a regular file
+pub trait FileOps {
+    /// Reads from file `ino` at `offset`, returning the bytes read; 0 at the
+    /// end of the file
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `data` at `offset`, growing the file as needed, and returns the
+    /// bytes written
+    fn write(&mut self, _ino: u32, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Shrinks or grows file `ino` to `size` bytes
+    fn truncate(&mut self, _ino: u32, _size: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Deallocates `len` bytes of file `ino` from `offset`, which then read
+    /// as zeros; the size does not change
+    fn punch_hole(&mut self, _ino: u32, _offset: u64, _len: u64) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+pub trait FileSystem: InodeOps + FileOps {
+    /// Filesystem type, as `mount` lists it
+    fn name(&self) -> &'static str;
+
+    /// Inode number of the root directory
+    fn root(&self) -> u32;
+
+    /// Writes all cached changes back
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Called while the system is idle, to write back changes that have
+    /// waited long enough
+    fn tick(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Checks the filesystem, repairing it with `repair`
+    fn check(&mut self, _repair: bool) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Counters of the block cache, for filesystems that have one
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+
+    /// Limits and usage of user `uid`, for filesystems with quotas
+    fn quota(&self, _uid: u16) -> Result<Quota, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Sets the limits of user `uid`; all zeros removes them
+    fn set_quota(&mut self, _uid: u16, _limits: Limits) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// How long usage may stay over a soft limit
+    fn grace(&self) -> Result<Grace, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn set_grace(&mut self, _grace: Grace) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// A file in the tree: a mount and an inode number in its filesystem
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Loc {
+    pub mount: usize,
+    pub ino: u32,
+}
+
+/// Position `Vfs::seek` moves to, as in `lseek`
+#[derive(Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An entry as `Vfs::readdir` reports it. A mount point shows the root of
+/// the filesystem mounted on it.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub stat: Option<Stat>,      // None if it could not be read
+    pub target: Option<&'a str>, // where a symlink points
+}
+
+/// An open file description
+#[derive(Clone, Copy)]
+struct OpenFile {
+    loc: Loc,
+    offset: u64,
+    flags: u16,
+}
+
+pub struct Vfs<D: ?Sized> {
+    mounts: MountTable<D>,
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+    hook: Option<fn(&Vfs<D>)>, // called when the mount table changes
+}
+
+impl<D: FileSystem + ?Sized> Default for Vfs<D> {
+    fn default() -> Vfs<D> {
+        Vfs::new()
+    }
+}
+
+impl<D: FileSystem + ?Sized> Vfs<D> {
+    pub const fn new() -> Vfs<D> {
+        Vfs { mounts: MountTable::new(), files: [None; MAX_OPEN_FILES], hook: None }
+    }
+
+    /// Has `hook` called with the tree after every mount and unmount, for
+    /// whoever keeps a view of the mount table
+    pub fn set_mount_hook(&mut self, hook: fn(&Vfs<D>)) {
+        self.hook = Some(hook);
+    }
+
+    fn mounts_changed(&self) {
+        if let Some(hook) = self.hook {
+            hook(self);
+        }
+    }
+
+    fn fs(&self, mount: usize) -> Result<&D, FsError> {
+        self.mounts.get(mount).map(|m| &*m.fs).ok_or(FsError::NotFound)
+    }
+
+    fn fs_mut(&mut self, mount: usize) -> Result<&mut D, FsError> {
+        self.mounts.get_mut(mount).map(|m| &mut *m.fs).ok_or(FsError::NotFound)
+    }
+
+    /// The root directory of the tree
+    fn root(&self) -> Result<Loc, FsError> {
+        let mount = self.mounts.root_index().ok_or(FsError::NotFound)?;
+        Ok(Loc { mount, ino: self.fs(mount)?.root() })
+    }
+
+    fn stat_at(&self, loc: Loc) -> Result<Stat, FsError> {
+        self.fs(loc.mount)?.stat(loc.ino)
+    }
+
+    /// The root of whatever is mounted on `loc`, or `loc` itself
+    fn enter(&self, mut loc: Loc) -> Result<Loc, FsError> {
+        while let Some(mount) = self.mounts.covering(loc.mount, loc.ino) {
+            loc = Loc { mount, ino: self.fs(mount)?.root() };
+        }
+        Ok(loc)
+    }
+
+    /// Parent of directory `dir`, leaving a mounted filesystem at its root
+    fn parent(&self, mut dir: Loc) -> Result<Loc, FsError> {
+        loop {
+            let fs = self.fs(dir.mount)?;
+            if dir.ino != fs.root() {
+                return Ok(Loc { mount: dir.mount, ino: fs.lookup(dir.ino, "..")? });
+            }
+            match self.mounts.get(dir.mount).and_then(|m| m.parent()) {
+                Some((mount, ino)) => dir = Loc { mount, ino },
+                None => return Ok(dir), // `/..` is `/`
+            }
+        }
+    }
+
+    /// Walks `path` starting at `dir`, or the root for absolute paths.
+    /// Symlink targets are resolved relative to the directory holding the
+    /// link; `depth` counts links followed so far across the nested walks.
+    fn walk(&self, cred: &Cred, dir: Loc, path: &str, follow_last: bool, depth: &mut u32) -> Result<Loc, FsError> {
+        let mut cur = if path.starts_with('/') { self.root()? } else { dir };
+        let mut names = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(name) = names.next() {
+            let st = self.stat_at(cur)?;
+            if !st.is_dir() {
+                return Err(FsError::NotDir);
+            }
+            access(st.mode, st.uid, st.gid, cred, MAY_EXEC)?;
+            match name {
+                "." => continue,
+                ".." => {
+                    cur = self.parent(cur)?;
+                    continue;
+                }
+                _ => {}
+            }
+            let fs = self.fs(cur.mount)?;
+            let mut next = Loc { mount: cur.mount, ino: fs.lookup(cur.ino, name)? };
+            if (follow_last || names.peek().is_some()) && fs.stat(next.ino)?.is_symlink() {
+                *depth += 1;
+                if *depth > MAX_SYMLINKS {
+                    return Err(FsError::Loop);
+                }
+                let mut buf = [0u8; MAX_SYMLINK_LEN];
+                let n = fs.readlink(next.ino, &mut buf)?;
+                let target = core::str::from_utf8(&buf[..n]).map_err(|_| FsError::InvalidPath)?;
+                next = self.walk(cred, cur, target, true, depth)?;
+            }
+            cur = self.enter(next)?;
+        }
+        Ok(cur)
+    }
+
+    /// Resolves `path` from the root, following a symlink in the last
+    /// component
+    pub fn lookup(&self, cred: &Cred, path: &str) -> Result<Loc, FsError> {
+        self.walk(cred, self.root()?, path, true, &mut 0)
+    }
+
+    /// Like `lookup`, but a symlink in the last component is returned itself
+    pub fn lookup_nofollow(&self, cred: &Cred, path: &str) -> Result<Loc, FsError> {
+        self.walk(cred, self.root()?, path, false, &mut 0)
+    }
+
+    /// Resolves everything but the last component, returning the directory
+    /// and the name, after checking that `cred` may change its entries
+    fn lookup_parent<'a>(&self, cred: &Cred, path: &'a str) -> Result<(Loc, &'a str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        let dir = self.lookup(cred, dir)?;
+        let st = self.stat_at(dir)?;
+        if !st.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        access(st.mode, st.uid, st.gid, cred, MAY_WRITE | MAY_EXEC)?;
+        Ok((dir, name))
+    }
+
+    /// Fails with `Busy` if `name` in `dir` has something mounted on it
+    fn not_mounted_on(&self, dir: Loc, name: &str) -> Result<(), FsError> {
+        let ino = self.fs(dir.mount)?.lookup(dir.ino, name)?;
+        match self.mounts.covering(dir.mount, ino) {
+            Some(_) => Err(FsError::Busy),
+            None => Ok(()),
+        }
+    }
+
+    /// Attributes of the file at `path`
+    pub fn stat(&self, cred: &Cred, path: &str) -> Result<Stat, FsError> {
+        self.stat_at(self.lookup(cred, path)?)
+    }
+
+    /// Calls `f` for every entry of the directory at `path`, returning the
+    /// first error reading one
+    pub fn readdir(&self, cred: &Cred, path: &str, f: &mut dyn FnMut(&Entry)) -> Result<(), FsError> {
+        let dir = self.lookup(cred, path)?;
+        let st = self.stat_at(dir)?;
+        if !st.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        access(st.mode, st.uid, st.gid, cred, MAY_READ)?;
+        let fs = self.fs(dir.mount)?;
+        let mut result = Ok(());
+        fs.readdir(dir.ino, &mut |name, ino| {
+            let loc = Loc { mount: dir.mount, ino };
+            let stat = match self.enter(loc).and_then(|l| self.stat_at(l)) {
+                Ok(st) => Some(st),
+                Err(e) => {
+                    result = result.and(Err(e));
+                    None
+                }
+            };
+            let mut buf = [0u8; MAX_SYMLINK_LEN];
+            let mut target = None;
+            if stat.is_some_and(|st| st.is_symlink()) {
+                match fs.readlink(ino, &mut buf) {
+                    Ok(n) => target = Some(core::str::from_utf8(&buf[..n]).unwrap_or("<invalid>")),
+                    Err(e) => result = result.and(Err(e)),
+                }
+            }
+            f(&Entry { name, stat, target });
+        })?;
+        result
+    }
+
+    /// Creates an empty regular file
+    pub fn create(&mut self, cred: &Cred, path: &str, perm: u16) -> Result<Loc, FsError> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        let ino = self.fs_mut(dir.mount)?.create(cred, dir.ino, name, perm)?;
+        Ok(Loc { mount: dir.mount, ino })
+    }
+
+    pub fn mkdir(&mut self, cred: &Cred, path: &str, perm: u16) -> Result<Loc, FsError> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        let ino = self.fs_mut(dir.mount)?.mkdir(cred, dir.ino, name, perm)?;
+        Ok(Loc { mount: dir.mount, ino })
+    }
+
+    /// Creates a symbolic link at `path` pointing at `target`
+    pub fn symlink(&mut self, cred: &Cred, target: &str, path: &str) -> Result<Loc, FsError> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        let ino = self.fs_mut(dir.mount)?.symlink(cred, dir.ino, name, target)?;
+        Ok(Loc { mount: dir.mount, ino })
+    }
+
+    /// Copies the target of the symlink at `path` into `buf`
+    pub fn readlink(&self, cred: &Cred, path: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+        let loc = self.lookup_nofollow(cred, path)?;
+        if !self.stat_at(loc)?.is_symlink() {
+            return Err(FsError::InvalidPath);
+        }
+        self.fs(loc.mount)?.readlink(loc.ino, buf)
+    }
+
+    /// Adds the name `new` for the non‑directory at `existing`, which must
+    /// be on the same filesystem
+    pub fn link(&mut self, cred: &Cred, existing: &str, new: &str) -> Result<(), FsError> {
+        let src = self.lookup_nofollow(cred, existing)?;
+        let (dir, name) = self.lookup_parent(cred, new)?;
+        if src.mount != dir.mount {
+            return Err(FsError::CrossDevice);
+        }
+        self.fs_mut(dir.mount)?.link(src.ino, dir.ino, name)
+    }
+
+    /// Removes a name; the file goes away with its last name
+    pub fn unlink(&mut self, cred: &Cred, path: &str) -> Result<(), FsError> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        self.fs_mut(dir.mount)?.unlink(dir.ino, name)
+    }
+
+    /// Removes an empty directory that nothing is mounted on
+    pub fn rmdir(&mut self, cred: &Cred, path: &str) -> Result<(), FsError> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        self.not_mounted_on(dir, name)?;
+        self.fs_mut(dir.mount)?.rmdir(dir.ino, name)
+    }
+
+    /// Moves `from` to `to` within one filesystem. If `to` names an existing
+    /// directory the source is moved into it under its current name.
+    pub fn rename(&mut self, cred: &Cred, from: &str, to: &str) -> Result<(), FsError> {
+        let (src_dir, src_name) = self.lookup_parent(cred, from)?;
+        let src = Loc { mount: src_dir.mount, ino: self.fs(src_dir.mount)?.lookup(src_dir.ino, src_name)? };
+        let st = self.stat_at(src)?;
+        let (dst_dir, dst_name) = match self.lookup(cred, to) {
+            Ok(t) if t != src && self.stat_at(t)?.is_dir() => {
+                let t_st = self.stat_at(t)?;
+                access(t_st.mode, t_st.uid, t_st.gid, cred, MAY_WRITE | MAY_EXEC)?;
+                (t, src_name)
+            }
+            _ => self.lookup_parent(cred, to)?,
+        };
+        if src_dir.mount != dst_dir.mount {
+            return Err(FsError::CrossDevice);
+        }
+        if st.is_dir() && src_dir != dst_dir {
+            // Its ".." entry is rewritten
+            access(st.mode, st.uid, st.gid, cred, MAY_WRITE)?;
+        }
+        self.not_mounted_on(src_dir, src_name)?;
+        match self.not_mounted_on(dst_dir, dst_name) {
+            Ok(()) | Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        self.fs_mut(src_dir.mount)?.rename(src_dir.ino, src_name, dst_dir.ino, dst_name)
+    }
+
+    /// Changes the permission bits of `path` (owner or root)
+    pub fn chmod(&mut self, cred: &Cred, path: &str, mode: u16) -> Result<(), FsError> {
+        let loc = self.lookup(cred, path)?;
+        owner_or_root(self.stat_at(loc)?.uid, cred)?;
+        self.fs_mut(loc.mount)?.chmod(loc.ino, mode)
+    }
+
+    /// Changes the owner and/or group of `path` (root only)
+    pub fn chown(&mut self, cred: &Cred, path: &str, uid: Option<u16>, gid: Option<u16>) -> Result<(), FsError> {
+        let loc = self.lookup_nofollow(cred, path)?;
+        if !cred.is_root() {
+            return Err(FsError::NotPermitted);
+        }
+        self.fs_mut(loc.mount)?.chown(loc.ino, uid, gid)
+    }
+
+    pub fn get_xattr(&self, cred: &Cred, path: &str, name: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+        let loc = self.lookup(cred, path)?;
+        self.fs(loc.mount)?.get_xattr(cred, loc.ino, name, buf)
+    }
+
+    pub fn set_xattr(&mut self, cred: &Cred, path: &str, name: &str, value: &[u8]) -> Result<(), FsError> {
+        let loc = self.lookup(cred, path)?;
+        self.fs_mut(loc.mount)?.set_xattr(cred, loc.ino, name, value)
+    }
+
+    pub fn remove_xattr(&mut self, cred: &Cred, path: &str, name: &str) -> Result<(), FsError> {
+        let loc = self.lookup(cred, path)?;
+        self.fs_mut(loc.mount)?.remove_xattr(cred, loc.ino, name)
+    }
+
+    pub fn list_xattr(&self, cred: &Cred, path: &str, f: &mut dyn FnMut(&Xattr)) -> Result<(), FsError> {
+        let loc = self.lookup(cred, path)?;
+        self.fs(loc.mount)?.list_xattr(cred, loc.ino, f)
+    }
+
+    /// Opens the file at `path` with `O_*` `flags`, creating it with `perm`
+    /// under `O_CREATE`. Directories cannot be opened.
+    pub fn open(&mut self, cred: &Cred, path: &str, flags: u16, perm: u16) -> Result<Fd, FsError> {
+        let (loc, created) = match self.lookup(cred, path) {
+            Ok(loc) => (loc, false),
+            Err(FsError::NotFound) if flags & O_CREATE != 0 => (self.create(cred, path, perm)?, true),
+            Err(e) => return Err(e),
+        };
+        let st = self.stat_at(loc)?;
+        if st.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        let mut mask = 0;
+        if flags & O_READ != 0 {
+            mask |= MAY_READ;
+        }
+        if flags & O_WRITE != 0 {
+            mask |= MAY_WRITE;
+        }
+        if !created {
+            access(st.mode, st.uid, st.gid, cred, mask)?;
+        }
+        let fd = self.files.iter().position(|f| f.is_none()).ok_or(FsError::TooManyFiles)?;
+        if flags & O_TRUNC != 0 && flags & O_WRITE != 0 && st.size != 0 {
+            self.fs_mut(loc.mount)?.truncate(loc.ino, 0)?;
+        }
+        self.files[fd] = Some(OpenFile { loc, offset: 0, flags });
+        Ok(fd)
+    }
+
+    fn file(&mut self, fd: Fd) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(fd).and_then(|f| f.as_mut()).ok_or(FsError::BadFd)
+    }
+
+    /// Reads from the current offset of `fd`, advancing it
+    pub fn read(&mut self, fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = *self.file(fd)?;
+        if file.flags & O_READ == 0 {
+            return Err(FsError::BadFd);
+        }
+        let n = self.fs(file.loc.mount)?.read(file.loc.ino, file.offset, buf)?;
+        self.file(fd)?.offset += n as u64;
+        Ok(n)
+    }
+
+    /// Writes at the current offset of `fd`, or at the end of the file
+    /// under `O_APPEND`, advancing the offset past the data
+    pub fn write(&mut self, fd: Fd, data: &[u8]) -> Result<usize, FsError> {
+        let file = *self.file(fd)?;
+        if file.flags & O_WRITE == 0 {
+            return Err(FsError::BadFd);
+        }
+        let offset = if file.flags & O_APPEND != 0 { self.stat_at(file.loc)?.size } else { file.offset };
+        let n = self.fs_mut(file.loc.mount)?.write(file.loc.ino, offset, data)?;
+        self.file(fd)?.offset = offset + n as u64;
+        Ok(n)
+    }
+
+    /// Shrinks or grows the file at `path` to `size` bytes
+    pub fn truncate(&mut self, cred: &Cred, path: &str, size: u64) -> Result<(), FsError> {
+        let loc = self.writable(cred, path)?;
+        self.fs_mut(loc.mount)?.truncate(loc.ino, size)
+    }
+
+    /// Turns `len` bytes of the file at `path` from `offset` into a hole
+    pub fn punch_hole(&mut self, cred: &Cred, path: &str, offset: u64, len: u64) -> Result<(), FsError> {
+        let loc = self.writable(cred, path)?;
+        self.fs_mut(loc.mount)?.punch_hole(loc.ino, offset, len)
+    }
+
+    /// The file at `path`, once `cred` may write it
+    fn writable(&self, cred: &Cred, path: &str) -> Result<Loc, FsError> {
+        let loc = self.lookup(cred, path)?;
+        let st = self.stat_at(loc)?;
+        if st.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        access(st.mode, st.uid, st.gid, cred, MAY_WRITE)?;
+        Ok(loc)
+    }
+
+    /// Moves the offset of `fd`, returning the new one
+    pub fn seek(&mut self, fd: Fd, pos: SeekFrom) -> Result<u64, FsError> {
+        let file = *self.file(fd)?;
+        let offset = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => file.offset.checked_add_signed(d),
+            SeekFrom::End(d) => self.stat_at(file.loc)?.size.checked_add_signed(d),
+        };
+        let offset = offset.ok_or(FsError::InvalidArgument)?;
+        self.file(fd)?.offset = offset;
+        Ok(offset)
+    }
+
+    /// Attributes of the file open as `fd`
+    pub fn fstat(&mut self, fd: Fd) -> Result<Stat, FsError> {
+        let file = *self.file(fd)?;
+        self.stat_at(file.loc)
+    }
+
+    pub fn close(&mut self, fd: Fd) -> Result<(), FsError> {
+        self.files.get_mut(fd).and_then(|f| f.take()).map(|_| ()).ok_or(FsError::BadFd)
+    }
+
+    /// Mounts `fs`, read from `source`, on the directory at `path`. The
+    /// first filesystem mounted must go on `/` and becomes the root; later
+    /// ones cannot cover another mount.
+    pub fn mount(&mut self, cred: &Cred, path: &str, source: &str, fs: Box<D>) -> Result<(), FsError> {
+        let at = match self.mounts.root_index() {
+            None if path.split('/').all(|c| c.is_empty() || c == ".") => None,
+            None => return Err(FsError::NotFound),
+            Some(_) => {
+                let loc = self.lookup(cred, path)?;
+                if !self.stat_at(loc)?.is_dir() {
+                    return Err(FsError::NotDir);
+                }
+                if loc.ino == self.fs(loc.mount)?.root() {
+                    // Already a mount point, or the root
+                    return Err(FsError::Busy);
+                }
+                Some((loc.mount, loc.ino))
+            }
+        };
+        self.mounts.mount(path, source, at, fs)?;
+        self.mounts_changed();
+        Ok(())
+    }
+
+    /// Syncs and unmounts the filesystem mounted at `path`, returning it
+    pub fn unmount(&mut self, cred: &Cred, path: &str) -> Result<Box<D>, FsError> {
+        let loc = self.lookup(cred, path)?;
+        if loc.ino != self.fs(loc.mount)?.root() {
+            return Err(FsError::InvalidPath);
+        }
+        if self.files.iter().flatten().any(|f| f.loc.mount == loc.mount) {
+            return Err(FsError::Busy);
+        }
+        self.fs_mut(loc.mount)?.sync()?;
+        let fs = self.mounts.unmount(loc.mount)?;
+        self.mounts_changed();
+        Ok(fs)
+    }
+
+    /// The filesystem holding `path`
+    pub fn filesystem(&mut self, cred: &Cred, path: &str) -> Result<&mut D, FsError> {
+        let loc = self.lookup(cred, path)?;
+        self.fs_mut(loc.mount)
+    }
+
+    pub fn mounts(&self) -> impl Iterator<Item = &Mount<D>> {
+        self.mounts.iter()
+    }
+
+    pub fn mounts_mut(&mut self) -> impl Iterator<Item = &mut Mount<D>> {
+        self.mounts.iter_mut()
+    }
+}
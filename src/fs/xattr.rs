@@ -0,0 +1,312 @@
+//! Extended attributes.
+//!
+//! Any inode can carry `namespace.name = value` pairs next to its contents,
+//! for metadata such as a content type or where a file came from. Two
+//! namespaces exist:
+//!
+//! - `user.*`: read with read permission on the file and changed with write
+//!   permission. Only regular files and directories can be given them.
+//! - `system.*`: readable by anyone who can reach the file, changed by the
+//!   superuser only.
+//!
+//! Attributes are packed into the inline area of the inode first, and into
+//! one overflow block once that is full (layouts in `structure.rs`). Setting
+//! or removing an attribute repacks both, allocating or freeing the block as
+//! needed. Attributes are not contents, so the mtime is left alone.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::result::Result::{self, Err, Ok};
+
+use super::error::FsError;
+use super::ops::lookup;
+use super::perm::{permission, Cred, MAY_READ, MAY_WRITE};
+use super::structure::{
+    block_checksum_ok, get_u16, get_u32, put_u16, put_u32, seal_block, BlockDevice, Inode,
+    BLOCK_SIZE, CHECKSUM_OFFSET, S_IFMT, S_IFREG, XATTR_INLINE_SIZE,
+};
+use super::utils::{alloc_data_block, read_inode, release_data_block, write_inode};
+
+const BLOCK_MAGIC: u32 = 0x41585050; // "PPXA"
+
+/// Bytes of the overflow block between its magic and its checksum
+const BLOCK_AREA: usize = CHECKSUM_OFFSET - 4;
+
+/// Room for all attributes of one inode
+const CAPACITY: usize = XATTR_INLINE_SIZE + BLOCK_AREA;
+
+/// Namespace byte, name length and value length in front of each entry
+const HEADER: usize = 4;
+
+/// Longest attribute name, not counting the namespace prefix
+pub const NAME_MAX: usize = 255;
+
+/// Longest value, for an attribute with a one byte name and no others
+pub const VALUE_MAX: usize = CAPACITY - HEADER - 1;
+
+/// Namespace prefixes; on disk a namespace is its index here plus one
+const NAMESPACES: [&str; 2] = ["user", "system"];
+const NS_USER: u8 = 1;
+
+/// An attribute as reported by `list`
+pub struct Xattr<'a> {
+    pub namespace: &'static str, // `user` or `system`
+    pub name: &'a [u8],          // the rest of the name, after the dot
+    pub value: &'a [u8],
+}
+
+/// One attribute as stored: `raw` is the whole encoded entry
+#[derive(Clone, Copy)]
+struct Entry<'a> {
+    ns: u8,
+    name: &'a [u8],
+    value: &'a [u8],
+    raw: &'a [u8],
+}
+
+/// Decodes the entries of an inline area or overflow block
+struct Entries<'a>(&'a [u8]);
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        let buf = self.0;
+        if buf.len() < HEADER || buf[0] == 0 {
+            return None;
+        }
+        let name_end = HEADER + buf[1] as usize;
+        let len = name_end + get_u16(buf, 2) as usize;
+        if len > buf.len() {
+            return None;
+        }
+        self.0 = &buf[len..];
+        Some(Entry { ns: buf[0], name: &buf[HEADER..name_end], value: &buf[name_end..len], raw: &buf[..len] })
+    }
+}
+
+/// All attributes of an inode, inline and overflow, as one list
+struct XattrSet {
+    buf: [u8; CAPACITY],
+    len: usize,
+}
+
+impl XattrSet {
+    const EMPTY: XattrSet = XattrSet { buf: [0; CAPACITY], len: 0 };
+
+    fn load(device: &dyn BlockDevice, inode: &Inode) -> Result<XattrSet, FsError> {
+        let mut set = XattrSet::EMPTY;
+        for e in Entries(&inode.xattr_inline) {
+            set.push_raw(e.raw)?;
+        }
+        if inode.xattr_block != 0 {
+            let mut buf = [0u8; BLOCK_SIZE];
+            read_xattr_block(device, inode.xattr_block, &mut buf)?;
+            for e in Entries(&buf[4..CHECKSUM_OFFSET]) {
+                set.push_raw(e.raw)?;
+            }
+        }
+        Ok(set)
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Entries(&self.buf[..self.len])
+    }
+
+    fn find(&self, ns: u8, name: &[u8]) -> Option<Entry<'_>> {
+        self.entries().find(|e| e.ns == ns && e.name == name)
+    }
+
+    fn push_raw(&mut self, raw: &[u8]) -> Result<(), FsError> {
+        if self.len + raw.len() > CAPACITY {
+            return Err(FsError::NoSpace);
+        }
+        self.buf[self.len..self.len + raw.len()].copy_from_slice(raw);
+        self.len += raw.len();
+        Ok(())
+    }
+
+    fn push(&mut self, ns: u8, name: &[u8], value: &[u8]) -> Result<(), FsError> {
+        let len = HEADER + name.len() + value.len();
+        if self.len + len > CAPACITY {
+            return Err(FsError::NoSpace);
+        }
+        let e = &mut self.buf[self.len..self.len + len];
+        e[0] = ns;
+        e[1] = name.len() as u8;
+        put_u16(e, 2, value.len() as u16);
+        e[HEADER..HEADER + name.len()].copy_from_slice(name);
+        e[HEADER + name.len()..].copy_from_slice(value);
+        self.len += len;
+        Ok(())
+    }
+
+    /// A copy of the list without the attribute `ns.name`
+    fn without(&self, ns: u8, name: &[u8]) -> Result<XattrSet, FsError> {
+        let mut rest = XattrSet::EMPTY;
+        for e in self.entries().filter(|e| e.ns != ns || e.name != name) {
+            rest.push_raw(e.raw)?;
+        }
+        Ok(rest)
+    }
+
+    /// Packs the list into `inode` and its overflow block. Entries fill the
+    /// inline area in order until one does not fit; the rest overflow.
+    fn store(&self, device: &mut dyn BlockDevice, inode: &mut Inode) -> Result<(), FsError> {
+        let mut split = 0;
+        for e in self.entries() {
+            if split + e.raw.len() > XATTR_INLINE_SIZE {
+                break;
+            }
+            split += e.raw.len();
+        }
+        let (inline, overflow) = self.buf[..self.len].split_at(split);
+        if overflow.len() > BLOCK_AREA {
+            return Err(FsError::NoSpace);
+        }
+
+        if overflow.is_empty() {
+            if inode.xattr_block != 0 {
+                release_data_block(device, inode.xattr_block, inode.uid)?;
+                inode.xattr_block = 0;
+            }
+        } else {
+            if inode.xattr_block == 0 {
+                inode.xattr_block = alloc_data_block(device, inode.uid)?;
+            }
+            let mut buf = [0u8; BLOCK_SIZE];
+            put_u32(&mut buf, 0, BLOCK_MAGIC);
+            buf[4..4 + overflow.len()].copy_from_slice(overflow);
+            seal_block(&mut buf);
+            device.write_block(inode.xattr_block, &buf)?;
+        }
+        inode.xattr_inline.fill(0);
+        inode.xattr_inline[..inline.len()].copy_from_slice(inline);
+        Ok(())
+    }
+}
+
+/// Reads an overflow block, verifying its magic and checksum
+pub fn read_xattr_block(device: &dyn BlockDevice, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    device.read_block(block, buf)?;
+    if get_u32(buf, 0) != BLOCK_MAGIC || !block_checksum_ok(buf) {
+        return Err(FsError::Corrupt(block));
+    }
+    Ok(())
+}
+
+/// Splits `user.mime` into its namespace byte and `mime`
+fn parse_name(name: &str) -> Result<(u8, &[u8]), FsError> {
+    let (prefix, rest) = name.split_once('.').ok_or(FsError::Unsupported)?;
+    let ns = NAMESPACES.iter().position(|&p| p == prefix).ok_or(FsError::Unsupported)?;
+    if rest.is_empty() {
+        return Err(FsError::Unsupported);
+    }
+    if rest.len() > NAME_MAX {
+        return Err(FsError::NameTooLong);
+    }
+    Ok((ns as u8 + 1, rest.as_bytes()))
+}
+
+/// Checks that `cred` may read (`MAY_READ`) or change (`MAY_WRITE`) the
+/// attributes of namespace `ns` on `inode`
+fn may_access(inode: &Inode, cred: &Cred, ns: u8, mask: u16) -> Result<(), FsError> {
+    if ns == NS_USER {
+        let kind_ok = inode.is_dir() || inode.mode & S_IFMT == S_IFREG;
+        if mask & MAY_WRITE != 0 && !kind_ok {
+            return Err(FsError::NotPermitted);
+        }
+        permission(inode, cred, mask)
+    } else if mask & MAY_WRITE != 0 && !cred.is_root() {
+        Err(FsError::NotPermitted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Copies the value of attribute `name` of `path` into `buf`, returning its
+/// full length
+pub fn get(device: &dyn BlockDevice, cred: &Cred, path: &str, name: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+    get_at(device, cred, lookup(device, cred, path)?, name, buf)
+}
+
+/// `get` for inode `ino`
+pub fn get_at(device: &dyn BlockDevice, cred: &Cred, ino: u32, name: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+    let (ns, name) = parse_name(name)?;
+    let inode = read_inode(device, ino)?;
+    may_access(&inode, cred, ns, MAY_READ)?;
+    let set = XattrSet::load(device, &inode)?;
+    let e = set.find(ns, name).ok_or(FsError::NoAttr)?;
+    let len = e.value.len().min(buf.len());
+    buf[..len].copy_from_slice(&e.value[..len]);
+    Ok(e.value.len())
+}
+
+/// Sets attribute `name` of `path` to `value`, replacing any previous value
+pub fn set(device: &mut dyn BlockDevice, cred: &Cred, path: &str, name: &str, value: &[u8]) -> Result<(), FsError> {
+    let ino = lookup(device, cred, path)?;
+    set_at(device, cred, ino, name, value)
+}
+
+/// `set` for inode `ino`
+pub fn set_at(device: &mut dyn BlockDevice, cred: &Cred, ino: u32, name: &str, value: &[u8]) -> Result<(), FsError> {
+    let (ns, name) = parse_name(name)?;
+    let mut inode = read_inode(device, ino)?;
+    may_access(&inode, cred, ns, MAY_WRITE)?;
+    if value.len() > VALUE_MAX {
+        return Err(FsError::NoSpace);
+    }
+    let mut set = XattrSet::load(device, &inode)?.without(ns, name)?;
+    set.push(ns, name, value)?;
+    set.store(device, &mut inode)?;
+    write_inode(device, ino, &inode)
+}
+
+/// Removes attribute `name` from `path`
+pub fn remove(device: &mut dyn BlockDevice, cred: &Cred, path: &str, name: &str) -> Result<(), FsError> {
+    let ino = lookup(device, cred, path)?;
+    remove_at(device, cred, ino, name)
+}
+
+/// `remove` for inode `ino`
+pub fn remove_at(device: &mut dyn BlockDevice, cred: &Cred, ino: u32, name: &str) -> Result<(), FsError> {
+    let (ns, name) = parse_name(name)?;
+    let mut inode = read_inode(device, ino)?;
+    may_access(&inode, cred, ns, MAY_WRITE)?;
+    let set = XattrSet::load(device, &inode)?;
+    if set.find(ns, name).is_none() {
+        return Err(FsError::NoAttr);
+    }
+    set.without(ns, name)?.store(device, &mut inode)?;
+    write_inode(device, ino, &inode)
+}
+
+/// Calls `f` for every attribute of `path` the caller may read
+pub fn list(device: &dyn BlockDevice, cred: &Cred, path: &str, f: &mut dyn FnMut(&Xattr)) -> Result<(), FsError> {
+    list_at(device, cred, lookup(device, cred, path)?, f)
+}
+
+/// `list` for inode `ino`
+pub fn list_at(device: &dyn BlockDevice, cred: &Cred, ino: u32, f: &mut dyn FnMut(&Xattr)) -> Result<(), FsError> {
+    let inode = read_inode(device, ino)?;
+    let set = XattrSet::load(device, &inode)?;
+    for e in set.entries() {
+        let Some(&namespace) = NAMESPACES.get(e.ns as usize - 1) else {
+            continue;
+        };
+        if may_access(&inode, cred, e.ns, MAY_READ).is_ok() {
+            f(&Xattr { namespace, name: e.name, value: e.value });
+        }
+    }
+    Ok(())
+}
+
+/// Frees the overflow block of an inode that is being released
+pub fn release(device: &mut dyn BlockDevice, inode: &mut Inode) -> Result<(), FsError> {
+    if inode.xattr_block != 0 {
+        release_data_block(device, inode.xattr_block, inode.uid)?;
+    }
+    inode.xattr_block = 0;
+    inode.xattr_inline.fill(0);
+    Ok(())
+}
@@ -1,20 +1,138 @@
 #![cfg_attr(not(test), no_std, no_main)]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
 
 use core::clone::Clone;
 use core::option::Option::{self, None, Some};
+use core::result::Result::{Err, Ok};
 use core::panic::PanicInfo;
+use alloc::boxed::Box;
 use core::prelude::v1::derive;
+use bootloader::{entry_point, BootInfo};
 use polib::print::clear;
+use polib::{heap, initrd, interrupts, memory, procfs};
+use polib::procfs::Procfs;
 use spin::Mutex;
-use crate::fs::structure::{BlockDevice, Inode, PPDev};
-use crate::fs::utils::mkfs;
+use crate::fs::archive;
+use crate::fs::cache::BlockCache;
+use crate::fs::structure::{BlockDevice, Inode, BLOCK_SIZE};
+use crate::fs::clock::set_clock;
+use crate::fs::journal::recover;
+use crate::fs::utils::{mkfs, probe};
+use crate::fs::partition::Partition;
+use crate::fs::perm::Cred;
+use crate::fs::error::FsError;
+use crate::fs::popoen::PopoenFs;
+use crate::fs::tmpfs::Tmpfs;
+use crate::fs::vfs::{FileSystem, Vfs};
+use driver::disk::{self, volume_name, Disk, Volume};
+use driver::devfs::Devfs;
+use driver::{pci, rtc, serial};
+use driver::ramdisk::RamDisk;
 use driver::shell::shell;
-lazy_static::lazy_static!{
-    // Replacing UnsafeCell with Mutex for thread-safe access
-    pub static ref GLOBAL_DEVICE: Mutex<Option<PPDev>> = Mutex::new(None);
-    pub static ref ROOT_INODE: Mutex<Option<Inode>> = Mutex::new(None);
+
+/// The file tree: mounted filesystems and open files
+pub static VFS: Mutex<Vfs<dyn FileSystem + Send>> = Mutex::new(Vfs::new());
+
+/// Size limit of a tmpfs: a quarter of the heap
+pub const TMPFS_SIZE: usize = heap::HEAP_SIZE / 4;
+
+/// Size limit of the tmpfs the initrd is unpacked into: half the heap
+const INITRD_SIZE: usize = heap::HEAP_SIZE / 2;
+
+/// Blocks of the RAM disk used as the root when there is no disk: 1 MiB
+const RAM_ROOT_BLOCKS: u32 = 2048;
+
+
+/// Finds the first volume holding a Popoen filesystem, trying virtio disks
+/// before IDE ones
+fn find_root() -> Option<Volume> {
+    let mut found = None;
+    disk::volumes(&mut |volume| {
+        if found.is_some() {
+            return;
+        }
+        match probe(&volume) {
+            Ok(_) => found = Some(volume),
+            Err(e) => poprint!("[pomain] {}: {}\n", volume_name(&volume), e),
+        }
+    });
+    found
 }
 
+/// Mounts `fs` on directory `path` of the root filesystem, creating the
+/// directory with `perm` if needed
+fn mount_on(path: &str, perm: u16, source: &str, fs: Box<dyn FileSystem + Send>) -> Result<(), FsError> {
+    let mut vfs = VFS.lock();
+    match vfs.mkdir(&Cred::ROOT, path, perm) {
+        Ok(_) | Err(FsError::Exists) => {}
+        Err(e) => return Err(e),
+    }
+    vfs.mount(&Cred::ROOT, path, source, fs)
+}
+
+/// Unpacks the initrd `archive` into a new tmpfs mounted at `path`
+fn mount_initrd(path: &str, archive: &[u8]) -> Result<(), FsError> {
+    let fs = Box::new(Tmpfs::new(INITRD_SIZE, 0o755)?);
+    if path == "/" {
+        VFS.lock().mount(&Cred::ROOT, path, "initrd", fs)?;
+    } else {
+        mount_on(path, 0o755, "initrd", fs)?;
+    }
+    let count = archive::unpack(&mut VFS.lock(), &Cred::ROOT, path, archive)?;
+    poprint!("[pomain] {} files from the initrd\n", count);
+    Ok(())
+}
+
+/// Mounts the Popoen filesystem on volume `found` as the root, or on a RAM
+/// disk formatted for it if there is none
+fn mount_disk_root(found: Option<Volume>) -> bool {
+    let (name, mut device) = match found {
+        Some(volume) => {
+            let name = volume_name(&volume);
+            poprint!("[pomain] Found a filesystem on {}\n", name);
+            (name, BlockCache::new(volume))
+        }
+        None => {
+            poprint!("[pomain] No disk found; creating RAM disk with {} blocks...\n", RAM_ROOT_BLOCKS);
+            let ram = match RamDisk::new(BLOCK_SIZE, RAM_ROOT_BLOCKS) {
+                Ok(ram) => ram,
+                Err(e) => {
+                    poprint!("[pomain] RAM disk: {}\n", e);
+                    return false;
+                }
+            };
+            let Some(ram) = disk::add(Disk::Ram(ram)) else {
+                poprint!("[pomain] Disk table full\n");
+                return false;
+            };
+            let volume = Partition::whole(ram);
+            let name = volume_name(&volume);
+            let mut device = BlockCache::new(volume);
+            poprint!("[pomain] Formatting device with mkfs...\n");
+            if let Err(e) = mkfs(&mut device, RAM_ROOT_BLOCKS) {
+                poprint!("[pomain] mkfs: {}\n", e);
+                return false;
+            }
+            (name, device)
+        }
+    };
+
+    poprint!("[pomain] Replaying journal...\n");
+    match recover(&mut device) {
+        Ok(true) => poprint!("[pomain] Finished an interrupted update.\n"),
+        Ok(false) => {}
+        Err(e) => poprint!("[pomain] Journal: {}\n", e),
+    }
+
+    poprint!("[pomain] Mounting {} on /...\n", name);
+    if let Err(e) = VFS.lock().mount(&Cred::ROOT, "/", name.as_str(), Box::new(PopoenFs::new(device))) {
+        poprint!("[pomain] mount: {}\n", e);
+        return false;
+    }
+    true
+}
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -49,38 +167,69 @@ pub fn list_files(device: &dyn BlockDevice, inode_table: &[Inode], files: &mut [
     }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    memory::init(boot_info);
+    if !heap::init() {
+        poprint!("[kernel] Not enough memory for the heap\n");
+    }
+    interrupts::init();
     pomain();
     loop {}
 }
 pub fn pomain() {
     poprint!("[pomain] Starting kernel main...\n");
 
-    poprint!("[pomain] Creating device with 1024 blocks...\n");
-    let mut device = PPDev { blocks: [[0; 64]; 64] };
+    poprint!("[pomain] Reading the real-time clock...\n");
+    set_clock(rtc::unix_time);
+    procfs::init();
 
-    poprint!("[pomain] Formatting device with mkfs...\n");
-    mkfs(&mut device);
+    if serial::init() {
+        poprint!("[pomain] Serial port COM1 ready\n");
+    }
 
-    poprint!("[pomain] Locking GLOBAL_DEVICE...\n");
-    let mut global = GLOBAL_DEVICE.lock();
-    poprint!("[pomain] GLOBAL_DEVICE locked. Installing device...\n");
-    global.replace(device);
+    poprint!("[pomain] Enumerating PCI devices...\n");
+    let found = pci::init(driver::PCI_DRIVERS);
+    poprint!("[pomain] {} PCI functions, configured through {}\n", found, pci::access_method());
 
-    poprint!("[pomain] Creating root inode...\n");
-    let root_inode = Inode {
-        mode: 0o755,
-        size: 0,
-        direct_ptrs: [0; 12],
-        indirect_ptr: 0,
-        is_used: 1,
-    };
-    ROOT_INODE.lock().replace(root_inode);
-    poprint!("[pomain] Root inode installed.\n");
+    poprint!("[pomain] Probing disks...\n");
+    disk::detect();
+    VFS.lock().set_mount_hook(procfs::record_mounts);
+    let found = find_root();
+    match initrd::archive() {
+        Some(archive) if found.is_none() => {
+            poprint!("[pomain] No disk found; unpacking the initrd as the root...\n");
+            if let Err(e) = mount_initrd("/", archive) {
+                poprint!("[pomain] initrd: {}\n", e);
+                return;
+            }
+        }
+        archive => {
+            if !mount_disk_root(found) {
+                return;
+            }
+            if let Some(archive) = archive {
+                poprint!("[pomain] Unpacking the initrd on /initrd...\n");
+                if let Err(e) = mount_initrd("/initrd", archive) {
+                    poprint!("[pomain] initrd: {}\n", e);
+                }
+            }
+        }
+    }
+
+    poprint!("[pomain] Mounting tmpfs on /tmp, devfs on /dev and procfs on /proc...\n");
+    if let Err(e) = Tmpfs::new(TMPFS_SIZE, 0o777).and_then(|fs| mount_on("/tmp", 0o777, "tmpfs", Box::new(fs))) {
+        poprint!("[pomain] /tmp: {}\n", e);
+    }
+    if let Err(e) = mount_on("/dev", 0o755, "devfs", Box::new(Devfs::new())) {
+        poprint!("[pomain] /dev: {}\n", e);
+    }
+    if let Err(e) = mount_on("/proc", 0o555, "proc", Box::new(Procfs::new())) {
+        poprint!("[pomain] /proc: {}\n", e);
+    }
 
     poprint!("[pomain] Launching shell...\n");
-    core::mem::drop(global);
 
     shell();
 }
@@ -0,0 +1,162 @@
+//! Kernel heap.
+//!
+//! One contiguous region of `HEAP_SIZE` bytes is taken from the boot frames
+//! at start-up and reached through the physical memory mapping, so no page
+//! tables change. Free space is a list of blocks sorted by address; an
+//! allocation takes the first block it fits in, and a freed block is merged
+//! with free neighbours. Sizes and addresses are multiples of `ALIGN`, so
+//! whatever is left over on either side of an allocation is large enough to
+//! stay on the list.
+//!
+//! The lock is taken with interrupts disabled, so a handler that allocates
+//! cannot deadlock against the code it interrupted.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::option::Option::{self, None, Some};
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::memory::{alloc_frames, phys_to_virt, PAGE_SIZE};
+
+/// Bytes of memory set aside for the heap
+pub const HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+/// Granularity of blocks: room for a free block header
+const ALIGN: usize = 16;
+
+/// Header written at the start of every free block
+struct FreeBlock {
+    size: usize,
+    next: Option<&'static mut FreeBlock>,
+}
+
+struct Heap {
+    head: FreeBlock, // size 0; `next` is the free block lowest in memory
+    size: usize,
+    used: usize,
+}
+
+// The free blocks are only reached through the lock
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Hands `size` bytes at `addr` to the free list, merging it with the
+    /// blocks on either side when they touch
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev = &mut self.head;
+        while let Some(next) = prev.next.as_ref()
+            && (&**next as *const FreeBlock as usize) < addr
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
+        let prev_addr = prev as *const FreeBlock as usize;
+        let mut block = FreeBlock { size, next: prev.next.take() };
+        if let Some(next) = block.next.as_mut()
+            && addr + size == &**next as *const FreeBlock as usize
+        {
+            block.size += next.size;
+            block.next = next.next.take();
+        }
+        if prev.size != 0 && prev_addr + prev.size == addr {
+            prev.size += block.size;
+            prev.next = block.next;
+        } else {
+            let p = addr as *mut FreeBlock;
+            unsafe {
+                p.write(block);
+                prev.next = Some(&mut *p);
+            }
+        }
+    }
+
+    /// First fit: the address of `size` bytes aligned to `align`, cut out of
+    /// the free list
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev = &mut self.head;
+        loop {
+            let block = prev.next.as_mut()?;
+            let addr = &**block as *const FreeBlock as usize;
+            let start = addr.next_multiple_of(align);
+            let end = addr + block.size;
+            if start + size <= end {
+                let next = block.next.take();
+                prev.next = next;
+                unsafe {
+                    if start + size < end {
+                        self.free(start + size, end - start - size);
+                    }
+                    if start > addr {
+                        self.free(addr, start - addr);
+                    }
+                }
+                return Some(start);
+            }
+            prev = prev.next.as_mut().unwrap();
+        }
+    }
+}
+
+/// Bytes of the heap in use and in total
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    pub used: usize,
+    pub size: usize,
+}
+
+pub struct Allocator(Mutex<Heap>);
+
+#[global_allocator]
+static HEAP: Allocator = Allocator(Mutex::new(Heap { head: FreeBlock { size: 0, next: None }, size: 0, used: 0 }));
+
+/// A layout's size rounded up to whole blocks, and its alignment
+fn block_layout(layout: Layout) -> (usize, usize) {
+    (layout.size().max(1).next_multiple_of(ALIGN), layout.align().max(ALIGN))
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            match heap.alloc(size, align) {
+                Some(addr) => {
+                    heap.used += size;
+                    addr as *mut u8
+                }
+                None => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        without_interrupts(|| {
+            let mut heap = self.0.lock();
+            heap.used -= size;
+            unsafe { heap.free(ptr as usize, size) };
+        })
+    }
+}
+
+/// Sets the heap up; called once at boot, after `memory::init`. Returns
+/// false if there is not enough memory, leaving every allocation to fail.
+pub fn init() -> bool {
+    let Some(phys) = alloc_frames(HEAP_SIZE / PAGE_SIZE) else {
+        return false;
+    };
+    without_interrupts(|| {
+        let mut heap = HEAP.0.lock();
+        unsafe { heap.free(phys_to_virt(phys), HEAP_SIZE) };
+        heap.size = HEAP_SIZE;
+    });
+    true
+}
+
+pub fn stats() -> HeapStats {
+    without_interrupts(|| {
+        let heap = HEAP.0.lock();
+        HeapStats { used: heap.used, size: heap.size }
+    })
+}
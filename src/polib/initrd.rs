@@ -0,0 +1,16 @@
+//! Initial ramdisk.
+//!
+//! The bootloader loads the kernel and nothing else, so there is no
+//! separate ramdisk for it to hand over: the archive is linked into the
+//! kernel image instead, copied there by `build.rs` from the file named by
+//! `POPOEN_INITRD` when the kernel is built. `pomain` unpacks it into a
+//! tmpfs, which becomes the root when no disk holds a Popoen filesystem.
+
+use core::option::Option;
+
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd"));
+
+/// The archive the kernel was built with, if any
+pub fn archive() -> Option<&'static [u8]> {
+    (!ARCHIVE.is_empty()).then_some(ARCHIVE)
+}
@@ -0,0 +1,160 @@
+//! Hardware interrupts.
+//!
+//! The two 8259 PICs are remapped to vectors 32..48 with every line masked.
+//! A driver that wants its device's interrupt calls `register`, which
+//! installs a handler for the line and unmasks it; the keyboard and timer
+//! stay masked and are polled as before. Handlers run with interrupts off
+//! and must only touch state they share through atomics or locks that the
+//! rest of the kernel takes with interrupts disabled.
+
+extern crate x86_64;
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// Vector of IRQ 0
+const PIC_OFFSET: u8 = 32;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+const PIC_EOI: u8 = 0x20;
+const ICW1_INIT: u8 = 0x11; // initialise, ICW4 follows
+const ICW4_8086: u8 = 0x01;
+const CASCADE_IRQ: u8 = 2;
+
+/// Handler called for an IRQ line
+pub type Handler = fn();
+
+/// Handler of each line and the name of the driver that installed it
+static HANDLERS: Mutex<[Option<(Handler, &'static str)>; 16]> = Mutex::new([None; 16]);
+
+/// Interrupts taken on each line since boot
+static COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+/// Set once `init` has run
+static READY: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        let stubs: [extern "x86-interrupt" fn(InterruptStackFrame); 16] = [
+            irq0, irq1, irq2, irq3, irq4, irq5, irq6, irq7, irq8, irq9, irq10, irq11, irq12, irq13,
+            irq14, irq15,
+        ];
+        for (irq, &stub) in stubs.iter().enumerate() {
+            idt[PIC_OFFSET + irq as u8].set_handler_fn(stub);
+        }
+        idt
+    };
+}
+
+macro_rules! irq_stub {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
+            dispatch($irq);
+        }
+    };
+}
+
+irq_stub!(irq0, 0);
+irq_stub!(irq1, 1);
+irq_stub!(irq2, 2);
+irq_stub!(irq3, 3);
+irq_stub!(irq4, 4);
+irq_stub!(irq5, 5);
+irq_stub!(irq6, 6);
+irq_stub!(irq7, 7);
+irq_stub!(irq8, 8);
+irq_stub!(irq9, 9);
+irq_stub!(irq10, 10);
+irq_stub!(irq11, 11);
+irq_stub!(irq12, 12);
+irq_stub!(irq13, 13);
+irq_stub!(irq14, 14);
+irq_stub!(irq15, 15);
+
+fn outb(port: u16, value: u8) {
+    let mut p: Port<u8> = Port::new(port);
+    unsafe { p.write(value) }
+}
+
+fn inb(port: u16) -> u8 {
+    let mut p: Port<u8> = Port::new(port);
+    unsafe { p.read() }
+}
+
+fn dispatch(irq: u8) {
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    let handler = HANDLERS.lock()[irq as usize];
+    if let Some((handler, _)) = handler {
+        handler();
+    }
+    if irq >= 8 {
+        outb(PIC2_COMMAND, PIC_EOI);
+    }
+    outb(PIC1_COMMAND, PIC_EOI);
+}
+
+/// Loads the IDT, remaps the PICs with all lines masked and enables
+/// interrupts; called once at boot
+pub fn init() {
+    IDT.load();
+    outb(PIC1_COMMAND, ICW1_INIT);
+    outb(PIC2_COMMAND, ICW1_INIT);
+    outb(PIC1_DATA, PIC_OFFSET);
+    outb(PIC2_DATA, PIC_OFFSET + 8);
+    outb(PIC1_DATA, 1 << CASCADE_IRQ); // slave on IRQ 2
+    outb(PIC2_DATA, CASCADE_IRQ); // its cascade identity
+    outb(PIC1_DATA, ICW4_8086);
+    outb(PIC2_DATA, ICW4_8086);
+    outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+    outb(PIC2_DATA, 0xFF);
+    READY.store(true, Ordering::Relaxed);
+    interrupts::enable();
+}
+
+/// Installs `handler` for IRQ `irq` (0..16) on behalf of driver `name` and
+/// unmasks the line. Returns false if interrupts are not set up or the line
+/// cannot be used, in which case the driver has to poll.
+pub fn register(irq: u8, name: &'static str, handler: Handler) -> bool {
+    if !READY.load(Ordering::Relaxed) || irq >= 16 || irq == CASCADE_IRQ {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        HANDLERS.lock()[irq as usize] = Some((handler, name));
+        let (port, bit) = if irq < 8 { (PIC1_DATA, irq) } else { (PIC2_DATA, irq - 8) };
+        outb(port, inb(port) & !(1 << bit));
+    });
+    true
+}
+
+/// Interrupts taken on IRQ `irq` since boot, and the driver handling the
+/// line if there is one
+pub fn irq_stats(irq: u8) -> (u64, Option<&'static str>) {
+    let count = COUNTS.get(irq as usize).map_or(0, |c| c.load(Ordering::Relaxed));
+    let name = interrupts::without_interrupts(|| HANDLERS.lock().get(irq as usize).copied().flatten());
+    (count, name.map(|(_, name)| name))
+}
+
+/// Waits with interrupts enabled until `done` returns true, checking it at
+/// most `polls` times; returns false if it never did. It spins rather than
+/// halting: with the timer masked, nothing would wake the CPU if the
+/// interrupt it waits for were lost.
+pub fn wait_until(done: &dyn Fn() -> bool, polls: u32) -> bool {
+    interrupts::enable();
+    for _ in 0..polls {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
@@ -0,0 +1,160 @@
+//! Physical memory for device drivers.
+//!
+//! The bootloader maps all physical memory at `physical_memory_offset`, so a
+//! physical address is reachable at that offset plus the address. Frames for
+//! DMA are handed out from the usable regions of its memory map by a bump
+//! allocator; they are physically contiguous, zeroed and never freed, which
+//! suits queues and buffers set up once per device. The kernel heap
+//! (`heap.rs`) takes its one region from the same allocator.
+//!
+//! Device registers above the end of RAM may not be in the bootloader's
+//! mapping; `map_mmio` adds them at the same offset, uncached.
+
+use core::option::Option::{self, None, Some};
+use core::iter::Iterator;
+use core::result::Result::{Err, Ok};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const PAGE_SIZE: usize = 4096;
+
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    static ref FRAMES: Mutex<Option<BootFrames>> = Mutex::new(None);
+}
+
+/// Bump allocator over the usable regions of the boot memory map
+struct BootFrames {
+    map: &'static MemoryMap,
+    region: usize, // index into `map` of the region being handed out
+    next: u64,     // next free physical address in that region
+    used: u64,     // bytes handed out or skipped over
+}
+
+impl BootFrames {
+    /// `count` contiguous frames, or None once memory runs out
+    fn alloc(&mut self, count: usize) -> Option<u64> {
+        let len = (count * PAGE_SIZE) as u64;
+        while let Some(r) = self.map.get(self.region) {
+            // Frame 0 is kept out of use so 0 can mean "no address"
+            let start = self.next.max(r.range.start_addr()).max(PAGE_SIZE as u64);
+            if r.region_type == MemoryRegionType::Usable && start + len <= r.range.end_addr() {
+                self.used += start + len - self.next.max(r.range.start_addr());
+                self.next = start + len;
+                return Some(start);
+            }
+            if r.region_type == MemoryRegionType::Usable {
+                // The rest of it is too small and stays unused
+                self.used += r.range.end_addr() - self.next.max(r.range.start_addr()).min(r.range.end_addr());
+            }
+            self.region += 1;
+            self.next = 0;
+        }
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.alloc(1).map(|a| PhysFrame::containing_address(PhysAddr::new(a)))
+    }
+}
+
+/// Records the bootloader's mapping and memory map; called once at boot
+pub fn init(boot_info: &'static BootInfo) {
+    PHYS_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    FRAMES.lock().replace(BootFrames { map: &boot_info.memory_map, region: 0, next: 0, used: 0 });
+}
+
+/// Usable RAM in the boot memory map, and how much of it has been handed
+/// out, in bytes
+pub fn usage() -> (u64, u64) {
+    let frames = FRAMES.lock();
+    let Some(frames) = frames.as_ref() else {
+        return (0, 0);
+    };
+    let total = frames
+        .map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum();
+    (total, frames.used)
+}
+
+/// Where physical address `phys` can be accessed
+pub fn phys_to_virt(phys: u64) -> usize {
+    (PHYS_OFFSET.load(Ordering::Relaxed) + phys) as usize
+}
+
+/// Physically contiguous, zeroed memory a device can read and write
+pub struct Dma {
+    pub phys: u64,
+    pub virt: usize,
+    pub len: usize,
+}
+
+impl Dma {
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        (self.virt + offset) as *mut T
+    }
+}
+
+/// Allocates `pages` contiguous frames, returning the physical address of
+/// the first; their contents are left as they are
+pub fn alloc_frames(pages: usize) -> Option<u64> {
+    FRAMES.lock().as_mut()?.alloc(pages)
+}
+
+/// Allocates `pages` contiguous pages for DMA
+pub fn alloc_dma(pages: usize) -> Option<Dma> {
+    let phys = alloc_frames(pages)?;
+    let virt = phys_to_virt(phys);
+    let len = pages * PAGE_SIZE;
+    unsafe { core::ptr::write_bytes(virt as *mut u8, 0, len) };
+    Some(Dma { phys, virt, len })
+}
+
+/// The active level 4 table, seen through the physical memory mapping
+fn page_table() -> OffsetPageTable<'static> {
+    let offset = PHYS_OFFSET.load(Ordering::Relaxed);
+    let (frame, _) = Cr3::read();
+    unsafe {
+        let table = &mut *((offset + frame.start_address().as_u64()) as *mut PageTable);
+        OffsetPageTable::new(table, VirtAddr::new(offset))
+    }
+}
+
+/// Makes the device registers at `phys..phys + len` accessible, returning
+/// their virtual address
+pub fn map_mmio(phys: u64, len: usize) -> Option<usize> {
+    let mut table = page_table();
+    let mut frames = FRAMES.lock();
+    let frames = frames.as_mut()?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let first = phys & !(PAGE_SIZE as u64 - 1);
+    for addr in (first..phys + len as u64).step_by(PAGE_SIZE) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(phys_to_virt(addr) as u64));
+        if table.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        match unsafe { table.map_to(page, frame, flags, frames) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {}
+            Err(_) => return None,
+        }
+    }
+    Some(phys_to_virt(phys))
+}
@@ -1,2 +1,8 @@
+pub mod heap;
+pub mod initrd;
+pub mod interrupts;
+pub mod macros;
+pub mod memory;
 pub mod print;
-pub mod macros;
\ No newline at end of file
+pub mod process;
+pub mod procfs;
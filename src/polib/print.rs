@@ -236,3 +236,62 @@ pub fn clear() {
         set_cursor(ROW, COL);
     }
 }
+
+/// Bytes of the text screen: a character and an attribute per cell
+pub const SCREEN_SIZE: usize = WIDTH * HEIGHT * 2;
+
+/// Copies screen memory from byte `offset` into `buf`, returning how much
+pub fn read_screen(offset: usize, buf: &mut [u8]) -> usize {
+    let n = buf.len().min(SCREEN_SIZE.saturating_sub(offset));
+    for (i, b) in buf[..n].iter_mut().enumerate() {
+        *b = unsafe { ptr::read_volatile(VGA.add(offset + i)) };
+    }
+    n
+}
+
+/// Writes `data` to screen memory from byte `offset`, returning how much
+/// fits; the cursor does not move
+pub fn write_screen(offset: usize, data: &[u8]) -> usize {
+    let n = data.len().min(SCREEN_SIZE.saturating_sub(offset));
+    for (i, &b) in data[..n].iter().enumerate() {
+        unsafe { ptr::write_volatile(VGA.add(offset + i), b) };
+    }
+    n
+}
+
+/// Prints `data` as it is, in the default colours: unlike `print`, colour
+/// tags are not interpreted
+pub fn print_bytes(data: &[u8]) {
+    let (fg, bg) = reset_colors();
+    let attr = (bg << 4) | (fg & 0x0F);
+    unsafe {
+        for &c in data {
+            match c {
+                b'\n' => COL = WIDTH,
+                b'\r' => COL = 0,
+                b'\x08' | b'\x7F' => {
+                    COL = COL.saturating_sub(1);
+                    let offset = (ROW * WIDTH + COL) * 2;
+                    ptr::write_volatile(VGA.add(offset), b' ');
+                    ptr::write_volatile(VGA.add(offset + 1), attr);
+                    continue;
+                }
+                c => {
+                    let offset = (ROW * WIDTH + COL) * 2;
+                    ptr::write_volatile(VGA.add(offset), c);
+                    ptr::write_volatile(VGA.add(offset + 1), attr);
+                    COL += 1;
+                }
+            }
+            if COL >= WIDTH {
+                COL = 0;
+                ROW += 1;
+                if ROW >= HEIGHT {
+                    scroll_up();
+                    ROW = HEIGHT - 1;
+                }
+            }
+        }
+        set_cursor(ROW, COL);
+    }
+}
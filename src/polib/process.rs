@@ -0,0 +1,79 @@
+//! Process table.
+//!
+//! There is no scheduler yet: a process is a record of something running on
+//! the one CPU, its name and the credentials it acts with, so the rest of
+//! the kernel (`/proc` for one) can tell what is running. The shell enters
+//! itself when it starts and leaves on `exit`. Pids count up from 1 and are
+//! not reused.
+
+use core::iter::Iterator;
+use core::option::Option::{self, None, Some};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+use crate::fs::clock;
+use crate::fs::perm::Cred;
+
+/// Processes alive at once
+pub const MAX_PROCESSES: usize = 16;
+
+pub type Pid = u32;
+
+#[derive(Clone, Copy)]
+pub struct Process {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub cred: Cred,
+    pub start: u32, // seconds since the epoch
+}
+
+static PROCESSES: Mutex<[Option<Process>; MAX_PROCESSES]> = Mutex::new([None; MAX_PROCESSES]);
+
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+
+/// Pid of the running process; 0 while only the kernel runs
+static CURRENT: AtomicU32 = AtomicU32::new(0);
+
+/// Enters a process `name` running as `cred` and makes it the current one.
+/// Returns None if the table is full.
+pub fn spawn(name: &'static str, cred: Cred) -> Option<Pid> {
+    let mut table = PROCESSES.lock();
+    let slot = table.iter_mut().find(|p| p.is_none())?;
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    *slot = Some(Process { pid, name, cred, start: clock::now() });
+    CURRENT.store(pid, Ordering::Relaxed);
+    Some(pid)
+}
+
+/// Changes the credentials of process `pid`, as `su` does
+pub fn set_cred(pid: Pid, cred: Cred) {
+    if let Some(p) = PROCESSES.lock().iter_mut().flatten().find(|p| p.pid == pid) {
+        p.cred = cred;
+    }
+}
+
+/// Removes process `pid` from the table
+pub fn exit(pid: Pid) {
+    if let Some(slot) = PROCESSES.lock().iter_mut().find(|p| p.is_some_and(|p| p.pid == pid)) {
+        *slot = None;
+    }
+    let _ = CURRENT.compare_exchange(pid, 0, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+pub fn current() -> Pid {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+pub fn get(pid: Pid) -> Option<Process> {
+    PROCESSES.lock().iter().flatten().find(|p| p.pid == pid).copied()
+}
+
+/// Calls `f` for every process, in table order. The table is copied first,
+/// so `f` may look processes up.
+pub fn list(f: &mut dyn FnMut(&Process)) {
+    let table = *PROCESSES.lock();
+    for p in table.iter().flatten() {
+        f(p);
+    }
+}
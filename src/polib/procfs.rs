@@ -0,0 +1,360 @@
+//! Process filesystem.
+//!
+//! `Procfs` shows kernel state as read-only text files, written out afresh
+//! on every read: `meminfo`, `uptime`, `cpuinfo`, `mounts`, `interrupts`
+//! and `version`, a directory per process named by its pid holding its
+//! `status`, and `self`, a symlink to the directory of the current process.
+//! Files report size 0, as their contents are not known until read.
+//!
+//! The VFS is locked while a file is read, so `mounts` is a copy of the
+//! mount table that the VFS hands over through `record_mounts` each time it
+//! changes.
+
+use core::fmt::Write;
+use core::iter::Iterator;
+use core::option::Option::{self, Some};
+use core::result::Result::{self, Err, Ok};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::string::String;
+use spin::Mutex;
+
+use crate::fs::clock;
+use crate::fs::error::FsError;
+use crate::fs::structure::{S_IFDIR, S_IFLNK, S_IFREG};
+use crate::fs::vfs::{FileOps, FileSystem, InodeOps, Stat, Vfs};
+use super::process::{self, Pid, Process};
+use super::{heap, interrupts, memory};
+
+/// Inode number of the directory
+const ROOT: u32 = 1;
+
+/// Inode number of file 0 of `FILES`; the others follow
+const FILE_BASE: u32 = 2;
+
+/// Inode number of the `self` symlink
+const SELF: u32 = 0x80;
+
+/// Inode number of the directory of process 0; that of process `pid` is
+/// `PID_BASE + pid * PID_STRIDE`, followed by its files
+const PID_BASE: u32 = 0x100;
+const PID_STRIDE: u32 = 8;
+
+/// Writes out the contents of a top-level file
+type Generate = fn(&mut String);
+
+/// Writes out the contents of a file of process directories
+type GenerateFor = fn(&Process, &mut String);
+
+/// Top-level files and what writes out their contents
+const FILES: &[(&str, Generate)] = &[
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+    ("cpuinfo", cpuinfo),
+    ("mounts", mounts),
+    ("interrupts", irqs),
+    ("version", version),
+];
+
+/// Files in the directory of each process
+const PROCESS_FILES: &[(&str, GenerateFor)] = &[("status", status)];
+
+/// Clock reading when the kernel started, for `uptime`
+static BOOT_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// `mounts` as of the last change of the mount table
+static MOUNTS: Mutex<String> = Mutex::new(String::new());
+
+/// Notes the boot time; called once at boot, after the clock is set
+pub fn init() {
+    BOOT_TIME.store(clock::now(), Ordering::Relaxed);
+}
+
+/// Seconds since `init`
+pub fn uptime_secs() -> u32 {
+    clock::now().saturating_sub(BOOT_TIME.load(Ordering::Relaxed))
+}
+
+/// Mount hook of the VFS: copies out the mount table, one
+/// `source path type` line per mount
+pub fn record_mounts(vfs: &Vfs<dyn FileSystem + Send>) {
+    let mut text = String::new();
+    for m in vfs.mounts() {
+        let _ = writeln!(text, "{} {} {}", m.source(), m.path(), m.fs.name());
+    }
+    *MOUNTS.lock() = text;
+}
+
+fn meminfo(out: &mut String) {
+    let (total, used) = memory::usage();
+    let heap = heap::stats();
+    let _ = writeln!(out, "MemTotal:  {:>10} kB", total / 1024);
+    let _ = writeln!(out, "MemFree:   {:>10} kB", total.saturating_sub(used) / 1024);
+    let _ = writeln!(out, "MemUsed:   {:>10} kB", used / 1024);
+    let _ = writeln!(out, "HeapTotal: {:>10} kB", heap.size / 1024);
+    let _ = writeln!(out, "HeapFree:  {:>10} kB", (heap.size - heap.used) / 1024);
+    let _ = writeln!(out, "HeapUsed:  {:>10} kB", heap.used / 1024);
+}
+
+fn uptime(out: &mut String) {
+    let _ = writeln!(out, "{}", uptime_secs());
+}
+
+/// Feature bits of CPUID leaf 1, EDX and then ECX, with their names
+const CPU_FLAGS: &[(u32, u32, &str)] = &[
+    (0, 0, "fpu"),
+    (0, 4, "tsc"),
+    (0, 5, "msr"),
+    (0, 6, "pae"),
+    (0, 8, "cx8"),
+    (0, 9, "apic"),
+    (0, 15, "cmov"),
+    (0, 19, "clflush"),
+    (0, 23, "mmx"),
+    (0, 24, "fxsr"),
+    (0, 25, "sse"),
+    (0, 26, "sse2"),
+    (0, 28, "ht"),
+    (1, 0, "pni"),
+    (1, 9, "ssse3"),
+    (1, 13, "cx16"),
+    (1, 19, "sse4_1"),
+    (1, 20, "sse4_2"),
+    (1, 22, "movbe"),
+    (1, 23, "popcnt"),
+    (1, 25, "aes"),
+    (1, 26, "xsave"),
+    (1, 28, "avx"),
+    (1, 30, "rdrand"),
+    (1, 31, "hypervisor"),
+];
+
+fn cpuinfo(out: &mut String) {
+    use core::arch::x86_64::__cpuid;
+
+    let leaf0 = __cpuid(0);
+    let mut vendor = [0u8; 12];
+    vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+    let _ = writeln!(out, "vendor_id:  {}", core::str::from_utf8(&vendor).unwrap_or("?"));
+
+    let leaf1 = __cpuid(1);
+    let mut family = (leaf1.eax >> 8) & 0xF;
+    let mut model = (leaf1.eax >> 4) & 0xF;
+    if family == 0xF {
+        family += (leaf1.eax >> 20) & 0xFF;
+    }
+    if family >= 6 {
+        model += ((leaf1.eax >> 16) & 0xF) << 4;
+    }
+    let _ = writeln!(out, "cpu family: {}", family);
+    let _ = writeln!(out, "model:      {}", model);
+    let _ = writeln!(out, "stepping:   {}", leaf1.eax & 0xF);
+
+    // The brand string takes leaves 0x80000002..=0x80000004, when present
+    if __cpuid(0x8000_0000).eax >= 0x8000_0004 {
+        let mut brand = [0u8; 48];
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let r = __cpuid(leaf);
+            for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].iter().enumerate() {
+                let at = i * 16 + j * 4;
+                brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+        let len = brand.iter().position(|&b| b == 0).unwrap_or(brand.len());
+        let _ = writeln!(out, "model name: {}", core::str::from_utf8(&brand[..len]).unwrap_or("?").trim());
+    }
+
+    let _ = write!(out, "flags:     ");
+    for &(reg, bit, name) in CPU_FLAGS {
+        let value = if reg == 0 { leaf1.edx } else { leaf1.ecx };
+        if value & (1 << bit) != 0 {
+            let _ = write!(out, " {}", name);
+        }
+    }
+    out.push('\n');
+}
+
+fn mounts(out: &mut String) {
+    out.push_str(&MOUNTS.lock());
+}
+
+/// Lines that have a handler or have fired: count and driver
+fn irqs(out: &mut String) {
+    for irq in 0..16 {
+        let (count, name) = interrupts::irq_stats(irq);
+        if count != 0 || name.is_some() {
+            let _ = writeln!(out, "{:>3}: {:>10}  {}", irq, count, name.unwrap_or("-"));
+        }
+    }
+}
+
+fn version(out: &mut String) {
+    let _ = writeln!(out, "PopoenOS version {}", env!("CARGO_PKG_VERSION"));
+}
+
+fn status(p: &Process, out: &mut String) {
+    let _ = writeln!(out, "Name:    {}", p.name);
+    let _ = writeln!(out, "Pid:     {}", p.pid);
+    let _ = writeln!(out, "Uid:     {}", p.cred.uid);
+    let _ = writeln!(out, "Gid:     {}", p.cred.gid);
+    let started = p.start.saturating_sub(BOOT_TIME.load(Ordering::Relaxed));
+    let _ = writeln!(out, "Started: {} s after boot", started);
+}
+
+/// What an inode number stands for
+enum Node {
+    Root,
+    File(Generate),
+    SelfLink,
+    ProcessDir(Process),
+    ProcessFile(Process, GenerateFor),
+}
+
+fn process_dir(pid: Pid) -> Option<u32> {
+    pid.checked_mul(PID_STRIDE)?.checked_add(PID_BASE)
+}
+
+pub struct Procfs {
+    mtime: u32,
+}
+
+impl Procfs {
+    pub fn new() -> Procfs {
+        Procfs { mtime: clock::now() }
+    }
+
+    fn node(ino: u32) -> Result<Node, FsError> {
+        if ino == ROOT {
+            return Ok(Node::Root);
+        }
+        if ino == SELF {
+            return Ok(Node::SelfLink);
+        }
+        if let Some(&(_, write)) = ino.checked_sub(FILE_BASE).and_then(|i| FILES.get(i as usize)) {
+            return Ok(Node::File(write));
+        }
+        let rel = ino.checked_sub(PID_BASE).ok_or(FsError::NotFound)?;
+        let p = process::get(rel / PID_STRIDE).ok_or(FsError::NotFound)?;
+        match rel % PID_STRIDE {
+            0 => Ok(Node::ProcessDir(p)),
+            i => PROCESS_FILES
+                .get(i as usize - 1)
+                .map(|&(_, write)| Node::ProcessFile(p, write))
+                .ok_or(FsError::NotFound),
+        }
+    }
+}
+
+impl Default for Procfs {
+    fn default() -> Procfs {
+        Procfs::new()
+    }
+}
+
+impl InodeOps for Procfs {
+    fn stat(&self, ino: u32) -> Result<Stat, FsError> {
+        let (mode, links, uid, gid, mtime) = match Procfs::node(ino)? {
+            Node::Root => (S_IFDIR | 0o555, 2, 0, 0, self.mtime),
+            Node::File(_) => (S_IFREG | 0o444, 1, 0, 0, clock::now()),
+            Node::SelfLink => (S_IFLNK | 0o777, 1, 0, 0, self.mtime),
+            Node::ProcessDir(p) => (S_IFDIR | 0o555, 2, p.cred.uid, p.cred.gid, p.start),
+            Node::ProcessFile(p, _) => (S_IFREG | 0o444, 1, p.cred.uid, p.cred.gid, clock::now()),
+        };
+        Ok(Stat { ino, mode, links, uid, gid, size: 0, blocks: 0, mtime })
+    }
+
+    fn lookup(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        match Procfs::node(dir)? {
+            Node::Root => match name {
+                "." | ".." => Ok(ROOT),
+                "self" => Ok(SELF),
+                _ => {
+                    if let Some(i) = FILES.iter().position(|&(n, _)| n == name) {
+                        return Ok(FILE_BASE + i as u32);
+                    }
+                    let pid: Pid = name.parse().map_err(|_| FsError::NotFound)?;
+                    process::get(pid).and_then(|p| process_dir(p.pid)).ok_or(FsError::NotFound)
+                }
+            },
+            Node::ProcessDir(_) => match name {
+                "." => Ok(dir),
+                ".." => Ok(ROOT),
+                _ => {
+                    let i = PROCESS_FILES.iter().position(|&(n, _)| n == name).ok_or(FsError::NotFound)?;
+                    Ok(dir + 1 + i as u32)
+                }
+            },
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    fn readdir(&self, dir: u32, f: &mut dyn FnMut(&str, u32)) -> Result<(), FsError> {
+        match Procfs::node(dir)? {
+            Node::Root => {
+                f(".", ROOT);
+                f("..", ROOT);
+                for (i, &(name, _)) in FILES.iter().enumerate() {
+                    f(name, FILE_BASE + i as u32);
+                }
+                f("self", SELF);
+                process::list(&mut |p| {
+                    if let Some(ino) = process_dir(p.pid) {
+                        let mut name = String::new();
+                        let _ = write!(name, "{}", p.pid);
+                        f(&name, ino);
+                    }
+                });
+                Ok(())
+            }
+            Node::ProcessDir(_) => {
+                f(".", dir);
+                f("..", ROOT);
+                for (i, &(name, _)) in PROCESS_FILES.iter().enumerate() {
+                    f(name, dir + 1 + i as u32);
+                }
+                Ok(())
+            }
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    fn readlink(&self, ino: u32, buf: &mut [u8]) -> Result<usize, FsError> {
+        if ino != SELF {
+            return Err(FsError::InvalidPath);
+        }
+        let mut target = String::new();
+        let _ = write!(target, "{}", process::current());
+        let n = target.len().min(buf.len());
+        buf[..n].copy_from_slice(&target.as_bytes()[..n]);
+        Ok(n)
+    }
+}
+
+impl FileOps for Procfs {
+    fn read(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut text = String::new();
+        match Procfs::node(ino)? {
+            Node::File(write) => write(&mut text),
+            Node::ProcessFile(p, write) => write(&p, &mut text),
+            Node::Root | Node::ProcessDir(_) => return Err(FsError::IsDir),
+            Node::SelfLink => return Err(FsError::InvalidPath),
+        }
+        let bytes = text.as_bytes();
+        let start = (offset.min(bytes.len() as u64)) as usize;
+        let n = buf.len().min(bytes.len() - start);
+        buf[..n].copy_from_slice(&bytes[start..start + n]);
+        Ok(n)
+    }
+}
+
+impl FileSystem for Procfs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> u32 {
+        ROOT
+    }
+}